@@ -0,0 +1,92 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+
+use crate::errors::AppError;
+
+// Why a single batch item failed, in the same code/message shape as error responses
+#[derive(Debug, Serialize)]
+pub struct BatchItemError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+// Outcome of one item, identified by its position in the request
+#[derive(Debug, Serialize)]
+pub struct BatchItemResult {
+    pub index: usize,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchItemError>,
+}
+
+// Envelope shared by batch endpoints. Items succeed or fail independently, so the response is
+// always `207 Multi-Status` and clients retry only the failed indexes.
+#[derive(Debug, Default, Serialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchItemResult>,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl BatchResponse {
+    // Record the outcome of the item at `index`
+    pub fn push(&mut self, index: usize, outcome: Result<StatusCode, AppError>) {
+        let result = match outcome {
+            Ok(status) => {
+                self.succeeded += 1;
+                BatchItemResult {
+                    index,
+                    status: status.as_u16(),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                self.failed += 1;
+                BatchItemResult {
+                    index,
+                    status: e.status().as_u16(),
+                    error: Some(BatchItemError {
+                        code: e.code(),
+                        message: e.to_string(),
+                    }),
+                }
+            }
+        };
+        self.results.push(result);
+    }
+}
+
+impl IntoResponse for BatchResponse {
+    fn into_response(self) -> Response {
+        (StatusCode::MULTI_STATUS, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_each_item_and_totals() {
+        let mut batch = BatchResponse::default();
+        batch.push(0, Ok(StatusCode::CREATED));
+        batch.push(1, Err(AppError::EmailRegistered));
+        batch.push(2, Ok(StatusCode::CREATED));
+
+        assert_eq!((batch.succeeded, batch.failed), (2, 1));
+        let body = serde_json::to_value(&batch).unwrap();
+        assert_eq!(body["results"][0], serde_json::json!({ "index": 0, "status": 201 }));
+        assert_eq!(
+            body["results"][1],
+            serde_json::json!({
+                "index": 1,
+                "status": 409,
+                "error": { "code": "email_registered", "message": "Email is already registered" }
+            })
+        );
+        assert_eq!(batch.into_response().status(), StatusCode::MULTI_STATUS);
+    }
+}
@@ -0,0 +1,56 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+use crate::state::AppState;
+
+// Real client IP, resolved through the configured number of trusted proxy hops
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+// Resolve the client IP from X-Forwarded-For, counting trusted hops from the right.
+// Each trusted proxy appends the address it received the request from, so with N
+// trusted hops the client is the Nth entry from the right. Anything further left is
+// client-controlled and ignored. Falls back to the socket peer when no hops are
+// trusted or the header is missing, malformed, or shorter than the hop count.
+pub fn resolve_client_ip(headers: &HeaderMap, peer: IpAddr, trusted_proxy_hops: usize) -> IpAddr {
+    if trusted_proxy_hops == 0 {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    forwarded
+        .len()
+        .checked_sub(trusted_proxy_hops)
+        .and_then(|index| forwarded[index].parse::<IpAddr>().ok())
+        .unwrap_or(peer)
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        Ok(ClientIp(resolve_client_ip(
+            &parts.headers,
+            peer,
+            state.config.trusted_proxy_hops,
+        )))
+    }
+}
@@ -0,0 +1,108 @@
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::{errors::AppError, metrics};
+
+// A client registered in the `oauth_clients` registry
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub audience: Option<String>,
+    // bcrypt hash of the client secret; clients without one cannot use client credentials
+    pub client_secret_hash: Option<String>,
+    // Scopes granted to the client's own service tokens
+    pub scopes: Vec<String>,
+    pub token_policy: TokenPolicy,
+}
+
+// Per-client token lifetimes and refresh behaviour. Unset (or non-positive) settings fall back to
+// the global configuration; lifetimes are still clamped to MAX_TOKEN_TTL_SECONDS at issuance.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenPolicy {
+    pub access_ttl_seconds: Option<i64>,
+    pub refresh_enabled: Option<bool>,
+    pub refresh_ttl_seconds: Option<i64>,
+    // Whether each refresh returns a new refresh token (the default) or the presented one again
+    pub refresh_rotation: Option<bool>,
+}
+
+// Policy columns of `oauth_clients`, for queries joining it
+pub(crate) const TOKEN_POLICY_COLUMNS: &str =
+    "c.access_ttl_seconds, c.refresh_enabled, c.refresh_ttl_seconds, c.refresh_rotation";
+
+impl TokenPolicy {
+    pub(crate) fn from_row(row: &PgRow) -> Self {
+        let seconds = |column| row.get::<Option<i32>, _>(column).map(i64::from).filter(|ttl| *ttl > 0);
+        Self {
+            access_ttl_seconds: seconds("access_ttl_seconds"),
+            refresh_enabled: row.get("refresh_enabled"),
+            refresh_ttl_seconds: seconds("refresh_ttl_seconds"),
+            refresh_rotation: row.get("refresh_rotation"),
+        }
+    }
+
+    pub fn access_ttl_seconds(&self, default: i64) -> i64 {
+        self.access_ttl_seconds.unwrap_or(default)
+    }
+
+    pub fn refresh_enabled(&self, default: bool) -> bool {
+        self.refresh_enabled.unwrap_or(default)
+    }
+
+    pub fn refresh_ttl_seconds(&self, default: i64) -> i64 {
+        self.refresh_ttl_seconds.unwrap_or(default)
+    }
+
+    pub fn rotates_refresh_tokens(&self) -> bool {
+        self.refresh_rotation.unwrap_or(true)
+    }
+}
+
+// Look up a registered client by its id
+pub async fn find_client(pool: &PgPool, client_id: &str) -> Result<Option<Client>, AppError> {
+    let sql = format!(
+        "SELECT c.audience, c.client_secret_hash, c.scopes, {} FROM oauth_clients c WHERE c.client_id = $1",
+        TOKEN_POLICY_COLUMNS
+    );
+    let query = sqlx::query(&sql)
+        .bind(client_id)
+        .map(|row: PgRow| {
+            let scopes: Option<String> = row.get("scopes");
+            Client {
+                audience: row.get("audience"),
+                client_secret_hash: row.get("client_secret_hash"),
+                scopes: scopes
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect(),
+                token_policy: TokenPolicy::from_row(&row),
+            }
+        })
+        .fetch_optional(pool);
+    let client = metrics::observe("find_client", query).await?;
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unset_settings_fall_back_to_globals() {
+        let defaults = TokenPolicy::default();
+        assert_eq!(defaults.access_ttl_seconds(3600), 3600);
+        assert!(!defaults.refresh_enabled(false));
+        assert_eq!(defaults.refresh_ttl_seconds(7 * 86_400), 7 * 86_400);
+        assert!(defaults.rotates_refresh_tokens());
+
+        let backend = TokenPolicy {
+            access_ttl_seconds: Some(300),
+            refresh_enabled: Some(true),
+            refresh_ttl_seconds: Some(86_400),
+            refresh_rotation: Some(false),
+        };
+        assert_eq!(backend.access_ttl_seconds(3600), 300);
+        assert!(backend.refresh_enabled(false));
+        assert_eq!(backend.refresh_ttl_seconds(7 * 86_400), 86_400);
+        assert!(!backend.rotates_refresh_tokens());
+    }
+}
@@ -0,0 +1,64 @@
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::config::Config;
+
+// Source of the current time for everything time-dependent: token issuance and expiry, refresh
+// token lifetimes and rate-limit windows
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+// Wall-clock time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+// Always reports the same instant, so issued `iat`/`exp` are reproducible
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+// Manually advanced clock for exercising expiry and lockout logic without sleeping
+#[cfg(test)]
+pub struct FakeClock(Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+// The system clock, or TEST_MODE_NOW when test mode is enabled
+pub fn clock_from_config(config: &Config) -> Arc<dyn Clock> {
+    match config.test_mode_now {
+        Some(now) if config.test_mode_enabled() => {
+            warn!("Test mode clock is fixed at {}", now.to_rfc3339());
+            Arc::new(FixedClock(now))
+        }
+        _ => Arc::new(SystemClock),
+    }
+}
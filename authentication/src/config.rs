@@ -1,9 +1,255 @@
+use chrono::{DateTime, Utc};
+use jsonwebtoken::Algorithm;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{rate_limit::Limit, tarpit::TarpitPolicy};
+
+// Where a request's tenant comes from, for rate limiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TenantSource {
+    // No tenant dimension
+    None,
+    // The realm whose routes served the request; the root issuer counts as tenant `root`
+    Realm,
+    // RATE_LIMIT_TENANT_HEADER, set by a trusted gateway
+    Header,
+}
+
+// Shape of error response bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    // `{ "error": ..., "code": ... }`
+    Simple,
+    // RFC 7807 `application/problem+json`
+    Problem,
+}
+
+// Line format of the per-request access log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    // No access log
+    None,
+    // One JSON object per request
+    Json,
+    // Apache Combined Log Format
+    Combined,
+}
+
+// Source used to reject breached or common passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreachCheckerKind {
+    None,
+    Embedded,
+    Hibp,
+}
+
+// How the event producer authenticates to Kafka brokers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KafkaSaslMechanism {
+    Plain,
+    ScramSha256,
+    ScramSha512,
+}
+
+// Client-side hashes a password may arrive as with ACCEPT_CLIENT_HASH. Each is a 256-bit digest
+// sent as 64 hex characters, which fits within the 72 bytes bcrypt reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientHashScheme {
+    // Hex SHA-256 of the password, which the server can also compute itself
+    Sha256,
+    // Hex PBKDF2-HMAC-SHA256 of the password, with a salt and iteration count only the client knows
+    Pbkdf2Sha256,
+}
+
+impl ClientHashScheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientHashScheme::Sha256 => "sha256",
+            ClientHashScheme::Pbkdf2Sha256 => "pbkdf2-sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(ClientHashScheme::Sha256),
+            "pbkdf2-sha256" => Some(ClientHashScheme::Pbkdf2Sha256),
+            _ => None,
+        }
+    }
+}
+
+// Format of issued access tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenFormat {
+    // RS256 or ES256 JWT, verifiable via the JWKS and OIDC discovery endpoints
+    Jwt,
+    // PASETO v4.public, signed with Ed25519
+    Paseto,
+}
+
+// Shape of the `aud` claim in issued tokens, for relying parties that only accept one of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudienceClaimFormat {
+    String,
+    Array,
+}
+
+// `typ` header of issued JWT access tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessTokenType {
+    Jwt,
+    // RFC 9068 JWT access token, which stricter resource servers require
+    AtJwt,
+}
+
+impl AccessTokenType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessTokenType::Jwt => "JWT",
+            AccessTokenType::AtJwt => "at+jwt",
+        }
+    }
+}
+
+// How logins by users whose email address hasn't been verified are treated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnverifiedEmailPolicy {
+    // As if verification didn't exist; tokens carry no `email_verified`
+    Allow,
+    // Log in with `email_verified: false` and a short-lived access token
+    Warn,
+    // Refuse with `email_unverified`
+    Block,
+}
+
+// Response to a login from implausibly far away given the time since the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoVelocityAction {
+    // No geolocation at all
+    Off,
+    // Let the login through, but audit it and email the user
+    Notify,
+    // Audit it and refuse the password login until the user proves email access with a code
+    StepUp,
+}
+
+// A previous signing key, still published in the JWKS so tokens it signed keep verifying
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetiredKey {
+    pub kid: String,
+    pub public_key_path: String,
+    pub retired_at: DateTime<Utc>,
+}
+
+// A public key accepted when verifying our own tokens but never used to sign, e.g. the previous
+// key during a migration to another JWT algorithm, until the tokens it signed have expired
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JwtVerificationKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub public_key_path: String,
+}
+
+// A signing key reserved for tokens issued to particular audiences, so partners that each trust
+// their own key can't be handed one another's tokens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudienceKey {
+    pub kid: String,
+    pub audiences: Vec<String>,
+    pub private_key_path: String,
+    pub public_key_path: String,
+}
+
+// A logical issuer served under `/realms/<name>` with its own signing key and audience. Realms
+// share the user store; they separate who issues and verifies tokens, not who can log in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Realm {
+    pub name: String,
+    pub issuer: String,
+    // Signing key in the configured TOKEN_FORMAT
+    pub private_key_path: String,
+    pub public_key_path: String,
+    // X.509 certificate for the key, whose thumbprint goes in `x5t#S256`
+    pub certificate_path: Option<String>,
+    pub key_id: String,
+    pub audience: Option<String>,
+}
+
+impl Realm {
+    pub fn path_prefix(&self) -> String {
+        format!("/realms/{}", self.name)
+    }
+}
+
+// Password requirements, configured globally and optionally overridden per role
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    // Distinct character classes required: lowercase, uppercase, digit, symbol
+    pub min_character_classes: usize,
+    // Minimum estimated strength: length × log2 of the character pool in use
+    pub min_strength_bits: u32,
+    // Minimum estimated entropy, which unlike strength discounts repeated characters
+    pub min_entropy_bits: u32,
+    // Reject passwords below `min_entropy_bits`; otherwise they are only logged
+    pub enforce_min_entropy: bool,
+}
+
+impl PasswordPolicy {
+    // Apply `key:value,key:value` overrides; unknown keys and unparsable values are ignored
+    fn with_overrides(mut self, spec: &str) -> Self {
+        for (key, value) in spec.split(',').filter_map(|entry| entry.split_once(':')) {
+            let value = value.trim();
+            match key.trim() {
+                "min_length" => self.min_length = value.parse().unwrap_or(self.min_length),
+                "min_character_classes" => {
+                    self.min_character_classes = value.parse().unwrap_or(self.min_character_classes)
+                }
+                "min_strength_bits" => self.min_strength_bits = value.parse().unwrap_or(self.min_strength_bits),
+                "min_entropy_bits" => self.min_entropy_bits = value.parse().unwrap_or(self.min_entropy_bits),
+                _ => {}
+            }
+        }
+        self
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub rsa_private_key_path: String,
+    // Decrypts RSA private keys stored as encrypted PKCS#8, whichever issuer or audience they sign for
+    pub rsa_private_key_passphrase: Option<String>,
     pub rsa_public_key_path: String,
+    pub rsa_certificate_path: Option<String>,
+    pub token_format: TokenFormat,
+    pub access_token_type: AccessTokenType,
+    // Algorithm of the active signing key: RS256, or ES256 with a P-256 key
+    pub jwt_signing_algorithm: Algorithm,
+    pub jwt_allowed_algorithms: Vec<Algorithm>,
+    pub jwt_verification_keys: Vec<JwtVerificationKey>,
+    // Internal claim name → name emitted in JWT access tokens, for relying parties with fixed
+    // expectations
+    pub claim_name_map: HashMap<String, String>,
+    // Refuse to start when a configured key doesn't suit the token format/algorithms
+    pub validate_keys_at_startup: bool,
+    pub require_schema_at_startup: bool,
+    pub paseto_private_key_path: String,
     pub product_key_id: String,
+    pub retired_keys: Vec<RetiredKey>,
+    pub audience_keys: Vec<AudienceKey>,
+    pub retired_key_grace_seconds: i64,
+    pub key_last_good_fallback: bool,
+    pub key_reload_retry_seconds: u64,
+    pub jwks_max_keys: usize,
+    pub jwks_active_first: bool,
+    pub dpop_enabled: bool,
+    pub session_handles_enabled: bool,
+    pub session_handle_revokes_access_token: bool,
+    pub dpop_proof_max_age_seconds: i64,
+    pub openid_configuration_max_age_seconds: u64,
     pub base_url: String,
+    pub jwt_issuer: Option<String>,
+    pub realms: Vec<Realm>,
     pub postgres_user: String,
     pub postgres_password: String,
     pub postgres_host: String,
@@ -13,21 +259,631 @@ pub struct Config {
     pub app_version: String,
     pub deployment_environment: String,
     pub otel_exporter_otlp_endpoint: String,
+    pub otel_metrics_enabled: bool,
+    pub otel_metrics_export_interval_seconds: u64,
     pub port: String,
     pub internal_api_key: String,
+    pub previous_internal_api_key: Option<String>,
+    // Required while a previous key is set; startup refuses to go on without it
+    pub internal_key_rotated_at: Option<DateTime<Utc>>,
+    pub internal_key_rotation_grace_seconds: i64,
+    pub trusted_proxy_hops: usize,
+    pub tenant_source: TenantSource,
+    pub tenant_header: String,
+    // Shared rate-limit store for deployments with several replicas
+    pub redis_url: Option<String>,
+    pub redis_timeout_ms: u64,
+    // Realm whose routes this configuration serves; None at the root
+    pub realm_name: Option<String>,
+    pub breach_checker: BreachCheckerKind,
+    pub hibp_api_url: String,
+    pub jwt_audience: Option<String>,
+    pub audience_claim_format: AudienceClaimFormat,
+    pub debug_capture: bool,
+    pub access_log_format: AccessLogFormat,
+    pub db_schema: Option<String>,
+    pub db_statement_cache_capacity: usize,
+    pub db_pooler_compat: bool,
+    pub runtime_config_file: String,
+    pub registration_enabled: bool,
+    // Require a code emailed to the address before registering it
+    pub verify_email_before_register: bool,
+    pub feature_flags_refresh_seconds: u64,
+    pub health_check_timeout_ms: u64,
+    pub geo_velocity_action: GeoVelocityAction,
+    pub geoip_database_path: Option<String>,
+    pub geo_velocity_max_kmh: f64,
+    pub unverified_email_policy: UnverifiedEmailPolicy,
+    pub unverified_email_token_ttl_seconds: i64,
+    pub unique_username: bool,
+    pub unique_email: bool,
+    pub block_confusable_usernames: bool,
+    pub min_password_age_hours: i64,
+    pub sensitive_op_max_age_seconds: i64,
+    pub page_size_default: u32,
+    pub page_size_max: u32,
+    pub auth_cookies_enabled: bool,
+    pub introspection_accept_cookie: bool,
+    pub allowed_email_domains: Vec<String>,
+    pub blocked_email_domains: Vec<String>,
+    pub block_disposable_email_domains: bool,
+    pub password_policy: PasswordPolicy,
+    pub role_password_policies: HashMap<String, PasswordPolicy>,
+    pub data_export_sections: Vec<String>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: String,
+    pub webhook_events: Vec<String>,
+    pub event_queue_capacity: usize,
+    pub kafka_brokers: Vec<String>,
+    pub kafka_topic: Option<String>,
+    // TLS to the brokers, trusting KAFKA_TLS_CA_PATH or else the public web roots
+    pub kafka_tls: bool,
+    pub kafka_tls_ca_path: Option<String>,
+    pub kafka_sasl_mechanism: Option<KafkaSaslMechanism>,
+    pub kafka_sasl_username: String,
+    pub kafka_sasl_password: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub mail_from: String,
+    pub email_login_enabled: bool,
+    pub password_policy_endpoint_enabled: bool,
+    pub email_login_code_ttl_seconds: i64,
+    pub email_login_max_attempts: u32,
+    pub refresh_tokens_enabled: bool,
+    pub refresh_ttl_days: i64,
+    pub refresh_sliding: bool,
+    pub refresh_binding: bool,
+    pub refresh_absolute_max_days: i64,
+    pub refresh_reuse_window_seconds: i64,
+    pub slow_query_threshold_ms: u64,
+    pub client_token_ttl_seconds: i64,
+    pub impersonation_token_ttl_seconds: i64,
+    pub tarpit_max_concurrent: usize,
+    pub trusted_issuers: HashMap<String, String>,
+    pub token_exchange_audience: Option<String>,
+    pub token_exchange_subject_claim: String,
+    pub jwks_cache_ttl_seconds: u64,
+    pub max_token_ttl_seconds: i64,
+    // Tokens longer than this are rejected before being decoded; 0 disables the limit
+    pub max_token_bytes: usize,
+    pub clock_skew_seconds: i64,
+    // Claims every verified token must carry, or it is rejected as invalid
+    pub required_claims: Vec<String>,
+    pub hash_timeout_seconds: u64,
+    // Whether passwords may be submitted as a client-side hash, and under which schemes
+    pub accept_client_hash: bool,
+    pub client_hash_algorithms: Vec<ClientHashScheme>,
+    // Whether a client-side hash may set a new password, skipping the policy and breach check the
+    // server can't run on it. Roles with their own ROLE_PASSWORD_POLICIES entry never allow it
+    pub client_hash_skips_password_policy: bool,
+    pub max_concurrent_requests: usize,
+    // Locales error messages are translated into when `Accept-Language` asks for them
+    pub error_locales: Vec<String>,
+    pub queue_wait_timeout_ms: u64,
+    pub shutdown_hook_timeout_seconds: u64,
+    pub include_permissions: bool,
+    pub role_permissions: HashMap<String, Vec<String>>,
+    pub selftest_username: Option<String>,
+    pub selftest_password: Option<String>,
+    pub selftest_interval_seconds: u64,
+    pub test_mode: bool,
+    pub test_mode_now: Option<DateTime<Utc>>,
+}
+
+// Looks up a raw configuration value by name
+type Lookup<'a> = dyn Fn(&str) -> Option<String> + 'a;
+
+// Read values from the process environment
+fn env_lookup(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+// Parse a boolean flag, accepting "true"/"1" (case-insensitive)
+fn read_flag(lookup: &Lookup<'_>, name: &str, default: bool) -> bool {
+    lookup(name)
+        .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+        .unwrap_or(default)
+}
+
+// Parse a comma-separated list, dropping empty entries
+fn read_list(lookup: &Lookup<'_>, name: &str) -> Vec<String> {
+    lookup(name)
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Whether `algorithm` is one this service can sign and verify JWTs with, given a key of its type
+pub fn is_supported_jwt_algorithm(algorithm: Algorithm) -> bool {
+    matches!(
+        algorithm,
+        Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512
+            | Algorithm::ES256
+    )
+}
+
+// Parse the JWT algorithms accepted at verification. Only RSA algorithms and ES256 have keys we
+// can verify with; anything else (including `none`) is dropped, and an empty list falls back to
+// the signing algorithm
+fn read_jwt_algorithms(lookup: &Lookup<'_>, name: &str, signing_algorithm: Algorithm) -> Vec<Algorithm> {
+    let algorithms: Vec<Algorithm> = read_list(lookup, name)
+        .iter()
+        .filter_map(|alg| alg.parse().ok())
+        .filter(|alg| is_supported_jwt_algorithm(*alg))
+        .collect();
+    if algorithms.is_empty() {
+        vec![signing_algorithm]
+    } else {
+        algorithms
+    }
+}
+
+// Parse a `name=issuer;name=issuer` realm map. Each realm's keys come from `REALM_<NAME>_*`
+// settings, defaulting to `keys/<name>/`; names must be lowercase alphanumerics, `-` or `_`
+fn read_realms(lookup: &Lookup<'_>, name: &str, base_url: &str, default_audience: Option<&str>) -> Vec<Realm> {
+    let mut realms: Vec<Realm> = read_map(lookup, name, "")
+        .into_iter()
+        .filter(|(name, _)| {
+            name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+        })
+        .map(|(name, issuer)| {
+            let setting = |suffix: &str| {
+                lookup(&format!("REALM_{}_{}", name.to_ascii_uppercase().replace('-', "_"), suffix))
+                    .filter(|v| !v.is_empty())
+            };
+            Realm {
+                issuer: Some(issuer)
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| format!("{}/realms/{}", base_url, name)),
+                private_key_path: setting("PRIVATE_KEY_PATH")
+                    .unwrap_or_else(|| format!("keys/{}/private_key.pem", name)),
+                public_key_path: setting("PUBLIC_KEY_PATH")
+                    .unwrap_or_else(|| format!("keys/{}/public_key.pem", name)),
+                certificate_path: setting("CERTIFICATE_PATH"),
+                key_id: setting("KEY_ID").unwrap_or_else(|| format!("{}-key-1", name)),
+                audience: setting("AUDIENCE").or_else(|| default_audience.map(str::to_string)),
+                name,
+            }
+        })
+        .collect();
+    realms.sort_by(|a, b| a.name.cmp(&b.name));
+    realms
+}
+
+// Parse a `kid=audience audience;kid=audience` map of per-audience signing keys. Each key's files
+// come from `AUDIENCE_KEY_<KID>_*` settings, defaulting to `keys/<kid>/`; kids follow the realm
+// name rules
+fn read_audience_keys(lookup: &Lookup<'_>, name: &str) -> Vec<AudienceKey> {
+    let mut keys: Vec<AudienceKey> = read_map(lookup, name, "")
+        .into_iter()
+        .filter(|(kid, _)| kid.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'))
+        .map(|(kid, audiences)| {
+            let setting = |suffix: &str| {
+                lookup(&format!("AUDIENCE_KEY_{}_{}", kid.to_ascii_uppercase().replace('-', "_"), suffix))
+                    .filter(|v| !v.is_empty())
+            };
+            AudienceKey {
+                audiences: audiences.split_whitespace().map(str::to_string).collect(),
+                private_key_path: setting("PRIVATE_KEY_PATH").unwrap_or_else(|| format!("keys/{}/private_key.pem", kid)),
+                public_key_path: setting("PUBLIC_KEY_PATH").unwrap_or_else(|| format!("keys/{}/public_key.pem", kid)),
+                kid,
+            }
+        })
+        .filter(|key| !key.audiences.is_empty())
+        .collect();
+    keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+    keys
+}
+
+// Parse a `key=value;key=value` map, falling back to the default
+fn read_map(lookup: &Lookup<'_>, name: &str, default: &str) -> HashMap<String, String> {
+    lookup(name)
+        .unwrap_or_else(|| default.to_string())
+        .split(';')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .filter(|(key, _)| !key.is_empty())
+        .collect()
+}
+
+// Claims that token libraries and protocols look up by name, so they're never renamed
+const UNMAPPABLE_CLAIMS: [&str; 7] = ["exp", "nbf", "iat", "iss", "aud", "jti", "cnf"];
+
+// Parse `internal=output;...` claim renames. Renaming a reserved claim, or two claims to one
+// name, would make tokens unverifiable, so such entries are dropped.
+fn read_claim_name_map(lookup: &Lookup<'_>, name: &str) -> HashMap<String, String> {
+    let map = read_map(lookup, name, "");
+    let reserved = |claim: &String| UNMAPPABLE_CLAIMS.contains(&claim.as_str());
+    map.iter()
+        .filter(|(internal, output)| {
+            !output.is_empty()
+                && internal != output
+                && !reserved(internal)
+                && !reserved(output)
+                && map.values().filter(|other| other == output).count() == 1
+        })
+        .map(|(internal, output)| (internal.clone(), output.clone()))
+        .collect()
+}
+
+// Parse a value, falling back to the default when unset or invalid
+fn read_parse<T: std::str::FromStr>(lookup: &Lookup<'_>, name: &str, default: T) -> T {
+    lookup(name)
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+// Parse JWT_VERIFICATION_KEYS as `kid=ALG:public_key_path;...`, dropping entries with an algorithm
+// we can't verify
+fn read_jwt_verification_keys(lookup: &Lookup<'_>, name: &str) -> Vec<JwtVerificationKey> {
+    let mut keys: Vec<JwtVerificationKey> = read_map(lookup, name, "")
+        .into_iter()
+        .filter_map(|(kid, spec)| {
+            let (algorithm, path) = spec.split_once(':')?;
+            let algorithm: Algorithm = algorithm.trim().parse().ok()?;
+            is_supported_jwt_algorithm(algorithm).then(|| JwtVerificationKey {
+                kid,
+                algorithm,
+                public_key_path: path.trim().to_string(),
+            })
+        })
+        .collect();
+    keys.sort_by(|a, b| a.kid.cmp(&b.kid));
+    keys
+}
+
+// Parse `kid=path@retired_at;kid=path@retired_at`, with RFC 3339 retirement times; malformed
+// entries are dropped
+fn read_retired_keys(lookup: &Lookup<'_>, name: &str) -> Vec<RetiredKey> {
+    read_map(lookup, name, "")
+        .into_iter()
+        .filter_map(|(kid, spec)| {
+            let (path, retired_at) = spec.rsplit_once('@')?;
+            Some(RetiredKey {
+                kid,
+                public_key_path: path.trim().to_string(),
+                retired_at: DateTime::parse_from_rfc3339(retired_at.trim()).ok()?.with_timezone(&Utc),
+            })
+        })
+        .collect()
+}
+
+// Parse `KEY=VALUE` lines from an env file; a missing file yields no overrides
+fn read_env_file(path: &str) -> HashMap<String, String> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.trim_start_matches("export ").split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect()
+}
+
+// Settings that are safe to change while running; reloaded without a restart
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeConfig {
+    pub log_level: String,
+    pub enumeration_safe_registration: bool,
+    pub error_format: ErrorFormat,
+    pub login_single_flight: bool,
+    pub role_scopes: HashMap<String, Vec<String>>,
+    pub rate_limit_ip_per_minute: u32,
+    pub rate_limit_user_per_minute: u32,
+    pub rate_limit_export_per_minute: u32,
+    pub rate_limit_impersonate_per_minute: u32,
+    // Allowance shared by all of a tenant's requests, and per-tenant exceptions to it
+    pub rate_limit_tenant_per_minute: u32,
+    pub rate_limit_tenant_limits: HashMap<String, u32>,
+    pub tarpit_base_delay_ms: u64,
+    pub tarpit_max_delay_ms: u64,
+    pub tarpit_window_seconds: u64,
+}
+
+impl RuntimeConfig {
+    fn from_lookup(lookup: &Lookup<'_>) -> Self {
+        Self {
+            log_level: lookup("RUST_LOG").unwrap_or_else(|| "info".to_string()),
+            enumeration_safe_registration: read_flag(lookup, "ENUMERATION_SAFE_REGISTRATION", false),
+            error_format: match lookup("ERROR_FORMAT").as_deref() {
+                Some("problem") => ErrorFormat::Problem,
+                _ => ErrorFormat::Simple,
+            },
+            login_single_flight: read_flag(lookup, "LOGIN_SINGLE_FLIGHT", false),
+            role_scopes: read_map(lookup, "ROLE_SCOPES", "user=openid profile email;admin=openid profile email admin")
+                .into_iter()
+                .map(|(role, scopes)| (role, scopes.split_whitespace().map(str::to_string).collect()))
+                .collect(),
+            rate_limit_ip_per_minute: read_parse(lookup, "RATE_LIMIT_IP_PER_MINUTE", 60),
+            rate_limit_user_per_minute: read_parse(lookup, "RATE_LIMIT_USER_PER_MINUTE", 10),
+            rate_limit_export_per_minute: read_parse(lookup, "RATE_LIMIT_EXPORT_PER_MINUTE", 1),
+            rate_limit_impersonate_per_minute: read_parse(lookup, "RATE_LIMIT_IMPERSONATE_PER_MINUTE", 5),
+            rate_limit_tenant_per_minute: read_parse(lookup, "RATE_LIMIT_TENANT_PER_MINUTE", 0),
+            rate_limit_tenant_limits: read_map(lookup, "RATE_LIMIT_TENANT_LIMITS", "")
+                .into_iter()
+                .filter_map(|(tenant, per_minute)| Some((tenant, per_minute.parse().ok()?)))
+                .collect(),
+            tarpit_base_delay_ms: read_parse(lookup, "TARPIT_BASE_DELAY_MS", 0),
+            tarpit_max_delay_ms: read_parse(lookup, "TARPIT_MAX_DELAY_MS", 5000),
+            tarpit_window_seconds: read_parse(lookup, "TARPIT_WINDOW_SECONDS", 900),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::from_lookup(&env_lookup)
+    }
+
+    // Re-read runtime settings, letting values in the given env file override the environment
+    pub fn reload(path: &str) -> Self {
+        let overrides = read_env_file(path);
+        Self::from_lookup(&|name: &str| overrides.get(name).cloned().or_else(|| env_lookup(name)))
+    }
+
+    // Per-IP and per-user limits applied together; whichever is exhausted first rejects. Within a
+    // tenant, those buckets are the tenant's own, and the tenant's overall allowance comes last.
+    pub fn rate_limits(&self, client_ip: impl std::fmt::Display, tenant: Option<&str>, user: &str) -> Vec<Limit> {
+        let dimension = |name: &str| match tenant {
+            Some(tenant) => format!("{}/{}", tenant, name),
+            None => name.to_string(),
+        };
+        let mut limits = vec![
+            Limit::new(&dimension("ip"), client_ip, self.rate_limit_ip_per_minute),
+            Limit::new(&dimension("user"), user, self.rate_limit_user_per_minute),
+        ];
+        limits.extend(tenant.map(|tenant| self.tenant_limit(tenant)));
+        limits
+    }
+
+    // A tenant's overall allowance: its RATE_LIMIT_TENANT_LIMITS entry, or the default
+    pub fn tenant_limit(&self, tenant: &str) -> Limit {
+        let per_minute = self.rate_limit_tenant_limits.get(tenant).copied().unwrap_or(self.rate_limit_tenant_per_minute);
+        Limit::new("tenant", tenant, per_minute)
+    }
+
+    // Delay policy for failed logins, or `None` when the tarpit is disabled
+    pub fn tarpit_policy(&self) -> Option<TarpitPolicy> {
+        (self.tarpit_base_delay_ms > 0).then(|| TarpitPolicy {
+            base: Duration::from_millis(self.tarpit_base_delay_ms),
+            max: Duration::from_millis(self.tarpit_max_delay_ms),
+            window: Duration::from_secs(self.tarpit_window_seconds),
+        })
+    }
+
+    // Human-readable list of settings that differ from `other`
+    pub fn changes(&self, other: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, old: String, new: String| {
+            if old != new {
+                changes.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+        compare("log_level", self.log_level.clone(), other.log_level.clone());
+        compare(
+            "enumeration_safe_registration",
+            self.enumeration_safe_registration.to_string(),
+            other.enumeration_safe_registration.to_string(),
+        );
+        compare("error_format", format!("{:?}", self.error_format), format!("{:?}", other.error_format));
+        compare(
+            "login_single_flight",
+            self.login_single_flight.to_string(),
+            other.login_single_flight.to_string(),
+        );
+        let sorted = |scopes: &HashMap<String, Vec<String>>| {
+            let mut entries: Vec<_> = scopes.iter().collect();
+            entries.sort();
+            format!("{:?}", entries)
+        };
+        compare("role_scopes", sorted(&self.role_scopes), sorted(&other.role_scopes));
+        compare(
+            "rate_limit_ip_per_minute",
+            self.rate_limit_ip_per_minute.to_string(),
+            other.rate_limit_ip_per_minute.to_string(),
+        );
+        compare(
+            "rate_limit_user_per_minute",
+            self.rate_limit_user_per_minute.to_string(),
+            other.rate_limit_user_per_minute.to_string(),
+        );
+        compare(
+            "rate_limit_export_per_minute",
+            self.rate_limit_export_per_minute.to_string(),
+            other.rate_limit_export_per_minute.to_string(),
+        );
+        compare(
+            "rate_limit_impersonate_per_minute",
+            self.rate_limit_impersonate_per_minute.to_string(),
+            other.rate_limit_impersonate_per_minute.to_string(),
+        );
+        compare(
+            "rate_limit_tenant_per_minute",
+            self.rate_limit_tenant_per_minute.to_string(),
+            other.rate_limit_tenant_per_minute.to_string(),
+        );
+        let sorted_limits = |limits: &HashMap<String, u32>| {
+            let mut entries: Vec<_> = limits.iter().collect();
+            entries.sort();
+            format!("{:?}", entries)
+        };
+        compare(
+            "rate_limit_tenant_limits",
+            sorted_limits(&self.rate_limit_tenant_limits),
+            sorted_limits(&other.rate_limit_tenant_limits),
+        );
+        compare(
+            "tarpit_base_delay_ms",
+            self.tarpit_base_delay_ms.to_string(),
+            other.tarpit_base_delay_ms.to_string(),
+        );
+        compare(
+            "tarpit_max_delay_ms",
+            self.tarpit_max_delay_ms.to_string(),
+            other.tarpit_max_delay_ms.to_string(),
+        );
+        compare(
+            "tarpit_window_seconds",
+            self.tarpit_window_seconds.to_string(),
+            other.tarpit_window_seconds.to_string(),
+        );
+        changes
+    }
 }
 
 impl Config {
+    // This configuration as seen from within `realm`: its issuer, keys and audience replace the
+    // top-level ones, and discovery URLs move under its path prefix
+    pub fn for_realm(&self, realm: &Realm) -> Config {
+        Config {
+            rsa_private_key_path: realm.private_key_path.clone(),
+            rsa_public_key_path: realm.public_key_path.clone(),
+            rsa_certificate_path: realm.certificate_path.clone(),
+            paseto_private_key_path: realm.private_key_path.clone(),
+            product_key_id: realm.key_id.clone(),
+            retired_keys: Vec::new(),
+            audience_keys: Vec::new(),
+            jwt_verification_keys: Vec::new(),
+            realm_name: Some(realm.name.clone()),
+            base_url: format!("{}{}", self.base_url, realm.path_prefix()),
+            jwt_issuer: Some(realm.issuer.clone()),
+            jwt_audience: realm.audience.clone(),
+            realms: Vec::new(),
+            ..self.clone()
+        }
+    }
+
+    // The dedicated signing key for tokens issued to `audience`; an audience listed under several
+    // keys gets the first by kid
+    pub fn audience_key(&self, audience: Option<&str>) -> Option<&AudienceKey> {
+        let audience = audience?;
+        self.audience_keys.iter().find(|key| key.audiences.iter().any(|a| a == audience))
+    }
+
+    // This configuration signing with `key` in place of the default key
+    pub fn with_audience_key(&self, key: &AudienceKey) -> Config {
+        Config {
+            rsa_private_key_path: key.private_key_path.clone(),
+            rsa_public_key_path: key.public_key_path.clone(),
+            rsa_certificate_path: None,
+            product_key_id: key.kid.clone(),
+            ..self.clone()
+        }
+    }
+
+    // Body capture is a diagnostic aid and is never honoured in production
+    pub fn debug_capture_enabled(&self) -> bool {
+        self.debug_capture && self.deployment_environment != "production"
+    }
+
+    // Embedded test keys and a fixed clock are for integration tests and never honoured in production
+    pub fn test_mode_enabled(&self) -> bool {
+        self.test_mode && self.deployment_environment != "production"
+    }
+
+    // How long to keep serving the last good key material after a key file fails to load, or None
+    // to fail requests instead
+    pub fn key_fallback_retry(&self) -> Option<std::time::Duration> {
+        self.key_last_good_fallback
+            .then(|| std::time::Duration::from_secs(self.key_reload_retry_seconds))
+    }
+
+    // Algorithm a retired key signed with: RS256, unless JWT_VERIFICATION_KEYS lists its kid under
+    // another
+    pub fn retired_key_algorithm(&self, kid: &str) -> Algorithm {
+        self.jwt_verification_keys
+            .iter()
+            .find(|key| key.kid == kid)
+            .map_or(Algorithm::RS256, |key| key.algorithm)
+    }
+
+    // Clock difference tolerated when judging token timestamps
+    pub fn clock_skew(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.clock_skew_seconds.max(0))
+    }
+
+    // The synthetic-probe account only ever authenticates through the selftest endpoint
+    pub fn is_selftest_user(&self, username: &str) -> bool {
+        self.selftest_username.as_deref() == Some(username)
+    }
+
     pub fn from_env() -> Self {
+        let password_policy = PasswordPolicy {
+            min_length: read_parse(&env_lookup, "PASSWORD_MIN_LENGTH", 8),
+            min_character_classes: read_parse(&env_lookup, "PASSWORD_MIN_CHARACTER_CLASSES", 1),
+            min_strength_bits: read_parse(&env_lookup, "PASSWORD_MIN_STRENGTH_BITS", 0),
+            min_entropy_bits: read_parse(&env_lookup, "PASSWORD_MIN_ENTROPY_BITS", 0),
+            enforce_min_entropy: read_flag(&env_lookup, "PASSWORD_ENTROPY_ENFORCED", true),
+        };
+        let role_password_policies = read_map(&env_lookup, "ROLE_PASSWORD_POLICIES", "")
+            .into_iter()
+            .map(|(role, spec)| (role, password_policy.with_overrides(&spec)))
+            .collect();
+        let base_url = std::env::var("BASE_URL").unwrap_or_else(|_| "http://authentication:8082".to_string());
+        let jwt_audience = std::env::var("JWT_AUDIENCE").ok().filter(|v| !v.is_empty());
+        let jwt_signing_algorithm = match std::env::var("JWT_SIGNING_ALGORITHM").as_deref() {
+            Ok("ES256") => Algorithm::ES256,
+            _ => Algorithm::RS256,
+        };
+
         Self {
             rsa_private_key_path: std::env::var("RSA_PRIVATE_KEY_PATH")
                 .unwrap_or_else(|_| "keys/private_key.pem".to_string()),
+            rsa_private_key_passphrase: std::env::var("RSA_PRIVATE_KEY_PASSPHRASE").ok().filter(|v| !v.is_empty()),
             rsa_public_key_path: std::env::var("RSA_PUBLIC_KEY_PATH")
                 .unwrap_or_else(|_| "keys/public_key.pem".to_string()),
+            rsa_certificate_path: std::env::var("RSA_CERTIFICATE_PATH").ok().filter(|v| !v.is_empty()),
+            token_format: match std::env::var("TOKEN_FORMAT").as_deref() {
+                Ok("paseto") => TokenFormat::Paseto,
+                _ => TokenFormat::Jwt,
+            },
+            access_token_type: match std::env::var("ACCESS_TOKEN_TYP").as_deref() {
+                Ok("at+jwt") => AccessTokenType::AtJwt,
+                _ => AccessTokenType::Jwt,
+            },
+            jwt_signing_algorithm,
+            jwt_allowed_algorithms: read_jwt_algorithms(&env_lookup, "JWT_ALLOWED_ALGORITHMS", jwt_signing_algorithm),
+            jwt_verification_keys: read_jwt_verification_keys(&env_lookup, "JWT_VERIFICATION_KEYS"),
+            claim_name_map: read_claim_name_map(&env_lookup, "CLAIM_NAME_MAP"),
+            validate_keys_at_startup: read_flag(&env_lookup, "VALIDATE_KEYS_AT_STARTUP", true),
+            require_schema_at_startup: read_flag(&env_lookup, "REQUIRE_SCHEMA_AT_STARTUP", false),
+            paseto_private_key_path: std::env::var("PASETO_PRIVATE_KEY_PATH")
+                .unwrap_or_else(|_| "keys/paseto_private_key.pem".to_string()),
             product_key_id: std::env::var("PRODUCT_KEY_ID")
                 .unwrap_or_else(|_| "product-service-key-1".to_string()),
-            base_url: std::env::var("BASE_URL")
-                .unwrap_or_else(|_| "http://authentication:8082".to_string()),
+            retired_keys: read_retired_keys(&env_lookup, "RETIRED_KEYS"),
+            audience_keys: read_audience_keys(&env_lookup, "AUDIENCE_KEYS"),
+            retired_key_grace_seconds: read_parse(&env_lookup, "RETIRED_KEY_GRACE_SECONDS", 24 * 3600),
+            key_last_good_fallback: read_flag(&env_lookup, "KEY_LAST_GOOD_FALLBACK", true),
+            key_reload_retry_seconds: read_parse(&env_lookup, "KEY_RELOAD_RETRY_SECONDS", 30),
+            jwks_max_keys: read_parse(&env_lookup, "JWKS_MAX_KEYS", 5),
+            jwks_active_first: read_flag(&env_lookup, "JWKS_ACTIVE_FIRST", true),
+            dpop_enabled: read_flag(&env_lookup, "DPOP_ENABLED", false),
+            dpop_proof_max_age_seconds: read_parse(&env_lookup, "DPOP_PROOF_MAX_AGE_SECONDS", 300),
+            session_handles_enabled: read_flag(&env_lookup, "SESSION_HANDLES_ENABLED", false),
+            session_handle_revokes_access_token: read_flag(&env_lookup, "SESSION_HANDLE_REVOKES_ACCESS_TOKEN", true),
+            openid_configuration_max_age_seconds: read_parse(&env_lookup, "OPENID_CONFIGURATION_MAX_AGE_SECONDS", 3600),
+            realms: read_realms(&env_lookup, "REALMS", &base_url, jwt_audience.as_deref()),
+            base_url,
+            jwt_issuer: std::env::var("JWT_ISSUER").ok().filter(|v| !v.is_empty()),
             postgres_user: std::env::var("POSTGRES_USER")
                 .unwrap_or_else(|_| "devops".to_string()),
             postgres_password: std::env::var("POSTGRES_PASSWORD")
@@ -38,6 +894,9 @@ impl Config {
                 .unwrap_or_else(|_| "5432".to_string()),
             postgres_db: std::env::var("POSTGRES_DB")
                 .unwrap_or_else(|_| "products-db".to_string()),
+            db_schema: std::env::var("DB_SCHEMA").ok().filter(|v| !v.is_empty()),
+            db_statement_cache_capacity: read_parse(&env_lookup, "DB_STATEMENT_CACHE_CAPACITY", 100),
+            db_pooler_compat: read_flag(&env_lookup, "DB_POOLER_COMPAT", false),
             otel_service_name: std::env::var("OTEL_SERVICE_NAME")
                 .unwrap_or_else(|_| "craftista-authentication".to_string()),
             app_version: std::env::var("APP_VERSION")
@@ -46,10 +905,156 @@ impl Config {
                 .unwrap_or_else(|_| "production".to_string()),
             otel_exporter_otlp_endpoint: std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
                 .unwrap_or_else(|_| "http://otel-collector:4318/v1/traces".to_string()),
+            otel_metrics_enabled: read_flag(&env_lookup, "OTEL_METRICS_ENABLED", false),
+            otel_metrics_export_interval_seconds: read_parse(&env_lookup, "OTEL_METRICS_EXPORT_INTERVAL_SECONDS", 60),
             port: std::env::var("PORT")
                 .unwrap_or_else(|_| "8082".to_string()),
             internal_api_key: std::env::var("INTERNAL_API_KEY")
                 .unwrap_or_else(|_| "a-super-secret-key".to_string()),
+            previous_internal_api_key: std::env::var("PREVIOUS_INTERNAL_API_KEY").ok().filter(|v| !v.is_empty()),
+            internal_key_rotated_at: std::env::var("INTERNAL_KEY_ROTATED_AT")
+                .ok()
+                .and_then(|v| DateTime::parse_from_rfc3339(v.trim()).ok())
+                .map(|rotated_at| rotated_at.with_timezone(&Utc)),
+            internal_key_rotation_grace_seconds: read_parse(&env_lookup, "INTERNAL_KEY_ROTATION_GRACE", 24 * 3600),
+            trusted_proxy_hops: read_parse(&env_lookup, "TRUSTED_PROXY_HOPS", 0),
+            tenant_source: match std::env::var("RATE_LIMIT_TENANT_SOURCE").as_deref() {
+                Ok("realm") => TenantSource::Realm,
+                Ok("header") => TenantSource::Header,
+                _ => TenantSource::None,
+            },
+            tenant_header: std::env::var("RATE_LIMIT_TENANT_HEADER").unwrap_or_else(|_| "X-Tenant-ID".to_string()),
+            redis_url: std::env::var("REDIS_URL").ok().filter(|v| !v.is_empty()),
+            redis_timeout_ms: read_parse(&env_lookup, "REDIS_TIMEOUT_MS", 250),
+            realm_name: None,
+            breach_checker: match std::env::var("BREACH_CHECKER").as_deref() {
+                Ok("none") => BreachCheckerKind::None,
+                Ok("hibp") => BreachCheckerKind::Hibp,
+                _ => BreachCheckerKind::Embedded,
+            },
+            hibp_api_url: std::env::var("HIBP_API_URL")
+                .unwrap_or_else(|_| "https://api.pwnedpasswords.com/range/".to_string()),
+            jwt_audience,
+            audience_claim_format: match std::env::var("AUDIENCE_CLAIM_FORMAT").as_deref() {
+                Ok("array") => AudienceClaimFormat::Array,
+                _ => AudienceClaimFormat::String,
+            },
+            debug_capture: read_flag(&env_lookup, "DEBUG_CAPTURE", false),
+            access_log_format: match std::env::var("ACCESS_LOG_FORMAT").as_deref() {
+                Ok("none") => AccessLogFormat::None,
+                Ok("combined") => AccessLogFormat::Combined,
+                _ => AccessLogFormat::Json,
+            },
+            runtime_config_file: std::env::var("RUNTIME_CONFIG_FILE")
+                .unwrap_or_else(|_| ".env".to_string()),
+            registration_enabled: read_flag(&env_lookup, "REGISTRATION_ENABLED", true),
+            verify_email_before_register: read_flag(&env_lookup, "VERIFY_EMAIL_BEFORE_REGISTER", false),
+            feature_flags_refresh_seconds: read_parse(&env_lookup, "FEATURE_FLAGS_REFRESH_SECONDS", 10),
+            health_check_timeout_ms: read_parse(&env_lookup, "HEALTH_CHECK_TIMEOUT_MS", 2000),
+            geo_velocity_action: match std::env::var("GEO_VELOCITY_ACTION").as_deref() {
+                Ok("notify") => GeoVelocityAction::Notify,
+                Ok("step_up") => GeoVelocityAction::StepUp,
+                _ => GeoVelocityAction::Off,
+            },
+            geoip_database_path: std::env::var("GEOIP_DATABASE_PATH").ok().filter(|v| !v.is_empty()),
+            geo_velocity_max_kmh: read_parse(&env_lookup, "GEO_VELOCITY_MAX_KMH", 900.0),
+            unverified_email_policy: match std::env::var("UNVERIFIED_EMAIL_POLICY").as_deref() {
+                Ok("warn") => UnverifiedEmailPolicy::Warn,
+                Ok("block") => UnverifiedEmailPolicy::Block,
+                _ => UnverifiedEmailPolicy::Allow,
+            },
+            unverified_email_token_ttl_seconds: read_parse(&env_lookup, "UNVERIFIED_EMAIL_TOKEN_TTL_SECONDS", 300),
+            unique_username: read_flag(&env_lookup, "UNIQUE_USERNAME", true),
+            unique_email: read_flag(&env_lookup, "UNIQUE_EMAIL", true),
+            block_confusable_usernames: read_flag(&env_lookup, "BLOCK_CONFUSABLE_USERNAMES", false),
+            min_password_age_hours: read_parse(&env_lookup, "MIN_PASSWORD_AGE_HOURS", 0),
+            sensitive_op_max_age_seconds: read_parse(&env_lookup, "SENSITIVE_OP_MAX_AGE", 0),
+            page_size_default: read_parse(&env_lookup, "PAGE_SIZE_DEFAULT", 20),
+            page_size_max: read_parse(&env_lookup, "PAGE_SIZE_MAX", 100),
+            auth_cookies_enabled: read_flag(&env_lookup, "AUTH_COOKIES_ENABLED", false),
+            introspection_accept_cookie: read_flag(&env_lookup, "INTROSPECTION_ACCEPT_COOKIE", false),
+            allowed_email_domains: read_list(&env_lookup, "ALLOWED_EMAIL_DOMAINS"),
+            blocked_email_domains: read_list(&env_lookup, "BLOCKED_EMAIL_DOMAINS"),
+            block_disposable_email_domains: read_flag(&env_lookup, "BLOCK_DISPOSABLE_EMAIL_DOMAINS", false),
+            password_policy,
+            role_password_policies,
+            data_export_sections: match read_list(&env_lookup, "DATA_EXPORT_SECTIONS") {
+                sections if sections.is_empty() => vec!["profile".to_string(), "logins".to_string(), "sessions".to_string()],
+                sections => sections,
+            },
+            webhook_url: std::env::var("WEBHOOK_URL").ok().filter(|v| !v.is_empty()),
+            webhook_secret: std::env::var("WEBHOOK_SECRET").unwrap_or_default(),
+            webhook_events: read_list(&env_lookup, "WEBHOOK_EVENTS"),
+            event_queue_capacity: read_parse(&env_lookup, "EVENT_QUEUE_CAPACITY", 1000),
+            kafka_brokers: read_list(&env_lookup, "KAFKA_BROKERS"),
+            kafka_topic: std::env::var("KAFKA_TOPIC").ok().filter(|v| !v.is_empty()),
+            kafka_tls: read_flag(&env_lookup, "KAFKA_TLS", false),
+            kafka_tls_ca_path: std::env::var("KAFKA_TLS_CA_PATH").ok().filter(|v| !v.is_empty()),
+            kafka_sasl_mechanism: match std::env::var("KAFKA_SASL_MECHANISM").as_deref() {
+                Ok("PLAIN") => Some(KafkaSaslMechanism::Plain),
+                Ok("SCRAM-SHA-256") => Some(KafkaSaslMechanism::ScramSha256),
+                Ok("SCRAM-SHA-512") => Some(KafkaSaslMechanism::ScramSha512),
+                _ => None,
+            },
+            kafka_sasl_username: std::env::var("KAFKA_SASL_USERNAME").unwrap_or_default(),
+            kafka_sasl_password: std::env::var("KAFKA_SASL_PASSWORD").unwrap_or_default(),
+            smtp_host: std::env::var("SMTP_HOST").ok().filter(|v| !v.is_empty()),
+            smtp_port: read_parse(&env_lookup, "SMTP_PORT", 25),
+            mail_from: std::env::var("MAIL_FROM")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .unwrap_or_else(|| "no-reply@localhost".to_string()),
+            email_login_enabled: read_flag(&env_lookup, "EMAIL_LOGIN_ENABLED", false),
+            password_policy_endpoint_enabled: read_flag(&env_lookup, "PASSWORD_POLICY_ENDPOINT_ENABLED", true),
+            email_login_code_ttl_seconds: read_parse(&env_lookup, "EMAIL_LOGIN_CODE_TTL_SECONDS", 600),
+            email_login_max_attempts: read_parse(&env_lookup, "EMAIL_LOGIN_MAX_ATTEMPTS", 5),
+            refresh_tokens_enabled: read_flag(&env_lookup, "REFRESH_TOKENS_ENABLED", false),
+            refresh_ttl_days: read_parse(&env_lookup, "REFRESH_TTL_DAYS", 7),
+            refresh_sliding: read_flag(&env_lookup, "REFRESH_SLIDING", false),
+            refresh_binding: read_flag(&env_lookup, "REFRESH_BINDING", false),
+            refresh_absolute_max_days: read_parse(&env_lookup, "REFRESH_ABSOLUTE_MAX_DAYS", 30),
+            refresh_reuse_window_seconds: read_parse(&env_lookup, "REFRESH_REUSE_WINDOW_SECONDS", 0),
+            slow_query_threshold_ms: read_parse(&env_lookup, "SLOW_QUERY_THRESHOLD_MS", 200),
+            client_token_ttl_seconds: read_parse(&env_lookup, "CLIENT_TOKEN_TTL_SECONDS", 300),
+            impersonation_token_ttl_seconds: read_parse(&env_lookup, "IMPERSONATION_TOKEN_TTL_SECONDS", 900),
+            tarpit_max_concurrent: read_parse(&env_lookup, "TARPIT_MAX_CONCURRENT", 100),
+            trusted_issuers: read_map(&env_lookup, "TRUSTED_ISSUERS", ""),
+            token_exchange_audience: std::env::var("TOKEN_EXCHANGE_AUDIENCE").ok().filter(|v| !v.is_empty()),
+            token_exchange_subject_claim: std::env::var("TOKEN_EXCHANGE_SUBJECT_CLAIM")
+                .ok()
+                .unwrap_or_else(|| "sub".to_string()),
+            jwks_cache_ttl_seconds: read_parse(&env_lookup, "JWKS_CACHE_TTL_SECONDS", 3600),
+            max_token_ttl_seconds: read_parse(&env_lookup, "MAX_TOKEN_TTL_SECONDS", 30 * 24 * 3600),
+            max_token_bytes: read_parse(&env_lookup, "MAX_TOKEN_BYTES", 8192),
+            clock_skew_seconds: read_parse(&env_lookup, "CLOCK_SKEW_SECONDS", 60),
+            required_claims: match read_list(&env_lookup, "REQUIRED_CLAIMS") {
+                claims if claims.is_empty() => ["sub", "exp", "iat"].map(str::to_string).to_vec(),
+                claims => claims,
+            },
+            hash_timeout_seconds: read_parse(&env_lookup, "HASH_TIMEOUT_SECONDS", 10),
+            accept_client_hash: read_flag(&env_lookup, "ACCEPT_CLIENT_HASH", false),
+            client_hash_algorithms: match read_list(&env_lookup, "CLIENT_HASH_ALGORITHMS") {
+                names if names.is_empty() => vec![ClientHashScheme::Pbkdf2Sha256],
+                names => names.iter().filter_map(|name| ClientHashScheme::from_name(name)).collect(),
+            },
+            client_hash_skips_password_policy: read_flag(&env_lookup, "CLIENT_HASH_SKIPS_PASSWORD_POLICY", false),
+            max_concurrent_requests: read_parse(&env_lookup, "MAX_CONCURRENT_REQUESTS", 0),
+            error_locales: read_list(&env_lookup, "ERROR_LOCALES"),
+            queue_wait_timeout_ms: read_parse(&env_lookup, "QUEUE_WAIT_TIMEOUT_MS", 0),
+            shutdown_hook_timeout_seconds: read_parse(&env_lookup, "SHUTDOWN_HOOK_TIMEOUT_SECONDS", 5),
+            include_permissions: read_flag(&env_lookup, "INCLUDE_PERMISSIONS", false),
+            role_permissions: read_map(&env_lookup, "ROLE_PERMISSIONS", "")
+                .into_iter()
+                .map(|(role, permissions)| (role, permissions.split_whitespace().map(str::to_string).collect()))
+                .collect(),
+            selftest_username: std::env::var("SELFTEST_USERNAME").ok().filter(|v| !v.is_empty()),
+            selftest_password: std::env::var("SELFTEST_PASSWORD").ok().filter(|v| !v.is_empty()),
+            selftest_interval_seconds: read_parse(&env_lookup, "SELFTEST_INTERVAL_SECONDS", 0),
+            test_mode: read_flag(&env_lookup, "TEST_MODE", false),
+            test_mode_now: std::env::var("TEST_MODE_NOW")
+                .ok()
+                .and_then(|v| DateTime::parse_from_rfc3339(v.trim()).ok())
+                .map(|now| now.with_timezone(&Utc)),
         }
     }
 }
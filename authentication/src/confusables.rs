@@ -0,0 +1,71 @@
+use std::{collections::HashMap, sync::OnceLock};
+use unicode_normalization::UnicodeNormalization;
+
+// Embedded confusables table in the UTS #39 `confusables.txt` format
+const CONFUSABLES: &str = include_str!("data/confusables.txt");
+
+// Invisible characters that would otherwise let two identical-looking names differ
+const IGNORABLE: [char; 8] = [
+    '\u{00AD}', '\u{034F}', '\u{180E}', '\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}',
+];
+
+// Parse `source ; target ; type # comment` lines, where source and target are hex code points
+fn parse_table(table: &str) -> HashMap<char, String> {
+    let code_points = |field: &str| -> Option<String> {
+        field
+            .split_whitespace()
+            .map(|hex| u32::from_str_radix(hex, 16).ok().and_then(char::from_u32))
+            .collect()
+    };
+    table
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| {
+            let mut fields = line.split(';');
+            let source = code_points(fields.next()?)?;
+            let target = code_points(fields.next()?)?;
+            let mut source = source.chars();
+            match (source.next(), source.next()) {
+                (Some(source), None) if !target.is_empty() => Some((source, target)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn table() -> &'static HashMap<char, String> {
+    static TABLE: OnceLock<HashMap<char, String>> = OnceLock::new();
+    TABLE.get_or_init(|| parse_table(CONFUSABLES))
+}
+
+// UTS #39 skeleton of a username: two names with the same skeleton look alike. Applies NFKD,
+// drops invisible characters, replaces each confusable with its prototype, then applies NFD.
+pub fn skeleton(username: &str) -> String {
+    let table = table();
+    let mut mapped = String::with_capacity(username.len());
+    for c in username.nfkd().filter(|c| !IGNORABLE.contains(c)) {
+        match table.get(&c) {
+            Some(prototype) => mapped.push_str(prototype),
+            None => mapped.push(c),
+        }
+    }
+    mapped.nfd().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookalikes_share_a_skeleton() {
+        // Cyrillic а and о, fullwidth letters, a zero-width space, digit one and "rn"
+        assert_eq!(skeleton("p\u{0430}yp\u{043E}l"), skeleton("paypol"));
+        assert_eq!(skeleton("\u{FF41}lice"), skeleton("alice"));
+        assert_eq!(skeleton("ali\u{200B}ce"), skeleton("alice"));
+        assert_eq!(skeleton("a1ice"), skeleton("alice"));
+        assert_eq!(skeleton("rnallory"), skeleton("mallory"));
+
+        assert_ne!(skeleton("alice"), skeleton("alicia"));
+        assert_ne!(skeleton("bob"), skeleton("Bob"));
+    }
+}
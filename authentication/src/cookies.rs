@@ -0,0 +1,97 @@
+use axum::http::{header, HeaderMap, HeaderValue, Method};
+use base64::{engine::general_purpose, Engine as _};
+use rand::{rngs::OsRng, RngCore};
+
+use crate::errors::AppError;
+
+// HttpOnly cookie carrying the access token for browser clients
+pub const ACCESS_TOKEN_COOKIE: &str = "access_token";
+// Readable cookie whose value must be echoed in `X-CSRF-Token` (double-submit)
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+// Random, URL-safe CSRF token
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Value of the named cookie from the request's `Cookie` headers
+pub fn read_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+// `Set-Cookie` headers for a cookie-based session: the access token, HttpOnly so scripts can't
+// read it, and a fresh CSRF token the client's scripts must read and send back
+pub fn session_cookies(access_token: &str, max_age: i64) -> HeaderMap {
+    let attributes = format!("Path=/; Max-Age={}; Secure; SameSite=Lax", max_age);
+    let mut headers = HeaderMap::new();
+    for cookie in [
+        format!("{}={}; {}; HttpOnly", ACCESS_TOKEN_COOKIE, access_token, attributes),
+        format!("{}={}; {}", CSRF_COOKIE, random_token(), attributes),
+    ] {
+        if let Ok(value) = HeaderValue::from_str(&cookie) {
+            headers.append(header::SET_COOKIE, value);
+        }
+    }
+    headers
+}
+
+// Require a matching `X-CSRF-Token` on state-changing requests authenticated by cookie.
+// Browsers attach cookies to cross-site requests but a forged page can't read them to set the header.
+pub fn check_csrf(method: &Method, headers: &HeaderMap) -> Result<(), AppError> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+    let cookie = read_cookie(headers, CSRF_COOKIE);
+    let header = headers.get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.is_empty() && cookie == header => Ok(()),
+        _ => Err(AppError::CsrfFailed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(cookie: &str, csrf_header: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(cookie).unwrap());
+        if let Some(value) = csrf_header {
+            headers.insert(CSRF_HEADER, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn accepts_matching_csrf_header() {
+        let headers = headers("access_token=abc; csrf_token=xyz", Some("xyz"));
+        assert!(check_csrf(&Method::POST, &headers).is_ok());
+    }
+
+    #[test]
+    fn rejects_mismatched_or_missing_csrf_header() {
+        let mismatched = headers("access_token=abc; csrf_token=xyz", Some("other"));
+        assert!(matches!(check_csrf(&Method::POST, &mismatched), Err(AppError::CsrfFailed)));
+        let missing = headers("access_token=abc; csrf_token=xyz", None);
+        assert!(matches!(check_csrf(&Method::DELETE, &missing), Err(AppError::CsrfFailed)));
+        let no_cookie = headers("access_token=abc", Some(""));
+        assert!(matches!(check_csrf(&Method::POST, &no_cookie), Err(AppError::CsrfFailed)));
+    }
+
+    #[test]
+    fn safe_methods_need_no_csrf_header() {
+        let headers = headers("access_token=abc; csrf_token=xyz", None);
+        assert!(check_csrf(&Method::GET, &headers).is_ok());
+    }
+}
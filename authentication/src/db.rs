@@ -0,0 +1,109 @@
+use sqlx::postgres::{PgConnectOptions, PgPool};
+use tracing::{info, warn};
+
+use crate::{config::Config, errors::is_undefined_table};
+
+// Schema names are interpolated into DDL, so only plain identifiers are accepted
+fn is_plain_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Statements cached per connection behind a pooler when DB_STATEMENT_CACHE_CAPACITY is 0
+const POOLER_STATEMENT_CACHE_CAPACITY: usize = 100;
+
+// Prepared statements cached per connection. sqlx prepares a named statement for every query
+// with arguments, in its own round trip before Bind and Execute, whatever the capacity; a capacity
+// of 0 only stops reusing them and never closes them. Behind a pooler the cache stays on, so the
+// statements are bounded and evicted ones are closed.
+fn statement_cache_capacity(config: &Config) -> usize {
+    match config.db_statement_cache_capacity {
+        0 if config.db_pooler_compat => POOLER_STATEMENT_CACHE_CAPACITY,
+        capacity => capacity,
+    }
+}
+
+// Connection options built from config, scoping every connection to DB_SCHEMA when set
+fn connect_options(config: &Config) -> PgConnectOptions {
+    let port = config
+        .postgres_port
+        .parse()
+        .expect("POSTGRES_PORT must be a valid port number");
+    let options = PgConnectOptions::new()
+        .host(&config.postgres_host)
+        .port(port)
+        .username(&config.postgres_user)
+        .password(&config.postgres_password)
+        .database(&config.postgres_db)
+        .statement_cache_capacity(statement_cache_capacity(config));
+    match &config.db_schema {
+        Some(schema) => options.options([("search_path", schema.as_str())]),
+        None => options,
+    }
+}
+
+// Check that the schema has been initialized, so a deployment whose migrations haven't run is
+// reported as such up front rather than by its first login failing with a database error
+pub async fn check_schema(pool: &PgPool) -> Result<(), String> {
+    match sqlx::query("SELECT 1 FROM users LIMIT 0").execute(pool).await {
+        Ok(_) => Ok(()),
+        Err(e) if is_undefined_table(&e) => Err(
+            "schema not initialized: the users table does not exist; run database/init-db.sql or the migrations against this database".to_string(),
+        ),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Run the parameterized lookups login and registration depend on, on two connections held at
+// once. A transaction-mode pooler that doesn't track prepared statements (PgBouncer before 1.21,
+// or with max_prepared_statements = 0) fails them with "prepared statement ... already exists" or
+// "does not exist", as the statements sqlx prepares on one server connection are bound on another.
+pub async fn check_prepared_statements(pool: &PgPool) -> Result<(), String> {
+    let mut first = pool.acquire().await.map_err(|e| e.to_string())?;
+    let mut second = pool.acquire().await.map_err(|e| e.to_string())?;
+    for _ in 0..3 {
+        for connection in [&mut first, &mut second] {
+            sqlx::query("SELECT id FROM users WHERE username = $1")
+                .bind("pooler-check")
+                .fetch_optional(&mut **connection)
+                .await
+                .map_err(|e| e.to_string())?;
+            sqlx::query("SELECT id FROM users WHERE email = $1")
+                .bind("pooler-check@localhost")
+                .fetch_optional(&mut **connection)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// Connect to Postgres, creating the configured schema if it doesn't exist yet
+pub async fn connect(config: &Config) -> PgPool {
+    if let Some(schema) = &config.db_schema {
+        assert!(is_plain_identifier(schema), "DB_SCHEMA must be a plain SQL identifier, got {:?}", schema);
+        // PgBouncer rejects the `options` startup parameter that carries search_path, and a
+        // per-session `SET` wouldn't survive transaction pooling
+        assert!(!config.db_pooler_compat, "DB_SCHEMA is not supported with DB_POOLER_COMPAT");
+    }
+    if config.db_pooler_compat && config.db_statement_cache_capacity == 0 {
+        warn!(
+            "DB_STATEMENT_CACHE_CAPACITY=0 would leave a prepared statement open per query behind the pooler; caching {} per connection instead",
+            POOLER_STATEMENT_CACHE_CAPACITY
+        );
+    }
+    let pool = PgPool::connect_with(connect_options(config))
+        .await
+        .expect("Failed to connect to Postgres");
+    if let Some(schema) = &config.db_schema {
+        info!("Using database schema: {}", schema);
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS \"{}\"", schema))
+            .execute(&pool)
+            .await
+            .expect("Failed to create database schema");
+    }
+    pool
+}
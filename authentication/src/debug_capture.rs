@@ -0,0 +1,128 @@
+use axum::{
+    body::{to_bytes, Body, Bytes},
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use tracing::debug;
+
+// Largest body buffered for capture, matching axum's default request body limit
+const MAX_CAPTURE_BYTES: usize = 2 * 1024 * 1024;
+
+// Field-name fragments whose values are never logged. Session handles and emailed login and
+// registration codes (`code`, `email_code`) are credentials too; this also hides error codes in
+// response bodies, whose status is still logged.
+const SENSITIVE_FIELDS: [&str; 6] = ["password", "token", "secret", "hash", "handle", "code"];
+
+const REDACTED: &str = "[REDACTED]";
+
+// Replace the value of every sensitive field, at any depth, with a placeholder
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if SENSITIVE_FIELDS.iter().any(|fragment| key.contains(fragment)) {
+                    *field = Value::String(REDACTED.to_string());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+// Render a body for logging only if it parses as JSON; anything else is never logged
+fn redacted_body(bytes: &Bytes) -> Option<String> {
+    if bytes.is_empty() {
+        return None;
+    }
+    let mut value: Value = serde_json::from_slice(bytes).ok()?;
+    redact(&mut value);
+    Some(value.to_string())
+}
+
+// Logs redacted request and response bodies at debug level. Only installed when
+// DEBUG_CAPTURE is enabled outside production.
+pub async fn capture(req: Request, next: Next) -> Response {
+    let (parts, body) = req.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_CAPTURE_BYTES).await else {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    };
+    let method = parts.method.clone();
+    let path = parts.uri.path().to_string();
+    match redacted_body(&bytes) {
+        Some(body) => debug!("{} {} request body: {}", method, path, body),
+        None => debug!("{} {} request body not captured", method, path),
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(bytes))).await;
+
+    let (parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_CAPTURE_BYTES).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match redacted_body(&bytes) {
+        Some(body) => debug!("{} {} response {} body: {}", method, path, parts.status, body),
+        None => debug!("{} {} response {} body not captured", method, path, parts.status),
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TokenResponse;
+
+    fn captured<T: serde::Serialize>(body: &T) -> String {
+        redacted_body(&Bytes::from(serde_json::to_vec(body).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn redacts_credentials_in_a_login_response() {
+        let body = captured(&TokenResponse {
+            access_token: "eyJhbGciOi.payload.signature".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: Some("refresh-secret".to_string()),
+            issued_token_type: None,
+            email_verified: Some(true),
+            session_handle: Some("handle-secret".to_string()),
+            warnings: Vec::new(),
+        });
+        for credential in ["eyJhbGciOi", "refresh-secret", "handle-secret"] {
+            assert!(!body.contains(credential), "{} leaked in {}", credential, body);
+        }
+        assert!(body.contains(r#""token_type":"[REDACTED]""#));
+        assert!(body.contains(r#""expires_in":3600"#));
+    }
+
+    #[test]
+    fn redacts_the_emailed_login_code() {
+        let body = captured(&serde_json::json!({
+            "email": "alice@example.com",
+            "code": "482913",
+            "client_id": "web",
+        }));
+        assert!(!body.contains("482913"), "login code leaked in {}", body);
+        assert!(body.contains(r#""client_id":"web""#));
+    }
+
+    #[test]
+    fn redacts_the_registration_email_code() {
+        let body = captured(&serde_json::json!({
+            "username": "alice",
+            "email": "alice@example.com",
+            "password": "Str0ng-unique-pass!",
+            "email_code": "739104",
+        }));
+        for credential in ["Str0ng-unique-pass!", "739104"] {
+            assert!(!body.contains(credential), "{} leaked in {}", credential, body);
+        }
+        assert!(body.contains(r#""username":"alice""#));
+    }
+}
@@ -0,0 +1,337 @@
+use axum::http::{request::Parts, HeaderMap, Method};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, errors::AppError, models::Claims};
+
+// Request header carrying the proof, and the authorization scheme for tokens bound by one
+pub const DPOP_HEADER: &str = "dpop";
+pub const DPOP_SCHEME: &str = "DPoP";
+
+// `typ` every proof must declare, so no other kind of JWT can pass for one
+const PROOF_TYPE: &str = "dpop+jwt";
+
+// Asymmetric algorithms accepted for proofs; a MAC can't prove possession of a public key
+const PROOF_ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+// Proof algorithms as advertised in `dpop_signing_alg_values_supported` and DPoP challenges
+pub fn proof_algorithm_names() -> Vec<String> {
+    PROOF_ALGORITHMS.iter().map(|alg| format!("{:?}", alg)).collect()
+}
+
+#[derive(Deserialize)]
+struct ProofClaims {
+    // Makes each proof unique, though replays within its lifetime aren't tracked yet
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: i64,
+    #[serde(default)]
+    ath: Option<String>,
+}
+
+// RFC 7638 thumbprint of a public JWK: the SHA-256 of its required members in lexicographic order
+pub fn thumbprint(jwk: &Jwk) -> Result<String, String> {
+    let quote = |value: &str| serde_json::Value::from(value).to_string();
+    let curve = |curve: &EllipticCurve| match curve {
+        EllipticCurve::P256 => "P-256",
+        EllipticCurve::P384 => "P-384",
+        EllipticCurve::P521 => "P-521",
+        EllipticCurve::Ed25519 => "Ed25519",
+    };
+    let canonical = match &jwk.algorithm {
+        AlgorithmParameters::RSA(key) => format!(r#"{{"e":{},"kty":"RSA","n":{}}}"#, quote(&key.e), quote(&key.n)),
+        AlgorithmParameters::EllipticCurve(key) => format!(
+            r#"{{"crv":{},"kty":"EC","x":{},"y":{}}}"#,
+            quote(curve(&key.curve)),
+            quote(&key.x),
+            quote(&key.y)
+        ),
+        AlgorithmParameters::OctetKeyPair(key) => {
+            format!(r#"{{"crv":{},"kty":"OKP","x":{}}}"#, quote(curve(&key.curve)), quote(&key.x))
+        }
+        AlgorithmParameters::OctetKey(_) => return Err("the jwk must be a public key".to_string()),
+    };
+    Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes())))
+}
+
+// `ath` value binding a proof to the access token sent with it
+fn access_token_hash(access_token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(access_token.as_bytes()))
+}
+
+// The URL a request was addressed to: BASE_URL plus the request path, which is taken as relative
+// to BASE_URL's own path when it starts with it (as it does under a realm's prefix)
+pub fn request_url(base_url: &str, path: &str) -> String {
+    let base_path = base_url
+        .split_once("://")
+        .and_then(|(_, rest)| rest.find('/').map(|i| &rest[i..]))
+        .unwrap_or("");
+    let relative = path
+        .strip_prefix(base_path)
+        .filter(|rest| !base_path.is_empty() && (rest.is_empty() || rest.starts_with('/')))
+        .unwrap_or(path);
+    format!("{}{}", base_url, relative)
+}
+
+// A private key in the proof header would mean the client has leaked it, so the proof is refused
+fn has_private_key(proof: &str) -> bool {
+    proof
+        .split('.')
+        .next()
+        .and_then(|header| URL_SAFE_NO_PAD.decode(header).ok())
+        .and_then(|header| serde_json::from_slice::<serde_json::Value>(&header).ok())
+        .is_some_and(|header| header["jwk"].get("d").is_some())
+}
+
+// Verify a DPoP proof JWT (RFC 9449 section 4.3) for a `method` request to `url`, made `now`, and
+// return the thumbprint of the key that signed it. Proofs sent with an access token must also
+// carry its hash. Proofs older than `max_age` (or dated more than `skew` ahead) are refused.
+pub fn verify_proof(
+    proof: &str,
+    method: &Method,
+    url: &str,
+    access_token: Option<&str>,
+    now: DateTime<Utc>,
+    max_age: Duration,
+    skew: Duration,
+) -> Result<String, String> {
+    let header = decode_header(proof).map_err(|_| "malformed proof".to_string())?;
+    if header.typ.as_deref() != Some(PROOF_TYPE) {
+        return Err(format!("typ must be {}", PROOF_TYPE));
+    }
+    if !PROOF_ALGORITHMS.contains(&header.alg) {
+        return Err(format!("{:?} is not an accepted proof algorithm", header.alg));
+    }
+    let jwk = header.jwk.ok_or_else(|| "the header must carry the signing jwk".to_string())?;
+    if has_private_key(proof) {
+        return Err("the jwk must be a public key".to_string());
+    }
+    let jkt = thumbprint(&jwk)?;
+
+    let key = DecodingKey::from_jwk(&jwk).map_err(|_| "unusable jwk".to_string())?;
+    let mut validation = Validation::new(header.alg);
+    validation.set_required_spec_claims::<&str>(&[]);
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    let claims = decode::<ProofClaims>(proof, &key, &validation)
+        .map_err(|_| "signature or claims invalid".to_string())?
+        .claims;
+
+    if claims.jti.is_empty() {
+        return Err("jti is required".to_string());
+    }
+    if claims.htm != method.as_str() {
+        return Err(format!("htm does not match {}", method));
+    }
+    let htu = claims.htu.split(['?', '#']).next().unwrap_or_default();
+    if htu != url {
+        return Err(format!("htu does not match {}", url));
+    }
+    let age = now.timestamp() - claims.iat;
+    if age > (max_age + skew).num_seconds() || -age > skew.num_seconds() {
+        return Err("iat is outside the accepted window".to_string());
+    }
+    if let Some(access_token) = access_token {
+        if claims.ath.as_deref() != Some(access_token_hash(access_token).as_str()) {
+            return Err("ath does not match the access token".to_string());
+        }
+    }
+    Ok(jkt)
+}
+
+// The single `DPoP` header of a request, if it sent one
+fn proof_header(headers: &HeaderMap) -> Result<Option<&str>, String> {
+    let mut proofs = headers.get_all(DPOP_HEADER).iter();
+    let Some(proof) = proofs.next() else {
+        return Ok(None);
+    };
+    if proofs.next().is_some() {
+        return Err("only one DPoP header is allowed".to_string());
+    }
+    proof.to_str().map(Some).map_err(|_| "malformed proof".to_string())
+}
+
+// Key thumbprint to bind tokens issued for a token request to, when DPOP_ENABLED is set and the
+// client sent a proof. Without one the tokens are plain bearer tokens.
+pub fn token_request_binding(
+    config: &Config,
+    headers: &HeaderMap,
+    path: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, AppError> {
+    if !config.dpop_enabled {
+        return Ok(None);
+    }
+    let Some(proof) = proof_header(headers).map_err(AppError::InvalidDpopProof)? else {
+        return Ok(None);
+    };
+    let url = request_url(&config.base_url, path);
+    let max_age = Duration::seconds(config.dpop_proof_max_age_seconds);
+    verify_proof(proof, &Method::POST, &url, None, now, max_age, config.clock_skew())
+        .map(Some)
+        .map_err(AppError::InvalidDpopProof)
+}
+
+// Check that a verified access token is presented the way it was issued: a bound token only under
+// the DPoP scheme with a proof from its key, and an unbound one never under that scheme. `path` is
+// the request's path before any nesting stripped it.
+pub fn check_binding(
+    config: &Config,
+    claims: &Claims,
+    dpop_scheme: bool,
+    token: &str,
+    request: &Parts,
+    path: &str,
+    now: DateTime<Utc>,
+) -> Result<(), AppError> {
+    let Some(cnf) = &claims.cnf else {
+        if dpop_scheme {
+            return Err(AppError::DpopProofRejected("the token is not DPoP-bound".to_string()));
+        }
+        return Ok(());
+    };
+    if !dpop_scheme {
+        return Err(AppError::DpopProofRejected(format!(
+            "the token is DPoP-bound and must be sent with the {} scheme",
+            DPOP_SCHEME
+        )));
+    }
+    let proof = proof_header(&request.headers)
+        .map_err(AppError::DpopProofRejected)?
+        .ok_or_else(|| AppError::DpopProofRejected("a DPoP proof is required".to_string()))?;
+    let url = request_url(&config.base_url, path);
+    let max_age = Duration::seconds(config.dpop_proof_max_age_seconds);
+    let jkt = verify_proof(proof, &request.method, &url, Some(token), now, max_age, config.clock_skew())
+        .map_err(AppError::DpopProofRejected)?;
+    if jkt != cnf.jkt {
+        return Err(AppError::DpopProofRejected("the proof is signed by a different key".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{
+        encode,
+        jwk::{CommonParameters, OctetKeyPairParameters, OctetKeyPairType},
+        EncodingKey, Header,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    const URL: &str = "https://auth.example.com/api/auth/token";
+    const MAX_AGE: Duration = Duration::seconds(300);
+    const SKEW: Duration = Duration::seconds(60);
+
+    struct ClientKey {
+        encoding: EncodingKey,
+        jwk: Jwk,
+    }
+
+    fn client_key() -> ClientKey {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        ClientKey {
+            encoding: EncodingKey::from_ed_der(pkcs8.as_ref()),
+            jwk: Jwk {
+                common: CommonParameters::default(),
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(pair.public_key().as_ref()),
+                }),
+            },
+        }
+    }
+
+    fn proof(key: &ClientKey, typ: &str, claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.typ = Some(typ.to_string());
+        header.jwk = Some(key.jwk.clone());
+        encode(&header, &claims, &key.encoding).unwrap()
+    }
+
+    fn claims(now: DateTime<Utc>) -> serde_json::Value {
+        serde_json::json!({ "jti": "a1", "htm": "POST", "htu": URL, "iat": now.timestamp() })
+    }
+
+    #[test]
+    fn accepts_a_fresh_proof_and_returns_the_key_thumbprint() {
+        let now = Utc::now();
+        let key = client_key();
+        let jkt = verify_proof(&proof(&key, PROOF_TYPE, claims(now)), &Method::POST, URL, None, now, MAX_AGE, SKEW);
+        assert_eq!(jkt, thumbprint(&key.jwk));
+    }
+
+    #[test]
+    fn rejects_proofs_for_another_request_or_time() {
+        let now = Utc::now();
+        let key = client_key();
+        let verify = |proof: String, method: &Method| verify_proof(&proof, method, URL, None, now, MAX_AGE, SKEW);
+
+        assert!(verify(proof(&key, "JWT", claims(now)), &Method::POST).is_err());
+        assert!(verify(proof(&key, PROOF_TYPE, claims(now)), &Method::GET).is_err());
+        let mut other_url = claims(now);
+        other_url["htu"] = "https://auth.example.com/api/auth/me".into();
+        assert!(verify(proof(&key, PROOF_TYPE, other_url), &Method::POST).is_err());
+        let stale = claims(now - Duration::seconds(600));
+        assert!(verify(proof(&key, PROOF_TYPE, stale), &Method::POST).is_err());
+        let mut no_jti = claims(now);
+        no_jti.as_object_mut().unwrap().remove("jti");
+        assert!(verify(proof(&key, PROOF_TYPE, no_jti), &Method::POST).is_err());
+    }
+
+    #[test]
+    fn proofs_with_an_access_token_must_carry_its_hash() {
+        let now = Utc::now();
+        let key = client_key();
+        let mut bound = claims(now);
+        bound["ath"] = access_token_hash("token-a").into();
+        let bound = proof(&key, PROOF_TYPE, bound);
+        assert!(verify_proof(&bound, &Method::POST, URL, Some("token-a"), now, MAX_AGE, SKEW).is_ok());
+        assert!(verify_proof(&bound, &Method::POST, URL, Some("token-b"), now, MAX_AGE, SKEW).is_err());
+        let unbound = proof(&key, PROOF_TYPE, claims(now));
+        assert!(verify_proof(&unbound, &Method::POST, URL, Some("token-a"), now, MAX_AGE, SKEW).is_err());
+    }
+
+    #[test]
+    fn thumbprints_follow_rfc7638() {
+        // The example key from RFC 7638 section 3.1
+        let jwk: Jwk = serde_json::from_value(serde_json::json!({
+            "kty": "RSA",
+            "e": "AQAB",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw"
+        }))
+        .unwrap();
+        assert_eq!(thumbprint(&jwk).unwrap(), "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs");
+    }
+
+    #[test]
+    fn request_urls_follow_the_base_url_path() {
+        assert_eq!(request_url("http://auth:8080", "/api/auth/me"), "http://auth:8080/api/auth/me");
+        assert_eq!(
+            request_url("http://auth:8080/realms/acme", "/realms/acme/api/auth/me"),
+            "http://auth:8080/realms/acme/api/auth/me"
+        );
+        assert_eq!(request_url("https://example.com/auth", "/api/auth/me"), "https://example.com/auth/api/auth/me");
+    }
+}
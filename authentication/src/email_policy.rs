@@ -0,0 +1,131 @@
+use crate::{config::Config, errors::AppError};
+
+// Embedded list of disposable email domains, one per line
+const DISPOSABLE_DOMAINS: &str = include_str!("data/disposable_email_domains.txt");
+
+// A domain entry: `example.com` matches exactly, `*.example.com` matches any subdomain
+#[derive(Debug, Clone)]
+enum DomainPattern {
+    Exact(String),
+    Subdomains(String),
+}
+
+impl DomainPattern {
+    fn parse(entry: &str) -> Option<Self> {
+        let entry = entry.trim().to_lowercase();
+        if entry.is_empty() {
+            return None;
+        }
+        match entry.strip_prefix("*.") {
+            Some(parent) => Some(Self::Subdomains(format!(".{}", parent))),
+            None => Some(Self::Exact(entry)),
+        }
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            Self::Exact(exact) => domain == exact,
+            Self::Subdomains(suffix) => domain.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+// Registration policy on the domain part of email addresses
+#[derive(Debug, Clone)]
+pub struct EmailDomainPolicy {
+    allowed: Vec<DomainPattern>,
+    blocked: Vec<DomainPattern>,
+}
+
+impl EmailDomainPolicy {
+    fn new(allowed: &[String], blocked: &[String], block_disposable: bool) -> Self {
+        let allowed = allowed.iter().filter_map(|entry| DomainPattern::parse(entry)).collect();
+        let mut blocked: Vec<DomainPattern> = blocked
+            .iter()
+            .filter_map(|entry| DomainPattern::parse(entry))
+            .collect();
+        if block_disposable {
+            blocked.extend(DISPOSABLE_DOMAINS.lines().filter_map(DomainPattern::parse));
+        }
+        Self { allowed, blocked }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            &config.allowed_email_domains,
+            &config.blocked_email_domains,
+            config.block_disposable_email_domains,
+        )
+    }
+
+    // Validate an email address against the policy, comparing the lowercased domain. A
+    // non-empty allowlist is checked first; the denylist then only narrows allowed domains.
+    pub fn check(&self, email: &str) -> Result<(), AppError> {
+        let domain = email
+            .rsplit_once('@')
+            .map(|(local, domain)| (local, domain.trim().to_lowercase()))
+            .filter(|(local, domain)| !local.is_empty() && !domain.is_empty())
+            .map(|(_, domain)| domain)
+            .ok_or_else(|| AppError::Validation("Email address is invalid".to_string()))?;
+
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|pattern| pattern.matches(&domain)) {
+            return Err(AppError::Validation(format!(
+                "Registrations are restricted to approved email domains; {} is not one of them",
+                domain
+            )));
+        }
+        if self.blocked.iter().any(|pattern| pattern.matches(&domain)) {
+            return Err(AppError::Validation(format!(
+                "Registrations from the email domain {} are not allowed",
+                domain
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allowed: &[&str], blocked: &[&str]) -> EmailDomainPolicy {
+        let to_owned = |entries: &[&str]| entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>();
+        EmailDomainPolicy::new(&to_owned(allowed), &to_owned(blocked), false)
+    }
+
+    #[test]
+    fn empty_policy_allows_any_domain() {
+        assert!(policy(&[], &[]).check("john@example.com").is_ok());
+    }
+
+    #[test]
+    fn allowlist_rejects_other_domains() {
+        let policy = policy(&["company.com"], &[]);
+        assert!(policy.check("john@company.com").is_ok());
+        assert!(policy.check("john@example.com").is_err());
+        assert!(policy.check("john@sub.company.com").is_err());
+    }
+
+    #[test]
+    fn allowlist_matching_is_case_insensitive() {
+        let policy = policy(&["Company.COM"], &[]);
+        assert!(policy.check("john@COMPANY.com").is_ok());
+    }
+
+    #[test]
+    fn denylist_only_narrows_allowed_domains() {
+        let policy = policy(&["*.company.com"], &["contractors.company.com", "example.com"]);
+        assert!(policy.check("john@eng.company.com").is_ok());
+        assert!(policy.check("john@contractors.company.com").is_err());
+        // Not allowed in the first place; the denylist entry doesn't make it acceptable either way
+        assert!(policy.check("john@example.com").is_err());
+        assert!(policy.check("john@other.org").is_err());
+    }
+
+    #[test]
+    fn denylist_applies_without_allowlist() {
+        let policy = policy(&[], &["*.example.com"]);
+        assert!(policy.check("john@mail.example.com").is_err());
+        assert!(policy.check("john@example.com").is_ok());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use crate::errors::ErrorDetails;
+
+// Embedded translations of error messages by locale; English is the `AppError` messages themselves
+const TRANSLATIONS: [(&str, &str); 2] = [
+    ("de", include_str!("data/error_messages/de.txt")),
+    ("fr", include_str!("data/error_messages/fr.txt")),
+];
+
+// `code = template` lines of each locale's table
+static TABLES: LazyLock<HashMap<&'static str, HashMap<&'static str, &'static str>>> = LazyLock::new(|| {
+    TRANSLATIONS
+        .iter()
+        .map(|(locale, table)| {
+            let templates = table
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(|line| line.split_once('='))
+                .map(|(code, template)| (code.trim(), template.trim()))
+                .collect();
+            (*locale, templates)
+        })
+        .collect()
+});
+
+// The enabled locale an `Accept-Language` header prefers, matching on the primary language tag
+// (`de-CH` picks `de`). None means English, whether asked for or nothing else matched.
+pub fn negotiate(accept_language: &str, enabled: &[String]) -> Option<&'static str> {
+    let mut ranges: Vec<(&str, f32)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';').map(str::trim);
+            let tag = parts.next().filter(|tag| !tag.is_empty())?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+            Some((tag, quality))
+        })
+        .filter(|(_, quality)| *quality > 0.0)
+        .collect();
+    // Stable, so equally weighted ranges keep the client's order
+    ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranges.iter().find_map(|(tag, _)| {
+        let language = tag.split('-').next().unwrap_or(tag).to_ascii_lowercase();
+        if language == "en" {
+            return Some(None);
+        }
+        let (locale, _) = TRANSLATIONS.iter().find(|(locale, _)| *locale == language)?;
+        enabled.iter().any(|e| e == locale).then_some(Some(*locale))
+    })?
+}
+
+// The error's message in `locale`, or None when the table has no entry for its code
+pub fn localize(locale: &str, details: &ErrorDetails) -> Option<String> {
+    let template = TABLES.get(locale)?.get(details.code)?;
+    let mut message = template.replace("{detail}", details.detail.as_deref().unwrap_or_default());
+    for (name, value) in &details.fields {
+        let value = value.as_str().map_or_else(|| value.to_string(), str::to_string);
+        message = message.replace(&format!("{{{}}}", name), &value);
+    }
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::AppError;
+
+    fn enabled() -> Vec<String> {
+        vec!["de".to_string(), "fr".to_string()]
+    }
+
+    #[test]
+    fn negotiates_by_quality_and_primary_tag() {
+        assert_eq!(negotiate("de-CH, fr;q=0.8", &enabled()), Some("de"));
+        assert_eq!(negotiate("fr;q=0.5, de;q=0.9", &enabled()), Some("de"));
+        assert_eq!(negotiate("es, fr;q=0.4", &enabled()), Some("fr"));
+        // English, unknown or disabled locales get the English messages
+        assert_eq!(negotiate("en-GB, de;q=0.9", &enabled()), None);
+        assert_eq!(negotiate("es, it", &enabled()), None);
+        assert_eq!(negotiate("de", &["fr".to_string()]), None);
+        assert_eq!(negotiate("de;q=0, fr", &enabled()), Some("fr"));
+        assert_eq!(negotiate("", &enabled()), None);
+    }
+
+    #[test]
+    fn locales_cover_the_same_codes() {
+        let codes = |locale: &str| {
+            let mut codes: Vec<&str> = TABLES[locale].keys().copied().collect();
+            codes.sort();
+            codes
+        };
+        assert_eq!(codes("de"), codes("fr"));
+        assert!(codes("de").contains(&"queue_timeout"));
+    }
+
+    #[test]
+    fn translates_messages_with_their_details() {
+        let errors = [
+            AppError::Unauthorized,
+            AppError::RateLimited(30),
+            AppError::InvalidRequest("missing username".to_string()),
+            AppError::WeakPassword(12, 40),
+            AppError::Overloaded,
+            AppError::QueueTimeout,
+        ];
+        for (locale, _) in TRANSLATIONS {
+            for error in &errors {
+                let message = localize(locale, &error.details());
+                assert!(message.is_some_and(|m| !m.contains('{')), "{} has no {} message", locale, error.code());
+            }
+        }
+        assert_eq!(localize("de", &AppError::RateLimited(30).details()).unwrap(), "Zu viele Anfragen, bitte in 30 Sekunden erneut versuchen");
+        assert_eq!(
+            localize("fr", &AppError::WeakPassword(12, 40).details()).unwrap(),
+            "Le mot de passe est trop prévisible (entropie estimée 12 bits, au moins 40 requis) ; évitez les caractères répétés et les motifs"
+        );
+        assert!(localize("es", &AppError::Unauthorized.details()).is_none());
+    }
+}
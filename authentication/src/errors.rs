@@ -1,10 +1,13 @@
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json as AxumJson,
 };
+use serde_json::{Map, Value};
 use thiserror::Error;
 
+use crate::dpop::proof_algorithm_names;
+
 #[derive(Debug, Error)]
 pub enum AppError {
     #[error("Database error: {0}")]
@@ -17,27 +20,425 @@ pub enum AppError {
     PasswordVerification(String),
     #[error("Password hashing error: {0}")]
     PasswordHashing(String),
-    #[error("Username or email already exists")]
-    Conflict,
+    #[error("Username is already taken")]
+    UsernameTaken,
+    #[error("Email is already registered")]
+    EmailRegistered,
+    #[error("Username is too similar to an existing username")]
+    UsernameConfusable,
     #[error("Invalid credentials")]
     Unauthorized,
     #[error("Bcrypt error: {0}")]
     Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("{0}")]
+    Validation(String),
+    #[error("Unknown client or invalid client credentials")]
+    InvalidClient,
+    #[error("Requested scope exceeds the scopes granted to the user: {0}")]
+    InsufficientScope(String),
+    #[error("Too many requests, retry after {0} seconds")]
+    RateLimited(u64),
+    // The tenant's overall allowance is spent, whoever is asking
+    #[error("Too many requests for tenant {0}, retry after {1} seconds")]
+    TenantRateLimited(String, u64),
+    #[error("Requested authentication context cannot be satisfied: {0}")]
+    UnmetAuthenticationRequirements(String),
+    #[error("Refresh token is invalid or expired")]
+    InvalidGrant,
+    #[error("Unsupported grant type: {0}")]
+    UnsupportedGrantType(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Password hashing is overloaded, retry after {0} seconds")]
+    HashingUnavailable(u64),
+    #[error("Method not allowed")]
+    MethodNotAllowed,
+    #[error("not found")]
+    NotFound,
+    #[error("Bearer token required")]
+    MissingToken,
+    #[error("Invalid bearer token: {0}")]
+    InvalidToken(String),
+    #[error("Invalid or missing internal API key")]
+    InvalidApiKey,
+    #[error("CSRF token missing or does not match")]
+    CsrfFailed,
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Password was changed too recently; it can be changed again after {0}")]
+    PasswordTooRecent(String),
+    #[error("Invalid role; allowed roles are: {0}")]
+    InvalidRole(String),
+    #[error("This operation requires having signed in within the last {0} seconds")]
+    ReauthRequired(u64),
+    #[error("The service is down for maintenance")]
+    Maintenance,
+    #[error("Sign-in from an unusual location; log in with an emailed code instead")]
+    StepUpRequired,
+    #[error("Email address has not been verified")]
+    EmailUnverified,
+    // Registration under VERIFY_EMAIL_BEFORE_REGISTER without a valid code sent to the address
+    #[error("Email verification code is missing, wrong or expired; request a new one")]
+    EmailCodeInvalid,
+    // A DPoP proof sent to the token endpoint that doesn't hold up (RFC 9449 section 5)
+    #[error("Invalid DPoP proof: {0}")]
+    InvalidDpopProof(String),
+    // A DPoP-bound token presented without a matching proof (RFC 9449 section 7.1)
+    #[error("Invalid DPoP proof: {0}")]
+    DpopProofRejected(String),
+    #[error("The service is overloaded, retry shortly")]
+    Overloaded,
+    #[error("Request waited too long for the service to be free, retry shortly")]
+    QueueTimeout,
+    // Estimated entropy of a new password and the policy's minimum, both in bits
+    #[error("Password is too predictable (estimated entropy {0} bits, at least {1} required); avoid repeated characters and patterns")]
+    WeakPassword(u32, u32),
+    // A password submitted under a client-hash scheme that isn't accepted
+    #[error("Unsupported password scheme: {0}")]
+    UnsupportedPasswordScheme(String),
+    // A new password sent as a client-side hash, which the role's password policy can't be checked on
+    #[error("A client-side hash can't set a new password for {0} accounts; send the password itself")]
+    ClientHashNotAllowed(String),
+}
+
+// Protection space named in `WWW-Authenticate` challenges
+const REALM: &str = "authentication";
+
+// Postgres `read_only_sql_transaction`, raised by writes while a replica is being promoted
+const READ_ONLY_SQLSTATE: &str = "25006";
+
+// Failovers usually complete within seconds
+const READ_ONLY_RETRY_AFTER_SECONDS: u64 = 5;
+
+// Shed requests only wait for in-flight ones to finish, which is usually well under a second
+const OVERLOADED_RETRY_AFTER_SECONDS: u64 = 1;
+
+// Postgres `undefined_table`, raised by queries against tables the migrations haven't created
+const UNDEFINED_TABLE_SQLSTATE: &str = "42P01";
+
+fn has_sqlstate(error: &sqlx::Error, sqlstate: &str) -> bool {
+    error
+        .as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|code| code == sqlstate)
+}
+
+// Whether a write was refused because the database is in recovery or read-only
+fn is_read_only(error: &sqlx::Error) -> bool {
+    has_sqlstate(error, READ_ONLY_SQLSTATE)
+}
+
+// Whether a query failed because a table it uses doesn't exist, i.e. the schema isn't initialized
+pub fn is_undefined_table(error: &sqlx::Error) -> bool {
+    has_sqlstate(error, UNDEFINED_TABLE_SQLSTATE)
+}
+
+// Error details attached to the response so the error-format middleware can re-render it
+#[derive(Debug, Clone)]
+pub struct ErrorDetails {
+    pub code: &'static str,
+    pub message: String,
+    // The variant's own value (a reason, a count of seconds), for localized messages to embed
+    pub detail: Option<String>,
+    pub fields: Map<String, Value>,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Database(e) if is_read_only(e) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(e) if is_undefined_table(e) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::KeyLoading(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Jwt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PasswordVerification(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::PasswordHashing(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UsernameTaken => StatusCode::CONFLICT,
+            AppError::EmailRegistered => StatusCode::CONFLICT,
+            AppError::UsernameConfusable => StatusCode::CONFLICT,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Bcrypt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidClient => StatusCode::UNAUTHORIZED,
+            AppError::InsufficientScope(_) => StatusCode::FORBIDDEN,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::TenantRateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::UnmetAuthenticationRequirements(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidGrant => StatusCode::BAD_REQUEST,
+            AppError::UnsupportedGrantType(_) => StatusCode::BAD_REQUEST,
+            AppError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::HashingUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::MissingToken => StatusCode::UNAUTHORIZED,
+            AppError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            AppError::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            AppError::CsrfFailed => StatusCode::FORBIDDEN,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::PasswordTooRecent(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidRole(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::ReauthRequired(_) => StatusCode::UNAUTHORIZED,
+            AppError::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::StepUpRequired => StatusCode::UNAUTHORIZED,
+            AppError::EmailUnverified => StatusCode::UNAUTHORIZED,
+            AppError::EmailCodeInvalid => StatusCode::FORBIDDEN,
+            AppError::InvalidDpopProof(_) => StatusCode::BAD_REQUEST,
+            AppError::DpopProofRejected(_) => StatusCode::UNAUTHORIZED,
+            AppError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::QueueTimeout => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::WeakPassword(..) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::UnsupportedPasswordScheme(_) => StatusCode::BAD_REQUEST,
+            AppError::ClientHashNotAllowed(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    // Stable, machine-readable error code returned alongside the message
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::Database(e) if is_read_only(e) => "database_read_only",
+            AppError::Database(e) if is_undefined_table(e) => "schema_not_initialized",
+            AppError::Database(_) => "database_error",
+            AppError::KeyLoading(_) => "key_loading_error",
+            AppError::Jwt(_) => "jwt_error",
+            AppError::PasswordVerification(_) => "password_verification_error",
+            AppError::PasswordHashing(_) => "password_hashing_error",
+            AppError::UsernameTaken => "username_taken",
+            AppError::EmailRegistered => "email_registered",
+            AppError::UsernameConfusable => "username_confusable",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Bcrypt(_) => "bcrypt_error",
+            AppError::Validation(_) => "validation_error",
+            AppError::InvalidClient => "invalid_client",
+            AppError::InsufficientScope(_) => "insufficient_scope",
+            AppError::RateLimited(_) => "rate_limited",
+            AppError::TenantRateLimited(..) => "tenant_rate_limited",
+            AppError::UnmetAuthenticationRequirements(_) => "unmet_authentication_requirements",
+            AppError::InvalidGrant => "invalid_grant",
+            AppError::UnsupportedGrantType(_) => "unsupported_grant_type",
+            AppError::InvalidRequest(_) => "invalid_request",
+            AppError::HashingUnavailable(_) => "hashing_unavailable",
+            AppError::MethodNotAllowed => "method_not_allowed",
+            AppError::NotFound => "not_found",
+            AppError::MissingToken => "missing_token",
+            AppError::InvalidToken(_) => "invalid_token",
+            AppError::InvalidApiKey => "invalid_api_key",
+            AppError::CsrfFailed => "csrf_failed",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::PasswordTooRecent(_) => "password_too_recent",
+            AppError::InvalidRole(_) => "invalid_role",
+            AppError::ReauthRequired(_) => "reauth_required",
+            AppError::Maintenance => "maintenance",
+            AppError::StepUpRequired => "step_up_required",
+            AppError::EmailUnverified => "email_unverified",
+            AppError::EmailCodeInvalid => "email_code_invalid",
+            AppError::InvalidDpopProof(_) | AppError::DpopProofRejected(_) => "invalid_dpop_proof",
+            AppError::Overloaded => "overloaded",
+            AppError::QueueTimeout => "queue_timeout",
+            AppError::WeakPassword(..) => "weak_password",
+            AppError::UnsupportedPasswordScheme(_) => "unsupported_password_scheme",
+            AppError::ClientHashNotAllowed(_) => "client_hash_not_allowed",
+        }
+    }
+
+    // The value a variant carries, as it appears in the message
+    fn detail(&self) -> Option<String> {
+        match self {
+            AppError::Database(e) => Some(e.to_string()),
+            AppError::Jwt(e) => Some(e.to_string()),
+            AppError::Bcrypt(e) => Some(e.to_string()),
+            AppError::KeyLoading(detail)
+            | AppError::PasswordVerification(detail)
+            | AppError::PasswordHashing(detail)
+            | AppError::Validation(detail)
+            | AppError::InsufficientScope(detail)
+            | AppError::UnmetAuthenticationRequirements(detail)
+            | AppError::UnsupportedGrantType(detail)
+            | AppError::InvalidRequest(detail)
+            | AppError::InvalidToken(detail)
+            | AppError::Forbidden(detail)
+            | AppError::PasswordTooRecent(detail)
+            | AppError::InvalidRole(detail)
+            | AppError::InvalidDpopProof(detail)
+            | AppError::DpopProofRejected(detail)
+            | AppError::UnsupportedPasswordScheme(detail)
+            | AppError::ClientHashNotAllowed(detail) => Some(detail.clone()),
+            AppError::RateLimited(seconds)
+            | AppError::TenantRateLimited(_, seconds)
+            | AppError::HashingUnavailable(seconds)
+            | AppError::ReauthRequired(seconds) => Some(seconds.to_string()),
+            _ => None,
+        }
+    }
+
+    // Human-readable message. A missing table says what to do about it rather than only what
+    // Postgres reported.
+    fn message(&self) -> String {
+        match self {
+            AppError::Database(e) if is_undefined_table(e) => {
+                format!("Database schema not initialized ({}); run the migrations", e)
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    // Everything the error-format middleware needs to re-render the response
+    pub fn details(&self) -> ErrorDetails {
+        ErrorDetails {
+            code: self.code(),
+            message: self.message(),
+            detail: self.detail(),
+            fields: self.fields(),
+        }
+    }
+
+    // Machine-readable details added to the body next to `error` and `code`
+    pub fn fields(&self) -> Map<String, Value> {
+        let mut fields = Map::new();
+        match self {
+            AppError::WeakPassword(entropy_bits, min_entropy_bits) => {
+                fields.insert("entropy_bits".to_string(), Value::from(*entropy_bits));
+                fields.insert("min_entropy_bits".to_string(), Value::from(*min_entropy_bits));
+            }
+            AppError::TenantRateLimited(tenant, _) => {
+                fields.insert("tenant".to_string(), Value::from(tenant.clone()));
+            }
+            _ => {}
+        }
+        fields
+    }
+
+    // Seconds the client should wait before retrying, for transient failures
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            AppError::RateLimited(seconds)
+            | AppError::TenantRateLimited(_, seconds)
+            | AppError::HashingUnavailable(seconds) => Some(*seconds),
+            AppError::Database(e) if is_read_only(e) => Some(READ_ONLY_RETRY_AFTER_SECONDS),
+            AppError::Overloaded | AppError::QueueTimeout => Some(OVERLOADED_RETRY_AFTER_SECONDS),
+            _ => None,
+        }
+    }
+
+    // `WWW-Authenticate` challenge telling the client how to authenticate (RFC 6750 for bearer
+    // tokens). Login credential failures carry none as they aren't HTTP authentication.
+    pub fn www_authenticate(&self) -> Option<String> {
+        match self {
+            AppError::MissingToken => Some(format!("Bearer realm=\"{}\"", REALM)),
+            AppError::InvalidToken(_) => Some(format!(
+                "Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"",
+                REALM,
+                self.to_string().replace(['"', '\\'], "")
+            )),
+            AppError::InvalidApiKey => Some(format!("ApiKey realm=\"{}\", header=\"X-Internal-API-Key\"", REALM)),
+            // RFC 9470 step-up challenge
+            AppError::ReauthRequired(max_age) => Some(format!(
+                "Bearer realm=\"{}\", error=\"insufficient_user_authentication\", max_age=\"{}\"",
+                REALM, max_age
+            )),
+            AppError::DpopProofRejected(_) => Some(format!(
+                "DPoP realm=\"{}\", algs=\"{}\", error=\"invalid_dpop_proof\", error_description=\"{}\"",
+                REALM,
+                proof_algorithm_names().join(" "),
+                self.to_string().replace(['"', '\\'], "")
+            )),
+            _ => None,
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, message) = match &self {
-            AppError::Database(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::KeyLoading(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::Jwt(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::PasswordVerification(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::PasswordHashing(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-            AppError::Conflict => (StatusCode::CONFLICT, self.to_string()),
-            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, self.to_string()),
-            AppError::Bcrypt(_) => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
-        };
-        let body = serde_json::json!({ "error": message });
-        (status, AxumJson(body)).into_response()
+        let details = self.details();
+        let mut body = serde_json::json!({ "error": details.message, "code": details.code });
+        body.as_object_mut().expect("object").extend(details.fields.clone());
+        let mut response = (self.status(), AxumJson(body)).into_response();
+        if let Some(retry_after) = self.retry_after() {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        if let Some(challenge) = self.www_authenticate().and_then(|c| HeaderValue::from_str(&c).ok()) {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+        }
+        response.extensions_mut().insert(details);
+        response
+    }
+}
+
+// Fallback for known routes hit with the wrong method; axum adds the `Allow` header
+pub async fn method_not_allowed() -> AppError {
+    AppError::MethodNotAllowed
+}
+
+// Fallback for paths that match no route
+pub async fn not_found() -> AppError {
+    AppError::NotFound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn wrong_method_returns_json_envelope_with_allow_header() {
+        let app = Router::new()
+            .route("/api/auth/login", post(|| async {}))
+            .method_not_allowed_fallback(method_not_allowed);
+        let response = app
+            .oneshot(Request::get("/api/auth/login").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "POST");
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body["code"], "method_not_allowed");
+        assert_eq!(body["error"], "Method not allowed");
+    }
+
+    #[tokio::test]
+    async fn unknown_path_returns_json_not_found() {
+        let app = Router::new()
+            .route("/.well-known/jwks.json", get(|| async { "jwks" }))
+            .fallback(not_found);
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/no/such/path").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({ "error": "not found", "code": "not_found" }));
+
+        let response = app
+            .oneshot(Request::get("/.well-known/jwks.json").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn bearer_errors_carry_rfc6750_challenges() {
+        let response = AppError::InvalidToken("expired".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[header::WWW_AUTHENTICATE],
+            "Bearer realm=\"authentication\", error=\"invalid_token\", error_description=\"Invalid bearer token: expired\""
+        );
+        assert_eq!(
+            AppError::MissingToken.into_response().headers()[header::WWW_AUTHENTICATE],
+            "Bearer realm=\"authentication\""
+        );
+        assert!(!AppError::Unauthorized.into_response().headers().contains_key(header::WWW_AUTHENTICATE));
     }
 }
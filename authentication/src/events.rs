@@ -0,0 +1,252 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{info, warn};
+
+use crate::{config::Config, kafka, metrics::Metrics};
+
+// Delivery attempts per event before it is dropped
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+// How often `flush` checks whether the queue has drained
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// User lifecycle event types. Accounts can't be deactivated yet; `user.deactivated` comes with that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserEventKind {
+    Registered,
+    Login,
+    Impersonated,
+    LoginAnomaly,
+    PasswordChanged,
+}
+
+impl UserEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserEventKind::Registered => "user.registered",
+            UserEventKind::Login => "user.login",
+            UserEventKind::Impersonated => "user.impersonated",
+            UserEventKind::LoginAnomaly => "user.login_anomaly",
+            UserEventKind::PasswordChanged => "user.password_changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserEventData {
+    pub user_id: i32,
+    pub username: String,
+    // Admin acting as the user, for impersonation events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserEvent {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub occurred_at: DateTime<Utc>,
+    pub data: UserEventData,
+}
+
+impl UserEvent {
+    pub fn new(kind: UserEventKind, user_id: i32, username: &str, occurred_at: DateTime<Utc>) -> Self {
+        Self {
+            kind: kind.as_str(),
+            occurred_at,
+            data: UserEventData {
+                user_id,
+                username: username.to_string(),
+                actor: None,
+            },
+        }
+    }
+
+    pub fn with_actor(mut self, actor: &str) -> Self {
+        self.data.actor = Some(actor.to_string());
+        self
+    }
+}
+
+// Destination for user lifecycle events
+#[async_trait]
+pub trait EventSink: Send + Sync {
+    async fn deliver(&self, event: &UserEvent) -> Result<(), String>;
+}
+
+// Default sink that only logs events
+pub struct LogEventSink;
+
+#[async_trait]
+impl EventSink for LogEventSink {
+    async fn deliver(&self, event: &UserEvent) -> Result<(), String> {
+        info!("User event {} for user {}", event.kind, event.data.username);
+        Ok(())
+    }
+}
+
+// POSTs events as JSON, signed with HMAC-SHA256 over the body in `X-Signature: sha256=<hex>`
+pub struct WebhookEventSink {
+    client: reqwest::Client,
+    url: String,
+    secret: String,
+}
+
+impl WebhookEventSink {
+    pub fn new(url: String, secret: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+            secret,
+        }
+    }
+
+    fn sign(&self, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+#[async_trait]
+impl EventSink for WebhookEventSink {
+    async fn deliver(&self, event: &UserEvent) -> Result<(), String> {
+        let body = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        self.client
+            .post(&self.url)
+            .header("Content-Type", "application/json")
+            .header("X-Signature", self.sign(&body))
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Produces events as JSON to a Kafka topic, keyed by user id so each user's events stay in order
+pub struct KafkaEventSink {
+    producer: kafka::Producer,
+}
+
+impl KafkaEventSink {
+    pub fn new(config: &Config, topic: String) -> Result<Self, kafka::KafkaError> {
+        Ok(Self {
+            producer: kafka::Producer::new(config, topic)?,
+        })
+    }
+}
+
+#[async_trait]
+impl EventSink for KafkaEventSink {
+    async fn deliver(&self, event: &UserEvent) -> Result<(), String> {
+        let value = serde_json::to_vec(event).map_err(|e| e.to_string())?;
+        let key = event.data.user_id.to_string();
+        self.producer
+            .send(key.as_bytes(), &value, event.occurred_at)
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Deliver an event, retrying with exponential backoff
+async fn deliver_with_retry(sink: Arc<dyn EventSink>, event: UserEvent) {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+        match sink.deliver(&event).await {
+            Ok(()) => return,
+            Err(e) if attempt < MAX_DELIVERY_ATTEMPTS => {
+                warn!("Delivery of {} event failed (attempt {}): {}", event.kind, attempt, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => warn!("Dropping {} event after {} attempts: {}", event.kind, attempt, e),
+        }
+    }
+}
+
+// Queues events for background delivery so emitting never blocks the triggering request. At most
+// `capacity` events are queued and `capacity` more in delivery; a slow sink fills the queue and
+// further events are dropped rather than piling up.
+pub struct EventEmitter {
+    sender: mpsc::Sender<UserEvent>,
+    subscribed: Vec<String>,
+    metrics: Arc<Metrics>,
+    // Events queued or in delivery
+    pending: Arc<AtomicUsize>,
+}
+
+impl EventEmitter {
+    pub fn spawn(sink: Arc<dyn EventSink>, subscribed: Vec<String>, capacity: usize, metrics: Arc<Metrics>) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<UserEvent>(capacity);
+        let in_flight = Arc::new(Semaphore::new(capacity));
+        let pending = Arc::new(AtomicUsize::new(0));
+        let delivered = pending.clone();
+        tokio::spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                let permit = in_flight.clone().acquire_owned().await.expect("semaphore is never closed");
+                let sink = sink.clone();
+                let delivered = delivered.clone();
+                tokio::spawn(async move {
+                    deliver_with_retry(sink, event).await;
+                    delivered.fetch_sub(1, Ordering::SeqCst);
+                    drop(permit);
+                });
+            }
+        });
+        Self {
+            sender,
+            subscribed,
+            metrics,
+            pending,
+        }
+    }
+
+    // Kafka when KAFKA_BROKERS and KAFKA_TOPIC are set, else the webhook when WEBHOOK_URL is, else
+    // events are only logged
+    pub fn from_config(config: &Config, metrics: Arc<Metrics>) -> Self {
+        let sink: Arc<dyn EventSink> = match (&config.webhook_url, &config.kafka_topic) {
+            (_, Some(topic)) if !config.kafka_brokers.is_empty() => {
+                Arc::new(KafkaEventSink::new(config, topic.clone()).unwrap_or_else(|e| panic!("Failed to configure Kafka: {}", e)))
+            }
+            (Some(url), _) => Arc::new(WebhookEventSink::new(url.clone(), config.webhook_secret.clone())),
+            _ => Arc::new(LogEventSink),
+        };
+        Self::spawn(sink, config.webhook_events.clone(), config.event_queue_capacity, metrics)
+    }
+
+    // Queue an event if its type is subscribed; drops it with a warning when the queue is full
+    pub fn emit(&self, event: UserEvent) {
+        if !self.subscribed.is_empty() && !self.subscribed.iter().any(|kind| kind == event.kind) {
+            return;
+        }
+        let kind = event.kind;
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = self.sender.try_send(event) {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            warn!("Dropping user event, queue unavailable: {}", e);
+            self.metrics.record_event_dropped(kind);
+        }
+    }
+
+    // Wait until every queued event has been delivered, or dropped after its retries. Used at
+    // shutdown, where the hook's timeout bounds the wait.
+    pub async fn flush(&self) {
+        while self.pending.load(Ordering::SeqCst) > 0 {
+            tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+        }
+    }
+}
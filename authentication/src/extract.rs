@@ -0,0 +1,145 @@
+use axum::{
+    async_trait,
+    extract::{FromRequest, FromRequestParts, OriginalUri, Request},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use serde::de::DeserializeOwned;
+use tracing::info;
+
+use crate::{
+    cookies::{check_csrf, read_cookie, ACCESS_TOKEN_COOKIE},
+    dpop::{self, DPOP_SCHEME},
+    errors::AppError,
+    models::Claims,
+    session_handles,
+    state::AppState,
+    tokens::{load_verification_key, verify_token, TokenError},
+};
+
+// Body extractor accepting either JSON or `application/x-www-form-urlencoded`,
+// selected by the request's Content-Type
+pub struct JsonOrForm<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for JsonOrForm<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let is_form = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+
+        if is_form {
+            let Form(value) = Form::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            Ok(Self(value))
+        } else {
+            let Json(value) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            Ok(Self(value))
+        }
+    }
+}
+
+// Claims of a verified `Authorization: Bearer` token issued by this service, or of the
+// access token cookie when cookie auth is enabled. DPoP-bound tokens come under the `DPoP`
+// scheme instead, with a proof from their key.
+pub struct BearerClaims(pub Claims);
+
+#[async_trait]
+impl FromRequestParts<AppState> for BearerClaims {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let authorization = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        let dpop_token = authorization.and_then(|value| value.strip_prefix(DPOP_SCHEME)?.strip_prefix(' '));
+        let bearer = authorization.and_then(|value| value.strip_prefix("Bearer ")).or(dpop_token);
+        let token = match bearer {
+            Some(token) => token,
+            None if state.config.auth_cookies_enabled => {
+                let token = read_cookie(&parts.headers, ACCESS_TOKEN_COOKIE).ok_or(AppError::MissingToken)?;
+                // Only cookies are sent automatically by browsers, so only they need CSRF protection
+                check_csrf(&parts.method, &parts.headers)?;
+                token
+            }
+            None => return Err(AppError::MissingToken),
+        };
+        let verification_key = load_verification_key(&state.config)?;
+        let claims = verify_token(&verification_key, token, state.config.jwt_issuer.as_deref(), &state.config.required_claims, state.clock.now(), state.config.clock_skew(), state.config.max_token_bytes).map_err(|e| {
+            info!("Bearer token rejected: {}", e);
+            match e {
+                TokenError::TooLarge => AppError::InvalidToken(TokenError::Invalid.to_string()),
+                e => AppError::InvalidToken(e.to_string()),
+            }
+        })?;
+        if session_handles::is_access_token_revoked(&state.pool, &state.config, &claims).await? {
+            info!("Bearer token rejected: {}", TokenError::Revoked);
+            return Err(AppError::InvalidToken(TokenError::Revoked.to_string()));
+        }
+        // Nested routers see a stripped path, but the proof names the one the client used
+        let path = parts
+            .extensions
+            .get::<OriginalUri>()
+            .map_or_else(|| parts.uri.path().to_string(), |uri| uri.path().to_string());
+        dpop::check_binding(&state.config, &claims, dpop_token.is_some(), token, parts, &path, state.clock.now())?;
+        Ok(Self(claims))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::LoginRequest;
+    use axum::{body::Body, http::StatusCode};
+
+    async fn extract(content_type: &str, body: &str) -> Result<LoginRequest, Response> {
+        let req = Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        JsonOrForm::<LoginRequest>::from_request(req, &())
+            .await
+            .map(|JsonOrForm(value)| value)
+    }
+
+    #[tokio::test]
+    async fn accepts_json_credentials() {
+        let login = extract("application/json", r#"{"username":"johndoe","password":"s3cret pass"}"#)
+            .await
+            .unwrap();
+        assert_eq!(login.username, "johndoe");
+        assert_eq!(login.password, "s3cret pass");
+    }
+
+    #[tokio::test]
+    async fn accepts_form_encoded_credentials() {
+        let login = extract("application/x-www-form-urlencoded", "username=johndoe&password=s3cret+pass")
+            .await
+            .unwrap();
+        assert_eq!(login.username, "johndoe");
+        assert_eq!(login.password, "s3cret pass");
+    }
+
+    #[tokio::test]
+    async fn rejects_form_body_missing_fields() {
+        let rejection = extract("application/x-www-form-urlencoded", "username=johndoe")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(rejection.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,292 @@
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+use crate::{config::Config, errors::AppError};
+
+// Minimum time between refetches triggered by an unknown `kid`, so forged tokens can't
+// make us hammer an issuer's JWKS endpoint
+const JWKS_REFRESH_COOLDOWN: Duration = Duration::from_secs(60);
+
+// Claims read from a validated upstream token
+#[derive(Debug, Deserialize)]
+pub struct ExternalClaims {
+    pub iss: String,
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    // Whether the issuer vouches that `email` belongs to the subject; absent counts as no
+    #[serde(default, deserialize_with = "lenient_bool")]
+    pub email_verified: Option<bool>,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+}
+
+// Accept a boolean claim as JSON `true`/`false` or as the strings some issuers send instead
+fn lenient_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<bool>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+    Ok(match Option::<BoolOrString>::deserialize(deserializer)? {
+        Some(BoolOrString::Bool(value)) => Some(value),
+        Some(BoolOrString::String(value)) => value.parse().ok(),
+        None => None,
+    })
+}
+
+impl ExternalClaims {
+    // The local identity TOKEN_EXCHANGE_SUBJECT_CLAIM names. An email address is only taken when
+    // the issuer says it verified it, or anyone who could get an unverified address asserted
+    // would be handed that address's account.
+    pub fn mapped_subject(&self, claim: &str) -> Result<String, AppError> {
+        let missing = || AppError::InvalidRequest("subject_token lacks the mapped subject claim".to_string());
+        match claim {
+            "email" => {
+                if self.email_verified != Some(true) {
+                    info!("Rejected subject token from {}: email is not verified", self.iss);
+                    return Err(AppError::InvalidRequest("subject_token email is not verified".to_string()));
+                }
+                self.email.clone().ok_or_else(missing)
+            }
+            "preferred_username" => self.preferred_username.clone().ok_or_else(missing),
+            _ => Ok(self.sub.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UnverifiedIssuer {
+    iss: String,
+}
+
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+// Upstream identity providers whose tokens we accept for exchange, with their signing keys
+// fetched from each issuer's JWKS URI and cached
+pub struct TrustedIssuers {
+    client: reqwest::Client,
+    jwks_uris: HashMap<String, String>,
+    audience: String,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, CachedJwks>>,
+}
+
+impl TrustedIssuers {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            jwks_uris: config.trusted_issuers.clone(),
+            audience: config
+                .token_exchange_audience
+                .clone()
+                .unwrap_or_else(|| config.base_url.clone()),
+            cache_ttl: Duration::from_secs(config.jwks_cache_ttl_seconds),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn fetch_jwks(&self, jwks_uri: &str) -> Result<JwkSet, String> {
+        let body = self
+            .client
+            .get(jwks_uri)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?
+            .text()
+            .await
+            .map_err(|e| e.to_string())?;
+        serde_json::from_str(&body).map_err(|e| e.to_string())
+    }
+
+    // Find the issuer's key for `kid`, refetching the JWKS when the cache is stale or the key
+    // is unknown (e.g. after the issuer rotated keys)
+    async fn find_key(&self, issuer: &str, jwks_uri: &str, kid: Option<&str>) -> Option<Jwk> {
+        let lookup = |keys: &JwkSet| match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None => keys.keys.first().cloned(),
+        };
+        let refetch = {
+            let cache = self.cache.lock().unwrap();
+            match cache.get(issuer) {
+                Some(cached) => {
+                    let age = cached.fetched_at.elapsed();
+                    let fresh = age < self.cache_ttl;
+                    if fresh {
+                        if let Some(key) = lookup(&cached.keys) {
+                            return Some(key);
+                        }
+                    }
+                    !fresh || age >= JWKS_REFRESH_COOLDOWN
+                }
+                None => true,
+            }
+        };
+        if !refetch {
+            return None;
+        }
+
+        info!("Fetching JWKS for trusted issuer {}", issuer);
+        match self.fetch_jwks(jwks_uri).await {
+            Ok(keys) => {
+                let key = lookup(&keys);
+                self.cache.lock().unwrap().insert(
+                    issuer.to_string(),
+                    CachedJwks {
+                        keys,
+                        fetched_at: Instant::now(),
+                    },
+                );
+                key
+            }
+            Err(e) => {
+                warn!("Failed to fetch JWKS for {}: {}", issuer, e);
+                None
+            }
+        }
+    }
+
+    // Validate an upstream token's signature, issuer, audience and expiry
+    pub async fn validate(&self, token: &str) -> Result<ExternalClaims, AppError> {
+        let invalid = |reason: &str| AppError::InvalidRequest(format!("subject_token {}", reason));
+
+        let header = decode_header(token).map_err(|_| invalid("is not a valid JWT"))?;
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512 | Algorithm::PS256 | Algorithm::PS384
+                | Algorithm::PS512 | Algorithm::ES256 | Algorithm::ES384 | Algorithm::EdDSA
+        ) {
+            return Err(invalid("uses an unsupported algorithm"));
+        }
+
+        // Peek at the issuer to pick the key set; nothing is trusted until the signature checks out
+        let mut peek = Validation::new(header.alg);
+        peek.insecure_disable_signature_validation();
+        peek.validate_aud = false;
+        peek.validate_exp = false;
+        peek.required_spec_claims.clear();
+        let issuer = decode::<UnverifiedIssuer>(token, &DecodingKey::from_secret(&[]), &peek)
+            .map_err(|_| invalid("has no issuer"))?
+            .claims
+            .iss;
+        let jwks_uri = self
+            .jwks_uris
+            .get(&issuer)
+            .ok_or_else(|| invalid("is from an untrusted issuer"))?;
+
+        let jwk = self
+            .find_key(&issuer, jwks_uri, header.kid.as_deref())
+            .await
+            .ok_or_else(|| invalid("is signed with an unknown key"))?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|_| invalid("is signed with an unusable key"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        decode::<ExternalClaims>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                info!("Rejected subject token from {}: {}", issuer, e);
+                invalid("failed validation")
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_mode;
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use rsa::{pkcs8::DecodePublicKey, traits::PublicKeyParts, RsaPublicKey};
+
+    const ISSUER: &str = "https://idp.example.com";
+    const AUDIENCE: &str = "http://authentication:8082";
+
+    // Trusts ISSUER, with the test RSA key already cached as its `idp-1` key
+    fn trusted() -> TrustedIssuers {
+        let public_key = RsaPublicKey::from_public_key_pem(test_mode::RSA_PUBLIC_KEY).unwrap();
+        let keys: JwkSet = serde_json::from_value(serde_json::json!({ "keys": [{
+            "kty": "RSA",
+            "kid": "idp-1",
+            "alg": "RS256",
+            "n": URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+            "e": URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+        }]}))
+        .unwrap();
+        TrustedIssuers {
+            client: reqwest::Client::new(),
+            jwks_uris: HashMap::from([(ISSUER.to_string(), "http://127.0.0.1:1/jwks".to_string())]),
+            audience: AUDIENCE.to_string(),
+            cache_ttl: Duration::from_secs(3600),
+            cache: Mutex::new(HashMap::from([(ISSUER.to_string(), CachedJwks { keys, fetched_at: Instant::now() })])),
+        }
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("idp-1".to_string());
+        let mut payload = serde_json::json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "sub": "idp|12345",
+            "exp": chrono::Utc::now().timestamp() + 300,
+        });
+        payload.as_object_mut().unwrap().extend(claims.as_object().unwrap().clone());
+        encode(&header, &payload, &EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn validates_issuer_and_audience() {
+        let issuers = trusted();
+        let claims = issuers.validate(&sign(serde_json::json!({}))).await.unwrap();
+        assert_eq!((claims.iss.as_str(), claims.sub.as_str()), (ISSUER, "idp|12345"));
+
+        for claims in [
+            serde_json::json!({ "aud": "https://someone-else.example.com" }),
+            serde_json::json!({ "iss": "https://untrusted.example.com" }),
+            serde_json::json!({ "exp": chrono::Utc::now().timestamp() - 3600 }),
+        ] {
+            let result = issuers.validate(&sign(claims.clone())).await;
+            assert!(matches!(result, Err(AppError::InvalidRequest(_))), "accepted {}", claims);
+        }
+    }
+
+    #[tokio::test]
+    async fn maps_email_only_when_verified() {
+        let claims = |extra| async move { trusted().validate(&sign(extra)).await.unwrap() };
+
+        let verified = claims(serde_json::json!({ "email": "alice@example.com", "email_verified": true })).await;
+        assert_eq!(verified.mapped_subject("email").unwrap(), "alice@example.com");
+        // Some issuers send the flag as a string
+        let as_string = claims(serde_json::json!({ "email": "alice@example.com", "email_verified": "true" })).await;
+        assert_eq!(as_string.mapped_subject("email").unwrap(), "alice@example.com");
+
+        for extra in [
+            serde_json::json!({ "email": "alice@example.com", "email_verified": false }),
+            serde_json::json!({ "email": "alice@example.com" }),
+        ] {
+            let unverified = claims(extra).await;
+            assert!(matches!(unverified.mapped_subject("email"), Err(AppError::InvalidRequest(_))));
+            // The subject is still usable under the default mapping
+            assert_eq!(unverified.mapped_subject("sub").unwrap(), "idp|12345");
+        }
+        assert!(claims(serde_json::json!({})).await.mapped_subject("preferred_username").is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use sqlx::{postgres::PgRow, PgPool, Row};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::{config::Config, errors::AppError, metrics};
+
+// Refuse state-changing requests other than admin and token checks, e.g. during a migration
+pub const MAINTENANCE_MODE: &str = "maintenance_mode";
+// Serve POST /api/auth/register
+pub const REGISTRATION_ENABLED: &str = "registration_enabled";
+
+// Boolean switches operators can flip at runtime through the admin API. Each flag's value is read
+// from the `feature_flags` table, falling back to its configured default until a row sets it.
+// Every instance re-reads the table periodically, so a change reaches them all within one
+// FEATURE_FLAGS_REFRESH_SECONDS.
+pub struct FeatureFlags {
+    pool: PgPool,
+    defaults: BTreeMap<&'static str, bool>,
+    stored: RwLock<HashMap<String, bool>>,
+}
+
+impl FeatureFlags {
+    pub fn new(pool: PgPool, config: &Config) -> Self {
+        Self {
+            pool,
+            defaults: BTreeMap::from([(MAINTENANCE_MODE, false), (REGISTRATION_ENABLED, config.registration_enabled)]),
+            stored: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        let stored = self.stored.read().expect("flag cache lock poisoned");
+        stored
+            .get(name)
+            .copied()
+            .or_else(|| self.defaults.get(name).copied())
+            .unwrap_or(false)
+    }
+
+    // Current value of every known flag
+    pub fn all(&self) -> BTreeMap<&'static str, bool> {
+        self.defaults.keys().map(|&name| (name, self.is_enabled(name))).collect()
+    }
+
+    // Reload the stored values; flags without a row revert to their defaults
+    pub async fn refresh(&self) -> Result<(), AppError> {
+        let query = sqlx::query("SELECT name, enabled FROM feature_flags")
+            .map(|row: PgRow| (row.get::<String, _>("name"), row.get::<bool, _>("enabled")))
+            .fetch_all(&self.pool);
+        let stored = metrics::observe("feature_flags.list", query).await?.into_iter().collect();
+        *self.stored.write().expect("flag cache lock poisoned") = stored;
+        Ok(())
+    }
+
+    // Store a flag's value; it applies here at once and on other instances at their next refresh
+    pub async fn set(&self, name: &str, enabled: bool) -> Result<(), AppError> {
+        if !self.defaults.contains_key(name) {
+            return Err(AppError::NotFound);
+        }
+        let upsert = sqlx::query(
+            "INSERT INTO feature_flags (name, enabled, updated_at) VALUES ($1, $2, NOW()) \
+             ON CONFLICT (name) DO UPDATE SET enabled = EXCLUDED.enabled, updated_at = NOW()"
+        )
+        .bind(name)
+        .bind(enabled)
+        .execute(&self.pool);
+        metrics::observe("feature_flags.set", upsert).await?;
+        self.stored
+            .write()
+            .expect("flag cache lock poisoned")
+            .insert(name.to_string(), enabled);
+        Ok(())
+    }
+
+    // Keep the cache in step with the table; a failed refresh keeps the last known values
+    pub fn spawn_refresh(self: &Arc<Self>, interval: Duration) {
+        let flags = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = flags.refresh().await {
+                    warn!("Failed to refresh feature flags: {}", e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,229 @@
+use chrono::{DateTime, Utc};
+use maxminddb::Reader;
+use serde::Deserialize;
+use std::{net::IpAddr, sync::Arc};
+use tracing::{error, info};
+
+use crate::config::{Config, GeoVelocityAction};
+
+// A place on the globe, in degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+// Where an IP address is, as far as a geolocation source knows
+pub trait GeoResolver: Send + Sync {
+    fn locate(&self, ip: IpAddr) -> Option<GeoPoint>;
+}
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+// Great-circle distance between two points
+pub fn distance_km(a: GeoPoint, b: GeoPoint) -> f64 {
+    let (lat1, lat2) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.longitude - a.longitude).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+// IP geolocation is only accurate to a city or region, so nearby logins never count as travel
+const MIN_TRAVEL_KM: f64 = 300.0;
+
+// Speed the user would have needed to get from the previous login to this one, when that is
+// faster than `max_kmh` and the distance is beyond geolocation noise
+pub fn impossible_travel_kmh(
+    previous: GeoPoint,
+    previous_at: DateTime<Utc>,
+    current: GeoPoint,
+    now: DateTime<Utc>,
+    max_kmh: f64,
+) -> Option<f64> {
+    let distance = distance_km(previous, current);
+    if distance < MIN_TRAVEL_KM {
+        return None;
+    }
+    // Logins moments apart would otherwise divide by (almost) zero
+    let hours = ((now - previous_at).num_seconds().max(60)) as f64 / 3600.0;
+    let kmh = distance / hours;
+    (kmh > max_kmh).then_some(kmh)
+}
+
+// The part of a GeoLite2-City (or compatible) record the velocity check needs
+#[derive(Deserialize)]
+struct CityRecord {
+    location: Option<RecordLocation>,
+}
+
+#[derive(Deserialize)]
+struct RecordLocation {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+// Offline lookups in a MaxMind DB file (e.g. GeoLite2-City.mmdb), held in memory
+pub struct MaxMindGeoResolver {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMindGeoResolver {
+    pub fn open(path: &str) -> Result<Self, String> {
+        Ok(Self {
+            reader: Reader::open_readfile(path).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+impl GeoResolver for MaxMindGeoResolver {
+    fn locate(&self, ip: IpAddr) -> Option<GeoPoint> {
+        let record: CityRecord = self.reader.lookup(ip).ok()?.decode().ok()??;
+        let location = record.location?;
+        Some(GeoPoint {
+            latitude: location.latitude?,
+            longitude: location.longitude?,
+        })
+    }
+}
+
+// The resolver for GEOIP_DATABASE_PATH when geo-velocity checks are on. A database that can't be
+// loaded disables the checks rather than the service.
+pub fn resolver_from_config(config: &Config) -> Option<Arc<dyn GeoResolver>> {
+    if config.geo_velocity_action == GeoVelocityAction::Off {
+        return None;
+    }
+    let Some(path) = &config.geoip_database_path else {
+        error!("GEO_VELOCITY_ACTION is set but GEOIP_DATABASE_PATH is not; geo-velocity checks are disabled");
+        return None;
+    };
+    match MaxMindGeoResolver::open(path) {
+        Ok(resolver) => {
+            info!("Loaded GeoIP database from {}", path);
+            Some(Arc::new(resolver))
+        }
+        Err(e) => {
+            error!("Failed to load GeoIP database from {}: {}; geo-velocity checks are disabled", path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = vec![(2 << 5) | s.len() as u8];
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    fn double(value: f64) -> Vec<u8> {
+        let mut out = vec![(3 << 5) | 8];
+        out.extend_from_slice(&value.to_be_bytes());
+        out
+    }
+
+    fn uint16(value: u16) -> Vec<u8> {
+        let mut out = vec![(5 << 5) | 2];
+        out.extend_from_slice(&value.to_be_bytes());
+        out
+    }
+
+    fn uint32(value: u32) -> Vec<u8> {
+        let mut out = vec![(6 << 5) | 4];
+        out.extend_from_slice(&value.to_be_bytes());
+        out
+    }
+
+    fn uint64(value: u64) -> Vec<u8> {
+        // Extended type 9, stored as 9 - 7 after the type field
+        let mut out = vec![8, 9 - 7];
+        out.extend_from_slice(&value.to_be_bytes());
+        out
+    }
+
+    fn empty_array() -> Vec<u8> {
+        // Extended type 11, stored as 11 - 7 after the type field
+        vec![0, 11 - 7]
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![(7 << 5) | entries.len() as u8];
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn location(latitude: f64, longitude: f64) -> Vec<u8> {
+        map(&[("location", map(&[("latitude", double(latitude)), ("longitude", double(longitude))]))])
+    }
+
+    // Marks the start of the metadata section at the end of the file
+    const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+    // Zero bytes between the search tree and the data section
+    const DATA_SECTION_SEPARATOR: usize = 16;
+
+    // A one-node IPv4 tree (https://maxmind.github.io/MaxMind-DB/): 0.0.0.0/1 is in Paris,
+    // 128.0.0.0/1 in New York
+    fn database() -> Vec<u8> {
+        let paris = location(48.8566, 2.3522);
+        let new_york = location(40.7128, -74.0060);
+        let node_count = 1;
+        let left = node_count + DATA_SECTION_SEPARATOR;
+        let right = left + paris.len();
+
+        let mut file = Vec::new();
+        file.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+        file.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+        file.extend_from_slice(&[0; DATA_SECTION_SEPARATOR]);
+        file.extend(paris);
+        file.extend(new_york);
+        file.extend_from_slice(METADATA_MARKER);
+        file.extend(map(&[
+            ("binary_format_major_version", uint16(2)),
+            ("binary_format_minor_version", uint16(0)),
+            ("build_epoch", uint64(0)),
+            ("database_type", string("Test-City")),
+            ("description", map(&[])),
+            ("ip_version", uint16(4)),
+            ("languages", empty_array()),
+            ("node_count", uint32(node_count as u32)),
+            ("record_size", uint16(24)),
+        ]));
+        file
+    }
+
+    #[test]
+    fn locates_addresses_in_a_maxmind_database() {
+        let resolver = MaxMindGeoResolver {
+            reader: Reader::from_source(database()).unwrap(),
+        };
+        let paris = resolver.locate("10.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(paris, GeoPoint { latitude: 48.8566, longitude: 2.3522 });
+        let new_york = resolver.locate("203.0.113.9".parse().unwrap()).unwrap();
+        assert_eq!(new_york.longitude, -74.0060);
+        assert!(resolver.locate("2001:db8::1".parse().unwrap()).is_none());
+
+        assert!(Reader::from_source(b"not a database".to_vec()).is_err());
+    }
+
+    #[test]
+    fn flags_only_implausible_travel() {
+        let paris = GeoPoint { latitude: 48.8566, longitude: 2.3522 };
+        let new_york = GeoPoint { latitude: 40.7128, longitude: -74.0060 };
+        let versailles = GeoPoint { latitude: 48.8049, longitude: 2.1204 };
+        let then = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let hours = |h: i64| then + chrono::Duration::hours(h);
+
+        assert!((distance_km(paris, new_york) - 5837.0).abs() < 10.0);
+        // Paris to New York in an hour, but not in a day
+        assert!(impossible_travel_kmh(paris, then, new_york, hours(1), 900.0).is_some());
+        assert!(impossible_travel_kmh(paris, then, new_york, hours(24), 900.0).is_none());
+        // Nearby locations are within geolocation noise, however quick
+        assert!(impossible_travel_kmh(paris, then, versailles, then, 900.0).is_none());
+    }
+}
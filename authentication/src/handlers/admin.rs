@@ -0,0 +1,149 @@
+use crate::{
+    batch::BatchResponse,
+    config::RuntimeConfig,
+    errors::AppError,
+    events::{UserEvent, UserEventKind},
+    handlers::register::create_user,
+    metrics,
+    models::{ImportUsersRequest, ResetPasswordRequest, SetFlagRequest, UserExportQuery, UserSummary},
+    pagination::{Page, Pagination},
+    passwords::{check_new_password, hash_password},
+    state::AppState,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use futures_util::{stream, StreamExt};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+// Largest import accepted in one request; each user costs a bcrypt hash
+const MAX_IMPORT_USERS: usize = 100;
+
+// Exported rows read ahead of a slow client before the database read waits for it
+const EXPORT_BUFFER_ROWS: usize = 64;
+
+// Re-read runtime-safe settings and swap them in atomically. Immutable settings such as
+// ports, keys and database connection still require a restart.
+pub async fn reload(State(state): State<AppState>) -> Result<Json<serde_json::Value>, AppError> {
+    info!("Runtime configuration reload requested");
+    let current = state.runtime.load_full();
+    let reloaded = RuntimeConfig::reload(&state.config.runtime_config_file);
+
+    if reloaded.log_level != current.log_level {
+        (state.log_reloader)(&reloaded.log_level)
+            .map_err(|e| AppError::Validation(format!("Invalid log level {:?}: {}", reloaded.log_level, e)))?;
+    }
+
+    let changes = current.changes(&reloaded);
+    for change in &changes {
+        info!("Runtime configuration changed: {}", change);
+    }
+    if changes.is_empty() {
+        info!("Runtime configuration reloaded with no changes");
+    }
+    state.runtime.store(Arc::new(reloaded));
+
+    Ok(Json(serde_json::json!({ "changed": changes })))
+}
+
+// Current value of every feature flag
+pub async fn list_flags(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "flags": state.flags.all() }))
+}
+
+// Switch a feature flag on or off; other instances pick it up within FEATURE_FLAGS_REFRESH_SECONDS
+pub async fn set_flag(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    Json(payload): Json<SetFlagRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    state.flags.set(&name, payload.enabled).await?;
+    info!("Feature flag {} set to {}", name, payload.enabled);
+    Ok(Json(serde_json::json!({ "flags": state.flags.all() })))
+}
+
+// List accounts a page at a time
+pub async fn list_users(State(state): State<AppState>, pagination: Pagination) -> Result<Json<Page<UserSummary>>, AppError> {
+    let (users, total) = state.users.list(pagination).await?;
+    Ok(Json(Page::new(users, total, pagination)))
+}
+
+// Stream every user, or those changed since `since`, as newline-delimited JSON without password
+// hashes. Rows are written as Postgres returns them, so memory stays flat however many there are;
+// a database error mid-export cuts the response short rather than ending it cleanly.
+pub async fn export_users(State(state): State<AppState>, Query(query): Query<UserExportQuery>) -> Response {
+    info!("User export requested (since {:?})", query.since);
+    let (sender, receiver) = mpsc::channel::<Result<Bytes, AppError>>(EXPORT_BUFFER_ROWS);
+    let users = state.users.clone();
+    tokio::spawn(metrics::in_current_scope(async move {
+        let mut rows = users.export(query.since);
+        let mut exported = 0;
+        while let Some(row) = rows.next().await {
+            let line = row.map(|row| Bytes::from(format!("{}\n", row)));
+            if let Err(e) = &line {
+                warn!("User export failed after {} rows: {}", exported, e);
+            }
+            let failed = line.is_err();
+            if sender.send(line).await.is_err() {
+                info!("User export abandoned by the client after {} rows", exported);
+                return;
+            }
+            if failed {
+                return;
+            }
+            exported += 1;
+        }
+        info!("Exported {} users", exported);
+    }));
+
+    let body = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|line| (line, receiver))
+    });
+    ([(header::CONTENT_TYPE, "application/x-ndjson")], Body::from_stream(body)).into_response()
+}
+
+// Create users in bulk. Each is validated and created independently, so one bad entry doesn't
+// sink the rest; the 207 response reports every index's outcome.
+pub async fn import_users(
+    State(state): State<AppState>,
+    Json(payload): Json<ImportUsersRequest>,
+) -> Result<BatchResponse, AppError> {
+    if payload.users.len() > MAX_IMPORT_USERS {
+        return Err(AppError::InvalidRequest(format!(
+            "at most {} users can be imported per request",
+            MAX_IMPORT_USERS
+        )));
+    }
+    info!("Bulk import of {} users requested", payload.users.len());
+
+    let mut batch = BatchResponse::default();
+    for (index, user) in payload.users.iter().enumerate() {
+        let outcome = create_user(&state, user).await.map(|_| StatusCode::CREATED);
+        batch.push(index, outcome);
+    }
+    info!("Bulk import finished: {} succeeded, {} failed", batch.succeeded, batch.failed);
+    Ok(batch)
+}
+
+// Set a user's password on their behalf, e.g. after a lockout. Applies the password policy but
+// not MIN_PASSWORD_AGE_HOURS, and restarts the minimum age from now.
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Path(user_id): Path<i32>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let user = state.users.find_by_id(user_id).await?.ok_or(AppError::NotFound)?;
+    check_new_password(&state.config, state.breach_checker.as_ref(), &user.role, &payload.new_password).await?;
+
+    let password_hash = hash_password(&state.config, payload.new_password).await?;
+    let now = state.clock.now();
+    state.users.update_password(user.id, &password_hash, now).await?;
+    info!("Password reset by admin for user: {}", user.username);
+    state.events.emit(UserEvent::new(UserEventKind::PasswordChanged, user.id, &user.username, now));
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,114 @@
+use crate::{
+    client_ip::ClientIp,
+    errors::AppError,
+    extract::JsonOrForm,
+    handlers::login::{check_login_location, complete_login},
+    login_codes,
+    mailer::Email,
+    metrics,
+    models::{EmailCodeRequest, EmailCodeVerifyRequest, TokenResponse},
+    state::AppState,
+    tenant::Tenant,
+};
+use axum::{extract::State, http::HeaderMap, response::Json};
+use chrono::Duration;
+use tracing::{info, warn};
+
+// Authentication method reported in `amr` for logins with an emailed code (RFC 8176 has no
+// dedicated value, so this follows the common OIDC provider usage)
+const EMAIL_AMR: &str = "email";
+
+// Email a one-time login code to the account with this address. The response is the same whether
+// or not such an account exists, and the lookup and mailing happen after it is sent so timing
+// doesn't tell either.
+pub async fn request_code(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    JsonOrForm(payload): JsonOrForm<EmailCodeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    info!("Email login code requested from {}", client_ip);
+    state.check_rate_limits(client_ip, tenant.as_deref(), &payload.email).await?;
+
+    tokio::spawn(metrics::in_current_scope(async move {
+        if let Err(e) = send_code(&state, &payload.email).await {
+            warn!("Failed to send email login code: {}", e);
+        }
+    }));
+
+    Ok(Json(serde_json::json!({
+        "message": "If an account uses this email, a login code has been sent to it"
+    })))
+}
+
+async fn send_code(state: &AppState, email: &str) -> Result<(), String> {
+    let config = &state.config;
+    let user = match state.users.find_by_email(email).await.map_err(|e| e.to_string())? {
+        Some(user) if !config.is_selftest_user(&user.username) => user,
+        _ => {
+            info!("No account to send an email login code to");
+            return Ok(());
+        }
+    };
+    let ttl = Duration::seconds(config.email_login_code_ttl_seconds);
+    let code = login_codes::create(&state.pool, user.id, ttl, state.clock.now())
+        .await
+        .map_err(|e| e.to_string())?;
+    let message = Email {
+        to: user.email,
+        subject: "Your login code".to_string(),
+        body: format!(
+            "Your login code is {}. It expires in {} minutes and can be used once.\n\n\
+             If you didn't ask to log in, you can ignore this email.",
+            code,
+            (config.email_login_code_ttl_seconds / 60).max(1)
+        ),
+    };
+    state.mailer.send(&message).await?;
+    info!("Sent email login code to user: {}", user.username);
+    Ok(())
+}
+
+// Exchange an emailed code for tokens, as a login without the password
+pub async fn verify_code(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    request_headers: HeaderMap,
+    JsonOrForm(payload): JsonOrForm<EmailCodeVerifyRequest>,
+) -> Result<(HeaderMap, Json<TokenResponse>), AppError> {
+    info!("Email login code presented from {}", client_ip);
+    state.check_rate_limits(client_ip, tenant.as_deref(), &payload.email).await?;
+
+    let mut user = match state.users.find_by_email(&payload.email).await? {
+        Some(user) if !state.config.is_selftest_user(&user.username) => user,
+        _ => return Err(AppError::Unauthorized),
+    };
+    let max_attempts = state.config.email_login_max_attempts;
+    if !login_codes::redeem(&state.pool, user.id, &payload.code, max_attempts, state.clock.now()).await? {
+        info!("Email login code rejected for user: {}", user.username);
+        return Err(AppError::Unauthorized);
+    }
+    info!("Email login code accepted for user: {}", user.username);
+
+    // Receiving the code proves the address, which also satisfies UNVERIFIED_EMAIL_POLICY
+    if user.email_verified_at.is_none() {
+        let now = state.clock.now();
+        state.users.mark_email_verified(user.id, now).await?;
+        user.email_verified_at = Some(now);
+    }
+
+    // The code is the step-up, so unusual travel is audited but doesn't block this login
+    check_login_location(&state, &user, client_ip, false).await?;
+
+    complete_login(
+        &state,
+        user,
+        payload.client_id.as_deref(),
+        payload.scope.as_deref(),
+        None,
+        Some(vec![EMAIL_AMR.to_string()]),
+        &request_headers,
+    )
+    .await
+}
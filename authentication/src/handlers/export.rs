@@ -0,0 +1,110 @@
+use crate::{
+    client_ip::ClientIp,
+    errors::AppError,
+    extract::BearerClaims,
+    metrics,
+    rate_limit::Limit,
+    state::AppState,
+    tenant::Tenant,
+};
+use axum::{extract::State, response::Json};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+use sqlx::{PgPool, Row};
+use tracing::{info, warn};
+
+// The user's own row, minus credentials
+async fn profile(pool: &PgPool, username: &str) -> Result<Value, AppError> {
+    let query = sqlx::query("SELECT to_jsonb(u) - 'password_hash' AS profile FROM users u WHERE username = $1")
+        .bind(username)
+        .fetch_optional(pool);
+    let row = metrics::observe("export.profile", query)
+        .await?
+        .ok_or_else(|| AppError::InvalidToken("unknown subject".to_string()))?;
+    Ok(row.get("profile"))
+}
+
+// Login history as far as it is kept: the last login and the last GeoIP-located one. Read through
+// `to_jsonb` so columns from migrations that haven't been run come back null.
+async fn logins(pool: &PgPool, username: &str) -> Result<Value, AppError> {
+    let query = sqlx::query(
+        "SELECT jsonb_build_object( \
+            'last_login_at', u.row -> 'last_login_at', \
+            'last_located_login_at', u.row -> 'last_located_login_at', \
+            'last_login_latitude', u.row -> 'last_login_latitude', \
+            'last_login_longitude', u.row -> 'last_login_longitude') AS logins \
+         FROM (SELECT to_jsonb(users) AS row FROM users WHERE username = $1) u"
+    )
+    .bind(username)
+    .fetch_optional(pool);
+    let row = metrics::observe("export.logins", query)
+        .await?
+        .ok_or_else(|| AppError::InvalidToken("unknown subject".to_string()))?;
+    Ok(row.get("logins"))
+}
+
+// Every session handle issued at login, revoked and expired ones included, without the handle hashes
+async fn session_handles(pool: &PgPool, username: &str) -> Result<Value, AppError> {
+    let query = sqlx::query(
+        "SELECT COALESCE(jsonb_agg(to_jsonb(h) - 'handle_hash' - 'user_id' ORDER BY h.created_at), '[]') AS session_handles \
+         FROM session_handles h JOIN users u ON u.id = h.user_id WHERE u.username = $1"
+    )
+    .bind(username)
+    .fetch_one(pool);
+    let row = metrics::observe("export.session_handles", query).await?;
+    Ok(row.get("session_handles"))
+}
+
+// Active refresh-token sessions, without the token hashes
+async fn sessions(pool: &PgPool, username: &str, now: DateTime<Utc>) -> Result<Value, AppError> {
+    let query = sqlx::query(
+        "SELECT COALESCE(jsonb_agg(to_jsonb(t) - 'token_hash' - 'user_id' ORDER BY t.created_at), '[]') AS sessions \
+         FROM refresh_tokens t JOIN users u ON u.id = t.user_id \
+         WHERE u.username = $1 AND t.revoked_at IS NULL AND t.expires_at > $2"
+    )
+    .bind(username)
+    .bind(now)
+    .fetch_one(pool);
+    let row = metrics::observe("export.sessions", query).await?;
+    Ok(row.get("sessions"))
+}
+
+// Self-service export of the data held about the bearer-token user (GDPR/CCPA access request)
+pub async fn export(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    BearerClaims(claims): BearerClaims,
+) -> Result<Json<Value>, AppError> {
+    let pool = &state.pool;
+    info!("Data export requested for user: {}", claims.sub);
+    state.require_recent_auth(&claims)?;
+
+    // Exports scan several tables, so they get their own, much tighter per-user budget
+    state.check_rate_limits(client_ip, tenant.as_deref(), &claims.sub).await?;
+    let per_minute = state.runtime.load().rate_limit_export_per_minute;
+    state
+        .rate_limiter
+        .check(&[Limit::new("export", &claims.sub, per_minute)], state.clock.now())
+        .await
+        .map_err(|retry_after| AppError::RateLimited(retry_after.as_secs().max(1)))?;
+
+    let mut bundle = Map::new();
+    bundle.insert("subject".to_string(), Value::String(claims.sub.clone()));
+    let now = state.clock.now();
+    bundle.insert("exported_at".to_string(), Value::String(now.to_rfc3339()));
+    for section in &state.config.data_export_sections {
+        let data = match section.as_str() {
+            "profile" => profile(pool, &claims.sub).await?,
+            "logins" => logins(pool, &claims.sub).await?,
+            "sessions" => sessions(pool, &claims.sub, now).await?,
+            "session_handles" => session_handles(pool, &claims.sub).await?,
+            other => {
+                warn!("Skipping unknown data export section: {}", other);
+                continue;
+            }
+        };
+        bundle.insert(section.clone(), data);
+    }
+    Ok(Json(Value::Object(bundle)))
+}
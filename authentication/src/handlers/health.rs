@@ -0,0 +1,121 @@
+use crate::{
+    db,
+    key_check,
+    models::{ComponentHealth, DetailedHealthResponse, HealthStatus},
+    state::AppState,
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use reqwest::Url;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+// Run one check within `timeout`. A failure marks the component `failed_status`: `down` for
+// dependencies logins can't do without, `degraded` for ones that only cost a feature.
+async fn check<F>(name: &'static str, failed_status: HealthStatus, timeout: Duration, probe: F) -> ComponentHealth
+where
+    F: Future<Output = Result<String, String>>,
+{
+    let started = Instant::now();
+    let (status, detail) = match tokio::time::timeout(timeout, probe).await {
+        Ok(Ok(detail)) => (HealthStatus::Up, detail),
+        Ok(Err(e)) => (failed_status, e),
+        Err(_) => (failed_status, format!("timed out after {}ms", timeout.as_millis())),
+    };
+    if status != HealthStatus::Up {
+        warn!("Health check {} is {:?}: {}", name, status, detail);
+    }
+    ComponentHealth {
+        name,
+        status,
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        detail: Some(detail),
+    }
+}
+
+async fn check_database(state: &AppState) -> Result<String, String> {
+    sqlx::query("SELECT 1")
+        .execute(&state.pool)
+        .await
+        .map_err(|e| e.to_string())?;
+    db::check_schema(&state.pool).await?;
+    Ok(format!("{} connections, {} idle", state.pool.size(), state.pool.num_idle()))
+}
+
+// The collector only receives telemetry, so reaching its port is all that is checked
+async fn check_otlp_collector(endpoint: &str) -> Result<String, String> {
+    let url = Url::parse(endpoint).map_err(|e| format!("invalid endpoint {}: {}", endpoint, e))?;
+    let host = url.host_str().ok_or_else(|| format!("endpoint {} has no host", endpoint))?;
+    let port = url.port_or_known_default().unwrap_or(4318);
+    TcpStream::connect((host, port)).await.map_err(|e| format!("{}:{}: {}", host, port, e))?;
+    Ok(format!("{}:{} is reachable", host, port))
+}
+
+async fn check_keys(state: &AppState) -> Result<String, String> {
+    let problems = key_check::validate_keys(&state.config);
+    if !problems.is_empty() {
+        return Err(problems.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "));
+    }
+    Ok("every configured key suits the token format".to_string())
+}
+
+// Health of each dependency for dashboards, checked concurrently with HEALTH_CHECK_TIMEOUT_MS
+// each. Answers 503 when any of them is down.
+pub async fn detailed(State(state): State<AppState>) -> Response {
+    let config = &state.config;
+    let timeout = Duration::from_millis(config.health_check_timeout_ms);
+    let (database, collector, mailer, rate_limits, keys) = tokio::join!(
+        check("postgres", HealthStatus::Down, timeout, check_database(&state)),
+        check(
+            "otlp_collector",
+            HealthStatus::Degraded,
+            timeout,
+            check_otlp_collector(&config.otel_exporter_otlp_endpoint)
+        ),
+        check("mailer", HealthStatus::Degraded, timeout, state.mailer.check()),
+        check("rate_limit_store", HealthStatus::Degraded, timeout, state.rate_limiter.probe()),
+        check("keys", HealthStatus::Down, timeout, check_keys(&state)),
+    );
+    let components = vec![database, collector, mailer, rate_limits, keys];
+    let status = components
+        .iter()
+        .map(|component| component.status)
+        .min()
+        .unwrap_or(HealthStatus::Up);
+    info!("Detailed health check: {:?}", status);
+
+    let code = if status == HealthStatus::Down {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (code, Json(DetailedHealthResponse { status, components })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_and_slow_probes_take_the_component_status() {
+        let timeout = Duration::from_millis(20);
+        let up = check("fast", HealthStatus::Down, timeout, async { Ok("fine".to_string()) }).await;
+        assert_eq!(up.status, HealthStatus::Up);
+
+        let failed = check("broken", HealthStatus::Degraded, timeout, async { Err("refused".to_string()) }).await;
+        assert_eq!(failed.status, HealthStatus::Degraded);
+        assert_eq!(failed.detail.as_deref(), Some("refused"));
+
+        let slow = check("slow", HealthStatus::Down, timeout, std::future::pending()).await;
+        assert_eq!(slow.status, HealthStatus::Down);
+        assert_eq!(slow.detail.as_deref(), Some("timed out after 20ms"));
+        assert_eq!([up.status, failed.status, slow.status].into_iter().min(), Some(HealthStatus::Down));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    client_ip::ClientIp,
+    errors::AppError,
+    events::{UserEvent, UserEventKind},
+    extract::BearerClaims,
+    handlers::login::granted_scopes,
+    issuance_warnings,
+    models::TokenResponse,
+    rate_limit::Limit,
+    state::AppState,
+    tenant::Tenant,
+    tokens::{issue_access_token, TokenGrant},
+};
+use axum::{
+    extract::{Path, State},
+    response::Json,
+};
+use tracing::{info, warn};
+
+const ADMIN_ROLE: &str = "admin";
+
+// Issue a short-lived token for another user on behalf of an admin. Requires both the internal
+// API key and an admin bearer token; the token's `act` claim names the admin so downstream
+// services can tell the session is impersonated.
+pub async fn impersonate(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    BearerClaims(admin): BearerClaims,
+    Path(user_id): Path<i32>,
+) -> Result<Json<TokenResponse>, AppError> {
+    let config = &state.config;
+    let runtime = state.runtime.load();
+
+    if admin.role != ADMIN_ROLE {
+        warn!(
+            target: "audit",
+            "Impersonation of user {} denied: {} from {} is not an admin",
+            user_id, admin.sub, client_ip
+        );
+        return Err(AppError::Forbidden("impersonation requires the admin role".to_string()));
+    }
+    // An impersonated session must not be able to chain into further impersonation
+    if let Some(actor) = &admin.act {
+        warn!(
+            target: "audit",
+            "Impersonation of user {} denied: {} is already being impersonated by {}",
+            user_id, admin.sub, actor.sub
+        );
+        return Err(AppError::Forbidden("impersonated sessions cannot impersonate".to_string()));
+    }
+    state.require_recent_auth(&admin)?;
+
+    state.check_rate_limits(client_ip, tenant.as_deref(), &admin.sub).await?;
+    state
+        .rate_limiter
+        .check(
+            &[Limit::new("impersonate", &admin.sub, runtime.rate_limit_impersonate_per_minute)],
+            state.clock.now(),
+        )
+        .await
+        .map_err(|retry_after| AppError::RateLimited(retry_after.as_secs().max(1)))?;
+
+    let target = state.users.find_by_id(user_id).await?.ok_or(AppError::NotFound)?;
+    if target.role == ADMIN_ROLE || config.is_selftest_user(&target.username) {
+        warn!(
+            target: "audit",
+            "Impersonation of {} denied: {} from {} may not impersonate this account",
+            target.username, admin.sub, client_ip
+        );
+        return Err(AppError::Forbidden("this account cannot be impersonated".to_string()));
+    }
+
+    let scopes = granted_scopes(&runtime, &target.role, None)?;
+    let grant = TokenGrant {
+        sub: target.username,
+        role: target.role,
+        aud: config.jwt_audience.clone(),
+        scope: (!scopes.is_empty()).then(|| scopes.join(" ")),
+        acr: None,
+        auth_time: None,
+        amr: None,
+        act: Some(admin.sub.clone()),
+        jkt: None,
+        email_verified: None,
+        jti: None,
+    };
+    let (token, expires_in) =
+        issue_access_token(config, &grant, config.impersonation_token_ttl_seconds, state.clock.now())?;
+
+    warn!(
+        target: "audit",
+        "IMPERSONATION: admin {} from {} issued a {}s token for user {} (id {})",
+        admin.sub, client_ip, expires_in, grant.sub, target.id
+    );
+    state.events.emit(
+        UserEvent::new(UserEventKind::Impersonated, target.id, &grant.sub, state.clock.now()).with_actor(&admin.sub),
+    );
+    info!("Impersonation token issued for {}", grant.sub);
+
+    Ok(Json(TokenResponse {
+        access_token: token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        refresh_token: None,
+        issued_token_type: None,
+        email_verified: None,
+        session_handle: None,
+        warnings: issuance_warnings::take(&grant.sub),
+    }))
+}
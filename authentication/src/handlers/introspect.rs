@@ -0,0 +1,230 @@
+use crate::{
+    cookies::{read_cookie, ACCESS_TOKEN_COOKIE},
+    dpop::DPOP_SCHEME,
+    errors::AppError,
+    models::{IntrospectionRequest, IntrospectionResponse, RevocationRequest},
+    refresh_tokens,
+    session_handles::{self, HANDLE_PREFIX},
+    state::AppState,
+    tokens::{audience_claim, load_verification_key, verify_token, TokenError},
+};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::Json,
+    Form,
+};
+use tracing::info;
+
+// The token to introspect: the `token` parameter, or with INTROSPECTION_ACCEPT_COOKIE the access
+// token cookie of a browser request forwarded by a proxy. The parameter wins when both are sent.
+fn presented_token<'a>(token: Option<&'a str>, headers: &'a HeaderMap, accept_cookie: bool) -> Option<&'a str> {
+    token
+        .filter(|token| !token.is_empty())
+        .or_else(|| accept_cookie.then(|| read_cookie(headers, ACCESS_TOKEN_COOKIE)).flatten())
+}
+
+// Token introspection endpoint (RFC 7662)
+pub async fn introspect(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(payload): Form<IntrospectionRequest>,
+) -> Result<Json<IntrospectionResponse>, AppError> {
+    let config = &state.config;
+    info!("Introspection endpoint called");
+
+    let token = presented_token(payload.token.as_deref(), &headers, config.introspection_accept_cookie)
+        .ok_or_else(|| AppError::InvalidRequest("token is required".to_string()))?;
+
+    if config.session_handles_enabled && token.starts_with(HANDLE_PREFIX) {
+        return introspect_handle(&state, token).await;
+    }
+
+    // Cookie-delivered tokens go through exactly the same verification as the parameter
+    let verification_key = load_verification_key(config)?;
+    let mut verified = verify_token(&verification_key, token, config.jwt_issuer.as_deref(), &config.required_claims, state.clock.now(), config.clock_skew(), config.max_token_bytes);
+    if let Ok(claims) = &verified {
+        if session_handles::is_access_token_revoked(&state.pool, config, claims).await? {
+            verified = Err(TokenError::Revoked);
+        }
+    }
+    let claims = match verified {
+        Ok(claims) => claims,
+        Err(e) => {
+            info!("Introspected token is not active: {}", e);
+            return Ok(Json(IntrospectionResponse::inactive()));
+        }
+    };
+
+    Ok(Json(IntrospectionResponse {
+        active: true,
+        scope: claims.scope.clone(),
+        username: Some(claims.sub.clone()),
+        token_type: Some(if claims.cnf.is_some() { DPOP_SCHEME } else { "Bearer" }.to_string()),
+        exp: Some(claims.exp),
+        iat: Some(claims.iat),
+        sub: Some(claims.sub),
+        aud: claims.aud,
+        iss: claims.iss,
+        jti: claims.jti,
+        act: claims.act,
+        cnf: claims.cnf,
+        ..IntrospectionResponse::inactive()
+    }))
+}
+
+// Describe the session behind an opaque handle, as introspection of its access token would
+async fn introspect_handle(state: &AppState, handle: &str) -> Result<Json<IntrospectionResponse>, AppError> {
+    let Some(session) = session_handles::find_active(&state.pool, handle, state.clock.now()).await? else {
+        info!("Introspected session handle is not active");
+        return Ok(Json(IntrospectionResponse::inactive()));
+    };
+    Ok(Json(IntrospectionResponse {
+        active: true,
+        scope: session.scope,
+        username: Some(session.username.clone()),
+        token_type: Some("Bearer".to_string()),
+        exp: Some(session.expires_at.timestamp() as usize),
+        iat: Some(session.issued_at.timestamp() as usize),
+        sub: Some(session.username),
+        aud: session.audience.map(|aud| audience_claim(state.config.audience_claim_format, aud)),
+        iss: state.config.jwt_issuer.clone(),
+        ..IntrospectionResponse::inactive()
+    }))
+}
+
+// Token revocation endpoint (RFC 7009) for session handles. The response is the same whether or
+// not the handle was known, as the RFC asks. The login's refresh token family is revoked with it,
+// and with SESSION_HANDLE_REVOKES_ACCESS_TOKEN the JWTs issued for the session stop verifying too.
+pub async fn revoke(
+    State(state): State<AppState>,
+    Form(payload): Form<RevocationRequest>,
+) -> Result<StatusCode, AppError> {
+    if !state.config.session_handles_enabled {
+        return Err(AppError::NotFound);
+    }
+    if let Some(jti) = session_handles::revoke(&state.pool, &payload.token, state.clock.now()).await? {
+        refresh_tokens::revoke_session(&state.pool, &jti).await?;
+        info!("Revoked a session handle");
+    }
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::Claims, test_mode, tokens::VerificationKey};
+    use axum::http::HeaderValue;
+    use chrono::{Duration, Utc};
+    use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header};
+
+    const SKEW: Duration = Duration::seconds(60);
+    const MAX_BYTES: usize = 8192;
+
+    #[test]
+    fn introspects_a_cookie_delivered_token() {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: "johndoe".to_string(),
+            role: "user".to_string(),
+            exp: now.timestamp() as usize + 3600,
+            iat: now.timestamp() as usize,
+            nbf: None,
+            iss: None,
+            aud: None,
+            scope: Some("openid".to_string()),
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            permissions: None,
+            cnf: None,
+            email_verified: None,
+            jti: None,
+        };
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let token = encode(&Header::new(Algorithm::RS256), &claims, &encoding_key).unwrap();
+        let mut headers = HeaderMap::new();
+        let cookie = format!("csrf_token=abc; {}={}", ACCESS_TOKEN_COOKIE, token);
+        headers.insert(axum::http::header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+
+        // Ignored unless enabled, and never preferred over an explicit parameter
+        assert_eq!(presented_token(None, &headers, false), None);
+        assert_eq!(presented_token(Some("explicit"), &headers, true), Some("explicit"));
+
+        let presented = presented_token(None, &headers, true).unwrap();
+        let key = VerificationKey::Jwt {
+            key: DecodingKey::from_rsa_pem(test_mode::RSA_PUBLIC_KEY.as_bytes()).unwrap(),
+            algorithms: vec![jsonwebtoken::Algorithm::RS256],
+            claim_names: Default::default(),
+            audience_keys: Default::default(),
+            verification_keys: Default::default(),
+        };
+        let introspected = verify_token(&key, presented, None, &[], now, SKEW, MAX_BYTES).unwrap();
+        assert_eq!(introspected.sub, "johndoe");
+
+        // A tampered cookie is rejected like any other token
+        let mut tampered = HeaderMap::new();
+        let cookie = format!("{}={}x", ACCESS_TOKEN_COOKIE, token);
+        tampered.insert(axum::http::header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert!(verify_token(&key, presented_token(None, &tampered, true).unwrap(), None, &[], now, SKEW, MAX_BYTES).is_err());
+    }
+
+    // Needs a database initialized with database/init-db.sql at TEST_DATABASE_URL; skipped without one
+    #[tokio::test]
+    async fn cookie_delivered_token_of_a_revoked_handle_is_inactive() {
+        use crate::{config::Config, tokens::{issue_access_token, TokenGrant}};
+        use sqlx::PgPool;
+
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let pool = PgPool::connect(&url).await.unwrap();
+        let mut config = Config::from_env();
+        config.introspection_accept_cookie = true;
+        config.session_handle_revokes_access_token = true;
+        let state = AppState::for_tests(config, pool.clone());
+        let now = state.clock.now();
+
+        let username = format!("introspect-{}", session_handles::new_jti());
+        let user_id: i32 = sqlx::query_scalar(
+            "INSERT INTO users (username, email, password_hash) VALUES ($1, $2, 'unused') RETURNING id"
+        )
+        .bind(&username)
+        .bind(format!("{}@example.com", username))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let jti = session_handles::new_jti();
+        let handle = session_handles::create(&pool, user_id, &jti, None, None, now + Duration::hours(1), now).await.unwrap();
+        let grant = TokenGrant {
+            sub: username,
+            role: "user".to_string(),
+            aud: None,
+            scope: None,
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            jkt: None,
+            email_verified: None,
+            jti: Some(jti),
+        };
+        let (token, _) = issue_access_token(&state.config, &grant, 3600, now).unwrap();
+        let mut headers = HeaderMap::new();
+        let cookie = format!("{}={}", ACCESS_TOKEN_COOKIE, token);
+        headers.insert(axum::http::header::COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        let introspected = || async {
+            let request = IntrospectionRequest { token: None };
+            introspect(State(state.clone()), headers.clone(), Form(request)).await.unwrap().0
+        };
+
+        assert!(introspected().await.active);
+        session_handles::revoke(&pool, &handle, now).await.unwrap();
+        let response = introspected().await;
+        assert!(!response.active);
+        assert_eq!(response.username, None);
+
+        sqlx::query("DELETE FROM users WHERE id = $1").bind(user_id).execute(&pool).await.unwrap();
+    }
+}
@@ -1,108 +1,369 @@
 use crate::{
+    client_ip::ClientIp,
+    clients::find_client,
     errors::AppError,
-    models::{Claims, LoginRequest, TokenResponse, User},
+    events::{UserEvent, UserEventKind},
+    issuance_warnings,
+    metrics,
+    models::{LoginRequest, TokenResponse, User},
+    passwords::{run_hash_task, HashTaskError, SubmittedPassword},
+    refresh_tokens,
+    session_handles,
     state::AppState,
-    config::Config,
+    config::{Config, GeoVelocityAction, RuntimeConfig},
+    geo::impossible_travel_kmh,
+    mailer::Email,
+    cookies,
+    extract::JsonOrForm,
+    tenant::Tenant,
+    tokens::{email_verified_claim, issue_access_token, TokenGrant, ACCESS_TOKEN_TTL_SECONDS},
 };
-use axum::{extract::State, response::Json};
+use axum::{extract::State, http::HeaderMap, response::Json};
 use bcrypt::verify;
-use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, Algorithm, Header};
-use sqlx::{postgres::PgRow, Row};
-use std::fs;
-use jsonwebtoken::EncodingKey;
-use tracing::info;
-
-
-// Helper function to load RSA private key
-fn load_private_key(config: &Config) -> Result<EncodingKey, AppError> {
-    let private_key_path = &config.rsa_private_key_path;
-    info!("Loading private key from: {}", private_key_path);
-    let private_key_pem = fs::read_to_string(private_key_path)
-        .map_err(|e| AppError::KeyLoading(format!("Failed to read private key from {}: {}", private_key_path, e)))?;
-    EncodingKey::from_rsa_pem(private_key_pem.as_bytes())
-        .map_err(|e| AppError::KeyLoading(format!("Failed to parse RSA private key: {}", e)))
+use sha2::{Digest, Sha256};
+use std::{net::IpAddr, time::Duration};
+use tracing::{info, warn};
+
+// Authentication context classes this service can achieve. Only password authentication
+// exists today, so requests for stronger contexts such as `mfa` cannot be satisfied.
+pub const SUPPORTED_ACR_VALUES: [&str; 1] = ["pwd"];
+
+// Pick the first requested authentication context we can satisfy
+fn resolve_acr(acr_values: Option<&str>) -> Result<&'static str, AppError> {
+    let Some(acr_values) = acr_values else {
+        return Ok(SUPPORTED_ACR_VALUES[0]);
+    };
+    acr_values
+        .split_whitespace()
+        .find_map(|requested| SUPPORTED_ACR_VALUES.iter().find(|supported| **supported == requested))
+        .copied()
+        .ok_or_else(|| AppError::UnmetAuthenticationRequirements(acr_values.to_string()))
+}
+
+// Offload password verification to blocking thread pool, bounded by HASH_TIMEOUT_SECONDS
+pub async fn verify_password(
+    config: &Config,
+    password: String,
+    stored_hash: String,
+) -> Result<bool, HashTaskError> {
+    let timeout = Duration::from_secs(config.hash_timeout_seconds);
+    run_hash_task(timeout, move || verify(&password, &stored_hash)).await
+}
+
+// Verify a user's submitted password against their stored hash, which may have been made from a
+// client-side hash of it; passwords that can't be compared with the stored hash don't match
+pub async fn verify_user_password(
+    config: &Config,
+    password: SubmittedPassword,
+    stored_hash: &str,
+) -> Result<bool, HashTaskError> {
+    match password.verification_input(stored_hash) {
+        Some((secret, bcrypt_hash)) => verify_password(config, secret, bcrypt_hash).await,
+        None => Ok(false),
+    }
+}
+
+// Single-flight key binding the username, stored hash and submitted password so that
+// only identical attempts against the same account state are ever coalesced
+fn single_flight_key(username: &str, stored_hash: &str, password: &SubmittedPassword) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    let scheme = password.scheme.map_or("", |scheme| scheme.as_str());
+    for part in [username, stored_hash, scheme, &password.secret] {
+        hasher.update(part.as_bytes());
+        hasher.update([0u8]);
+    }
+    hasher.finalize().to_vec()
+}
+
+// Tarpit key counting failed logins against a username
+fn tarpit_user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+// Reject the credentials, first holding the response for the escalating delay owed by recent
+// failures from this address or against this username
+async fn reject_credentials(state: &AppState, runtime: &RuntimeConfig, client_ip: IpAddr, username: &str) -> AppError {
+    if let Some(policy) = runtime.tarpit_policy() {
+        let keys = [format!("ip:{}", client_ip), tarpit_user_key(username)];
+        let delay = state.tarpit.record_failure(&keys, &policy, state.clock.now());
+        if !delay.is_zero() {
+            info!("Delaying failed login for {} from {} by {}ms", username, client_ip, delay.as_millis());
+        }
+        state.tarpit.sleep(delay).await;
+    }
+    AppError::Unauthorized
+}
+
+// Resolve the scopes to grant: the user's full role-derived set, or the requested subset of it
+pub fn granted_scopes(runtime: &RuntimeConfig, role: &str, requested: Option<&str>) -> Result<Vec<String>, AppError> {
+    let available = runtime.role_scopes.get(role).cloned().unwrap_or_else(|| {
+        issuance_warnings::add(format!("role {} has no ROLE_SCOPES entry, so no scopes were granted", role));
+        Vec::new()
+    });
+    granted_subset(&available, requested)
+}
+
+// Resolve the scopes to grant out of `available`: all of them, or the requested subset. Requesting
+// any scope outside it is refused as insufficient.
+pub fn granted_subset(available: &[String], requested: Option<&str>) -> Result<Vec<String>, AppError> {
+    let Some(requested) = requested else {
+        return Ok(available.to_vec());
+    };
+    let requested: Vec<String> = requested.split_whitespace().map(str::to_string).collect();
+    let excess: Vec<&str> = requested
+        .iter()
+        .filter(|scope| !available.contains(scope))
+        .map(String::as_str)
+        .collect();
+    if !excess.is_empty() {
+        return Err(AppError::InsufficientScope(excess.join(" ")));
+    }
+    Ok(requested)
+}
+
+// Compare a login's location with the previous located login when GEO_VELOCITY_ACTION is set.
+// Impossible travel is audited, then mailed to the user or, for `step_up` with `enforce`, refused
+// so the user has to prove email access instead. An accepted login's location becomes the one the
+// next is judged against.
+pub async fn check_login_location(state: &AppState, user: &User, client_ip: IpAddr, enforce: bool) -> Result<(), AppError> {
+    let config = &state.config;
+    let Some(location) = state.geo.as_ref().and_then(|geo| geo.locate(client_ip)) else {
+        return Ok(());
+    };
+    let now = state.clock.now();
+    let travel = state
+        .users
+        .last_located_login(user.id)
+        .await?
+        .and_then(|(previous, previous_at)| {
+            impossible_travel_kmh(previous, previous_at, location, now, config.geo_velocity_max_kmh)
+        });
+    if let Some(kmh) = travel {
+        warn!(
+            target: "security",
+            "Impossible travel for user {}: login from {} implies {:.0} km/h since the previous login",
+            user.username,
+            client_ip,
+            kmh
+        );
+        state
+            .events
+            .emit(UserEvent::new(UserEventKind::LoginAnomaly, user.id, &user.username, now));
+        match config.geo_velocity_action {
+            GeoVelocityAction::StepUp if enforce => return Err(AppError::StepUpRequired),
+            GeoVelocityAction::Notify => {
+                let mailer = state.mailer.clone();
+                let email = Email {
+                    to: user.email.clone(),
+                    subject: "New sign-in from an unusual location".to_string(),
+                    body: format!(
+                        "Your account was just signed in to from {}, far from where you last signed in.\n\n\
+                         If this wasn't you, change your password now.",
+                        client_ip
+                    ),
+                };
+                tokio::spawn(metrics::in_current_scope(async move {
+                    if let Err(e) = mailer.send(&email).await {
+                        warn!("Failed to send unusual sign-in notice: {}", e);
+                    }
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    // Like `last_login_at`, recording the location never holds up or fails the login
+    let users = state.users.clone();
+    let user_id = user.id;
+    tokio::spawn(metrics::in_current_scope(async move {
+        if let Err(e) = users.record_login_location(user_id, location, now).await {
+            warn!("Failed to record login location for user {}: {}", user_id, e);
+        }
+    }));
+    Ok(())
 }
 
-// Login endpoint that generates JWT token
+// Login endpoint that generates JWT token from JSON or form-encoded credentials
 pub async fn login(
     State(state): State<AppState>,
-    Json(payload): Json<LoginRequest>,
-) -> Result<Json<TokenResponse>, AppError> {
-    let pool = &state.pool;
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    request_headers: HeaderMap,
+    JsonOrForm(payload): JsonOrForm<LoginRequest>,
+) -> Result<(HeaderMap, Json<TokenResponse>), AppError> {
     let config = &state.config;
-    info!("Login attempt for user: {}", payload.username);
+    let runtime = state.runtime.load();
+    info!("Login attempt for user: {} from {}", payload.username, client_ip);
+
+    // Limit by source IP and by the submitted username, so credential stuffing against one
+    // account is slowed regardless of how many addresses it comes from
+    state.check_rate_limits(client_ip, tenant.as_deref(), &payload.username).await?;
+
+    // The probe account exists for the selftest alone and never gets a real token
+    if config.is_selftest_user(&payload.username) {
+        info!("Rejected login as the selftest user from {}", client_ip);
+        return Err(AppError::Unauthorized);
+    }
+
+    // Fail fast when the requested authentication context is beyond what we can achieve
+    let acr = resolve_acr(payload.acr_values.as_deref())?;
+    let password = SubmittedPassword::parse(config, payload.password_scheme.as_deref(), payload.password.clone())?;
 
     // Query the database for the user
-    let user = sqlx::query("SELECT id, username, email, password_hash, role FROM users WHERE username = $1")
-        .bind(&payload.username)
-        .map(|row: PgRow| {
-            let stored_hash: String = row.get("password_hash");
-            User {
-                id: row.get("id"),
-                username: row.get("username"),
-                email: row.get("email"),
-                password_hash: stored_hash,
-                role: row.get("role"),
-            }
-        })
-        .fetch_optional(pool)
-        .await?;
+    let user = state.users.find_by_username(&payload.username).await?;
 
     // Check if user exists and verify password
     let user = match user {
         Some(user) => {
-            // Offload password verification to blocking thread pool
-            let password = payload.password.clone();
             let stored_hash = user.password_hash.clone();
-            
-            let password_matches = tokio::task::spawn_blocking(move || {
-                verify(&password, &stored_hash)
-            })
-            .await
-            .map_err(|e| AppError::PasswordVerification(format!("Task join error: {}", e)))??;
+
+            let password_matches = if runtime.login_single_flight {
+                // Coalesce concurrent identical attempts into one bcrypt verification
+                let key = single_flight_key(&user.username, &stored_hash, &password);
+                state
+                    .login_flights
+                    .run(key, || verify_user_password(config, password, &stored_hash))
+                    .await
+            } else {
+                verify_user_password(config, password, &stored_hash).await
+            }
+            .map_err(|e| e.into_app_error(AppError::PasswordVerification))?;
 
             if password_matches {
                 info!("Password verified successfully");
+                state.tarpit.clear(&tarpit_user_key(&user.username));
                 user
             } else {
                 info!("Password verification failed - hash mismatch");
-                return Err(AppError::Unauthorized);
+                return Err(reject_credentials(&state, &runtime, client_ip, &payload.username).await);
             }
         }
         None => {
             info!("User not found: {}", payload.username);
-            return Err(AppError::Unauthorized)
+            return Err(reject_credentials(&state, &runtime, client_ip, &payload.username).await);
         },
     };
 
-    // Set token expiration time
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::hours(1))
-        .expect("valid timestamp")
-        .timestamp() as usize;
-    let issued_at = Utc::now().timestamp() as usize;
+    check_login_location(&state, &user, client_ip, true).await?;
+
+    complete_login(
+        &state,
+        user,
+        payload.client_id.as_deref(),
+        payload.scope.as_deref(),
+        Some(acr.to_string()),
+        None,
+        &request_headers,
+    )
+    .await
+}
+
+// Issue the tokens for a user who has just authenticated, whether by password or by an emailed
+// code: apply UNVERIFIED_EMAIL_POLICY, resolve the client and scopes, record the login and start a
+// refresh token family
+pub async fn complete_login(
+    state: &AppState,
+    user: User,
+    client_id: Option<&str>,
+    scope: Option<&str>,
+    acr: Option<String>,
+    amr: Option<Vec<String>>,
+    request_headers: &HeaderMap,
+) -> Result<(HeaderMap, Json<TokenResponse>), AppError> {
+    let pool = &state.pool;
+    let config = &state.config;
+    let runtime = state.runtime.load();
+
+    let email_verified = email_verified_claim(config, user.email_verified_at.is_some()).inspect_err(|_| {
+        info!("Rejected login for user {} with an unverified email address", user.username);
+    })?;
+
+    // Audience and token lifetimes come from the requesting client's registration, falling back
+    // to the global defaults
+    let client = match client_id {
+        Some(client_id) => Some(find_client(pool, client_id).await?.ok_or_else(|| {
+            info!("Unknown client: {}", client_id);
+            AppError::InvalidClient
+        })?),
+        None => None,
+    };
+    let audience = client
+        .as_ref()
+        .and_then(|client| client.audience.clone())
+        .or_else(|| config.jwt_audience.clone());
+    let token_policy = client.map(|client| client.token_policy).unwrap_or_default();
+
+    // Step down to the requested scopes, never beyond what the user's role grants
+    let scopes = granted_scopes(&runtime, &user.role, scope)?;
+
+    // Record the login without holding up the response; failures never fail the login
+    let users = state.users.clone();
+    let user_id = user.id;
+    tokio::spawn(metrics::in_current_scope(async move {
+        if let Err(e) = users.record_login(user_id).await {
+            warn!("Failed to record last login for user {}: {}", user_id, e);
+        }
+    }));
 
-    // Create the JWT claims
-    let claims = Claims {
+    state
+        .events
+        .emit(UserEvent::new(UserEventKind::Login, user.id, &user.username, state.clock.now()));
+
+    let jti = config.session_handles_enabled.then(session_handles::new_jti);
+    let grant = TokenGrant {
         sub: user.username,
         role: user.role,
-        exp: expiration,
-        iat: issued_at,
+        aud: audience,
+        scope: (!scopes.is_empty()).then(|| scopes.join(" ")),
+        acr,
+        auth_time: Some(state.clock.now().timestamp() as usize),
+        amr,
+        act: None,
+        jkt: None,
+        email_verified,
+        jti,
+    };
+    let access_ttl_seconds = token_policy.access_ttl_seconds(ACCESS_TOKEN_TTL_SECONDS);
+    let (token, expires_in) = issue_access_token(config, &grant, access_ttl_seconds, state.clock.now())?;
+
+    // Start a new refresh token family for this login
+    let refresh_token = if token_policy.refresh_enabled(config.refresh_tokens_enabled) {
+        let fingerprint = refresh_tokens::client_fingerprint(request_headers);
+        let client = client_id.map(|client_id| (client_id, &token_policy));
+        Some(refresh_tokens::create(pool, config, user.id, &grant, client, &fingerprint, state.clock.now()).await?)
+    } else {
+        None
     };
 
-    // Load RSA private key and create token with RS256
-    let encoding_key = load_private_key(config)?;
-    let mut header = Header::new(Algorithm::RS256);
-    header.kid = Some(config.product_key_id.clone());
+    // The same login as an opaque handle, for services that would rather look it up and revoke it
+    let session_handle = match &grant.jti {
+        Some(jti) => {
+            let expires_at = state.clock.now() + chrono::Duration::seconds(expires_in);
+            let (scope, audience) = (grant.scope.as_deref(), grant.aud.as_deref());
+            Some(session_handles::create(pool, user.id, jti, scope, audience, expires_at, state.clock.now()).await?)
+        }
+        None => None,
+    };
 
-    // Create the token using RSA private key
-    let token = encode(&header, &claims, &encoding_key)?;
+    // Browser clients can authenticate with cookies instead of holding the token in script
+    let headers = if config.auth_cookies_enabled {
+        cookies::session_cookies(&token, expires_in)
+    } else {
+        HeaderMap::new()
+    };
 
     // Return the token
-    Ok(Json(TokenResponse {
-        access_token: token,
-        token_type: "Bearer".to_string(),
-        expires_in: (expiration - issued_at) as i64,
-    }))
+    Ok((
+        headers,
+        Json(TokenResponse {
+            access_token: token,
+            token_type: "Bearer".to_string(),
+            expires_in,
+            refresh_token,
+            issued_token_type: None,
+            email_verified: grant.email_verified,
+            session_handle,
+            warnings: issuance_warnings::take(&grant.sub),
+        }),
+    ))
 }
@@ -0,0 +1,42 @@
+use crate::{
+    client_ip::ClientIp,
+    errors::AppError,
+    extract::JsonOrForm,
+    handlers::login::SUPPORTED_ACR_VALUES,
+    models::{LoginChallengeRequest, LoginChallengeResponse},
+    state::AppState,
+    tenant::Tenant,
+};
+use axum::{extract::State, response::Json};
+use tracing::info;
+
+// Login method offered for each supported authentication context class
+fn method_for_acr(acr: &str) -> &'static str {
+    match acr {
+        "pwd" => "password",
+        _ => "unknown",
+    }
+}
+
+// First step of an adaptive login flow: report which methods the user may authenticate with.
+// The user is never looked up, so the answer is identical for existing and unknown usernames;
+// with password as the only method there is no per-user MFA requirement to reveal.
+pub async fn login_challenge(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    JsonOrForm(payload): JsonOrForm<LoginChallengeRequest>,
+) -> Result<Json<LoginChallengeResponse>, AppError> {
+    info!("Login challenge for user: {} from {}", payload.username, client_ip);
+
+    // Share the login buckets so probing here counts against the same allowance
+    state.check_rate_limits(client_ip, tenant.as_deref(), &payload.username).await?;
+
+    Ok(Json(LoginChallengeResponse {
+        methods: SUPPORTED_ACR_VALUES
+            .iter()
+            .map(|acr| method_for_acr(acr).to_string())
+            .collect(),
+        acr_values_supported: SUPPORTED_ACR_VALUES.iter().map(|acr| acr.to_string()).collect(),
+    }))
+}
@@ -0,0 +1,62 @@
+use crate::{
+    client_ip::ClientIp,
+    errors::AppError,
+    extract::BearerClaims,
+    metrics,
+    models::MeResponse,
+    state::AppState,
+    tenant::Tenant,
+};
+use axum::{extract::State, response::Json};
+use chrono::{DateTime, Utc};
+use sqlx::{postgres::PgRow, Row};
+use tracing::info;
+
+// Claims released by the userinfo endpoint for each granted scope, per OIDC conventions
+pub const SCOPE_CLAIMS: [(&str, &[&str]); 3] = [
+    ("openid", &["sub"]),
+    ("profile", &["preferred_username", "role", "last_login_at"]),
+    ("email", &["email"]),
+];
+
+// Userinfo for the user identified by the bearer token, limited to the token's scopes
+pub async fn me(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    BearerClaims(claims): BearerClaims,
+) -> Result<Json<MeResponse>, AppError> {
+    let pool = &state.pool;
+    info!("Me endpoint called for user: {}", claims.sub);
+    state.check_rate_limits(client_ip, tenant.as_deref(), &claims.sub).await?;
+
+    let query = sqlx::query("SELECT email, role, last_login_at FROM users WHERE username = $1")
+        .bind(&claims.sub)
+        .map(|row: PgRow| {
+            let email: String = row.get("email");
+            let role: String = row.get("role");
+            let last_login_at: Option<DateTime<Utc>> = row.get("last_login_at");
+            (email, role, last_login_at)
+        })
+        .fetch_optional(pool);
+    let (email, role, last_login_at) = metrics::observe("me.find_user", query)
+        .await?
+        .ok_or_else(|| AppError::InvalidToken("unknown subject".to_string()))?;
+
+    // Tokens without a scope claim only identify the subject
+    let granted = |scope: &str| {
+        claims
+            .scope
+            .as_deref()
+            .is_some_and(|scopes| scopes.split_whitespace().any(|s| s == scope))
+    };
+    let profile = granted("profile");
+
+    Ok(Json(MeResponse {
+        preferred_username: profile.then(|| claims.sub.clone()),
+        role: profile.then_some(role),
+        last_login_at: if profile { last_login_at } else { None },
+        email: granted("email").then_some(email),
+        sub: claims.sub,
+    }))
+}
@@ -0,0 +1,14 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::IntoResponse,
+};
+
+use crate::{metrics::render_tenant_quotas, state::AppState};
+
+// Prometheus scrape endpoint
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let mut body = state.metrics.render(&state.pool);
+    body.push_str(&render_tenant_quotas(&state.tenant_quotas().await));
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
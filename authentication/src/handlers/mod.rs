@@ -1,4 +1,18 @@
+pub mod admin;
+pub mod email_login;
+pub mod export;
+pub mod health;
+pub mod impersonate;
+pub mod introspect;
 pub mod login;
+pub mod login_challenge;
+pub mod me;
+pub mod metrics;
+pub mod password;
+pub mod refresh;
 pub mod register;
+pub mod selftest;
 pub mod status;
+pub mod token;
 pub mod openid;
+pub mod validate;
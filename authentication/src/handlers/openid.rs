@@ -1,78 +1,317 @@
 use crate::{
+    dpop::proof_algorithm_names,
     errors::AppError,
-    models::{JwkKey, JwksResponse, OpenIdConfiguration},
+    key_pem,
+    handlers::{login::SUPPORTED_ACR_VALUES, me::SCOPE_CLAIMS, token::SUPPORTED_GRANT_TYPES},
+    models::{JwkKey, JwksQuery, JwksResponse, OpenIdConfiguration},
     state::AppState,
-    config::Config,
+    config::{AudienceKey, RetiredKey},
+    test_mode::{self, read_key_pem},
+    tokens::load_certificate_thumbprint,
 };
-use axum::{extract::State, response::Json};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::Algorithm;
 use rsa::{pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, RsaPublicKey, traits::PublicKeyParts};
-use std::fs;
-use tracing::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::io;
+use tracing::{info, warn};
 
 // Helper function to load RSA public key for JWKS
-fn load_public_key_for_jwks(config: &Config) -> Result<RsaPublicKey, AppError> {
-    let public_key_path = &config.rsa_public_key_path;
+fn load_public_key_for_jwks(public_key_pem: io::Result<String>, public_key_path: &str) -> Result<RsaPublicKey, AppError> {
     info!("Loading public key from: {}", public_key_path);
-    let public_key_pem = fs::read_to_string(public_key_path)
+    let public_key_pem = public_key_pem
         .map_err(|e| AppError::KeyLoading(format!("Failed to read public key from {}: {}", public_key_path, e)))?;
     // Try PKCS#8 format first (default OpenSSL output), then PKCS#1 as fallback
     RsaPublicKey::from_public_key_pem(&public_key_pem)
         .or_else(|_| RsaPublicKey::from_pkcs1_pem(&public_key_pem))
-        .map_err(|e| AppError::KeyLoading(format!("Failed to parse RSA public key: {}", e)))
+        .map_err(|e| {
+            let reason = key_pem::describe_error(&public_key_pem, e);
+            AppError::KeyLoading(format!("Failed to parse RSA public key {}: {}", public_key_path, reason))
+        })
+}
+
+// Load a public key and describe it as a JWK for `algorithm`: a P-256 key for ES256, an RSA key
+// otherwise
+fn load_jwk(
+    kid: &str,
+    algorithm: Algorithm,
+    public_key_pem: io::Result<String>,
+    public_key_path: &str,
+) -> Result<JwkKey, AppError> {
+    use base64::{Engine as _, engine::general_purpose};
+
+    let encode = |bytes: &[u8]| Some(general_purpose::URL_SAFE_NO_PAD.encode(bytes));
+    let mut jwk = JwkKey {
+        kty: "RSA".to_string(),
+        key_use: "sig".to_string(),
+        kid: kid.to_string(),
+        alg: format!("{:?}", algorithm),
+        n: None,
+        e: None,
+        crv: None,
+        x: None,
+        y: None,
+        x5t_s256: None,
+    };
+    if algorithm == Algorithm::ES256 {
+        info!("Loading public key from: {}", public_key_path);
+        let public_key_pem = public_key_pem
+            .map_err(|e| AppError::KeyLoading(format!("Failed to read public key from {}: {}", public_key_path, e)))?;
+        let (x, y) = key_pem::ec_public_point(&public_key_pem).map_err(|e| {
+            AppError::KeyLoading(format!("Failed to parse EC public key {}: {}", public_key_path, e))
+        })?;
+        jwk.kty = "EC".to_string();
+        jwk.crv = Some("P-256".to_string());
+        (jwk.x, jwk.y) = (encode(&x), encode(&y));
+        return Ok(jwk);
+    }
+
+    // Convert modulus and exponent to base64url encoding
+    let public_key = load_public_key_for_jwks(public_key_pem, public_key_path)?;
+    jwk.n = encode(&public_key.n().to_bytes_be());
+    jwk.e = encode(&public_key.e().to_bytes_be());
+    Ok(jwk)
+}
+
+// Retired keys to publish alongside the active one: those still within their grace window,
+// most recently retired first, leaving room for the active key within `max_keys`
+fn retired_keys_to_publish(
+    retired: &[RetiredKey],
+    now: DateTime<Utc>,
+    grace: Duration,
+    max_keys: usize,
+) -> Vec<&RetiredKey> {
+    let mut keys: Vec<&RetiredKey> = retired.iter().filter(|key| key.retired_at + grace > now).collect();
+    keys.sort_by_key(|key| std::cmp::Reverse(key.retired_at));
+    keys.truncate(max_keys.saturating_sub(1));
+    keys
+}
+
+// Keys to publish for a JWKS request limited to `audience`: when some AUDIENCE_KEYS entry signs for
+// it, just those keys; otherwise the default keys (whether to publish them comes first). Without a
+// limit, every key.
+fn keys_for_audience<'a>(audience_keys: &'a [AudienceKey], audience: Option<&str>) -> (bool, Vec<&'a AudienceKey>) {
+    let Some(audience) = audience else {
+        return (true, audience_keys.iter().collect());
+    };
+    let dedicated: Vec<&AudienceKey> =
+        audience_keys.iter().filter(|key| key.audiences.iter().any(|a| a == audience)).collect();
+    (dedicated.is_empty(), dedicated)
+}
+
+// Strong validator for a JSON document: a digest of its serialized form, so it changes exactly
+// when the content does (e.g. after a reload or a key change)
+fn etag_for(body: &[u8]) -> String {
+    format!("\"{}\"", &hex::encode(Sha256::digest(body))[..32])
+}
+
+// Whether an `If-None-Match` header lists `etag` (weak comparison, as RFC 9110 requires for it)
+fn matches_if_none_match(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim())
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+// Serve a polled, rarely-changing document with `Cache-Control` and an `ETag`, answering a
+// matching conditional request with `304 Not Modified`
+fn cacheable_json<T: Serialize>(request_headers: &HeaderMap, document: &T, max_age_seconds: u64) -> Response {
+    let body = match serde_json::to_vec(document) {
+        Ok(body) => body,
+        Err(_) => return Json(document).into_response(),
+    };
+    let etag = etag_for(&body);
+    let cache_control = if max_age_seconds == 0 {
+        "no-cache".to_string()
+    } else {
+        format!("public, max-age={}", max_age_seconds)
+    };
+
+    let mut response = if matches_if_none_match(request_headers, &etag) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        ([(header::CONTENT_TYPE, HeaderValue::from_static("application/json"))], body).into_response()
+    };
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Ok(cache_control) = HeaderValue::from_str(&cache_control) {
+        headers.insert(header::CACHE_CONTROL, cache_control);
+    }
+    response
 }
 
 // JWKS endpoint for public key distribution
 pub async fn jwks(
     State(state): State<AppState>,
+    Query(query): Query<JwksQuery>,
 ) -> Result<Json<JwksResponse>, AppError> {
     let config = &state.config;
     info!("JWKS endpoint called");
-    use base64::{Engine as _, engine::general_purpose};
-    
-    // Get key ID from config
-    let key_id = config.product_key_id.clone();
-    
-    // Load RSA public key
-    let public_key = load_public_key_for_jwks(config)?;
-    
-    // Extract modulus and exponent
-    let modulus = public_key.n();
-    let exponent = public_key.e();
-    
-    // Convert to base64url encoding
-    let modulus_bytes = modulus.to_bytes_be();
-    let exponent_bytes = exponent.to_bytes_be();
-    let modulus_b64 = general_purpose::URL_SAFE_NO_PAD.encode(&modulus_bytes);
-    let exponent_b64 = general_purpose::URL_SAFE_NO_PAD.encode(&exponent_bytes);
-    
-    Ok(Json(JwksResponse {
-        keys: vec![JwkKey {
-            kty: "RSA".to_string(),
-            key_use: "sig".to_string(),
-            kid: key_id,
-            alg: "RS256".to_string(),
-            n: modulus_b64,
-            e: exponent_b64,
-        }],
-    }))
+    let (include_default, audience_keys) = keys_for_audience(&config.audience_keys, query.aud.as_deref());
+    let mut keys = if include_default { default_keys(&state)? } else { Vec::new() };
+    for audience_key in audience_keys {
+        let path = &audience_key.public_key_path;
+        let jwk = key_pem::load_last_good(path, config.key_fallback_retry(), || key_pem::read(path), |public_key_pem| {
+            load_jwk(&audience_key.kid, config.jwt_signing_algorithm, public_key_pem, path)
+        });
+        match jwk {
+            Ok(key) => keys.push(key),
+            Err(e) => warn!("Skipping audience key {} in JWKS: {}", audience_key.kid, e),
+        }
+    }
+    Ok(Json(JwksResponse { keys }))
+}
+
+// The active signing key and any retired keys still in their grace window
+fn default_keys(state: &AppState) -> Result<Vec<JwkKey>, AppError> {
+    let config = &state.config;
+
+    // The active signing key is always published
+    let path = &config.rsa_public_key_path;
+    let (_, test_key) = test_mode::jwt_key_pair(config.jwt_signing_algorithm);
+    let read = || read_key_pem(config, path, test_key);
+    let mut active = key_pem::load_last_good(path, config.key_fallback_retry(), read, |public_key_pem| {
+        load_jwk(&config.product_key_id, config.jwt_signing_algorithm, public_key_pem, path)
+    })?;
+    active.x5t_s256 = load_certificate_thumbprint(config)?;
+    let mut keys = vec![active];
+
+    // A retired key that can't be read is left out rather than failing the whole set
+    let grace = Duration::seconds(config.retired_key_grace_seconds);
+    for retired in retired_keys_to_publish(&config.retired_keys, state.clock.now(), grace, config.jwks_max_keys) {
+        let path = &retired.public_key_path;
+        let jwk = key_pem::load_last_good(path, config.key_fallback_retry(), || key_pem::read(path), |public_key_pem| {
+            load_jwk(&retired.kid, config.retired_key_algorithm(&retired.kid), public_key_pem, path)
+        });
+        match jwk {
+            Ok(key) => keys.push(key),
+            Err(e) => warn!("Skipping retired key {} in JWKS: {}", retired.kid, e),
+        }
+    }
+
+    // Newest first puts the signing key where lenient clients look when a token has no `kid`;
+    // otherwise list keys in the order they were introduced
+    if !config.jwks_active_first {
+        keys.reverse();
+    }
+    Ok(keys)
 }
 
 // OpenID Connect Discovery endpoint
 pub async fn openid_configuration(
     State(state): State<AppState>,
-) -> Json<OpenIdConfiguration> {
+    headers: HeaderMap,
+) -> Response {
     let config = &state.config;
     info!("OpenID configuration endpoint called");
     let base_url = config.base_url.clone();
-    
-    Json(OpenIdConfiguration {
-        issuer: base_url.clone(),
+
+    let document = OpenIdConfiguration {
+        issuer: config.jwt_issuer.clone().unwrap_or_else(|| base_url.clone()),
         jwks_uri: format!("{}/.well-known/jwks.json", base_url),
         authorization_endpoint: format!("{}/api/auth/login", base_url),
-        token_endpoint: format!("{}/api/auth/login", base_url),
-        userinfo_endpoint: format!("{}/api/auth/status", base_url),
+        token_endpoint: format!("{}/api/auth/token", base_url),
+        userinfo_endpoint: format!("{}/api/auth/me", base_url),
         response_types_supported: vec!["code".to_string(), "token".to_string()],
         subject_types_supported: vec!["public".to_string()],
-        id_token_signing_alg_values_supported: vec!["RS256".to_string()],
-    })
+        id_token_signing_alg_values_supported: vec![format!("{:?}", config.jwt_signing_algorithm)],
+        scopes_supported: SCOPE_CLAIMS.iter().map(|(scope, _)| scope.to_string()).collect(),
+        claims_supported: SCOPE_CLAIMS
+            .iter()
+            .flat_map(|(_, claims)| claims.iter().map(|claim| claim.to_string()))
+            .collect(),
+        acr_values_supported: SUPPORTED_ACR_VALUES.iter().map(|acr| acr.to_string()).collect(),
+        grant_types_supported: SUPPORTED_GRANT_TYPES.iter().map(|grant| grant.to_string()).collect(),
+        dpop_signing_alg_values_supported: config.dpop_enabled.then(proof_algorithm_names),
+    };
+    cacheable_json(&headers, &document, config.openid_configuration_max_age_seconds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retired(kid: &str, hours_ago: i64, now: DateTime<Utc>) -> RetiredKey {
+        RetiredKey {
+            kid: kid.to_string(),
+            public_key_path: format!("keys/{}.pem", kid),
+            retired_at: now - Duration::hours(hours_ago),
+        }
+    }
+
+    #[test]
+    fn limits_jwks_to_the_keys_for_an_audience() {
+        let audience_key = |kid: &str, audiences: &[&str]| AudienceKey {
+            kid: kid.to_string(),
+            audiences: audiences.iter().map(|a| a.to_string()).collect(),
+            private_key_path: format!("keys/{}/private_key.pem", kid),
+            public_key_path: format!("keys/{}/public_key.pem", kid),
+        };
+        let keys = [audience_key("partner-a", &["catalogue", "orders"]), audience_key("partner-b", &["payments"])];
+        let kids = |(include_default, keys): (bool, Vec<&AudienceKey>)| {
+            (include_default, keys.iter().map(|key| key.kid.clone()).collect::<Vec<_>>().join(" "))
+        };
+
+        assert_eq!(kids(keys_for_audience(&keys, None)), (true, "partner-a partner-b".to_string()));
+        assert_eq!(kids(keys_for_audience(&keys, Some("orders"))), (false, "partner-a".to_string()));
+        // Audiences without a key of their own are signed with the default key
+        assert_eq!(kids(keys_for_audience(&keys, Some("frontend"))), (true, String::new()));
+        assert_eq!(kids(keys_for_audience(&[], Some("orders"))), (true, String::new()));
+    }
+
+    #[test]
+    fn caps_published_keys_and_drops_those_past_grace() {
+        let now = Utc::now();
+        let retired = vec![
+            retired("key-2", 30, now),
+            retired("key-5", 1, now),
+            retired("key-3", 20, now),
+            retired("key-4", 10, now),
+            retired("key-1", 50, now),
+        ];
+
+        // Cap of 3 leaves room for the active key plus the two most recently retired
+        let published = retired_keys_to_publish(&retired, now, Duration::hours(48), 3);
+        let kids: Vec<&str> = published.iter().map(|key| key.kid.as_str()).collect();
+        assert_eq!(kids, ["key-5", "key-4"]);
+
+        // Keys past the grace window are dropped even when under the cap
+        let published = retired_keys_to_publish(&retired, now, Duration::hours(24), 10);
+        let kids: Vec<&str> = published.iter().map(|key| key.kid.as_str()).collect();
+        assert_eq!(kids, ["key-5", "key-4", "key-3"]);
+
+        // A cap of one publishes only the active key
+        assert!(retired_keys_to_publish(&retired, now, Duration::hours(48), 1).is_empty());
+    }
+
+    #[test]
+    fn revalidates_with_etag() {
+        let document = serde_json::json!({ "issuer": "http://authentication:8082" });
+        let response = cacheable_json(&HeaderMap::new(), &document, 3600);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CACHE_CONTROL], "public, max-age=3600");
+        let etag = response.headers()[header::ETAG].clone();
+
+        let mut conditional = HeaderMap::new();
+        let if_none_match = format!("\"stale\", W/{}", etag.to_str().unwrap());
+        conditional.insert(header::IF_NONE_MATCH, HeaderValue::from_str(&if_none_match).unwrap());
+        let response = cacheable_json(&conditional, &document, 3600);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        // Any change to the document changes the validator
+        let changed = serde_json::json!({ "issuer": "https://auth.example.com" });
+        assert_eq!(cacheable_json(&conditional, &changed, 3600).status(), StatusCode::OK);
+    }
 }
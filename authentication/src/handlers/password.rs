@@ -0,0 +1,88 @@
+use crate::{
+    client_ip::ClientIp,
+    errors::AppError,
+    events::{UserEvent, UserEventKind},
+    extract::BearerClaims,
+    handlers::login::verify_user_password,
+    config::{BreachCheckerKind, Config},
+    models::{ChangePasswordRequest, PasswordPolicyQuery, PasswordPolicyResponse},
+    passwords::{check_min_password_age, check_new_submitted_password, hash_submitted_password, SubmittedPassword, CHARACTER_CLASSES},
+    state::AppState,
+    tenant::Tenant,
+};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use chrono::Duration;
+use tracing::info;
+
+// Self-service password change for the bearer-token user. Requires the current password and
+// honours MIN_PASSWORD_AGE_HOURS; admin resets go through `admin::reset_password` instead.
+pub async fn change_password(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    BearerClaims(claims): BearerClaims,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let config = &state.config;
+    info!("Password change requested for user: {}", claims.sub);
+    state.check_rate_limits(client_ip, tenant.as_deref(), &claims.sub).await?;
+
+    // Whoever is impersonating the user must not be able to take over the account
+    if claims.act.is_some() {
+        return Err(AppError::Forbidden("impersonated sessions cannot change the password".to_string()));
+    }
+    state.require_recent_auth(&claims)?;
+
+    let user = state.users.find_by_username(&claims.sub).await?.ok_or(AppError::Unauthorized)?;
+    let scheme = payload.password_scheme.as_deref();
+    let current_password = SubmittedPassword::parse(config, scheme, payload.current_password)?;
+    let new_password = SubmittedPassword::parse(config, scheme, payload.new_password)?;
+    let password_matches = verify_user_password(config, current_password, &user.password_hash)
+        .await
+        .map_err(|e| e.into_app_error(AppError::PasswordVerification))?;
+    if !password_matches {
+        info!("Password change for {} rejected: current password does not match", user.username);
+        return Err(AppError::Unauthorized);
+    }
+
+    let now = state.clock.now();
+    check_min_password_age(user.password_changed_at, now, Duration::hours(config.min_password_age_hours))?;
+    check_new_submitted_password(config, state.breach_checker.as_ref(), &user.role, &new_password).await?;
+
+    let password_hash = hash_submitted_password(config, new_password).await?;
+    state.users.update_password(user.id, &password_hash, now).await?;
+    info!("Password changed for user: {}", user.username);
+    state.events.emit(UserEvent::new(UserEventKind::PasswordChanged, user.id, &user.username, now));
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The policy a password set for an account with `role` is held to: the role's own when it has
+// one, else the global policy
+fn describe_policy(config: &Config, role: Option<&str>) -> PasswordPolicyResponse {
+    let (role, policy) = match role.and_then(|role| config.role_password_policies.get_key_value(role)) {
+        Some((role, policy)) => (Some(role.clone()), policy),
+        None => (None, &config.password_policy),
+    };
+    PasswordPolicyResponse {
+        role,
+        min_length: policy.min_length,
+        min_character_classes: policy.min_character_classes,
+        character_classes: CHARACTER_CLASSES,
+        min_strength_bits: policy.min_strength_bits,
+        min_entropy_bits: policy.min_entropy_bits,
+        entropy_enforced: policy.enforce_min_entropy,
+        breach_check: config.breach_checker != BreachCheckerKind::None,
+        min_password_age_hours: config.min_password_age_hours,
+    }
+}
+
+// The active password policy, optionally for `?role=`, so sign-up and password forms validate
+// against exactly what the server enforces instead of a hardcoded copy
+pub async fn policy(State(state): State<AppState>, Query(query): Query<PasswordPolicyQuery>) -> Json<PasswordPolicyResponse> {
+    info!("Password policy requested");
+    Json(describe_policy(&state.config, query.role.as_deref()))
+}
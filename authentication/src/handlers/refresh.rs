@@ -0,0 +1,59 @@
+use crate::{
+    errors::AppError,
+    extract::JsonOrForm,
+    issuance_warnings,
+    models::{RefreshRequest, TokenResponse},
+    refresh_tokens,
+    state::AppState,
+    tokens::issue_access_token,
+};
+use axum::{extract::State, http::HeaderMap, response::Json};
+use chrono::Duration;
+use tracing::info;
+
+// Exchange a refresh token for a new access token and a rotated refresh token
+pub async fn refresh(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    JsonOrForm(payload): JsonOrForm<RefreshRequest>,
+) -> Result<Json<TokenResponse>, AppError> {
+    let pool = &state.pool;
+    let config = &state.config;
+    info!("Refresh endpoint called");
+
+    let now = state.clock.now();
+    let window = Duration::seconds(config.refresh_reuse_window_seconds);
+    let fingerprint = refresh_tokens::client_fingerprint(&headers);
+
+    // A retry of a refresh rotated moments ago gets the same response, as long as the token it
+    // issued is still unused; anything else falls through to reuse detection
+    if window > Duration::zero() {
+        if let Some(response) = state.recent_rotations.replay(&payload.refresh_token, &fingerprint, now, window) {
+            let successor = response.refresh_token.as_deref().unwrap_or_default();
+            if refresh_tokens::is_active(pool, successor).await? {
+                info!("Replaying refresh response for a retried refresh token");
+                return Ok(Json(response));
+            }
+        }
+    }
+
+    let rotation = refresh_tokens::rotate(pool, config, &payload.refresh_token, &fingerprint, now).await?;
+    let (token, expires_in) = issue_access_token(config, &rotation.grant, rotation.access_ttl_seconds, now)?;
+    // Clients that don't rotate get the presented token back, so there is no rotation to replay
+    let rotated = rotation.refresh_token != payload.refresh_token;
+    let response = TokenResponse {
+        access_token: token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        refresh_token: Some(rotation.refresh_token),
+        issued_token_type: None,
+        email_verified: rotation.grant.email_verified,
+        session_handle: None,
+        warnings: issuance_warnings::take(&rotation.grant.sub),
+    };
+    if rotated && window > Duration::zero() {
+        state.recent_rotations.remember(&payload.refresh_token, &fingerprint, &response, now, window);
+    }
+
+    Ok(Json(response))
+}
@@ -1,59 +1,221 @@
 use crate::{
+    client_ip::ClientIp,
     errors::AppError,
-    models::RegisterRequest,
+    events::{UserEvent, UserEventKind},
+    flags,
+    mailer::Email,
+    metrics,
+    models::{EmailCodeRequest, RegisterRequest},
+    passwords::{check_new_submitted_password, hash_submitted_password, SubmittedPassword},
+    registration_codes,
     state::AppState,
+    tenant::Tenant,
+    users::NewUser,
 };
-use axum::{extract::State, response::Json};
-use bcrypt::{hash_with_result, Version, DEFAULT_COST};
-use sqlx::Row;
-use tracing::info;
+use axum::{extract::State, http::StatusCode, response::Json};
+use chrono::Duration;
+use tracing::{info, warn};
 
 // constant for the user role
 const USER_ROLE: &str = "user";
 
-pub async fn register(
-    State(state): State<AppState>,
-    Json(payload): Json<RegisterRequest>,
-) -> Result<Json<serde_json::Value>, AppError> {
-    let pool = &state.pool;
-    let _config = &state.config;
-    info!("Register endpoint called");
+// Generic response used when enumeration-safe registration is enabled. The outcome is only
+// reported by email, to the address that was registered.
+fn enumeration_safe_response() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({
+            "message": "Registration received; check your email for the outcome"
+        })),
+    )
+}
 
-    // Check if username or email already exists
-    let existing_user = sqlx::query("SELECT username, email FROM users WHERE username = $1 OR email = $2")
-        .bind(&payload.username)
-        .bind(&payload.email)
-        .fetch_optional(pool)
-        .await?;
-    if existing_user.is_some() {
-        return Err(AppError::Conflict);
+// The email reporting an enumeration-safe registration: the new account, or, for an address that
+// already has one, a notice to its owner. A taken username is only revealed to whoever reads the
+// submitted address.
+fn outcome_email(payload: &RegisterRequest, conflict: Option<&AppError>) -> Email {
+    let body = match conflict {
+        None => format!(
+            "Your account {} has been created and you can now sign in.\n\n\
+             If you didn't create it, contact support.",
+            payload.username
+        ),
+        Some(AppError::EmailRegistered) => "Someone tried to create an account with this email address, which already \
+             has one. If it was you, sign in or reset your password instead.\n\n\
+             If it wasn't you, you can ignore this email; your account hasn't changed."
+            .to_string(),
+        Some(_) => format!(
+            "Your account couldn't be created because the username {} isn't available. Register again \
+             with a different one.\n\n\
+             If you didn't ask to create an account, you can ignore this email.",
+            payload.username
+        ),
+    };
+    Email {
+        to: payload.email.clone(),
+        subject: "Your account registration".to_string(),
+        body,
+    }
+}
+
+// Mail after the response is sent, so mail delivery time doesn't tell the outcomes apart
+fn send_outcome_email(state: &AppState, message: Email) {
+    let mailer = state.mailer.clone();
+    tokio::spawn(metrics::in_current_scope(async move {
+        if let Err(e) = mailer.send(&message).await {
+            warn!("Failed to send registration outcome email: {}", e);
+        }
+    }));
+}
+
+// Validate and create a user with the default role, returning its id. Shared by registration
+// and bulk import. A username or email that must be unique and is already in use is reported as
+// `UsernameTaken` or `EmailRegistered`, and a lookalike of an existing username as
+// `UsernameConfusable`.
+pub async fn create_user(state: &AppState, payload: &RegisterRequest) -> Result<i32, AppError> {
+    // Enforce the email domain allow/deny lists
+    state.email_policy.check(&payload.email)?;
+
+    // Enforce the password policy for the role being registered and reject breached passwords
+    let password = SubmittedPassword::parse(&state.config, payload.password_scheme.as_deref(), payload.password.clone())?;
+    check_new_submitted_password(&state.config, state.breach_checker.as_ref(), USER_ROLE, &password).await?;
+
+    // Enforce the configured uniqueness policies; insert also maps the database constraints, which
+    // catches concurrent registrations of the same value
+    if state.config.unique_username && state.users.username_exists(&payload.username).await? {
+        return Err(AppError::UsernameTaken);
+    }
+    if state.config.unique_email && state.users.email_exists(&payload.email).await? {
+        return Err(AppError::EmailRegistered);
+    }
+    // Reject names that render like an existing one, e.g. with a Cyrillic "а" for a Latin "a"
+    if state.config.block_confusable_usernames && state.users.confusable_username_exists(&payload.username).await? {
+        return Err(AppError::UsernameConfusable);
     }
 
     // Offload password hashing to blocking thread pool
-    let password = payload.password.clone();
-    let password_hash = tokio::task::spawn_blocking(move || {
-        hash_with_result(&password, DEFAULT_COST)
-            .map(|hash_result| hash_result.format_for_version(Version::TwoA))
-    })
-    .await
-    .map_err(|e| AppError::PasswordHashing(format!("Task join error: {}", e)))??;
+    let password_hash = hash_submitted_password(&state.config, password).await?;
 
     // Insert the new user
-    let result = sqlx::query(
-        "INSERT INTO users (username, email, password_hash, role) VALUES ($1, $2, $3, $4) RETURNING id"
-    )
-    .bind(&payload.username)
-    .bind(&payload.email)
-    .bind(&password_hash)
-    .bind(USER_ROLE)
-    .fetch_one(pool)
-    .await?;
+    let user_id = state
+        .users
+        .insert(NewUser {
+            username: &payload.username,
+            email: &payload.email,
+            password_hash: &password_hash,
+            role: USER_ROLE,
+        })
+        .await?;
+    state
+        .events
+        .emit(UserEvent::new(UserEventKind::Registered, user_id, &payload.username, state.clock.now()));
+    Ok(user_id)
+}
 
-    let user_id: i32 = result.get("id");
+// With VERIFY_EMAIL_BEFORE_REGISTER, email a one-time code to an address about to be registered;
+// registering it then needs the code. The response is the same whether or not the address already
+// has an account, and the lookup and mailing happen after it is sent so timing doesn't tell either.
+pub async fn request_code(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    Json(payload): Json<EmailCodeRequest>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    if !state.flags.is_enabled(flags::REGISTRATION_ENABLED) {
+        return Err(AppError::NotFound);
+    }
+    info!("Registration email code requested from {}", client_ip);
+    state.check_rate_limits(client_ip, tenant.as_deref(), &payload.email).await?;
+    // The domain lists say nothing about who has an account, so they can be enforced up front
+    state.email_policy.check(&payload.email)?;
+
+    tokio::spawn(metrics::in_current_scope(async move {
+        if let Err(e) = send_code(&state, &payload.email).await {
+            warn!("Failed to send registration email code: {}", e);
+        }
+    }));
 
     Ok(Json(serde_json::json!({
+        "message": "If this email can be registered, a verification code has been sent to it"
+    })))
+}
+
+async fn send_code(state: &AppState, email: &str) -> Result<(), String> {
+    let config = &state.config;
+    // An address that can't be registered again gets nothing, rather than a code it can't use
+    if config.unique_email && state.users.email_exists(email).await.map_err(|e| e.to_string())? {
+        info!("Not sending a registration email code to an address already registered");
+        return Ok(());
+    }
+    let ttl = Duration::seconds(config.email_login_code_ttl_seconds);
+    let code = registration_codes::create(&state.pool, email, ttl, state.clock.now())
+        .await
+        .map_err(|e| e.to_string())?;
+    let message = Email {
+        to: email.to_string(),
+        subject: "Your verification code".to_string(),
+        body: format!(
+            "Your code to finish creating your account is {}. It expires in {} minutes and can be used once.\n\n\
+             If you didn't ask to create an account, you can ignore this email.",
+            code,
+            (config.email_login_code_ttl_seconds / 60).max(1)
+        ),
+    };
+    state.mailer.send(&message).await?;
+    info!("Sent registration email code");
+    Ok(())
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    Json(payload): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), AppError> {
+    // While registration is switched off the endpoint answers like an unknown path
+    if !state.flags.is_enabled(flags::REGISTRATION_ENABLED) {
+        return Err(AppError::NotFound);
+    }
+    let runtime = state.runtime.load();
+    info!("Register endpoint called");
+
+    // The code is spent before the account is created, so a registration rejected for any other
+    // reason needs a new one
+    let verify_email = state.config.verify_email_before_register;
+    if verify_email {
+        let code = payload.email_code.as_deref().ok_or(AppError::EmailCodeInvalid)?;
+        let max_attempts = state.config.email_login_max_attempts;
+        if !registration_codes::redeem(&state.pool, &payload.email, code, max_attempts, state.clock.now()).await? {
+            info!("Registration email code rejected");
+            return Err(AppError::EmailCodeInvalid);
+        }
+    }
+
+    let user_id = match create_user(&state, &payload).await {
+        // Don't reveal that the account exists. The conflict is found before the password is
+        // hashed, so hash it anyway to take as long as a registration that succeeds.
+        Err(conflict @ (AppError::UsernameTaken | AppError::EmailRegistered | AppError::UsernameConfusable))
+            if runtime.enumeration_safe_registration =>
+        {
+            info!("Registration for existing account suppressed (enumeration-safe mode)");
+            let password = SubmittedPassword::parse(&state.config, payload.password_scheme.as_deref(), payload.password.clone())?;
+            hash_submitted_password(&state.config, password).await?;
+            send_outcome_email(&state, outcome_email(&payload, Some(&conflict)));
+            return Ok(enumeration_safe_response());
+        }
+        result => result?,
+    };
+    // Receiving the code proves the address, just as redeeming an emailed login code does
+    if verify_email {
+        state.users.mark_email_verified(user_id, state.clock.now()).await?;
+    }
+
+    if runtime.enumeration_safe_registration {
+        send_outcome_email(&state, outcome_email(&payload, None));
+        return Ok(enumeration_safe_response());
+    }
+
+    Ok((StatusCode::OK, Json(serde_json::json!({
         "message": "User registered successfully",
         "user_id": user_id,
         "username": payload.username
-    })))
+    }))))
 }
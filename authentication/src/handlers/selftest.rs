@@ -0,0 +1,133 @@
+use crate::{
+    errors::AppError,
+    handlers::login::verify_password,
+    metrics::Metrics,
+    models::SelftestResponse,
+    state::AppState,
+    tokens::{issue_access_token, load_verification_key, verify_token, TokenGrant},
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+// The probe token is verified and discarded, so it only needs to outlive the check
+const SELFTEST_TOKEN_TTL_SECONDS: i64 = 60;
+
+fn elapsed_ms(started: Instant) -> f64 {
+    started.elapsed().as_secs_f64() * 1000.0
+}
+
+// Run the synthetic login once and record its timings, or None when no probe account is
+// configured. Only the probe's own metrics move: it bypasses the login handler, so login counts,
+// events and the account's last login are untouched.
+pub async fn run(state: &AppState) -> Option<SelftestResponse> {
+    let config = &state.config;
+    let (Some(username), Some(password)) = (&config.selftest_username, &config.selftest_password) else {
+        return None;
+    };
+    let report = probe(state, username, password).await;
+    record(&state.metrics, &report);
+    Some(report)
+}
+
+fn record(metrics: &Metrics, report: &SelftestResponse) {
+    let steps = [("db", report.db_ms), ("hash", report.hash_ms), ("sign", report.sign_ms), ("total", Some(report.total_ms))];
+    for (step, elapsed_ms) in steps {
+        if let Some(elapsed_ms) = elapsed_ms {
+            metrics.record_selftest_step(step, Duration::from_secs_f64(elapsed_ms / 1000.0));
+        }
+    }
+    if let Some(step) = report.failed_step {
+        metrics.record_selftest_failure(step);
+    }
+}
+
+// End-to-end internal login against the configured probe account, timing each layer
+async fn probe(state: &AppState, username: &str, password: &str) -> SelftestResponse {
+    let config = &state.config;
+    let started = Instant::now();
+    let mut report = SelftestResponse::default();
+    let fail = |mut report: SelftestResponse, step: &'static str, error: String| {
+        warn!("Selftest failed at {}: {}", step, error);
+        report.status = "fail";
+        report.failed_step = Some(step);
+        report.error = Some(error);
+        report.total_ms = elapsed_ms(started);
+        report
+    };
+
+    let step = Instant::now();
+    let user = state.users.find_by_username(username).await;
+    report.db_ms = Some(elapsed_ms(step));
+    let (stored_hash, role) = match user {
+        Ok(Some(user)) => (user.password_hash, user.role),
+        Ok(None) => return fail(report, "db", format!("selftest user {} does not exist", username)),
+        Err(e) => return fail(report, "db", e.to_string()),
+    };
+
+    let step = Instant::now();
+    let verified = verify_password(config, password.to_string(), stored_hash).await;
+    report.hash_ms = Some(elapsed_ms(step));
+    match verified {
+        Ok(true) => {}
+        Ok(false) => return fail(report, "hash", "selftest password does not match".to_string()),
+        Err(e) => return fail(report, "hash", e.into_app_error(AppError::PasswordVerification).to_string()),
+    }
+
+    let step = Instant::now();
+    let grant = TokenGrant {
+        sub: username.to_string(),
+        role,
+        aud: config.jwt_audience.clone(),
+        scope: None,
+        acr: None,
+        auth_time: None,
+        amr: None,
+        act: None,
+        jkt: None,
+        email_verified: None,
+        jti: None,
+    };
+    let now = state.clock.now();
+    let signed = issue_access_token(config, &grant, SELFTEST_TOKEN_TTL_SECONDS, now).and_then(|(token, _)| {
+        let verification_key = load_verification_key(config)?;
+        verify_token(&verification_key, &token, config.jwt_issuer.as_deref(), &config.required_claims, now, config.clock_skew(), config.max_token_bytes).map_err(|e| AppError::InvalidToken(e.to_string()))
+    });
+    report.sign_ms = Some(elapsed_ms(step));
+    if let Err(e) = signed {
+        return fail(report, "sign", e.to_string());
+    }
+
+    report.status = "ok";
+    report.total_ms = elapsed_ms(started);
+    info!("Selftest passed in {:.1}ms", report.total_ms);
+    report
+}
+
+// Synthetic login on demand for monitoring. Not rate limited, and the token it signs never leaves
+// the service.
+pub async fn selftest(State(state): State<AppState>) -> Result<Response, AppError> {
+    let report = run(&state).await.ok_or(AppError::NotFound)?;
+    let status = match report.failed_step {
+        Some(_) => StatusCode::SERVICE_UNAVAILABLE,
+        None => StatusCode::OK,
+    };
+    Ok((status, Json(report)).into_response())
+}
+
+// Run the selftest every `interval` in the background, for a continuous latency baseline per layer
+pub fn spawn_periodic(state: AppState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            run(&state).await;
+        }
+    });
+}
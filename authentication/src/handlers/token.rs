@@ -0,0 +1,193 @@
+use crate::{
+    client_ip::ClientIp,
+    clients::find_client,
+    dpop::{self, DPOP_SCHEME},
+    errors::AppError,
+    extract::JsonOrForm,
+    handlers::login::{granted_scopes, granted_subset, verify_password},
+    issuance_warnings,
+    models::{TokenRequest, TokenResponse},
+    state::AppState,
+    tenant::Tenant,
+    tokens::{issue_access_token, TokenGrant, ACCESS_TOKEN_TTL_SECONDS},
+};
+use axum::{
+    extract::{OriginalUri, State},
+    http::HeaderMap,
+    response::Json,
+};
+use tracing::info;
+
+const CLIENT_CREDENTIALS: &str = "client_credentials";
+const TOKEN_EXCHANGE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+// Grant types accepted by the token endpoint
+pub const SUPPORTED_GRANT_TYPES: [&str; 2] = [CLIENT_CREDENTIALS, TOKEN_EXCHANGE];
+
+// Subject token types accepted for exchange, all of which must be JWTs
+const JWT_TOKEN_TYPES: [&str; 3] = [
+    "urn:ietf:params:oauth:token-type:jwt",
+    "urn:ietf:params:oauth:token-type:access_token",
+    "urn:ietf:params:oauth:token-type:id_token",
+];
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+// Role claim carried by tokens issued to clients rather than users
+const SERVICE_ROLE: &str = "service";
+
+// `token_type` of issued access tokens, which DPoP-bound tokens name as such
+fn token_type(jkt: &Option<String>) -> String {
+    if jkt.is_some() { DPOP_SCHEME } else { "Bearer" }.to_string()
+}
+
+// OAuth2 token endpoint. With DPOP_ENABLED, a `DPoP` proof binds the issued token to the client's key.
+pub async fn token(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Tenant(tenant): Tenant,
+    OriginalUri(uri): OriginalUri,
+    request_headers: HeaderMap,
+    JsonOrForm(payload): JsonOrForm<TokenRequest>,
+) -> Result<Json<TokenResponse>, AppError> {
+    info!("Token request with grant type {} from {}", payload.grant_type, client_ip);
+    let jkt = dpop::token_request_binding(&state.config, &request_headers, uri.path(), state.clock.now())?;
+    match payload.grant_type.as_str() {
+        CLIENT_CREDENTIALS => client_credentials(&state, client_ip, tenant.as_deref(), payload, jkt).await,
+        TOKEN_EXCHANGE => token_exchange(&state, client_ip, tenant.as_deref(), payload, jkt).await,
+        _ => Err(AppError::UnsupportedGrantType(payload.grant_type)),
+    }
+}
+
+// Issue a short-lived service token to a registered client authenticating with its secret
+async fn client_credentials(
+    state: &AppState,
+    client_ip: std::net::IpAddr,
+    tenant: Option<&str>,
+    payload: TokenRequest,
+    jkt: Option<String>,
+) -> Result<Json<TokenResponse>, AppError> {
+    let pool = &state.pool;
+    let config = &state.config;
+    let (Some(client_id), Some(client_secret)) = (payload.client_id, payload.client_secret) else {
+        return Err(AppError::InvalidClient);
+    };
+    state.check_rate_limits(client_ip, tenant, &client_id).await?;
+
+    let client = find_client(pool, &client_id).await?.ok_or_else(|| {
+        info!("Unknown client: {}", client_id);
+        AppError::InvalidClient
+    })?;
+    let Some(secret_hash) = client.client_secret_hash else {
+        info!("Client {} has no secret and cannot use client credentials", client_id);
+        return Err(AppError::InvalidClient);
+    };
+    let secret_matches = verify_password(config, client_secret, secret_hash)
+        .await
+        .map_err(|e| e.into_app_error(AppError::PasswordVerification))?;
+    if !secret_matches {
+        info!("Client secret verification failed for {}", client_id);
+        return Err(AppError::InvalidClient);
+    }
+
+    let scopes = granted_subset(&client.scopes, payload.scope.as_deref())?;
+    let grant = TokenGrant {
+        sub: client_id,
+        role: SERVICE_ROLE.to_string(),
+        aud: client.audience.or_else(|| config.jwt_audience.clone()),
+        scope: (!scopes.is_empty()).then(|| scopes.join(" ")),
+        acr: None,
+        auth_time: None,
+        amr: None,
+        act: None,
+        jkt,
+        email_verified: None,
+        jti: None,
+    };
+    let (token, expires_in) = issue_access_token(
+        config,
+        &grant,
+        client.token_policy.access_ttl_seconds(config.client_token_ttl_seconds),
+        state.clock.now(),
+    )?;
+    info!("Issued service token for client {}", grant.sub);
+
+    Ok(Json(TokenResponse {
+        token_type: token_type(&grant.jkt),
+        access_token: token,
+        expires_in,
+        refresh_token: None,
+        issued_token_type: None,
+        email_verified: None,
+        session_handle: None,
+        warnings: issuance_warnings::take(&grant.sub),
+    }))
+}
+
+// Exchange a token from a trusted upstream issuer for one of ours for the mapped local user
+async fn token_exchange(
+    state: &AppState,
+    client_ip: std::net::IpAddr,
+    tenant: Option<&str>,
+    payload: TokenRequest,
+    jkt: Option<String>,
+) -> Result<Json<TokenResponse>, AppError> {
+    let config = &state.config;
+    let runtime = state.runtime.load();
+    let subject_token = payload
+        .subject_token
+        .ok_or_else(|| AppError::InvalidRequest("subject_token is required".to_string()))?;
+    match payload.subject_token_type.as_deref() {
+        Some(token_type) if JWT_TOKEN_TYPES.contains(&token_type) => {}
+        _ => {
+            return Err(AppError::InvalidRequest(
+                "subject_token_type must be a JWT, access token or ID token type".to_string(),
+            ))
+        }
+    }
+
+    let external = state.trusted_issuers.validate(&subject_token).await?;
+    let mapped = external.mapped_subject(&config.token_exchange_subject_claim)?;
+    state.check_rate_limits(client_ip, tenant, &mapped).await?;
+
+    let user = if config.token_exchange_subject_claim == "email" {
+        state.users.find_by_email(&mapped).await?
+    } else {
+        state.users.find_by_username(&mapped).await?
+    };
+    let user = user.ok_or_else(|| {
+            info!("No local user for {} from {}", mapped, external.iss);
+            AppError::InvalidRequest("subject_token does not map to a known user".to_string())
+        })?;
+    if config.is_selftest_user(&user.username) {
+        info!("Rejected token exchange for the selftest user from {}", external.iss);
+        return Err(AppError::InvalidRequest("subject_token does not map to a known user".to_string()));
+    }
+
+    let scopes = granted_scopes(&runtime, &user.role, payload.scope.as_deref())?;
+    let grant = TokenGrant {
+        sub: user.username,
+        role: user.role,
+        aud: config.jwt_audience.clone(),
+        scope: (!scopes.is_empty()).then(|| scopes.join(" ")),
+        acr: None,
+        auth_time: None,
+        amr: None,
+        act: None,
+        jkt,
+        email_verified: None,
+        jti: None,
+    };
+    let (token, expires_in) = issue_access_token(config, &grant, ACCESS_TOKEN_TTL_SECONDS, state.clock.now())?;
+    info!("Exchanged token from {} for user {}", external.iss, grant.sub);
+
+    Ok(Json(TokenResponse {
+        token_type: token_type(&grant.jkt),
+        access_token: token,
+        expires_in,
+        refresh_token: None,
+        issued_token_type: Some(ACCESS_TOKEN_TYPE.to_string()),
+        email_verified: None,
+        session_handle: None,
+        warnings: issuance_warnings::take(&grant.sub),
+    }))
+}
@@ -0,0 +1,116 @@
+use crate::{
+    errors::AppError,
+    models::ValidateRequest,
+    session_handles,
+    state::AppState,
+    tokens::{load_verification_key, verify_token, TokenError},
+};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use tracing::info;
+
+// The only reasons a caller is told, so a rejection never reveals more than whether the token
+// expired or was revoked
+fn coarse_reason(error: &TokenError) -> &'static str {
+    match error {
+        TokenError::Expired => "expired",
+        TokenError::Revoked => "revoked",
+        TokenError::Invalid | TokenError::FromTheFuture | TokenError::MissingClaim(_) | TokenError::TooLarge => "invalid",
+    }
+}
+
+// Lightweight validity check for internal callers that only gate on yes/no
+pub async fn validate(
+    State(state): State<AppState>,
+    Json(payload): Json<ValidateRequest>,
+) -> Result<Response, AppError> {
+    let config = &state.config;
+    info!("Validate endpoint called");
+
+    let verification_key = load_verification_key(config)?;
+    let mut verified = verify_token(&verification_key, &payload.token, config.jwt_issuer.as_deref(), &config.required_claims, state.clock.now(), config.clock_skew(), config.max_token_bytes);
+    if let Ok(claims) = &verified {
+        if session_handles::is_access_token_revoked(&state.pool, config, claims).await? {
+            verified = Err(TokenError::Revoked);
+        }
+    }
+    let response = match verified {
+        Ok(_) => Json(serde_json::json!({ "valid": true })).into_response(),
+        Err(e) => {
+            info!("Token validation failed: {}", e);
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(serde_json::json!({ "valid": false, "reason": coarse_reason(&e) })),
+            )
+                .into_response()
+        }
+    };
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, tokens::{issue_access_token, TokenGrant}};
+    use axum::body::to_bytes;
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+
+    fn grant() -> TokenGrant {
+        TokenGrant {
+            sub: "johndoe".to_string(),
+            role: "user".to_string(),
+            aud: None,
+            scope: None,
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            jkt: None,
+            email_verified: None,
+            jti: None,
+        }
+    }
+
+    async fn validated(state: &AppState, token: String) -> (StatusCode, serde_json::Value) {
+        let response = validate(State(state.clone()), Json(ValidateRequest { token })).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn reports_only_coarse_reasons() {
+        let mut config = Config::from_env();
+        config.required_claims = vec!["sub".to_string(), "exp".to_string(), "aud".to_string()];
+        let state = AppState::for_tests(config, PgPool::connect_lazy("postgres://localhost/test").unwrap());
+        let now = Utc::now();
+        let with_audience = TokenGrant { aud: Some("orders".to_string()), ..grant() };
+
+        let (token, _) = issue_access_token(&state.config, &with_audience, 3600, now).unwrap();
+        let (status, body) = validated(&state, token).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "valid": true }));
+
+        let (token, _) = issue_access_token(&state.config, &with_audience, 60, now - Duration::hours(1)).unwrap();
+        let (status, body) = validated(&state, token).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, serde_json::json!({ "valid": false, "reason": "expired" }));
+
+        // Neither the missing claim nor the clock problem is named
+        let (token, _) = issue_access_token(&state.config, &grant(), 3600, now).unwrap();
+        assert_eq!(validated(&state, token).await.1["reason"], "invalid");
+        let (token, _) = issue_access_token(&state.config, &with_audience, 3600, now + Duration::hours(1)).unwrap();
+        assert_eq!(validated(&state, token).await.1["reason"], "invalid");
+        assert_eq!(validated(&state, "not-a-token".to_string()).await.1["reason"], "invalid");
+
+        // An oversized token doesn't reveal MAX_TOKEN_BYTES
+        let oversized = "x".repeat(state.config.max_token_bytes + 1);
+        let (status, body) = validated(&state, oversized).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, serde_json::json!({ "valid": false, "reason": "invalid" }));
+    }
+}
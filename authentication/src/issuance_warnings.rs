@@ -0,0 +1,86 @@
+use axum::{
+    extract::{Query, Request},
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use std::cell::RefCell;
+use tracing::warn;
+
+// Non-fatal conditions met while issuing tokens for the current request, such as a last good key
+// being served or a lifetime being clamped. Whoever mints a token reports them in the logs, and
+// in the response for clients that ask with `?include=warnings`.
+struct IssuanceWarnings {
+    include: bool,
+    warnings: RefCell<Vec<String>>,
+}
+
+tokio::task_local! {
+    static ISSUANCE_WARNINGS: IssuanceWarnings;
+}
+
+#[derive(Deserialize)]
+struct IncludeQuery {
+    include: Option<String>,
+}
+
+// Whether a comma-separated `include` parameter asks for warnings
+fn includes_warnings(include: Option<&str>) -> bool {
+    include.is_some_and(|include| include.split(',').any(|item| item.trim() == "warnings"))
+}
+
+// Note a condition the token issued for the current request was issued under. Outside a request
+// (startup, spawned work) there is nobody to tell, so it is dropped.
+pub fn add(warning: impl Into<String>) {
+    let _ = ISSUANCE_WARNINGS.try_with(|scope| scope.warnings.borrow_mut().push(warning.into()));
+}
+
+// The warnings noted so far, logged together, and returned if the client asked for them
+pub fn take(subject: &str) -> Vec<String> {
+    let (include, warnings) = ISSUANCE_WARNINGS
+        .try_with(|scope| (scope.include, scope.warnings.take()))
+        .unwrap_or_default();
+    if !warnings.is_empty() {
+        warn!("Issued a token for {} with warnings: {}", subject, warnings.join("; "));
+    }
+    if include { warnings } else { Vec::new() }
+}
+
+// Collect the warnings of tokens issued while handling a request
+pub async fn collect(request: Request, next: Next) -> Response {
+    let query = Query::<IncludeQuery>::try_from_uri(request.uri()).ok();
+    let scope = IssuanceWarnings {
+        include: includes_warnings(query.as_ref().and_then(|query| query.include.as_deref())),
+        warnings: RefCell::new(Vec::new()),
+    };
+    ISSUANCE_WARNINGS.scope(scope, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn returns_warnings_only_when_asked_for() {
+        assert!(includes_warnings(Some("warnings")));
+        assert!(includes_warnings(Some("claims, warnings")));
+        assert!(!includes_warnings(Some("warning")));
+        assert!(!includes_warnings(None));
+
+        for include in [true, false] {
+            let scope = IssuanceWarnings { include, warnings: RefCell::new(Vec::new()) };
+            let taken = ISSUANCE_WARNINGS
+                .scope(scope, async {
+                    add("access token lifetime clamped");
+                    let first = take("johndoe");
+                    (first, take("johndoe"))
+                })
+                .await;
+            let expected = if include { vec!["access token lifetime clamped".to_string()] } else { Vec::new() };
+            assert_eq!(taken, (expected, Vec::new()));
+        }
+        // Outside a request nothing is collected
+        add("dropped");
+        assert!(take("johndoe").is_empty());
+    }
+}
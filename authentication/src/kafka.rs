@@ -0,0 +1,213 @@
+use chrono::{DateTime, Utc};
+use rskafka::{
+    client::{
+        partition::{Compression, PartitionClient, UnknownTopicHandling},
+        Client, ClientBuilder, Credentials, SaslConfig,
+    },
+    record::Record,
+};
+use rustls::{pki_types::{pem::PemObject, CertificateDer}, ClientConfig, RootCertStore};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use crate::config::{Config, KafkaSaslMechanism};
+
+const CLIENT_ID: &str = "craftista-authentication";
+// Bound on a whole send, including connecting and any metadata refresh
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum KafkaError {
+    #[error("{0}")]
+    Client(#[from] rskafka::client::error::Error),
+    #[error("timed out")]
+    Timeout,
+    #[error("topic has no partitions")]
+    NoPartitions,
+    #[error("invalid TLS configuration: {0}")]
+    Tls(String),
+}
+
+// Kafka's default partitioner hash, so keyed records land where Java clients would put them
+fn murmur2(data: &[u8]) -> i32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h: u32 = 0x9747_b28c ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    if tail.len() >= 3 {
+        h ^= u32::from(tail[2]) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= u32::from(tail[1]) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= u32::from(tail[0]);
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h as i32
+}
+
+fn partition_for(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+// Trust KAFKA_TLS_CA_PATH when set, else the public web roots
+fn tls_config(config: &Config) -> Result<Arc<ClientConfig>, KafkaError> {
+    let mut roots = RootCertStore::empty();
+    match &config.kafka_tls_ca_path {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| KafkaError::Tls(format!("{}: {}", path, e)))?;
+            for cert in certs {
+                roots.add(cert).map_err(|e| KafkaError::Tls(format!("{}: {}", path, e)))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| KafkaError::Tls(e.to_string()))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(tls))
+}
+
+fn sasl_config(config: &Config) -> Option<SaslConfig> {
+    let credentials = Credentials::new(config.kafka_sasl_username.clone(), config.kafka_sasl_password.clone());
+    Some(match config.kafka_sasl_mechanism? {
+        KafkaSaslMechanism::Plain => SaslConfig::Plain(credentials),
+        KafkaSaslMechanism::ScramSha256 => SaslConfig::ScramSha256(credentials),
+        KafkaSaslMechanism::ScramSha512 => SaslConfig::ScramSha512(credentials),
+    })
+}
+
+// Connection and per-partition clients, built on first use
+#[derive(Default)]
+struct ProducerState {
+    client: Option<Client>,
+    partition_count: usize,
+    partitions: HashMap<i32, Arc<PartitionClient>>,
+}
+
+// Publishes records to one topic. The client and topic metadata are cached, and dropped after any
+// failure so the next send starts from fresh metadata.
+pub struct Producer {
+    brokers: Vec<String>,
+    topic: String,
+    tls: Option<Arc<ClientConfig>>,
+    sasl: Option<SaslConfig>,
+    state: Mutex<ProducerState>,
+}
+
+impl Producer {
+    pub fn new(config: &Config, topic: String) -> Result<Self, KafkaError> {
+        Ok(Self {
+            brokers: config.kafka_brokers.clone(),
+            topic,
+            tls: if config.kafka_tls { Some(tls_config(config)?) } else { None },
+            sasl: sasl_config(config),
+            state: Mutex::new(ProducerState::default()),
+        })
+    }
+
+    // Publish one record; the key picks the partition, so records with the same key stay in order
+    pub async fn send(&self, key: &[u8], value: &[u8], timestamp: DateTime<Utc>) -> Result<(), KafkaError> {
+        let mut state = self.state.lock().await;
+        let result = tokio::time::timeout(SEND_TIMEOUT, self.send_locked(&mut state, key, value, timestamp))
+            .await
+            .unwrap_or(Err(KafkaError::Timeout));
+        if result.is_err() {
+            *state = ProducerState::default();
+        }
+        result
+    }
+
+    async fn send_locked(
+        &self,
+        state: &mut ProducerState,
+        key: &[u8],
+        value: &[u8],
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), KafkaError> {
+        if state.client.is_none() {
+            state.client = Some(self.connect().await?);
+        }
+        let client = state.client.as_ref().expect("client was just built");
+        if state.partition_count == 0 {
+            state.partition_count = client
+                .list_topics()
+                .await?
+                .into_iter()
+                .find(|topic| topic.name == self.topic)
+                .map_or(0, |topic| topic.partitions.len());
+        }
+        if state.partition_count == 0 {
+            return Err(KafkaError::NoPartitions);
+        }
+
+        let partition = partition_for(key, state.partition_count) as i32;
+        let partition_client = match state.partitions.get(&partition) {
+            Some(partition_client) => partition_client.clone(),
+            None => {
+                let partition_client = Arc::new(
+                    client
+                        .partition_client(self.topic.clone(), partition, UnknownTopicHandling::Error)
+                        .await?,
+                );
+                state.partitions.insert(partition, partition_client.clone());
+                partition_client
+            }
+        };
+        let record = Record {
+            key: Some(key.to_vec()),
+            value: Some(value.to_vec()),
+            headers: BTreeMap::new(),
+            timestamp,
+        };
+        partition_client.produce(vec![record], Compression::NoCompression).await?;
+        Ok(())
+    }
+
+    async fn connect(&self) -> Result<Client, KafkaError> {
+        let mut builder = ClientBuilder::new(self.brokers.clone()).client_id(CLIENT_ID);
+        if let Some(tls) = &self.tls {
+            builder = builder.tls_config(tls.clone());
+        }
+        if let Some(sasl) = &self.sasl {
+            builder = builder.sasl_config(sasl.clone());
+        }
+        Ok(builder.build().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_like_the_reference_client() {
+        // Vectors from Kafka's own partitioner tests
+        assert_eq!(murmur2(b"21"), -973_932_308);
+        assert_eq!(murmur2(b"foobar"), -790_332_482);
+        assert_eq!(murmur2(b"a-little-bit-long-string"), -985_981_536);
+        assert_eq!(murmur2(b"abc"), 479_470_107);
+        assert_eq!(partition_for(b"21", 3), (-973_932_308i32 & 0x7fff_ffff) as usize % 3);
+    }
+}
@@ -0,0 +1,314 @@
+use jsonwebtoken::Algorithm;
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{
+        der::{asn1::ObjectIdentifier, Document},
+        spki::SubjectPublicKeyInfoRef,
+        DecodePrivateKey, DecodePublicKey, PrivateKeyInfo,
+    },
+    RsaPrivateKey, RsaPublicKey,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use std::fmt;
+
+use crate::{
+    config::{Config, TokenFormat},
+    key_pem, paseto,
+    test_mode::{self, read_key_pem},
+    tokens::certificate_thumbprint,
+};
+
+const RSA_ENCRYPTION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+const RSASSA_PSS: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.10");
+const EC_PUBLIC_KEY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+const ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+// Kind of key a PEM file holds, as far as signing is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyType {
+    Rsa,
+    Ec,
+    Ed25519,
+    Other,
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyType::Rsa => "an RSA key",
+            KeyType::Ec => "an EC key",
+            KeyType::Ed25519 => "an Ed25519 key",
+            KeyType::Other => "a key of an unsupported type",
+        })
+    }
+}
+
+fn key_type_for(algorithm: ObjectIdentifier) -> KeyType {
+    match algorithm {
+        RSA_ENCRYPTION | RSASSA_PSS => KeyType::Rsa,
+        EC_PUBLIC_KEY => KeyType::Ec,
+        ED25519 => KeyType::Ed25519,
+        _ => KeyType::Other,
+    }
+}
+
+// Identify a key from its PEM label, or from the algorithm inside a PKCS#8/SPKI document
+fn key_type(pem: &str) -> Result<KeyType, String> {
+    let (label, document) =
+        Document::from_pem(pem).map_err(|e| format!("is not a valid PEM key: {}", key_pem::describe_error(pem, e)))?;
+    match label {
+        "RSA PUBLIC KEY" | "RSA PRIVATE KEY" => Ok(KeyType::Rsa),
+        "EC PRIVATE KEY" => Ok(KeyType::Ec),
+        "PUBLIC KEY" => SubjectPublicKeyInfoRef::try_from(document.as_bytes())
+            .map(|info| key_type_for(info.algorithm.oid))
+            .map_err(|e| format!("is not a valid public key: {}", e)),
+        "PRIVATE KEY" => PrivateKeyInfo::try_from(document.as_bytes())
+            .map(|info| key_type_for(info.algorithm.oid))
+            .map_err(|e| format!("is not a valid private key: {}", e)),
+        "ENCRYPTED PRIVATE KEY" => Err("is encrypted, which only RSA private keys may be".to_string()),
+        other => Err(format!("holds a {} rather than a key", other)),
+    }
+}
+
+// A key file that can't be used with the configured algorithm
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyProblem {
+    // What the key is for, e.g. "RSA_PUBLIC_KEY_PATH" or "RETIRED_KEYS (key-0)"
+    pub setting: String,
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for KeyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.setting, self.path, self.reason)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Expected {
+    // A JWT key used with this algorithm
+    Jwt(Algorithm),
+    // PASETO v4.public
+    Ed25519,
+}
+
+impl Expected {
+    fn key_type(self) -> KeyType {
+        match self {
+            Expected::Jwt(Algorithm::ES256) => KeyType::Ec,
+            Expected::Jwt(_) => KeyType::Rsa,
+            Expected::Ed25519 => KeyType::Ed25519,
+        }
+    }
+
+    fn describe(self) -> String {
+        match self {
+            Expected::Jwt(algorithm) => format!("{:?} is configured", algorithm),
+            Expected::Ed25519 => "TOKEN_FORMAT=paseto is configured".to_string(),
+        }
+    }
+}
+
+// Check one key's type against what the algorithm needs, then that it parses as such
+fn check_pem(pem: &str, private: bool, expected: Expected) -> Result<(), String> {
+    let found = key_type(pem)?;
+    if found != expected.key_type() {
+        return Err(format!(
+            "is {}, but {} which needs {}",
+            found,
+            expected.describe(),
+            expected.key_type()
+        ));
+    }
+    let parsed = match (expected.key_type(), private) {
+        (KeyType::Rsa, false) => RsaPublicKey::from_public_key_pem(pem)
+            .map(drop)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem).map(drop))
+            .map_err(|e| e.to_string()),
+        (KeyType::Rsa, true) => RsaPrivateKey::from_pkcs8_pem(pem)
+            .map(drop)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem).map(drop))
+            .map_err(|e| e.to_string()),
+        // ES256 signs with a PKCS#8 P-256 key, as ring loads it
+        (KeyType::Ec, true) => Document::from_pem(pem).map_err(|e| e.to_string()).and_then(|(_, document)| {
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, document.as_bytes(), &SystemRandom::new())
+                .map(drop)
+                .map_err(|e| e.to_string())
+        }),
+        (KeyType::Ec, false) => key_pem::ec_public_point(pem).map(drop),
+        (_, _) => paseto::key_pair_from_pem(pem).map(drop).map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|e| format!("is {} but could not be parsed: {}", found, key_pem::describe_error(pem, e)))
+}
+
+struct KeyFile<'a> {
+    setting: String,
+    path: &'a str,
+    // Embedded key used in test mode; keys without one are always read from disk
+    test_key: Option<&'static str>,
+    private: bool,
+    expected: Expected,
+    // Skipped rather than reported when it can't be read
+    optional: bool,
+}
+
+// Keys `config` signs and verifies with, under the settings that name them
+fn key_files(config: &Config) -> Vec<KeyFile<'_>> {
+    let key = |setting: &str, path, test_key, private, expected| KeyFile {
+        setting: setting.to_string(),
+        path,
+        test_key: Some(test_key),
+        private,
+        expected,
+        optional: false,
+    };
+    match config.token_format {
+        TokenFormat::Paseto => vec![key(
+            "PASETO_PRIVATE_KEY_PATH",
+            &config.paseto_private_key_path,
+            test_mode::PASETO_PRIVATE_KEY,
+            true,
+            Expected::Ed25519,
+        )],
+        TokenFormat::Jwt => {
+            let signing = Expected::Jwt(config.jwt_signing_algorithm);
+            let (private_test_key, public_test_key) = test_mode::jwt_key_pair(config.jwt_signing_algorithm);
+            let mut files = vec![
+                key("RSA_PRIVATE_KEY_PATH", &config.rsa_private_key_path, private_test_key, true, signing),
+                key("RSA_PUBLIC_KEY_PATH", &config.rsa_public_key_path, public_test_key, false, signing),
+            ];
+            // Retired keys are read from disk even in test mode, and like the JWKS, unreadable ones
+            // are left out rather than fatal
+            files.extend(config.retired_keys.iter().map(|retired| KeyFile {
+                setting: format!("RETIRED_KEYS ({})", retired.kid),
+                path: &retired.public_key_path,
+                test_key: None,
+                private: false,
+                expected: Expected::Jwt(config.retired_key_algorithm(&retired.kid)),
+                optional: true,
+            }));
+            // So are verification keys, which only ever verify their own algorithm
+            files.extend(config.jwt_verification_keys.iter().map(|verification_key| KeyFile {
+                setting: format!("JWT_VERIFICATION_KEYS ({})", verification_key.kid),
+                path: &verification_key.public_key_path,
+                test_key: None,
+                private: false,
+                expected: Expected::Jwt(verification_key.algorithm),
+                optional: true,
+            }));
+            // Audience keys are the only keys for their audiences, so each must be present
+            for audience_key in &config.audience_keys {
+                let setting = format!("AUDIENCE_KEYS ({}, for {})", audience_key.kid, audience_key.audiences.join(" "));
+                files.push(KeyFile {
+                    setting: format!("{} private key", setting),
+                    path: &audience_key.private_key_path,
+                    test_key: None,
+                    private: true,
+                    expected: signing,
+                    optional: false,
+                });
+                files.push(KeyFile {
+                    setting: format!("{} public key", setting),
+                    path: &audience_key.public_key_path,
+                    test_key: None,
+                    private: false,
+                    expected: signing,
+                    optional: false,
+                });
+            }
+            files
+        }
+    }
+}
+
+// Check the keys of one issuer, prefixing each problem's setting with `prefix`
+fn check_issuer(prefix: &str, config: &Config, problems: &mut Vec<KeyProblem>) {
+    for file in key_files(config) {
+        let pem = match file.test_key {
+            Some(test_key) => read_key_pem(config, file.path, test_key),
+            None => key_pem::read(file.path),
+        };
+        let result = match pem {
+            Err(_) if file.optional => continue,
+            pem => pem.map_err(|e| format!("could not be read: {}", e)),
+        };
+        // Encrypted RSA keys are checked as what RSA_PRIVATE_KEY_PASSPHRASE decrypts them to
+        let result = match file.expected.key_type() {
+            KeyType::Rsa if file.private => result
+                .and_then(|pem| key_pem::decrypt_rsa_private_key(&pem, config.rsa_private_key_passphrase.as_deref())),
+            _ => result,
+        };
+        let result = result.and_then(|pem| check_pem(&pem, file.private, file.expected));
+        if let Err(reason) = result {
+            problems.push(KeyProblem {
+                setting: format!("{}{}", prefix, file.setting),
+                path: file.path.to_string(),
+                reason,
+            });
+        }
+    }
+    // A certificate that can't be thumbprinted would fail every token issued with it
+    if let (TokenFormat::Jwt, Some(path)) = (config.token_format, &config.rsa_certificate_path) {
+        let result = key_pem::read(path)
+            .map_err(|e| format!("could not be read: {}", e))
+            .and_then(|pem| certificate_thumbprint(&pem).map_err(|e| format!("is not a valid PEM certificate: {}", e)));
+        if let Err(reason) = result {
+            problems.push(KeyProblem {
+                setting: format!("{}RSA_CERTIFICATE_PATH", prefix),
+                path: path.clone(),
+                reason,
+            });
+        }
+    }
+}
+
+// Cross-check every configured key (root, retired, per-audience and per-realm) against the algorithm it will be
+// used with, so a wrong key type fails at startup rather than as a 500 on the first request
+pub fn validate_keys(config: &Config) -> Vec<KeyProblem> {
+    let mut problems = Vec::new();
+    check_issuer("", config, &mut problems);
+    for realm in &config.realms {
+        check_issuer(&format!("realm {} ", realm.name), &config.for_realm(realm), &mut problems);
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // P-256 public key, as `openssl ec -pubout` writes it
+    const EC_PUBLIC_KEY_PEM: &str = "-----BEGIN PUBLIC KEY-----
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE3ZQsHQSFtCR0ghjmIzonRuWYjPfP
+J8xcaBM/QAwtlLvOZ+aKY/E5AZTaodS1SEbkLCQ4kpArxVQV2P8N6tPRXw==
+-----END PUBLIC KEY-----";
+
+    #[test]
+    fn accepts_keys_matching_the_algorithm() {
+        let rsa = Expected::Jwt(Algorithm::RS256);
+        assert_eq!(check_pem(test_mode::RSA_PUBLIC_KEY, false, rsa), Ok(()));
+        assert_eq!(check_pem(test_mode::RSA_PRIVATE_KEY, true, rsa), Ok(()));
+        let ec = Expected::Jwt(Algorithm::ES256);
+        assert_eq!(check_pem(test_mode::EC_PUBLIC_KEY, false, ec), Ok(()));
+        assert_eq!(check_pem(EC_PUBLIC_KEY_PEM, false, ec), Ok(()));
+        assert_eq!(check_pem(test_mode::EC_PRIVATE_KEY, true, ec), Ok(()));
+        assert_eq!(check_pem(test_mode::PASETO_PRIVATE_KEY, true, Expected::Ed25519), Ok(()));
+    }
+
+    #[test]
+    fn names_the_mismatched_key_type() {
+        let error = check_pem(EC_PUBLIC_KEY_PEM, false, Expected::Jwt(Algorithm::RS256)).unwrap_err();
+        assert_eq!(error, "is an EC key, but RS256 is configured which needs an RSA key");
+        let error = check_pem(test_mode::RSA_PUBLIC_KEY, false, Expected::Jwt(Algorithm::ES256)).unwrap_err();
+        assert_eq!(error, "is an RSA key, but ES256 is configured which needs an EC key");
+
+        let error = check_pem(test_mode::RSA_PRIVATE_KEY, true, Expected::Ed25519).unwrap_err();
+        assert_eq!(error, "is an RSA key, but TOKEN_FORMAT=paseto is configured which needs an Ed25519 key");
+
+        assert!(check_pem("not a key", false, Expected::Ed25519).unwrap_err().starts_with("is not a valid PEM key"));
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::OsRng, Rng};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::{errors::AppError, metrics};
+
+// Digits in an emailed login code
+pub const CODE_LENGTH: u32 = 6;
+
+pub fn random_code() -> String {
+    let code = OsRng.gen_range(0..10u32.pow(CODE_LENGTH));
+    format!("{:0width$}", code, width = CODE_LENGTH as usize)
+}
+
+// Codes are only ever stored as a SHA-256 digest bound to their user
+fn hash_code(user_id: i32, code: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", user_id, code).as_bytes()))
+}
+
+// Issue a fresh code for `user_id`, replacing any outstanding one, and return it for mailing
+pub async fn create(pool: &PgPool, user_id: i32, ttl: Duration, now: DateTime<Utc>) -> Result<String, AppError> {
+    let retire = sqlx::query("UPDATE login_codes SET used_at = $1 WHERE user_id = $2 AND used_at IS NULL")
+        .bind(now)
+        .bind(user_id)
+        .execute(pool);
+    metrics::observe("login_codes.retire", retire).await?;
+
+    let code = random_code();
+    let insert = sqlx::query("INSERT INTO login_codes (user_id, code_hash, expires_at) VALUES ($1, $2, $3)")
+        .bind(user_id)
+        .bind(hash_code(user_id, &code))
+        .bind(now + ttl)
+        .execute(pool);
+    metrics::observe("login_codes.insert", insert).await?;
+    Ok(code)
+}
+
+// Spend the user's outstanding code if `code` matches it. Each wrong guess counts against the
+// code, which stops being accepted after `max_attempts` of them.
+pub async fn redeem(pool: &PgPool, user_id: i32, code: &str, max_attempts: u32, now: DateTime<Utc>) -> Result<bool, AppError> {
+    let query = sqlx::query(
+        "SELECT id, code_hash, attempts FROM login_codes \
+         WHERE user_id = $1 AND used_at IS NULL AND expires_at > $2 ORDER BY id DESC LIMIT 1"
+    )
+    .bind(user_id)
+    .bind(now)
+    .map(|row: PgRow| (row.get::<i32, _>("id"), row.get::<String, _>("code_hash"), row.get::<i32, _>("attempts")))
+    .fetch_optional(pool);
+    let Some((id, code_hash, attempts)) = metrics::observe("login_codes.find", query).await? else {
+        return Ok(false);
+    };
+    if attempts >= max_attempts as i32 {
+        return Ok(false);
+    }
+
+    if code_hash != hash_code(user_id, code.trim()) {
+        let count = sqlx::query("UPDATE login_codes SET attempts = attempts + 1 WHERE id = $1")
+            .bind(id)
+            .execute(pool);
+        metrics::observe("login_codes.count_attempt", count).await?;
+        return Ok(false);
+    }
+
+    // Concurrent redemptions race on `used_at`; only one of them wins
+    let spend = sqlx::query("UPDATE login_codes SET used_at = $1 WHERE id = $2 AND used_at IS NULL")
+        .bind(now)
+        .bind(id)
+        .execute(pool);
+    Ok(metrics::observe("login_codes.spend", spend).await?.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_fixed_width_digits_hashed_per_user() {
+        for _ in 0..100 {
+            let code = random_code();
+            assert_eq!(code.len(), CODE_LENGTH as usize);
+            assert!(code.bytes().all(|b| b.is_ascii_digit()));
+        }
+        assert_ne!(hash_code(1, "012345"), hash_code(2, "012345"));
+        assert_eq!(hash_code(1, "012345"), hash_code(1, "012345"));
+    }
+}
@@ -0,0 +1,241 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::{debug, info};
+
+use crate::config::Config;
+
+// Upper bound on a whole SMTP conversation
+const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A plain-text message to a single recipient
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Outgoing mail, so handlers don't depend on a particular transport
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+    // Confirm mail could be handed over now, without sending any; describes the transport
+    async fn check(&self) -> Result<String, String>;
+}
+
+// Default mailer that only logs that a message would have been sent. The body carries secrets
+// such as login codes, so it is only logged at debug level.
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        info!("Mail \"{}\" to {} not sent: no SMTP_HOST configured", email.subject, email.to);
+        debug!(target: "mail", "Mail body for {}: {}", email.to, email.body);
+        Ok(())
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        Ok("no SMTP_HOST configured; mail is only logged".to_string())
+    }
+}
+
+// Hands messages to an SMTP relay without authentication or TLS, e.g. a local MTA or sidecar that
+// forwards them on
+pub struct SmtpMailer {
+    address: String,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, port: u16, from: String) -> Self {
+        Self {
+            address: format!("{}:{}", host, port),
+            from,
+        }
+    }
+
+    async fn deliver(&self, email: &Email) -> Result<(), String> {
+        let stream = TcpStream::connect(&self.address).await.map_err(|e| e.to_string())?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+
+        expect_reply(&mut reader, 220).await?;
+        command(&mut writer, &mut reader, "EHLO localhost", 250).await?;
+        command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>", self.from), 250).await?;
+        command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", email.to), 250).await?;
+        command(&mut writer, &mut reader, "DATA", 354).await?;
+        let message = format_message(&self.from, email);
+        writer.write_all(message.as_bytes()).await.map_err(|e| e.to_string())?;
+        command(&mut writer, &mut reader, ".", 250).await?;
+        // The message is accepted; a failed goodbye doesn't matter
+        let _ = command(&mut writer, &mut reader, "QUIT", 221).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        check_header_value(&email.to)?;
+        check_header_value(&email.subject)?;
+        tokio::time::timeout(SMTP_TIMEOUT, self.deliver(email))
+            .await
+            .map_err(|_| format!("SMTP relay {} timed out", self.address))?
+    }
+
+    async fn check(&self) -> Result<String, String> {
+        let stream = TcpStream::connect(&self.address).await.map_err(|e| e.to_string())?;
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        expect_reply(&mut reader, 220).await?;
+        let _ = command(&mut writer, &mut reader, "QUIT", 221).await;
+        Ok(format!("SMTP relay {} is accepting connections", self.address))
+    }
+}
+
+// Addresses and subjects end up in commands and headers, so line breaks would inject new ones
+fn check_header_value(value: &str) -> Result<(), String> {
+    if value.contains(['\r', '\n']) {
+        return Err("mail header values may not contain line breaks".to_string());
+    }
+    Ok(())
+}
+
+// RFC 5322 message with CRLF line endings and dot-stuffing for the SMTP DATA phase
+fn format_message(from: &str, email: &Email) -> String {
+    let mut message = format!(
+        "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        from,
+        email.to,
+        email.subject,
+        Utc::now().to_rfc2822()
+    );
+    for line in email.body.lines() {
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message
+}
+
+async fn command<W, R>(writer: &mut W, reader: &mut R, line: &str, expected: u16) -> Result<(), String>
+where
+    W: AsyncWriteExt + Unpin,
+    R: AsyncBufReadExt + Unpin,
+{
+    writer
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    expect_reply(reader, expected).await
+}
+
+// Read a possibly multi-line reply (`250-...` continued, `250 ...` last) and check its code
+async fn expect_reply<R: AsyncBufReadExt + Unpin>(reader: &mut R, expected: u16) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.map_err(|e| e.to_string())? == 0 {
+            return Err("SMTP relay closed the connection".to_string());
+        }
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+        if code != Some(expected) {
+            return Err(format!("unexpected SMTP reply: {}", line.trim_end()));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+// SMTP when SMTP_HOST is set, otherwise mail is only logged
+pub fn mailer_from_config(config: &Config) -> Arc<dyn Mailer> {
+    match &config.smtp_host {
+        Some(host) => Arc::new(SmtpMailer::new(host, config.smtp_port, config.mail_from.clone())),
+        None => Arc::new(LogMailer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn dot_stuffs_the_body() {
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "first\n.hidden\nlast".to_string(),
+        };
+        let message = format_message("auth@example.com", &email);
+        assert!(message.starts_with("From: <auth@example.com>\r\nTo: <alice@example.com>\r\nSubject: Hello\r\n"));
+        assert!(message.ends_with("\r\n\r\nfirst\r\n..hidden\r\nlast\r\n"));
+    }
+
+    #[tokio::test]
+    async fn delivers_through_an_smtp_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let relay = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            let mut transcript = Vec::new();
+            writer.write_all(b"220 relay ready\r\n").await.unwrap();
+            let mut in_data = false;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = if in_data {
+                    if line != "." {
+                        transcript.push(line);
+                        continue;
+                    }
+                    in_data = false;
+                    b"250 queued\r\n"
+                } else if line.starts_with("EHLO") {
+                    b"250-relay\r\n250 8BITMIME\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).await.unwrap();
+                if line == "QUIT" {
+                    break;
+                }
+                transcript.push(line);
+            }
+            transcript
+        });
+
+        let mailer = SmtpMailer::new("127.0.0.1", port, "auth@example.com".to_string());
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Your code".to_string(),
+            body: "123456".to_string(),
+        };
+        mailer.send(&email).await.unwrap();
+
+        let transcript = relay.await.unwrap();
+        assert_eq!(transcript[1], "MAIL FROM:<auth@example.com>");
+        assert_eq!(transcript[2], "RCPT TO:<alice@example.com>");
+        assert_eq!(transcript.last().map(String::as_str), Some("."));
+        assert!(transcript.iter().any(|line| line == "123456"));
+
+        let injected = Email {
+            subject: "Hi\r\nBcc: eve@example.com".to_string(),
+            ..email
+        };
+        assert!(mailer.send(&injected).await.is_err());
+    }
+}
@@ -1,58 +1,308 @@
+mod access_log;
+mod batch;
+mod client_ip;
+mod clients;
+mod clock;
 mod config;
+mod confusables;
+mod cookies;
+mod db;
+mod dpop;
+mod debug_capture;
+mod email_policy;
+mod error_messages;
 mod errors;
+mod events;
+mod extract;
+mod federation;
+mod flags;
+mod geo;
+mod issuance_warnings;
 mod handlers;
+mod kafka;
+mod key_check;
+mod key_pem;
+mod login_codes;
+mod mailer;
+mod metrics;
 mod middleware;
 mod models;
+mod pagination;
+mod paseto;
+mod passwords;
+mod rate_limit;
+mod refresh_tokens;
+mod registration_codes;
+mod session_handles;
+mod shutdown;
+mod single_flight;
 mod state;
+mod tarpit;
 mod telemetry;
+mod tenant;
+mod test_mode;
+mod tokens;
+mod users;
 
 use axum::{
     middleware as axum_middleware,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
-use config::Config;
+use arc_swap::ArcSwap;
+use config::{AccessLogFormat, Config, RuntimeConfig, TokenFormat};
 use dotenv::dotenv;
-use sqlx::postgres::PgPool;
+use email_policy::EmailDomainPolicy;
+use events::EventEmitter;
+use federation::TrustedIssuers;
+use flags::FeatureFlags;
+use metrics::Metrics;
 use state::AppState;
+use refresh_tokens::RecentRotations;
+use shutdown::ShutdownHooks;
+use single_flight::SingleFlight;
+use tarpit::Tarpit;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use users::PgUserRepository;
+
+// Authentication and discovery routes, signing and verifying with the keys in `state.config`.
+// Served at the root for the default issuer and again under each realm's path prefix.
+fn auth_routes(state: &AppState) -> Router<AppState> {
+    let config = &state.config;
+    let mut protected_routes = Router::new()
+        .route("/register", post(handlers::register::register))
+        .route("/introspect", post(handlers::introspect::introspect))
+        .route("/revoke", post(handlers::introspect::revoke))
+        .route("/validate", post(handlers::validate::validate))
+        .route("/admin/reload", post(handlers::admin::reload))
+        .route("/admin/flags", get(handlers::admin::list_flags))
+        .route("/admin/flags/:name", put(handlers::admin::set_flag))
+        .route("/admin/users", get(handlers::admin::list_users))
+        .route("/admin/users/import", post(handlers::admin::import_users))
+        .route("/users/export", get(handlers::admin::export_users))
+        .route("/admin/users/:id/password", post(handlers::admin::reset_password))
+        .route("/users/:id/impersonate", post(handlers::impersonate::impersonate))
+        .route("/selftest", post(handlers::selftest::selftest));
+    // First step of registration when the address has to be proven before it's registered
+    if config.verify_email_before_register {
+        protected_routes = protected_routes.route("/register/email-code", post(handlers::register::request_code));
+    }
+    let protected_routes = protected_routes.layer(axum_middleware::from_fn_with_state(state.clone(), middleware::auth));
+
+    let mut routes = Router::new()
+        .route("/api/auth/login", post(handlers::login::login))
+        .route("/api/auth/login/challenge", post(handlers::login_challenge::login_challenge))
+        .route("/api/auth/token", post(handlers::token::token))
+        .route("/api/auth/refresh", post(handlers::refresh::refresh))
+        .route("/api/auth/status", get(handlers::status::auth_status))
+        .route("/api/auth/me", get(handlers::me::me))
+        .route("/api/auth/me/export", get(handlers::export::export))
+        .route("/api/auth/me/password", post(handlers::password::change_password))
+        .nest("/api/auth", protected_routes);
+    // Passwordless login with an emailed one-time code
+    if config.email_login_enabled {
+        routes = routes
+            .route("/api/auth/login/email-code/request", post(handlers::email_login::request_code))
+            .route("/api/auth/login/email-code/verify", post(handlers::email_login::verify_code));
+    }
+    if config.password_policy_endpoint_enabled {
+        routes = routes.route("/api/auth/password-policy", get(handlers::password::policy));
+    }
+    // JWKS and discovery describe RS256 JWTs, so they're only served in JWT mode
+    if config.token_format == TokenFormat::Jwt {
+        routes = routes
+            .route("/.well-known/jwks.json", get(handlers::openid::jwks))
+            .route("/.well-known/openid-configuration", get(handlers::openid::openid_configuration));
+    }
+    routes
+}
 
 #[tokio::main]
 async fn main() {
     // Load environment variables and set config
     dotenv().ok();
     let config = Config::from_env();
+    let runtime = RuntimeConfig::from_env();
 
     // Initialize tracing
-    let tracer_provider = telemetry::init_tracing_subscriber(&config);
+    let (tracer_provider, log_reloader) = telemetry::init_tracing_subscriber(&config, &runtime.log_level);
+
+    // A key that doesn't suit the configured algorithm would otherwise only fail once requests arrive
+    if config.validate_keys_at_startup {
+        let problems = key_check::validate_keys(&config);
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("Key check failed: {}", problem);
+            }
+            error!("Refusing to start with {} unusable key(s); set VALIDATE_KEYS_AT_STARTUP=false to skip this check", problems.len());
+            std::process::exit(1);
+        }
+    }
+
+    // Without a rotation time the previous key's grace window would restart on every deploy
+    if config.previous_internal_api_key.is_some() && config.internal_key_rotated_at.is_none() {
+        error!("PREVIOUS_INTERNAL_API_KEY is set but INTERNAL_KEY_ROTATED_AT is missing or not an RFC 3339 time");
+        error!("Refusing to start; set INTERNAL_KEY_ROTATED_AT to when INTERNAL_API_KEY took effect, or unset PREVIOUS_INTERNAL_API_KEY");
+        std::process::exit(1);
+    }
+
+    // Unsigned webhooks could be forged by anyone who can reach the receiver
+    if config.webhook_url.is_some() && config.webhook_secret.is_empty() {
+        error!("Refusing to start with WEBHOOK_URL set but no WEBHOOK_SECRET to sign deliveries with");
+        std::process::exit(1);
+    }
 
     // Set up database connection
-    let database_url = format!(
-        "postgres://{}:{}@{}:{}/{}",
-        config.postgres_user, config.postgres_password, config.postgres_host, config.postgres_port, config.postgres_db
-    );
-    let pool = PgPool::connect(&database_url)
-        .await
-        .expect("Failed to connect to Postgres");
+    let pool = db::connect(&config).await;
+    // Migrations may be run externally after the service starts, so by default this is only logged
+    if let Err(e) = db::check_schema(&pool).await {
+        error!("Database check failed: {}", e);
+        if config.require_schema_at_startup {
+            error!("Refusing to start without an initialized schema; unset REQUIRE_SCHEMA_AT_STARTUP to start anyway");
+            std::process::exit(1);
+        }
+    }
+    // A pooler that loses prepared statements would otherwise fail logins and registrations at random
+    if config.db_pooler_compat {
+        match db::check_prepared_statements(&pool).await {
+            Ok(()) => info!("Prepared statements work through the connection pooler"),
+            Err(e) => {
+                error!("Prepared statements fail through the connection pooler: {}", e);
+                error!("Refusing to start; DB_POOLER_COMPAT needs PgBouncer 1.21 or later with max_prepared_statements above 0");
+                std::process::exit(1);
+            }
+        }
+    }
+    let meter_provider = telemetry::init_meter_provider(&config);
 
     // Build our application state
+    let metrics = Arc::new(Metrics::new(Duration::from_millis(config.slow_query_threshold_ms)));
     let app_state = AppState {
-        pool,
+        pool: pool.clone(),
         config: config.clone(),
+        runtime: Arc::new(ArcSwap::from_pointee(runtime)),
+        log_reloader,
+        login_flights: Arc::new(SingleFlight::new()),
+        breach_checker: passwords::breach_checker_from_config(&config),
+        rate_limiter: rate_limit::rate_limit_store_from_config(&config),
+        tarpit: Arc::new(Tarpit::new(config.tarpit_max_concurrent)),
+        recent_rotations: Arc::new(RecentRotations::new()),
+        email_policy: Arc::new(EmailDomainPolicy::from_config(&config)),
+        events: Arc::new(EventEmitter::from_config(&config, metrics.clone())),
+        flags: Arc::new(FeatureFlags::new(pool.clone(), &config)),
+        geo: geo::resolver_from_config(&config),
+        mailer: mailer::mailer_from_config(&config),
+        metrics,
+        trusted_issuers: Arc::new(TrustedIssuers::from_config(&config)),
+        clock: clock::clock_from_config(&config),
+        users: Arc::new(PgUserRepository::new(pool.clone())),
     };
+    if meter_provider.is_some() {
+        let meter = opentelemetry::global::meter("craftista-authentication");
+        app_state.metrics.register_instruments(&meter, pool.clone());
+    }
 
-    let protected_routes = Router::new()
-        .route("/register", post(handlers::register::register))
-        .layer(axum_middleware::from_fn_with_state(app_state.clone(), middleware::auth));
+    // Accounts created before skeletons were stored can't be matched until theirs are filled in
+    if config.block_confusable_usernames {
+        let users = app_state.users.clone();
+        tokio::spawn(async move {
+            match users.backfill_username_skeletons().await {
+                Ok(0) => {}
+                Ok(filled) => info!("Stored username skeletons for {} existing users", filled),
+                Err(e) => error!("Failed to backfill username skeletons: {}", e),
+            }
+        });
+    }
+
+    if config.selftest_interval_seconds > 0 {
+        if config.selftest_username.is_some() && config.selftest_password.is_some() {
+            handlers::selftest::spawn_periodic(app_state.clone(), Duration::from_secs(config.selftest_interval_seconds));
+        } else {
+            warn!("SELFTEST_INTERVAL_SECONDS is ignored without SELFTEST_USERNAME and SELFTEST_PASSWORD");
+        }
+    }
+
+    // Start from the stored flags rather than the defaults, then follow changes made elsewhere
+    if let Err(e) = app_state.flags.refresh().await {
+        warn!("Failed to load feature flags, using defaults until the next refresh: {}", e);
+    }
+    app_state
+        .flags
+        .spawn_refresh(Duration::from_secs(config.feature_flags_refresh_seconds.max(1)));
+    if !app_state.flags.is_enabled(flags::REGISTRATION_ENABLED) {
+        info!("Registration is disabled; POST /api/auth/register will answer 404");
+    }
+    if app_state.flags.is_enabled(flags::MAINTENANCE_MODE) {
+        warn!("Maintenance mode is on; state-changing requests will be refused");
+    }
+
+    // Cleanup once the server has drained, in reverse order: queued events are flushed while the
+    // pool is still open, and telemetry shuts down last so the other hooks' logs are exported
+    let mut shutdown_hooks = ShutdownHooks::new(Duration::from_secs(config.shutdown_hook_timeout_seconds));
+    shutdown_hooks.register("tracer_provider", move || async move {
+        tracer_provider.shutdown().map_err(|e| e.to_string())
+    });
+    if let Some(meter_provider) = meter_provider {
+        shutdown_hooks.register("meter_provider", move || async move {
+            meter_provider.shutdown().map_err(|e| e.to_string())
+        });
+    }
+    let shutdown_pool = pool.clone();
+    shutdown_hooks.register("database_pool", move || async move {
+        shutdown_pool.close().await;
+        Ok(())
+    });
+    let shutdown_events = app_state.events.clone();
+    shutdown_hooks.register("user_events", move || async move {
+        shutdown_events.flush().await;
+        Ok(())
+    });
 
     // Build our application with routes
-    let app = Router::new()
-        .route("/api/auth/login", post(handlers::login::login))
-        .route("/api/auth/status", get(handlers::status::auth_status))
-        .route("/.well-known/jwks.json", get(handlers::openid::jwks))
-        .route("/.well-known/openid-configuration", get(handlers::openid::openid_configuration))
-        .nest("/api/auth", protected_routes)
+    let health_routes = Router::new()
+        .route("/health/detailed", get(handlers::health::detailed))
+        .layer(axum_middleware::from_fn_with_state(app_state.clone(), middleware::auth));
+    let mut app = auth_routes(&app_state)
+        .route("/metrics", get(handlers::metrics::metrics))
+        .merge(health_routes);
+    // Each realm gets the same routes under its prefix, bound to its own issuer and keys
+    for realm in &config.realms {
+        let realm_state = AppState {
+            config: config.for_realm(realm),
+            ..app_state.clone()
+        };
+        info!("Serving realm {} (issuer {}) under {}", realm.name, realm.issuer, realm.path_prefix());
+        app = app.nest(&realm.path_prefix(), auth_routes(&realm_state).with_state(realm_state));
+    }
+    app = app
+        .method_not_allowed_fallback(errors::method_not_allowed)
+        .fallback(errors::not_found)
+        .route_layer(axum_middleware::from_fn_with_state(app_state.clone(), metrics::track_route))
+        .route_layer(axum_middleware::from_fn(issuance_warnings::collect))
+        .layer(axum_middleware::from_fn_with_state(app_state.clone(), middleware::maintenance));
+    if config.max_concurrent_requests > 0 {
+        let queue_wait = Duration::from_millis(config.queue_wait_timeout_ms);
+        let limit = middleware::ConcurrencyLimit::new(config.max_concurrent_requests, queue_wait);
+        app = app.layer(axum_middleware::from_fn_with_state(Arc::new(limit), middleware::limit_concurrency));
+    }
+    app = app.layer(axum_middleware::from_fn_with_state(app_state.clone(), middleware::error_format));
+    if config.debug_capture_enabled() {
+        warn!("Debug body capture is enabled; redacted request/response bodies will be logged");
+        app = app.layer(axum_middleware::from_fn(debug_capture::capture));
+    } else if config.debug_capture {
+        warn!("DEBUG_CAPTURE is ignored in the production environment");
+    }
+    if config.access_log_format != AccessLogFormat::None {
+        app = app.layer(axum_middleware::from_fn_with_state(app_state.clone(), access_log::log));
+    }
+    if config.test_mode_enabled() {
+        warn!("Test mode is enabled; tokens are signed with the embedded, publicly known test keys");
+    } else if config.test_mode {
+        warn!("TEST_MODE is ignored in the production environment");
+    }
+    let app = app
         .layer(CorsLayer::permissive())
         .layer(TraceLayer::new_for_http())
         .with_state(app_state);
@@ -60,20 +310,17 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     info!("Authentication service starting on {}", addr);
 
-    // Shutdown tracer provider
-    let tracer_provider_for_shutdown = tracer_provider.clone();
-    let shutdown_signal = async move {
+    let shutdown_signal = async {
         tokio::signal::ctrl_c()
             .await
             .expect("failed to install CTRL+C signal handler");
-        if let Err(e) = tracer_provider_for_shutdown.shutdown() {
-            error!("Failed to shutdown tracer provider: {}", e);
-        }
+        info!("Shutting down; waiting for in-flight requests");
     };
 
-    // Run the server with graceful shutdown
-    axum::serve(listener, app)
+    // Run the server with graceful shutdown, then clean up
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal)
         .await
         .unwrap();
+    shutdown_hooks.run().await;
 }
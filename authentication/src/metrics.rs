@@ -0,0 +1,353 @@
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{
+    metrics::{Counter, Histogram, Meter},
+    KeyValue,
+};
+use sqlx::PgPool;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    future::Future,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::state::AppState;
+
+#[derive(Default)]
+struct RouteDbStats {
+    queries: u64,
+    slow_queries: u64,
+    db_time: Duration,
+}
+
+// Upper bounds, in seconds, of the selftest latency histogram buckets
+const SELFTEST_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// Latency distribution of one selftest step, with cumulative bucket counts as Prometheus expects
+#[derive(Default)]
+struct StepHistogram {
+    buckets: [u64; SELFTEST_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+// OTLP instruments for the selftest, pushed as they happen rather than observed at export
+struct SelftestInstruments {
+    steps: Histogram<f64>,
+    failures: Counter<u64>,
+}
+
+// Per-route database usage, keyed by route template so label cardinality stays bounded
+pub struct Metrics {
+    slow_query_threshold: Duration,
+    routes: Mutex<BTreeMap<Arc<str>, RouteDbStats>>,
+    // User events dropped because the delivery queue was full, by event type
+    events_dropped: Mutex<BTreeMap<&'static str, u64>>,
+    // Synthetic-login timings and failures, by step
+    selftest_steps: Mutex<BTreeMap<&'static str, StepHistogram>>,
+    selftest_failures: Mutex<BTreeMap<&'static str, u64>>,
+    selftest_instruments: OnceLock<SelftestInstruments>,
+}
+
+// Route and registry that queries run by the current request are attributed to
+#[derive(Clone)]
+struct QueryScope {
+    route: Arc<str>,
+    metrics: Arc<Metrics>,
+}
+
+tokio::task_local! {
+    static QUERY_SCOPE: QueryScope;
+}
+
+impl Metrics {
+    pub fn new(slow_query_threshold: Duration) -> Self {
+        Self {
+            slow_query_threshold,
+            routes: Mutex::new(BTreeMap::new()),
+            events_dropped: Mutex::new(BTreeMap::new()),
+            selftest_steps: Mutex::new(BTreeMap::new()),
+            selftest_failures: Mutex::new(BTreeMap::new()),
+            selftest_instruments: OnceLock::new(),
+        }
+    }
+
+    fn record(&self, route: &Arc<str>, query: &str, elapsed: Duration) {
+        let slow = !self.slow_query_threshold.is_zero() && elapsed >= self.slow_query_threshold;
+        if slow {
+            warn!("Slow query {} on {} took {}ms", query, route, elapsed.as_millis());
+        }
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry(route.clone()).or_default();
+        stats.queries += 1;
+        stats.db_time += elapsed;
+        if slow {
+            stats.slow_queries += 1;
+        }
+    }
+
+    pub fn record_event_dropped(&self, kind: &'static str) {
+        *self.events_dropped.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn record_selftest_step(&self, step: &'static str, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        let mut steps = self.selftest_steps.lock().unwrap();
+        let histogram = steps.entry(step).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(SELFTEST_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+        if let Some(instruments) = self.selftest_instruments.get() {
+            instruments.steps.record(seconds, &[KeyValue::new("step", step)]);
+        }
+    }
+
+    pub fn record_selftest_failure(&self, step: &'static str) {
+        *self.selftest_failures.lock().unwrap().entry(step).or_default() += 1;
+        if let Some(instruments) = self.selftest_instruments.get() {
+            instruments.failures.add(1, &[KeyValue::new("step", step)]);
+        }
+    }
+
+    // Prometheus text exposition of the per-route counters and connection pool gauges
+    pub fn render(&self, pool: &PgPool) -> String {
+        let routes = self.routes.lock().unwrap();
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: &dyn Fn(&RouteDbStats) -> String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (route, stats) in routes.iter() {
+                let _ = writeln!(out, "{}{{route=\"{}\"}} {}", name, escape_label(route), value(stats));
+            }
+        };
+        counter("auth_db_queries_total", "Database queries executed, by route.", &|s| s.queries.to_string());
+        counter(
+            "auth_db_query_seconds_total",
+            "Cumulative time spent in database queries, by route.",
+            &|s| s.db_time.as_secs_f64().to_string(),
+        );
+        counter(
+            "auth_db_slow_queries_total",
+            "Database queries exceeding the slow-query threshold, by route.",
+            &|s| s.slow_queries.to_string(),
+        );
+
+        drop(routes);
+        let _ = writeln!(out, "# HELP auth_events_dropped_total User events dropped because the delivery queue was full, by type.");
+        let _ = writeln!(out, "# TYPE auth_events_dropped_total counter");
+        for (kind, dropped) in self.events_dropped.lock().unwrap().iter() {
+            let _ = writeln!(out, "auth_events_dropped_total{{type=\"{}\"}} {}", kind, dropped);
+        }
+
+        let _ = writeln!(out, "# HELP auth_selftest_step_seconds Synthetic-login latency, by step.");
+        let _ = writeln!(out, "# TYPE auth_selftest_step_seconds histogram");
+        for (step, histogram) in self.selftest_steps.lock().unwrap().iter() {
+            for (bound, count) in SELFTEST_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(out, "auth_selftest_step_seconds_bucket{{step=\"{}\",le=\"{}\"}} {}", step, bound, count);
+            }
+            let _ = writeln!(out, "auth_selftest_step_seconds_bucket{{step=\"{}\",le=\"+Inf\"}} {}", step, histogram.count);
+            let _ = writeln!(out, "auth_selftest_step_seconds_sum{{step=\"{}\"}} {}", step, histogram.sum);
+            let _ = writeln!(out, "auth_selftest_step_seconds_count{{step=\"{}\"}} {}", step, histogram.count);
+        }
+        let _ = writeln!(out, "# HELP auth_selftest_failures_total Synthetic logins that failed, by the step that failed.");
+        let _ = writeln!(out, "# TYPE auth_selftest_failures_total counter");
+        for (step, failures) in self.selftest_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "auth_selftest_failures_total{{step=\"{}\"}} {}", step, failures);
+        }
+
+        let gauges = [
+            ("auth_db_pool_connections", "Open connections in the pool.", pool.size() as usize),
+            ("auth_db_pool_idle_connections", "Idle connections in the pool.", pool.num_idle()),
+            (
+                "auth_db_pool_max_connections",
+                "Maximum connections the pool will open.",
+                pool.options().get_max_connections() as usize,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+impl Metrics {
+    // Report the same counters and gauges as `render` through OTLP. Instruments are observed
+    // from this registry at each export, so both exports always agree.
+    pub fn register_instruments(self: &Arc<Self>, meter: &Meter, pool: PgPool) {
+        let counter = |name: &'static str, help: &'static str, value: fn(&RouteDbStats) -> u64| {
+            let metrics = self.clone();
+            meter
+                .u64_observable_counter(name)
+                .with_description(help)
+                .with_callback(move |observer| {
+                    for (route, stats) in metrics.routes.lock().unwrap().iter() {
+                        observer.observe(value(stats), &[KeyValue::new("route", route.to_string())]);
+                    }
+                })
+                .build();
+        };
+        counter("auth_db_queries_total", "Database queries executed, by route.", |s| s.queries);
+        counter(
+            "auth_db_slow_queries_total",
+            "Database queries exceeding the slow-query threshold, by route.",
+            |s| s.slow_queries,
+        );
+        let metrics = self.clone();
+        meter
+            .f64_observable_counter("auth_db_query_seconds_total")
+            .with_description("Cumulative time spent in database queries, by route.")
+            .with_unit("s")
+            .with_callback(move |observer| {
+                for (route, stats) in metrics.routes.lock().unwrap().iter() {
+                    observer.observe(stats.db_time.as_secs_f64(), &[KeyValue::new("route", route.to_string())]);
+                }
+            })
+            .build();
+
+        let metrics = self.clone();
+        meter
+            .u64_observable_counter("auth_events_dropped_total")
+            .with_description("User events dropped because the delivery queue was full, by type.")
+            .with_callback(move |observer| {
+                for (kind, dropped) in metrics.events_dropped.lock().unwrap().iter() {
+                    observer.observe(*dropped, &[KeyValue::new("type", *kind)]);
+                }
+            })
+            .build();
+
+        let _ = self.selftest_instruments.set(SelftestInstruments {
+            steps: meter
+                .f64_histogram("auth_selftest_step_seconds")
+                .with_description("Synthetic-login latency, by step.")
+                .with_unit("s")
+                .with_boundaries(SELFTEST_BUCKETS.to_vec())
+                .build(),
+            failures: meter
+                .u64_counter("auth_selftest_failures_total")
+                .with_description("Synthetic logins that failed, by the step that failed.")
+                .build(),
+        });
+
+        let gauge = |name: &'static str, help: &'static str, value: fn(&PgPool) -> u64| {
+            let pool = pool.clone();
+            meter
+                .u64_observable_gauge(name)
+                .with_description(help)
+                .with_callback(move |observer| observer.observe(value(&pool), &[]))
+                .build();
+        };
+        gauge("auth_db_pool_connections", "Open connections in the pool.", |p| p.size() as u64);
+        gauge("auth_db_pool_idle_connections", "Idle connections in the pool.", |p| p.num_idle() as u64);
+        gauge("auth_db_pool_max_connections", "Maximum connections the pool will open.", |p| {
+            p.options().get_max_connections() as u64
+        });
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Prometheus gauges for each tenant's overall rate limit and the requests it has left
+pub fn render_tenant_quotas(quotas: &[(String, u32, f64)]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP auth_rate_limit_tenant_per_minute Requests per minute allowed to each tenant.");
+    let _ = writeln!(out, "# TYPE auth_rate_limit_tenant_per_minute gauge");
+    for (tenant, per_minute, _) in quotas {
+        let _ = writeln!(out, "auth_rate_limit_tenant_per_minute{{tenant=\"{}\"}} {}", escape_label(tenant), per_minute);
+    }
+    let _ = writeln!(out, "# HELP auth_rate_limit_tenant_remaining Requests each tenant could make right now.");
+    let _ = writeln!(out, "# TYPE auth_rate_limit_tenant_remaining gauge");
+    for (tenant, _, remaining) in quotas {
+        let _ = writeln!(out, "auth_rate_limit_tenant_remaining{{tenant=\"{}\"}} {}", escape_label(tenant), remaining.floor());
+    }
+    out
+}
+
+// Time a query, attributing it to the current request's route and logging it when slow.
+// Queries outside a request (startup, untracked background work) are not recorded.
+pub async fn observe<F: Future>(query: &str, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    let elapsed = started.elapsed();
+    let _ = QUERY_SCOPE.try_with(|scope| scope.metrics.record(&scope.route, query, elapsed));
+    output
+}
+
+// Carry the current request's query scope into a task spawned from it
+pub fn in_current_scope<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let scope = QUERY_SCOPE.try_with(QueryScope::clone).ok();
+    async move {
+        match scope {
+            Some(scope) => QUERY_SCOPE.scope(scope, future).await,
+            None => future.await,
+        }
+    }
+}
+
+// Attribute queries made while handling a request to its matched route template
+pub async fn track_route(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(route) = request.extensions().get::<MatchedPath>().map(|path| Arc::from(path.as_str())) else {
+        return next.run(request).await;
+    };
+    let scope = QueryScope {
+        route,
+        metrics: state.metrics.clone(),
+    };
+    QUERY_SCOPE.scope(scope, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn attributes_queries_to_the_scoped_route() {
+        let metrics = Arc::new(Metrics::new(Duration::from_secs(60)));
+        let scope = QueryScope {
+            route: Arc::from("/api/auth/login"),
+            metrics: metrics.clone(),
+        };
+        QUERY_SCOPE
+            .scope(scope, async {
+                observe("first", async {}).await;
+                tokio::spawn(in_current_scope(observe("spawned", async {}))).await.unwrap();
+            })
+            .await;
+        observe("unscoped", async {}).await;
+
+        let pool = PgPool::connect_lazy("postgres://localhost/test").unwrap();
+        let rendered = metrics.render(&pool);
+        assert!(rendered.contains("auth_db_queries_total{route=\"/api/auth/login\"} 2"));
+        assert!(rendered.contains("auth_db_slow_queries_total{route=\"/api/auth/login\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn renders_selftest_steps_as_cumulative_histograms() {
+        let metrics = Metrics::new(Duration::from_secs(60));
+        metrics.record_selftest_step("hash", Duration::from_millis(40));
+        metrics.record_selftest_step("hash", Duration::from_millis(300));
+        metrics.record_selftest_failure("db");
+
+        let rendered = metrics.render(&PgPool::connect_lazy("postgres://localhost/test").unwrap());
+        assert!(rendered.contains("auth_selftest_step_seconds_bucket{step=\"hash\",le=\"0.025\"} 0"));
+        assert!(rendered.contains("auth_selftest_step_seconds_bucket{step=\"hash\",le=\"0.05\"} 1"));
+        assert!(rendered.contains("auth_selftest_step_seconds_bucket{step=\"hash\",le=\"0.5\"} 2"));
+        assert!(rendered.contains("auth_selftest_step_seconds_bucket{step=\"hash\",le=\"+Inf\"} 2"));
+        assert!(rendered.contains("auth_selftest_step_seconds_count{step=\"hash\"} 2"));
+        assert!(rendered.contains("auth_selftest_failures_total{step=\"db\"} 1"));
+    }
+}
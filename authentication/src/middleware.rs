@@ -1,15 +1,48 @@
 use axum::{
     body::Body,
-    extract::State,
-    http::Request,
+    extract::{OriginalUri, State},
+    http::{header, HeaderValue, Method, Request},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Json,
 };
 
-use crate::{errors::AppError, state::AppState};
+use chrono::{DateTime, Duration, Utc};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::{
+    client_ip::ClientIp,
+    config::{Config, ErrorFormat},
+    error_messages,
+    errors::{AppError, ErrorDetails},
+    flags,
+    state::AppState,
+};
+
+// Which configured internal key a request presented
+#[derive(Debug, PartialEq, Eq)]
+enum InternalKey {
+    Current,
+    // The pre-rotation key, accepted until the grace window closes at the given time
+    Previous(DateTime<Utc>),
+}
+
+// Match a presented key against the current key, then the previous one while still in grace
+fn match_internal_key(config: &Config, presented: &str, now: DateTime<Utc>) -> Option<InternalKey> {
+    if presented == config.internal_api_key {
+        return Some(InternalKey::Current);
+    }
+    let previous = config.previous_internal_api_key.as_deref()?;
+    let expires_at = config.internal_key_rotated_at? + Duration::seconds(config.internal_key_rotation_grace_seconds);
+    (presented == previous && now < expires_at).then_some(InternalKey::Previous(expires_at))
+}
 
 pub async fn auth(
     State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    OriginalUri(uri): OriginalUri,
     req: Request<Body>,
     next: Next,
 ) -> Result<Response, AppError> {
@@ -18,8 +51,223 @@ pub async fn auth(
         .get("X-Internal-API-Key")
         .and_then(|value| value.to_str().ok());
 
-    match api_key {
-        Some(key) if key == state.config.internal_api_key => Ok(next.run(req).await),
-        _ => Err(AppError::Unauthorized),
+    match api_key.and_then(|key| match_internal_key(&state.config, key, state.clock.now())) {
+        Some(InternalKey::Current) => Ok(next.run(req).await),
+        Some(InternalKey::Previous(expires_at)) => {
+            // Name the caller so operators can chase it before the old key stops working
+            warn!(
+                "{} {} from {} authenticated with the previous internal API key, accepted until {}",
+                req.method(),
+                uri.path(),
+                client_ip,
+                expires_at.to_rfc3339()
+            );
+            Ok(next.run(req).await)
+        }
+        None => Err(AppError::InvalidApiKey),
+    }
+}
+
+// Requests still served in maintenance mode: reads, the admin API (so operators can switch it off
+// again) and the token checks other services depend on
+fn allowed_in_maintenance(method: &Method, path: &str) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+        || path.contains("/api/auth/admin/")
+        || path.ends_with("/api/auth/introspect")
+        || path.ends_with("/api/auth/validate")
+}
+
+// Refuse everything else with a 503 while the `maintenance_mode` flag is on
+pub async fn maintenance(State(state): State<AppState>, req: Request<Body>, next: Next) -> Result<Response, AppError> {
+    if state.flags.is_enabled(flags::MAINTENANCE_MODE) && !allowed_in_maintenance(req.method(), req.uri().path()) {
+        return Err(AppError::Maintenance);
+    }
+    Ok(next.run(req).await)
+}
+
+// Requests that are never shed, so probes still answer while the service is saturated
+fn exempt_from_concurrency_limit(path: &str) -> bool {
+    path.starts_with("/health")
+}
+
+// Request slots for MAX_CONCURRENT_REQUESTS, and how long a request may wait for one
+pub struct ConcurrencyLimit {
+    slots: Semaphore,
+    queue_wait: std::time::Duration,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_concurrent: usize, queue_wait: std::time::Duration) -> Self {
+        Self {
+            slots: Semaphore::new(max_concurrent),
+            queue_wait,
+        }
+    }
+}
+
+// Shed requests beyond MAX_CONCURRENT_REQUESTS with a 503, so a spike fails fast for the excess
+// rather than slowing every request down. With QUEUE_WAIT_TIMEOUT_MS a request may first wait that
+// long for a slot, and is refused as `queue_timeout` rather than run late if none frees up.
+pub async fn limit_concurrency(
+    State(limit): State<Arc<ConcurrencyLimit>>,
+    req: Request<Body>,
+    next: Next,
+) -> Result<Response, AppError> {
+    if exempt_from_concurrency_limit(req.uri().path()) {
+        return Ok(next.run(req).await);
+    }
+    let _slot = match limit.slots.try_acquire() {
+        Ok(slot) => slot,
+        Err(_) if limit.queue_wait.is_zero() => return Err(AppError::Overloaded),
+        Err(_) => match tokio::time::timeout(limit.queue_wait, limit.slots.acquire()).await {
+            Ok(Ok(slot)) => slot,
+            Ok(Err(_)) => return Err(AppError::Overloaded),
+            Err(_) => return Err(AppError::QueueTimeout),
+        },
+    };
+    Ok(next.run(req).await)
+}
+
+// Re-render error responses as RFC 7807 problem+json when configured or requested via Accept, and
+// in the caller's language when `Accept-Language` asks for one of ERROR_LOCALES. Only the message
+// is translated; `code` stays the same in every language.
+pub async fn error_format(
+    State(state): State<AppState>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let accepts_problem = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/problem+json"));
+    let wants_problem = accepts_problem || state.runtime.load().error_format == ErrorFormat::Problem;
+    let locale = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|accept| error_messages::negotiate(accept, &state.config.error_locales));
+    let instance = req.uri().path().to_string();
+
+    let response = next.run(req).await;
+    if !wants_problem && locale.is_none() {
+        return response;
+    }
+    let Some(details) = response.extensions().get::<ErrorDetails>().cloned() else {
+        return response;
+    };
+    let localized = locale.and_then(|locale| Some((locale, error_messages::localize(locale, &details)?)));
+    if !wants_problem && localized.is_none() {
+        return response;
+    }
+
+    let (mut parts, _) = response.into_parts();
+    let status = parts.status;
+    let message = localized.as_ref().map_or(details.message, |(_, message)| message.clone());
+    let mut body = if wants_problem {
+        serde_json::json!({
+            "type": "about:blank",
+            "title": status.canonical_reason().unwrap_or("Error"),
+            "status": status.as_u16(),
+            "detail": message,
+            "instance": instance,
+            "code": details.code,
+        })
+    } else {
+        serde_json::json!({ "error": message, "code": details.code })
+    };
+    // RFC 9457 extension members
+    body.as_object_mut().expect("object").extend(details.fields);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let mut rendered = (parts, Json(body)).into_response();
+    if wants_problem {
+        rendered.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+    }
+    if let Some((locale, _)) = localized {
+        rendered.headers_mut().insert(header::CONTENT_LANGUAGE, HeaderValue::from_static(locale));
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maintenance_mode_keeps_reads_admin_and_token_checks() {
+        assert!(allowed_in_maintenance(&Method::GET, "/api/auth/me"));
+        assert!(allowed_in_maintenance(&Method::POST, "/api/auth/admin/flags/maintenance_mode"));
+        assert!(allowed_in_maintenance(&Method::POST, "/realms/acme/api/auth/introspect"));
+        assert!(allowed_in_maintenance(&Method::POST, "/api/auth/validate"));
+
+        assert!(!allowed_in_maintenance(&Method::POST, "/api/auth/login"));
+        assert!(!allowed_in_maintenance(&Method::POST, "/api/auth/register"));
+        assert!(!allowed_in_maintenance(&Method::POST, "/realms/acme/api/auth/refresh"));
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_concurrency_limit_are_shed() {
+        use axum::{http::StatusCode, middleware::from_fn_with_state, routing::get, Router};
+        use tokio::sync::Notify;
+        use tower::ServiceExt;
+
+        let release = Arc::new(Notify::new());
+        let held = release.clone();
+        let app = Router::new()
+            .route("/slow", get(move || async move { held.notified().await }))
+            .route("/health/detailed", get(|| async {}))
+            .layer(from_fn_with_state(Arc::new(ConcurrencyLimit::new(1, std::time::Duration::ZERO)), limit_concurrency));
+        let request = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        let in_flight = tokio::spawn(app.clone().oneshot(request("/slow")));
+        tokio::task::yield_now().await;
+
+        let shed = app.clone().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shed.headers()[header::RETRY_AFTER], "1");
+        let health = app.clone().oneshot(request("/health/detailed")).await.unwrap();
+        assert_eq!(health.status(), StatusCode::OK);
+
+        release.notify_one();
+        assert_eq!(in_flight.await.unwrap().unwrap().status(), StatusCode::OK);
+
+        // The slot is free again once the first request completes
+        release.notify_one();
+        assert_eq!(app.oneshot(request("/slow")).await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_give_up_after_the_queue_wait_timeout() {
+        use axum::{http::StatusCode, middleware::from_fn_with_state, routing::get, Router};
+        use tokio::sync::Notify;
+        use tower::ServiceExt;
+
+        let release = Arc::new(Notify::new());
+        let held = release.clone();
+        let limit = Arc::new(ConcurrencyLimit::new(1, std::time::Duration::from_millis(50)));
+        let app = Router::new()
+            .route("/slow", get(move || async move { held.notified().await }))
+            .layer(from_fn_with_state(limit, limit_concurrency));
+        let request = || Request::get("/slow").body(Body::empty()).unwrap();
+
+        // The limiter is saturated for longer than the queue wait
+        let in_flight = tokio::spawn(app.clone().oneshot(request()));
+        tokio::task::yield_now().await;
+        let timed_out = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(timed_out.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(timed_out.headers()[header::RETRY_AFTER], "1");
+        let body = axum::body::to_bytes(timed_out.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["code"], "queue_timeout");
+
+        // A request queued while the slot frees up within the wait runs
+        let queued = tokio::spawn(app.clone().oneshot(request()));
+        tokio::task::yield_now().await;
+        release.notify_one();
+        assert_eq!(in_flight.await.unwrap().unwrap().status(), StatusCode::OK);
+        release.notify_one();
+        assert_eq!(queued.await.unwrap().unwrap().status(), StatusCode::OK);
     }
 }
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -6,19 +7,127 @@ pub struct Claims {
     pub role: String,
     pub exp: usize,
     pub iat: usize,
+    // Not-before time; we never set it, but tokens carrying one are checked against our clock
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+    // Set when JWT_ISSUER is configured, and always for realm tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    // A single string or an array, as AUDIENCE_CLAIM_FORMAT issues it; tokens in either form verify
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acr: Option<String>,
+    // When the user last presented credentials (OIDC `auth_time`); carried across refreshes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_time: Option<usize>,
+    // How the user authenticated (RFC 8176 `amr`), when the login records it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub amr: Option<Vec<String>>,
+    // Set on impersonation tokens: the admin acting as `sub` (RFC 8693 actor claim)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    // Role-derived permissions, included when INCLUDE_PERMISSIONS is set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<String>>,
+    // Set on DPoP-bound tokens: the key whose proofs must accompany them (RFC 9449 section 6)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+    // Whether the user's email address is verified, unless UNVERIFIED_EMAIL_POLICY is `allow`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    // Token ID, set on tokens issued alongside a session handle so revoking it can reach them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+// The `aud` claim, which RFC 7519 section 4.1.3 allows as one string or an array of them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    pub fn values(&self) -> &[String] {
+        match self {
+            Audience::Single(audience) => std::slice::from_ref(audience),
+            Audience::Multiple(audiences) => audiences,
+        }
+    }
+
+    // Whether the token is addressed to this audience, among any others
+    pub fn contains(&self, audience: &str) -> bool {
+        self.values().iter().any(|value| value == audience)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Actor {
+    pub sub: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Confirmation {
+    // RFC 7638 thumbprint of the client's public key
+    pub jkt: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TokenResponse {
     pub access_token: String,
     pub token_type: String,
     pub expires_in: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    // Set on token exchange responses (RFC 8693)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued_token_type: Option<String>,
+    // Mirrors the access token's `email_verified` claim
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    // Opaque, server-side handle for the same login when SESSION_HANDLES_ENABLED, revocable at
+    // /api/auth/revoke and accepted by introspection; it expires with the access token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_handle: Option<String>,
+    // Non-fatal conditions the token was issued under, for clients asking with `?include=warnings`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    // Client-hash scheme the password was submitted under, when ACCEPT_CLIENT_HASH allows it
+    #[serde(default)]
+    pub password_scheme: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    // Space-delimited subset of the user's scopes to restrict the token to
+    #[serde(default)]
+    pub scope: Option<String>,
+    // Space-delimited authentication context classes, in order of preference
+    #[serde(default)]
+    pub acr_values: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailCodeRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailCodeVerifyRequest {
+    pub email: String,
+    pub code: String,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -26,6 +135,36 @@ pub struct RegisterRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    // Client-hash scheme the password was submitted under, when ACCEPT_CLIENT_HASH allows it
+    #[serde(default)]
+    pub password_scheme: Option<String>,
+    // Code emailed to `email` by the register email-code endpoint, for VERIFY_EMAIL_BEFORE_REGISTER
+    #[serde(default)]
+    pub email_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    // Client-hash scheme both passwords were submitted under, when ACCEPT_CLIENT_HASH allows it
+    #[serde(default)]
+    pub password_scheme: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetFlagRequest {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportUsersRequest {
+    pub users: Vec<RegisterRequest>,
 }
 
 #[derive(Debug, Serialize)]
@@ -35,6 +174,65 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
     pub role: String,
+    // When the password was last changed after registration, if ever
+    pub password_changed_at: Option<DateTime<Utc>>,
+    // When the user proved they receive mail at `email`, if ever
+    pub email_verified_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserExportQuery {
+    // Only users created or changed at or after this RFC 3339 time
+    pub since: Option<DateTime<Utc>>,
+}
+
+// A user as listed to administrators; never includes the password hash
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MeResponse {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_login_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordPolicyQuery {
+    // Account role whose policy to describe, instead of the global one
+    pub role: Option<String>,
+}
+
+// The password requirements the server enforces, for clients to validate against
+#[derive(Debug, Serialize)]
+pub struct PasswordPolicyResponse {
+    // The role whose own policy this is; absent when the global policy applies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    pub min_length: usize,
+    // How many of `character_classes` a password must mix
+    pub min_character_classes: usize,
+    pub character_classes: [&'static str; 4],
+    pub min_strength_bits: u32,
+    pub min_entropy_bits: u32,
+    // Whether passwords below `min_entropy_bits` are refused rather than only logged
+    pub entropy_enforced: bool,
+    // Whether passwords found in a breach or common-password list are refused
+    pub breach_check: bool,
+    // Hours before a password may be changed again by its owner
+    pub min_password_age_hours: i64,
 }
 
 #[derive(Serialize)]
@@ -42,6 +240,12 @@ pub struct JwksResponse {
     pub keys: Vec<JwkKey>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct JwksQuery {
+    // Only the keys that sign tokens for this audience
+    pub aud: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct JwkKey {
     pub kty: String,
@@ -49,8 +253,21 @@ pub struct JwkKey {
     pub key_use: String,
     pub kid: String,
     pub alg: String,
-    pub n: String,
-    pub e: String,
+    // RSA modulus and exponent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    // EC curve and point coordinates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+    // Thumbprint of the key's X.509 certificate, when one is configured (RFC 7517 section 4.9)
+    #[serde(rename = "x5t#S256", skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -63,4 +280,143 @@ pub struct OpenIdConfiguration {
     pub response_types_supported: Vec<String>,
     pub subject_types_supported: Vec<String>,
     pub id_token_signing_alg_values_supported: Vec<String>,
+    pub scopes_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+    pub acr_values_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    // Present when DPOP_ENABLED is set (RFC 9449 section 5.1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpop_signing_alg_values_supported: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginChallengeRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginChallengeResponse {
+    pub methods: Vec<String>,
+    pub acr_values_supported: Vec<String>,
+}
+
+// OAuth2 token endpoint request (client credentials with client_secret_post, or token exchange)
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    #[serde(default)]
+    pub client_id: Option<String>,
+    #[serde(default)]
+    pub client_secret: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+    // Token exchange (RFC 8693) parameters
+    #[serde(default)]
+    pub subject_token: Option<String>,
+    #[serde(default)]
+    pub subject_token_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ValidateRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntrospectionRequest {
+    // Optional only so a forwarded access token cookie can stand in for it
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+// Token revocation request (RFC 7009 section 2.1); `token_type_hint` is accepted but not needed
+#[derive(Debug, Deserialize)]
+pub struct RevocationRequest {
+    pub token: String,
+}
+
+// Introspection response fields as defined in RFC 7662 section 2.2
+#[derive(Debug, Default, Serialize)]
+pub struct IntrospectionResponse {
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aud: Option<Audience>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub act: Option<Actor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cnf: Option<Confirmation>,
+}
+
+// Health of one dependency; variants are ordered worst first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Down,
+    Degraded,
+    Up,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComponentHealth {
+    pub name: &'static str,
+    pub status: HealthStatus,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+// Every dependency's health, with the worst of them as the overall status
+#[derive(Debug, Serialize)]
+pub struct DetailedHealthResponse {
+    pub status: HealthStatus,
+    pub components: Vec<ComponentHealth>,
+}
+
+// Outcome of the synthetic login, with per-layer timings up to the step that failed
+#[derive(Debug, Default, Serialize)]
+pub struct SelftestResponse {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_step: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sign_ms: Option<f64>,
+    pub total_ms: f64,
+}
+
+impl IntrospectionResponse {
+    // Inactive tokens only report `{ "active": false }`
+    pub fn inactive() -> Self {
+        Self::default()
+    }
 }
@@ -0,0 +1,111 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Query},
+    http::request::Parts,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::AppError, state::AppState};
+
+// Raw query parameters, kept as strings so bad values get our 422 rather than a generic 400
+#[derive(Debug, Default, Deserialize)]
+struct PaginationParams {
+    limit: Option<String>,
+    offset: Option<String>,
+}
+
+// Page requested via `?limit=&offset=`, shared by every list endpoint. `limit` defaults to
+// PAGE_SIZE_DEFAULT and may not exceed PAGE_SIZE_MAX.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl Pagination {
+    fn parse(params: &PaginationParams, default_limit: u32, max_limit: u32) -> Result<Self, AppError> {
+        let limit = match params.limit.as_deref() {
+            None => default_limit.min(max_limit),
+            Some(value) => match value.trim().parse::<u32>() {
+                Ok(limit) if (1..=max_limit).contains(&limit) => limit,
+                _ => {
+                    return Err(AppError::Validation(format!(
+                        "limit must be an integer between 1 and {}",
+                        max_limit
+                    )))
+                }
+            },
+        };
+        let offset = match params.offset.as_deref() {
+            None => 0,
+            Some(value) => value
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| AppError::Validation("offset must be a non-negative integer".to_string()))?,
+        };
+        Ok(Self { limit, offset })
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Pagination {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let Query(params) = Query::<PaginationParams>::try_from_uri(&parts.uri)
+            .map_err(|e| AppError::Validation(format!("invalid pagination parameters: {}", e)))?;
+        Self::parse(&params, state.config.page_size_default, state.config.page_size_max)
+    }
+}
+
+// Envelope returned by list endpoints
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl<T> Page<T> {
+    pub fn new(items: Vec<T>, total: i64, pagination: Pagination) -> Self {
+        Self {
+            items,
+            total,
+            limit: pagination.limit,
+            offset: pagination.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(limit: Option<&str>, offset: Option<&str>) -> Result<Pagination, AppError> {
+        let params = PaginationParams {
+            limit: limit.map(str::to_string),
+            offset: offset.map(str::to_string),
+        };
+        Pagination::parse(&params, 20, 100)
+    }
+
+    #[test]
+    fn applies_defaults_and_bounds() {
+        assert_eq!(parse(None, None).unwrap(), Pagination { limit: 20, offset: 0 });
+        assert_eq!(parse(Some("100"), Some("40")).unwrap(), Pagination { limit: 100, offset: 40 });
+
+        for (limit, offset) in [
+            (Some("0"), None),
+            (Some("101"), None),
+            (Some("-5"), None),
+            (Some("1000000"), None),
+            (Some("ten"), None),
+            (None, Some("-1")),
+            (None, Some("99999999999")),
+        ] {
+            let error = parse(limit, offset).unwrap_err();
+            assert_eq!(error.status(), axum::http::StatusCode::UNPROCESSABLE_ENTITY, "{:?} {:?}", limit, offset);
+        }
+    }
+}
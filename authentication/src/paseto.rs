@@ -0,0 +1,162 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, TimeZone, Utc};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde_json::Value;
+
+use crate::models::Claims;
+
+const HEADER: &str = "v4.public.";
+const SIGNATURE_LEN: usize = 64;
+
+// Why a PASETO token could not be verified
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasetoError {
+    Expired,
+    Invalid,
+}
+
+// Pre-authentication encoding: every piece is length-prefixed so boundaries can't be forged
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    let le64 = |n: usize| ((n as u64) & (u64::MAX >> 1)).to_le_bytes();
+    let mut out = le64(pieces.len()).to_vec();
+    for piece in pieces {
+        out.extend_from_slice(&le64(piece.len()));
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+// PASETO registered claims carry times as ISO 8601 strings rather than JWT's epoch seconds
+fn to_paseto_time(seconds: usize) -> Value {
+    let time = Utc.timestamp_opt(seconds as i64, 0).single().unwrap_or_default();
+    Value::String(time.to_rfc3339())
+}
+
+fn from_paseto_time(value: &Value) -> Option<Value> {
+    let time = DateTime::parse_from_rfc3339(value.as_str()?).ok()?;
+    Some(Value::from(time.timestamp().max(0)))
+}
+
+// Load an Ed25519 PKCS#8 PEM key, as written by `openssl genpkey -algorithm ed25519`
+pub fn key_pair_from_pem(pem: &str) -> Result<Ed25519KeyPair, String> {
+    let body: String = pem.lines().filter(|line| !line.starts_with("-----")).collect();
+    let der = base64::engine::general_purpose::STANDARD
+        .decode(body.trim())
+        .map_err(|e| format!("invalid PEM: {}", e))?;
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(|e| format!("invalid Ed25519 key: {}", e))
+}
+
+// Public half of a key pair, for verification
+pub fn public_key(key_pair: &Ed25519KeyPair) -> UnparsedPublicKey<Vec<u8>> {
+    UnparsedPublicKey::new(&ED25519, key_pair.public_key().as_ref().to_vec())
+}
+
+// Sign claims as a v4.public token; the footer carries the key id in the clear
+pub fn sign(key_pair: &Ed25519KeyPair, claims: &Claims, kid: &str) -> Result<String, serde_json::Error> {
+    let mut payload = serde_json::to_value(claims)?;
+    payload["exp"] = to_paseto_time(claims.exp);
+    payload["iat"] = to_paseto_time(claims.iat);
+    let message = serde_json::to_vec(&payload)?;
+    let footer = serde_json::to_vec(&serde_json::json!({ "kid": kid }))?;
+
+    let signature = key_pair.sign(&pae(&[HEADER.as_bytes(), &message, &footer, b""]));
+    let mut body = message;
+    body.extend_from_slice(signature.as_ref());
+    Ok(format!("{}{}.{}", HEADER, URL_SAFE_NO_PAD.encode(body), URL_SAFE_NO_PAD.encode(footer)))
+}
+
+// Verify a v4.public token's signature and expiry and return its claims
+pub fn verify(public_key: &UnparsedPublicKey<Vec<u8>>, token: &str, now: DateTime<Utc>) -> Result<Claims, PasetoError> {
+    let rest = token.strip_prefix(HEADER).ok_or(PasetoError::Invalid)?;
+    let (body, footer) = match rest.split_once('.') {
+        Some((body, footer)) => (body, URL_SAFE_NO_PAD.decode(footer).map_err(|_| PasetoError::Invalid)?),
+        None => (rest, Vec::new()),
+    };
+    let body = URL_SAFE_NO_PAD.decode(body).map_err(|_| PasetoError::Invalid)?;
+    if body.len() < SIGNATURE_LEN {
+        return Err(PasetoError::Invalid);
+    }
+    let (message, signature) = body.split_at(body.len() - SIGNATURE_LEN);
+    public_key
+        .verify(&pae(&[HEADER.as_bytes(), message, &footer, b""]), signature)
+        .map_err(|_| PasetoError::Invalid)?;
+
+    let mut payload: Value = serde_json::from_slice(message).map_err(|_| PasetoError::Invalid)?;
+    for claim in ["exp", "iat"] {
+        payload[claim] = from_paseto_time(&payload[claim]).ok_or(PasetoError::Invalid)?;
+    }
+    let claims: Claims = serde_json::from_value(payload).map_err(|_| PasetoError::Invalid)?;
+    if claims.exp as i64 <= now.timestamp() {
+        return Err(PasetoError::Expired);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn claims(exp_offset: i64) -> Claims {
+        let now = Utc::now().timestamp();
+        Claims {
+            sub: "johndoe".to_string(),
+            role: "user".to_string(),
+            exp: (now + exp_offset) as usize,
+            iat: now as usize,
+            nbf: None,
+            iss: None,
+            aud: None,
+            scope: Some("openid".to_string()),
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            permissions: None,
+            cnf: None,
+            email_verified: None,
+            jti: None,
+        }
+    }
+
+    #[test]
+    fn pae_matches_spec_vectors() {
+        assert_eq!(pae(&[]), b"\x00\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(
+            pae(&[b"test"]),
+            b"\x01\x00\x00\x00\x00\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00test"
+        );
+    }
+
+    #[test]
+    fn round_trips_claims() {
+        let key_pair = key_pair();
+        let token = sign(&key_pair, &claims(3600), "key-1").unwrap();
+        assert!(token.starts_with("v4.public."));
+        let verified = verify(&public_key(&key_pair), &token, Utc::now()).unwrap();
+        assert_eq!(verified.sub, "johndoe");
+        assert_eq!(verified.scope.as_deref(), Some("openid"));
+    }
+
+    #[test]
+    fn rejects_tampered_footer_and_other_keys() {
+        let key_pair = key_pair();
+        let token = sign(&key_pair, &claims(3600), "key-1").unwrap();
+        let (body, _) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.{}", body, URL_SAFE_NO_PAD.encode(br#"{"kid":"key-2"}"#));
+        assert_eq!(verify(&public_key(&key_pair), &forged, Utc::now()).unwrap_err(), PasetoError::Invalid);
+        assert_eq!(verify(&public_key(&self::key_pair()), &token, Utc::now()).unwrap_err(), PasetoError::Invalid);
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let key_pair = key_pair();
+        let token = sign(&key_pair, &claims(-60), "key-1").unwrap();
+        assert_eq!(verify(&public_key(&key_pair), &token, Utc::now()).unwrap_err(), PasetoError::Expired);
+    }
+}
@@ -0,0 +1,495 @@
+use async_trait::async_trait;
+use bcrypt::{hash_with_result, Version, DEFAULT_COST};
+use chrono::{DateTime, Utc};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::{collections::{HashMap, HashSet}, fmt::Display, sync::Arc, time::Duration};
+use tracing::warn;
+
+use crate::{
+    config::{BreachCheckerKind, ClientHashScheme, Config, PasswordPolicy},
+    errors::AppError,
+};
+
+// Embedded list of common passwords, one per line
+const COMMON_PASSWORDS: &str = include_str!("data/common_passwords.txt");
+
+// Why a bcrypt operation on the blocking pool produced no usable result
+#[derive(Debug, Clone)]
+pub enum HashTaskError {
+    // Not finished within HASH_TIMEOUT_SECONDS, typically because the blocking pool is
+    // saturated; carries the suggested retry delay in seconds
+    TimedOut(u64),
+    // The task panicked, or bcrypt itself returned an error
+    Failed(String),
+}
+
+impl HashTaskError {
+    // Timeouts become a retryable 503; genuine failures use the caller's error variant
+    pub fn into_app_error(self, failed: fn(String) -> AppError) -> AppError {
+        match self {
+            HashTaskError::TimedOut(retry_after) => AppError::HashingUnavailable(retry_after),
+            HashTaskError::Failed(message) => failed(message),
+        }
+    }
+}
+
+// Run a bcrypt operation on the blocking pool, waiting at most `timeout` for it. A timed-out
+// task can't be cancelled and still runs to completion; only the caller stops waiting.
+pub async fn run_hash_task<T, E>(
+    timeout: Duration,
+    op: impl FnOnce() -> Result<T, E> + Send + 'static,
+) -> Result<T, HashTaskError>
+where
+    T: Send + 'static,
+    E: Display + Send + 'static,
+{
+    match tokio::time::timeout(timeout, tokio::task::spawn_blocking(op)).await {
+        Ok(Ok(Ok(value))) => Ok(value),
+        Ok(Ok(Err(e))) => Err(HashTaskError::Failed(e.to_string())),
+        Ok(Err(e)) => Err(HashTaskError::Failed(format!("Task join error: {}", e))),
+        Err(_) => {
+            warn!("Password hashing task did not complete within {:?}", timeout);
+            Err(HashTaskError::TimedOut(timeout.as_secs().max(1)))
+        }
+    }
+}
+
+// Check a password against the role's policy, or the global policy when the role has none
+pub fn check_password_policy(config: &Config, role: &str, password: &str) -> Result<(), AppError> {
+    match config.role_password_policies.get(role) {
+        Some(policy) => policy.check(Some(role), password),
+        None => config.password_policy.check(None, password),
+    }
+}
+
+// Validate a password being set for an account with `role`: the role's policy, then the breach check
+pub async fn check_new_password(
+    config: &Config,
+    breach_checker: &dyn BreachChecker,
+    role: &str,
+    password: &str,
+) -> Result<(), AppError> {
+    check_password_policy(config, role, password)?;
+    if breach_checker.is_breached(password).await {
+        return Err(AppError::Validation(
+            "This password has appeared in a data breach or is too common; please choose another password".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// Validate a submitted password being set for an account with `role`. A client-side hash can't be
+// checked against the policy or the breach list, so it is only accepted with
+// CLIENT_HASH_SKIPS_PASSWORD_POLICY, and never for a role with its own ROLE_PASSWORD_POLICIES entry.
+pub async fn check_new_submitted_password(
+    config: &Config,
+    breach_checker: &dyn BreachChecker,
+    role: &str,
+    password: &SubmittedPassword,
+) -> Result<(), AppError> {
+    match password.scheme {
+        None => check_new_password(config, breach_checker, role, &password.secret).await,
+        Some(_) if client_hash_may_skip_policy(config.client_hash_skips_password_policy, &config.role_password_policies, role) => {
+            Ok(())
+        }
+        Some(_) => Err(AppError::ClientHashNotAllowed(role.to_string())),
+    }
+}
+
+fn client_hash_may_skip_policy(opted_out: bool, role_policies: &HashMap<String, PasswordPolicy>, role: &str) -> bool {
+    opted_out && !role_policies.contains_key(role)
+}
+
+// Hash a password on the blocking thread pool, bounded by HASH_TIMEOUT_SECONDS
+pub async fn hash_password(config: &Config, password: String) -> Result<String, AppError> {
+    let timeout = Duration::from_secs(config.hash_timeout_seconds);
+    run_hash_task(timeout, move || {
+        hash_with_result(&password, DEFAULT_COST).map(|hash_result| hash_result.format_for_version(Version::TwoA))
+    })
+    .await
+    .map_err(|e| e.into_app_error(AppError::PasswordHashing))
+}
+
+// A password as submitted: what the user typed, or with ACCEPT_CLIENT_HASH a client-side hash of it
+// under the scheme the request declares
+pub struct SubmittedPassword {
+    pub scheme: Option<ClientHashScheme>,
+    pub secret: String,
+}
+
+impl SubmittedPassword {
+    // Accept `password` as declared by `scheme`, which must be enabled and in
+    // CLIENT_HASH_ALGORITHMS; a digest must be 64 hex characters
+    pub fn parse(config: &Config, scheme: Option<&str>, password: String) -> Result<Self, AppError> {
+        let Some(name) = scheme else {
+            return Ok(SubmittedPassword { scheme: None, secret: password });
+        };
+        let scheme = ClientHashScheme::from_name(name)
+            .filter(|scheme| config.accept_client_hash && config.client_hash_algorithms.contains(scheme))
+            .ok_or_else(|| AppError::UnsupportedPasswordScheme(name.to_string()))?;
+        if password.len() != 64 || !password.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(AppError::Validation(format!("A {} password must be 64 hex characters", name)));
+        }
+        Ok(SubmittedPassword { scheme: Some(scheme), secret: password.to_ascii_lowercase() })
+    }
+
+    // The bcrypt input and hash to verify against `stored_hash`, or None when the two can't be
+    // compared. A plain password still verifies against a `sha256` hash, since the server can
+    // compute that digest itself; any other mix of schemes never matches.
+    pub fn verification_input(self, stored_hash: &str) -> Option<(String, String)> {
+        let (stored_scheme, bcrypt_hash) = split_stored_hash(stored_hash);
+        let secret = match (self.scheme, stored_scheme) {
+            (submitted, stored) if submitted == stored => self.secret,
+            (None, Some(ClientHashScheme::Sha256)) => hex::encode(Sha256::digest(self.secret.as_bytes())),
+            _ => return None,
+        };
+        Some((secret, bcrypt_hash.to_string()))
+    }
+}
+
+// Separate the client-hash scheme a stored hash was made under from the bcrypt hash itself. Hashes
+// of client digests are stored as `<scheme>:<bcrypt hash>`; bcrypt hashes never contain a `:`.
+pub fn split_stored_hash(stored_hash: &str) -> (Option<ClientHashScheme>, &str) {
+    match stored_hash.split_once(':') {
+        Some((name, bcrypt_hash)) => match ClientHashScheme::from_name(name) {
+            Some(scheme) => (Some(scheme), bcrypt_hash),
+            None => (None, stored_hash),
+        },
+        None => (None, stored_hash),
+    }
+}
+
+// Hash a submitted password for storage, recording the client-hash scheme it arrived under
+pub async fn hash_submitted_password(config: &Config, password: SubmittedPassword) -> Result<String, AppError> {
+    let hash = hash_password(config, password.secret).await?;
+    Ok(match password.scheme {
+        Some(scheme) => format!("{}:{}", scheme.as_str(), hash),
+        None => hash,
+    })
+}
+
+// Refuse a self-service change until MIN_PASSWORD_AGE_HOURS have passed since the last one, so
+// users can't cycle through passwords in quick succession. Never-changed passwords are exempt.
+pub fn check_min_password_age(
+    changed_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    min_age: chrono::Duration,
+) -> Result<(), AppError> {
+    match changed_at {
+        Some(changed_at) if changed_at + min_age > now => Err(AppError::PasswordTooRecent(
+            (changed_at + min_age).to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Character classes counted towards `min_character_classes`, as the policy endpoint names them
+pub const CHARACTER_CLASSES: [&str; 4] = ["lowercase", "uppercase", "digit", "symbol"];
+
+// Size of the pool of characters the password draws from, summing the classes it uses
+// (lowercase, uppercase, digits, symbols), and how many of those classes there are
+fn character_pool(password: &str) -> (f64, usize) {
+    let classes = [
+        (password.chars().any(|c| c.is_lowercase()), 26.0),
+        (password.chars().any(|c| c.is_uppercase()), 26.0),
+        (password.chars().any(|c| c.is_ascii_digit()), 10.0),
+        (password.chars().any(|c| !c.is_alphanumeric()), 33.0),
+    ];
+    let used: Vec<f64> = classes.iter().filter(|(present, _)| *present).map(|(_, size)| *size).collect();
+    (used.iter().sum(), used.len())
+}
+
+// Estimated entropy in bits: the length × log2(pool) strength, scaled by how evenly the password
+// spreads over its own characters (its Shannon entropy relative to the most a password of that
+// length could have). `aaaaaaaa` scores 0 and `abababab` a third of its strength.
+pub fn estimate_entropy_bits(password: &str) -> f64 {
+    let (pool, _) = character_pool(password);
+    let length = password.chars().count();
+    let strength_bits = length as f64 * pool.max(1.0).log2();
+    if length < 2 {
+        return strength_bits;
+    }
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in password.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let shannon: f64 = counts
+        .values()
+        .map(|&count| {
+            let p = count as f64 / length as f64;
+            -p * p.log2()
+        })
+        .sum();
+    strength_bits * shannon / (length as f64).log2()
+}
+
+impl PasswordPolicy {
+    // Check a password, naming the role in the message when it has its own policy
+    pub fn check(&self, role: Option<&str>, password: &str) -> Result<(), AppError> {
+        let subject = match role {
+            Some(role) => format!("Passwords for {} accounts", role),
+            None => "Passwords".to_string(),
+        };
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(AppError::Validation(format!(
+                "{} must be at least {} characters long",
+                subject, self.min_length
+            )));
+        }
+
+        let (pool, used) = character_pool(password);
+        if used < self.min_character_classes {
+            return Err(AppError::Validation(format!(
+                "{} must mix at least {} of: lowercase letters, uppercase letters, digits, symbols",
+                subject, self.min_character_classes
+            )));
+        }
+
+        let strength_bits = length as f64 * pool.max(1.0).log2();
+        if strength_bits < f64::from(self.min_strength_bits) {
+            return Err(AppError::Validation(format!(
+                "{} are too weak; use a longer password or more character types",
+                subject
+            )));
+        }
+
+        if self.min_entropy_bits > 0 {
+            let entropy_bits = estimate_entropy_bits(password) as u32;
+            if entropy_bits < self.min_entropy_bits {
+                if self.enforce_min_entropy {
+                    return Err(AppError::WeakPassword(entropy_bits, self.min_entropy_bits));
+                }
+                warn!(
+                    "Accepted a password with estimated entropy {} bits, below the {} bit minimum (not enforced)",
+                    entropy_bits, self.min_entropy_bits
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Checks whether a candidate password is known to be breached or too common
+#[async_trait]
+pub trait BreachChecker: Send + Sync {
+    async fn is_breached(&self, password: &str) -> bool;
+}
+
+// Never reports a password as breached
+pub struct NoopBreachChecker;
+
+#[async_trait]
+impl BreachChecker for NoopBreachChecker {
+    async fn is_breached(&self, _password: &str) -> bool {
+        false
+    }
+}
+
+// Matches against the embedded common-passwords list, case-insensitively
+pub struct EmbeddedBreachChecker {
+    passwords: HashSet<String>,
+}
+
+impl EmbeddedBreachChecker {
+    pub fn new() -> Self {
+        let passwords = COMMON_PASSWORDS
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect();
+        Self { passwords }
+    }
+}
+
+#[async_trait]
+impl BreachChecker for EmbeddedBreachChecker {
+    async fn is_breached(&self, password: &str) -> bool {
+        self.passwords.contains(&password.to_lowercase())
+    }
+}
+
+// Queries the HaveIBeenPwned range API using k-anonymity: only the first five hex
+// characters of the password's SHA-1 hash are sent, and the suffix is matched locally
+pub struct HibpBreachChecker {
+    client: reqwest::Client,
+    api_url: String,
+}
+
+impl HibpBreachChecker {
+    pub fn new(api_url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_url,
+        }
+    }
+}
+
+#[async_trait]
+impl BreachChecker for HibpBreachChecker {
+    async fn is_breached(&self, password: &str) -> bool {
+        let digest: String = Sha1::digest(password.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let (prefix, suffix) = digest.split_at(5);
+
+        let response = self
+            .client
+            .get(format!("{}{}", self.api_url, prefix))
+            .header("Add-Padding", "true")
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let body = match response {
+            Ok(response) => response.text().await,
+            Err(e) => Err(e),
+        };
+
+        // Fail open so a HIBP outage doesn't block registrations
+        match body {
+            Ok(body) => body.lines().any(|line| {
+                line.split_once(':')
+                    .is_some_and(|(candidate, count)| {
+                        candidate.eq_ignore_ascii_case(suffix) && count.trim() != "0"
+                    })
+            }),
+            Err(e) => {
+                warn!("HIBP breach check failed, allowing password: {}", e);
+                false
+            }
+        }
+    }
+}
+
+pub fn breach_checker_from_config(config: &Config) -> Arc<dyn BreachChecker> {
+    match config.breach_checker {
+        BreachCheckerKind::None => Arc::new(NoopBreachChecker),
+        BreachCheckerKind::Embedded => Arc::new(EmbeddedBreachChecker::new()),
+        BreachCheckerKind::Hibp => Arc::new(HibpBreachChecker::new(config.hibp_api_url.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: PasswordPolicy = PasswordPolicy {
+        min_length: 12,
+        min_character_classes: 3,
+        min_strength_bits: 60,
+        min_entropy_bits: 0,
+        enforce_min_entropy: true,
+    };
+
+    fn message(result: Result<(), AppError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_password_meeting_policy() {
+        assert!(POLICY.check(Some("admin"), "Correct-Horse-42").is_ok());
+    }
+
+    #[test]
+    fn rejects_short_password_with_role_in_message() {
+        assert_eq!(
+            message(POLICY.check(Some("admin"), "Sh0rt!")),
+            "Passwords for admin accounts must be at least 12 characters long"
+        );
+    }
+
+    #[test]
+    fn rejects_too_few_character_classes() {
+        assert!(message(POLICY.check(None, "alllowercaseletters")).starts_with("Passwords must mix at least 3"));
+    }
+
+    #[test]
+    fn rejects_weak_password() {
+        let policy = PasswordPolicy {
+            min_character_classes: 1,
+            ..POLICY
+        };
+        assert!(message(policy.check(None, "000000000000")).contains("too weak"));
+    }
+
+    #[test]
+    fn entropy_discounts_repetition() {
+        assert_eq!(estimate_entropy_bits("aaaaaaaa"), 0.0);
+        let strength = 8.0 * 26f64.log2();
+        assert!((estimate_entropy_bits("abababab") - strength / 3.0).abs() < 1e-9);
+        assert!((estimate_entropy_bits("abcdefgh") - strength).abs() < 1e-9);
+        assert!(estimate_entropy_bits("Correct-Horse-42") > 60.0);
+        assert_eq!(estimate_entropy_bits(""), 0.0);
+    }
+
+    #[test]
+    fn rejects_low_entropy_password_with_estimate() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            min_character_classes: 1,
+            min_strength_bits: 0,
+            min_entropy_bits: 30,
+            enforce_min_entropy: true,
+        };
+        // Passes a length-only check, but has no entropy at all
+        let error = policy.check(None, "aaaaaaaaaaaa").unwrap_err();
+        assert!(matches!(error, AppError::WeakPassword(0, 30)));
+        assert_eq!(error.code(), "weak_password");
+        assert_eq!(error.fields()["entropy_bits"], 0);
+        assert!(policy.check(None, "tangerine-bicycle").is_ok());
+
+        // Unenforced, the estimate is only logged
+        let report_only = PasswordPolicy {
+            enforce_min_entropy: false,
+            ..policy
+        };
+        assert!(report_only.check(None, "aaaaaaaaaaaa").is_ok());
+    }
+
+    #[test]
+    fn minimum_password_age_boundary() {
+        let changed_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let min_age = chrono::Duration::hours(24);
+
+        let just_before = changed_at + min_age - chrono::Duration::seconds(1);
+        assert_eq!(
+            message(check_min_password_age(Some(changed_at), just_before, min_age)),
+            "Password was changed too recently; it can be changed again after 2024-01-02T00:00:00Z"
+        );
+        assert!(check_min_password_age(Some(changed_at), changed_at + min_age, min_age).is_ok());
+        assert!(check_min_password_age(None, changed_at, min_age).is_ok());
+        // A zero minimum never blocks
+        assert!(check_min_password_age(Some(changed_at), changed_at, chrono::Duration::zero()).is_ok());
+    }
+
+    #[test]
+    fn client_hash_skips_policy_only_when_opted_out_and_role_has_none() {
+        let role_policies = HashMap::from([("admin".to_string(), POLICY)]);
+        assert!(!client_hash_may_skip_policy(false, &role_policies, "user"));
+        assert!(client_hash_may_skip_policy(true, &role_policies, "user"));
+        assert!(!client_hash_may_skip_policy(true, &role_policies, "admin"));
+    }
+
+    #[test]
+    fn client_hashes_only_verify_against_hashes_of_the_same_scheme() {
+        let plain = || SubmittedPassword { scheme: None, secret: "Str0ng-unique-pass!".to_string() };
+        let digest = hex::encode(Sha256::digest(b"Str0ng-unique-pass!"));
+        let hashed = |scheme| SubmittedPassword { scheme: Some(scheme), secret: digest.clone() };
+        let bcrypt_hash = "$2a$12$abcdefghijklmnopqrstuuABCDEFGHIJKLMNOPQRSTUVWXYZ01234";
+
+        assert_eq!(split_stored_hash(bcrypt_hash), (None, bcrypt_hash));
+        let sha256_hash = format!("sha256:{}", bcrypt_hash);
+        assert_eq!(split_stored_hash(&sha256_hash), (Some(ClientHashScheme::Sha256), bcrypt_hash));
+
+        let input = |password: SubmittedPassword, stored: &str| password.verification_input(stored).map(|(secret, _)| secret);
+        assert_eq!(input(plain(), bcrypt_hash).as_deref(), Some("Str0ng-unique-pass!"));
+        assert_eq!(input(hashed(ClientHashScheme::Sha256), &sha256_hash), Some(digest.clone()));
+        // The server can derive a `sha256` digest from the plain password, but not the reverse
+        assert_eq!(input(plain(), &sha256_hash), Some(digest.clone()));
+        assert_eq!(input(hashed(ClientHashScheme::Sha256), bcrypt_hash), None);
+        assert_eq!(input(plain(), &format!("pbkdf2-sha256:{}", bcrypt_hash)), None);
+        assert_eq!(input(hashed(ClientHashScheme::Pbkdf2Sha256), &sha256_hash), None);
+    }
+}
@@ -0,0 +1,422 @@
+use axum::http::{header::USER_AGENT, HeaderMap};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgRow, PgPool, Row};
+use std::{collections::HashMap, sync::Mutex};
+use tracing::warn;
+
+use crate::{
+    clients::{TokenPolicy, TOKEN_POLICY_COLUMNS},
+    config::Config,
+    errors::AppError,
+    metrics,
+    models::TokenResponse,
+    tokens::{clamp_ttl, email_verified_claim, TokenGrant, ACCESS_TOKEN_TTL_SECONDS},
+};
+
+// A stored refresh token joined with its user's current identity
+struct StoredRefreshToken {
+    id: i32,
+    user_id: i32,
+    family_id: String,
+    username: String,
+    role: String,
+    email_verified: bool,
+    scope: Option<String>,
+    audience: Option<String>,
+    acr: Option<String>,
+    auth_time: Option<DateTime<Utc>>,
+    // Space-separated `amr` values of the originating login
+    amr: Option<String>,
+    fingerprint: Option<String>,
+    client_id: Option<String>,
+    // `jti` of the login's session handle, carried into every access token the family issues
+    jti: Option<String>,
+    // Policy of the client the family was issued to, if any
+    policy: TokenPolicy,
+    expires_at: DateTime<Utc>,
+    absolute_expires_at: DateTime<Utc>,
+    revoked: bool,
+}
+
+// Result of a successful rotation
+pub struct Rotation {
+    pub grant: TokenGrant,
+    // The new refresh token, or the presented one for clients that don't rotate
+    pub refresh_token: String,
+    pub access_ttl_seconds: i64,
+}
+
+// Random, URL-safe opaque value
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// Refresh tokens are only ever stored as their SHA-256 digest
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Header carrying a client-chosen identifier for the installation or browser profile
+pub const DEVICE_ID_HEADER: &str = "x-device-id";
+
+// Digest of the client presenting a refresh token: its user agent with version numbers dropped,
+// so routine browser and app updates don't count as a different client, plus its device id
+pub fn client_fingerprint(headers: &HeaderMap) -> String {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    let user_agent: Vec<String> = header(USER_AGENT.as_str())
+        .split_whitespace()
+        .map(|product| product.split('/').next().unwrap_or_default().to_ascii_lowercase())
+        .collect();
+    let mut hasher = Sha256::new();
+    hasher.update(user_agent.join(" ").as_bytes());
+    hasher.update([0u8]);
+    hasher.update(header(DEVICE_ID_HEADER).trim().as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// A refresh token to be stored, the first of a new family or the successor of a rotated one
+struct NewRefreshToken<'a> {
+    user_id: i32,
+    family_id: &'a str,
+    grant: &'a TokenGrant,
+    client_id: Option<&'a str>,
+    fingerprint: Option<&'a str>,
+    expires_at: DateTime<Utc>,
+    absolute_expires_at: DateTime<Utc>,
+}
+
+async fn insert(pool: &PgPool, new: NewRefreshToken<'_>) -> Result<String, AppError> {
+    let token = random_token();
+    let insert = sqlx::query(
+        "INSERT INTO refresh_tokens \
+         (user_id, token_hash, family_id, scope, audience, acr, auth_time, amr, client_id, fingerprint, jti, expires_at, absolute_expires_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)"
+    )
+    .bind(new.user_id)
+    .bind(hash_token(&token))
+    .bind(new.family_id)
+    .bind(&new.grant.scope)
+    .bind(&new.grant.aud)
+    .bind(&new.grant.acr)
+    .bind(new.grant.auth_time.and_then(|t| DateTime::from_timestamp(t as i64, 0)))
+    .bind(new.grant.amr.as_ref().map(|amr| amr.join(" ")))
+    .bind(new.client_id)
+    .bind(new.fingerprint)
+    .bind(&new.grant.jti)
+    .bind(new.expires_at)
+    .bind(new.absolute_expires_at)
+    .execute(pool);
+    metrics::observe("refresh_tokens.insert", insert).await?;
+    Ok(token)
+}
+
+// Configured refresh token lifetimes, clamped to MAX_TOKEN_TTL_SECONDS. A client's own
+// refresh TTL replaces REFRESH_TTL_DAYS.
+fn sliding_lifetime(config: &Config, policy: &TokenPolicy) -> Duration {
+    let ttl = policy.refresh_ttl_seconds(Duration::days(config.refresh_ttl_days).num_seconds());
+    Duration::seconds(clamp_ttl("refresh token", ttl, config.max_token_ttl_seconds))
+}
+
+fn absolute_lifetime(config: &Config) -> Duration {
+    let ttl = Duration::days(config.refresh_absolute_max_days).num_seconds();
+    Duration::seconds(clamp_ttl("refresh session", ttl, config.max_token_ttl_seconds))
+}
+
+// Issue the first refresh token of a new family at login, bound to the logging-in client's
+// fingerprint. The fingerprint is always recorded but only enforced with REFRESH_BINDING. The
+// registered client (if any) is recorded so its policy also governs later refreshes.
+pub async fn create(
+    pool: &PgPool,
+    config: &Config,
+    user_id: i32,
+    grant: &TokenGrant,
+    client: Option<(&str, &TokenPolicy)>,
+    fingerprint: &str,
+    now: DateTime<Utc>,
+) -> Result<String, AppError> {
+    let (client_id, policy) = match client {
+        Some((client_id, policy)) => (Some(client_id), policy.clone()),
+        None => (None, TokenPolicy::default()),
+    };
+    let absolute_expires_at = now + absolute_lifetime(config);
+    let expires_at = (now + sliding_lifetime(config, &policy)).min(absolute_expires_at);
+    let new = NewRefreshToken {
+        user_id,
+        family_id: &random_token(),
+        grant,
+        client_id,
+        fingerprint: Some(fingerprint),
+        expires_at,
+        absolute_expires_at,
+    };
+    insert(pool, new).await
+}
+
+// Revoke every token in a family, e.g. when reuse of a rotated token is detected
+async fn revoke_family(pool: &PgPool, family_id: &str) -> Result<(), AppError> {
+    let revoke = sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL")
+        .bind(family_id)
+        .execute(pool);
+    metrics::observe("refresh_tokens.revoke_family", revoke).await?;
+    Ok(())
+}
+
+// Revoke the family started by the login whose session handle has `jti`, so revoking the handle
+// ends the session rather than leaving it to be refreshed
+pub async fn revoke_session(pool: &PgPool, jti: &str) -> Result<(), AppError> {
+    let revoke = sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE jti = $1 AND revoked_at IS NULL")
+        .bind(jti)
+        .execute(pool);
+    metrics::observe("refresh_tokens.revoke_session", revoke).await?;
+    Ok(())
+}
+
+// Exchange a refresh token for a new one in the same family. With sliding expiration the
+// new token's expiry is extended by the TTL, capped at the family's absolute lifetime;
+// otherwise it inherits the presented token's expiry. With REFRESH_BINDING, a token presented
+// by a client other than the one it was issued to revokes its family. Clients registered without
+// rotation get the presented token back, with its expiry slid in place.
+pub async fn rotate(
+    pool: &PgPool,
+    config: &Config,
+    presented: &str,
+    fingerprint: &str,
+    now: DateTime<Utc>,
+) -> Result<Rotation, AppError> {
+    let sql = format!(
+        "SELECT t.id, t.user_id, t.family_id, t.scope, t.audience, t.acr, t.auth_time, t.amr, t.fingerprint, t.client_id, t.jti, t.expires_at, \
+                t.absolute_expires_at, t.revoked_at IS NOT NULL AS revoked, u.username, u.role, \
+                u.email_verified_at IS NOT NULL AS email_verified, {} \
+         FROM refresh_tokens t JOIN users u ON u.id = t.user_id \
+         LEFT JOIN oauth_clients c ON c.client_id = t.client_id WHERE t.token_hash = $1",
+        TOKEN_POLICY_COLUMNS
+    );
+    let query = sqlx::query(&sql)
+    .bind(hash_token(presented))
+    .map(|row: PgRow| StoredRefreshToken {
+        id: row.get("id"),
+        user_id: row.get("user_id"),
+        family_id: row.get("family_id"),
+        username: row.get("username"),
+        role: row.get("role"),
+        email_verified: row.get("email_verified"),
+        scope: row.get("scope"),
+        audience: row.get("audience"),
+        acr: row.get("acr"),
+        auth_time: row.get("auth_time"),
+        amr: row.get("amr"),
+        fingerprint: row.get("fingerprint"),
+        client_id: row.get("client_id"),
+        jti: row.get("jti"),
+        policy: TokenPolicy::from_row(&row),
+        expires_at: row.get("expires_at"),
+        absolute_expires_at: row.get("absolute_expires_at"),
+        revoked: row.get("revoked"),
+    })
+    .fetch_optional(pool);
+    let stored = metrics::observe("refresh_tokens.find", query)
+        .await?
+        .ok_or(AppError::InvalidGrant)?;
+
+    if !stored.policy.refresh_enabled(config.refresh_tokens_enabled) {
+        return Err(AppError::InvalidGrant);
+    }
+    if stored.revoked {
+        warn!("Refresh token reuse detected for user {}, revoking family", stored.username);
+        revoke_family(pool, &stored.family_id).await?;
+        return Err(AppError::InvalidGrant);
+    }
+    if stored.expires_at <= now || stored.absolute_expires_at <= now {
+        return Err(AppError::InvalidGrant);
+    }
+    // Tokens issued before fingerprints were recorded stay unbound
+    if config.refresh_binding && stored.fingerprint.as_deref().is_some_and(|bound| bound != fingerprint) {
+        warn!(
+            target: "security",
+            "Refresh token for user {} presented by a different client, revoking family",
+            stored.username
+        );
+        revoke_family(pool, &stored.family_id).await?;
+        return Err(AppError::InvalidGrant);
+    }
+
+    let expires_at = if config.refresh_sliding {
+        (now + sliding_lifetime(config, &stored.policy)).min(stored.absolute_expires_at)
+    } else {
+        stored.expires_at
+    };
+    let grant = TokenGrant {
+        sub: stored.username,
+        role: stored.role,
+        aud: stored.audience,
+        scope: stored.scope,
+        acr: stored.acr,
+        auth_time: stored.auth_time.map(|t| t.timestamp() as usize),
+        amr: stored.amr.map(|amr| amr.split_whitespace().map(str::to_string).collect()),
+        act: None,
+        jkt: None,
+        // Judged afresh, so verifying during the session lifts the restrictions at the next refresh
+        email_verified: email_verified_claim(config, stored.email_verified)?,
+        // Same session as the login, so revoking its handle revokes refreshed tokens too
+        jti: stored.jti,
+    };
+    let access_ttl_seconds = stored.policy.access_ttl_seconds(ACCESS_TOKEN_TTL_SECONDS);
+
+    if !stored.policy.rotates_refresh_tokens() {
+        if expires_at != stored.expires_at {
+            let slide = sqlx::query("UPDATE refresh_tokens SET expires_at = $1 WHERE id = $2")
+                .bind(expires_at)
+                .bind(stored.id)
+                .execute(pool);
+            metrics::observe("refresh_tokens.slide", slide).await?;
+        }
+        return Ok(Rotation {
+            grant,
+            refresh_token: presented.to_string(),
+            access_ttl_seconds,
+        });
+    }
+
+    // Revoke the presented token; losing a race with a concurrent rotation counts as reuse
+    let revoke = sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL")
+        .bind(stored.id)
+        .execute(pool);
+    let revoked = metrics::observe("refresh_tokens.revoke", revoke).await?;
+    if revoked.rows_affected() != 1 {
+        warn!("Concurrent refresh token reuse detected for user {}, revoking family", grant.sub);
+        revoke_family(pool, &stored.family_id).await?;
+        return Err(AppError::InvalidGrant);
+    }
+
+    let new = NewRefreshToken {
+        user_id: stored.user_id,
+        family_id: &stored.family_id,
+        grant: &grant,
+        client_id: stored.client_id.as_deref(),
+        fingerprint: stored.fingerprint.as_deref(),
+        expires_at,
+        absolute_expires_at: stored.absolute_expires_at,
+    };
+    let refresh_token = insert(pool, new).await?;
+
+    Ok(Rotation {
+        grant,
+        refresh_token,
+        access_ttl_seconds,
+    })
+}
+
+// Whether a refresh token exists and hasn't been rotated or revoked
+pub async fn is_active(pool: &PgPool, token: &str) -> Result<bool, AppError> {
+    let query = sqlx::query("SELECT 1 FROM refresh_tokens WHERE token_hash = $1 AND revoked_at IS NULL")
+        .bind(hash_token(token))
+        .fetch_optional(pool);
+    Ok(metrics::observe("refresh_tokens.is_active", query).await?.is_some())
+}
+
+struct RecentRotation {
+    rotated_at: DateTime<Utc>,
+    fingerprint: String,
+    response: TokenResponse,
+}
+
+// Responses to recent rotations, keyed by the digest of the token that was presented, so a
+// client retrying a refresh whose response it never received gets the same tokens back instead
+// of tripping reuse detection. Held in memory for REFRESH_REUSE_WINDOW_SECONDS only.
+pub struct RecentRotations {
+    rotations: Mutex<HashMap<String, RecentRotation>>,
+}
+
+impl RecentRotations {
+    pub fn new() -> Self {
+        Self {
+            rotations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Remember the response issued for `presented` to the client with `fingerprint`, dropping
+    // entries older than `window`
+    pub fn remember(
+        &self,
+        presented: &str,
+        fingerprint: &str,
+        response: &TokenResponse,
+        now: DateTime<Utc>,
+        window: Duration,
+    ) {
+        let mut rotations = self.rotations.lock().unwrap();
+        rotations.retain(|_, rotation| rotation.rotated_at + window > now);
+        rotations.insert(
+            hash_token(presented),
+            RecentRotation {
+                rotated_at: now,
+                fingerprint: fingerprint.to_string(),
+                response: response.clone(),
+            },
+        );
+    }
+
+    // The response issued for `presented`, if it was rotated within `window` for the same client
+    pub fn replay(&self, presented: &str, fingerprint: &str, now: DateTime<Utc>, window: Duration) -> Option<TokenResponse> {
+        let rotations = self.rotations.lock().unwrap();
+        rotations
+            .get(&hash_token(presented))
+            .filter(|rotation| rotation.rotated_at + window > now && rotation.fingerprint == fingerprint)
+            .map(|rotation| rotation.response.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, FakeClock};
+
+    #[test]
+    fn replays_only_within_the_window() {
+        let clock = FakeClock::new(Utc::now());
+        let recent = RecentRotations::new();
+        let window = Duration::seconds(10);
+        let response = TokenResponse {
+            access_token: "access".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            refresh_token: Some("successor".to_string()),
+            issued_token_type: None,
+            email_verified: None,
+            session_handle: None,
+            warnings: Vec::new(),
+        };
+        recent.remember("presented", "client", &response, clock.now(), window);
+
+        clock.advance(Duration::seconds(9));
+        let replayed = recent.replay("presented", "client", clock.now(), window).unwrap();
+        assert_eq!(replayed.refresh_token.as_deref(), Some("successor"));
+        assert!(recent.replay("other", "client", clock.now(), window).is_none());
+        assert!(recent.replay("presented", "another client", clock.now(), window).is_none());
+
+        clock.advance(Duration::seconds(1));
+        assert!(recent.replay("presented", "client", clock.now(), window).is_none());
+    }
+
+    #[test]
+    fn fingerprint_ignores_versions_but_not_clients() {
+        let fingerprint = |user_agent: &str, device_id: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(USER_AGENT, user_agent.parse().unwrap());
+            headers.insert(DEVICE_ID_HEADER, device_id.parse().unwrap());
+            client_fingerprint(&headers)
+        };
+        let chrome = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+        let updated = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.6167.85 Safari/537.36";
+        let curl = "curl/8.4.0";
+
+        assert_eq!(fingerprint(chrome, "device-1"), fingerprint(updated, "device-1"));
+        assert_ne!(fingerprint(chrome, "device-1"), fingerprint(chrome, "device-2"));
+        assert_ne!(fingerprint(chrome, "device-1"), fingerprint(curl, "device-1"));
+    }
+}
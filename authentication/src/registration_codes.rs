@@ -0,0 +1,73 @@
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::{errors::AppError, login_codes::random_code, metrics};
+
+// Codes are only ever stored as a SHA-256 digest bound to the address they were sent to
+fn hash_code(email: &str, code: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}:{}", email, code).as_bytes()))
+}
+
+// Issue a fresh code for registering `email`, replacing any outstanding one, and return it for mailing
+pub async fn create(pool: &PgPool, email: &str, ttl: Duration, now: DateTime<Utc>) -> Result<String, AppError> {
+    let retire = sqlx::query("UPDATE registration_codes SET used_at = $1 WHERE email = $2 AND used_at IS NULL")
+        .bind(now)
+        .bind(email)
+        .execute(pool);
+    metrics::observe("registration_codes.retire", retire).await?;
+
+    let code = random_code();
+    let insert = sqlx::query("INSERT INTO registration_codes (email, code_hash, expires_at) VALUES ($1, $2, $3)")
+        .bind(email)
+        .bind(hash_code(email, &code))
+        .bind(now + ttl)
+        .execute(pool);
+    metrics::observe("registration_codes.insert", insert).await?;
+    Ok(code)
+}
+
+// Spend the outstanding code for `email` if `code` matches it. As with login codes, each wrong
+// guess counts against the code, which stops being accepted after `max_attempts` of them.
+pub async fn redeem(pool: &PgPool, email: &str, code: &str, max_attempts: u32, now: DateTime<Utc>) -> Result<bool, AppError> {
+    let query = sqlx::query(
+        "SELECT id, code_hash, attempts FROM registration_codes \
+         WHERE email = $1 AND used_at IS NULL AND expires_at > $2 ORDER BY id DESC LIMIT 1"
+    )
+    .bind(email)
+    .bind(now)
+    .map(|row: PgRow| (row.get::<i32, _>("id"), row.get::<String, _>("code_hash"), row.get::<i32, _>("attempts")))
+    .fetch_optional(pool);
+    let Some((id, code_hash, attempts)) = metrics::observe("registration_codes.find", query).await? else {
+        return Ok(false);
+    };
+    if attempts >= max_attempts as i32 {
+        return Ok(false);
+    }
+
+    if code_hash != hash_code(email, code.trim()) {
+        let count = sqlx::query("UPDATE registration_codes SET attempts = attempts + 1 WHERE id = $1")
+            .bind(id)
+            .execute(pool);
+        metrics::observe("registration_codes.count_attempt", count).await?;
+        return Ok(false);
+    }
+
+    // Concurrent registrations race on `used_at`; only one of them wins
+    let spend = sqlx::query("UPDATE registration_codes SET used_at = $1 WHERE id = $2 AND used_at IS NULL")
+        .bind(now)
+        .bind(id)
+        .execute(pool);
+    Ok(metrics::observe("registration_codes.spend", spend).await?.rows_affected() == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_are_hashed_per_address() {
+        assert_ne!(hash_code("a@example.com", "012345"), hash_code("b@example.com", "012345"));
+        assert_eq!(hash_code("a@example.com", "012345"), hash_code("a@example.com", "012345"));
+    }
+}
@@ -0,0 +1,121 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{postgres::PgRow, PgPool, Row};
+
+use crate::{config::Config, errors::AppError, metrics, models::Claims};
+
+// Marks opaque session handles, so introspection can tell them apart from JWTs
+pub const HANDLE_PREFIX: &str = "sh_";
+
+// A live session handle joined with its user's current identity
+pub struct SessionHandle {
+    pub username: String,
+    pub scope: Option<String>,
+    pub audience: Option<String>,
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+fn random_value(bytes: usize) -> String {
+    let mut value = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut value);
+    URL_SAFE_NO_PAD.encode(value)
+}
+
+// ID for an access token issued alongside a handle
+pub fn new_jti() -> String {
+    random_value(16)
+}
+
+// Handles are only ever stored as their SHA-256 digest
+fn hash_handle(handle: &str) -> String {
+    hex::encode(Sha256::digest(handle.as_bytes()))
+}
+
+// Store a handle for the login that issued the access token `jti`, valid until `expires_at`
+pub async fn create(
+    pool: &PgPool,
+    user_id: i32,
+    jti: &str,
+    scope: Option<&str>,
+    audience: Option<&str>,
+    expires_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<String, AppError> {
+    let handle = format!("{}{}", HANDLE_PREFIX, random_value(32));
+    let insert = sqlx::query(
+        "INSERT INTO session_handles (user_id, handle_hash, jti, scope, audience, expires_at, created_at) \
+         VALUES ($1, $2, $3, $4, $5, $6, $7)"
+    )
+    .bind(user_id)
+    .bind(hash_handle(&handle))
+    .bind(jti)
+    .bind(scope)
+    .bind(audience)
+    .bind(expires_at)
+    .bind(now)
+    .execute(pool);
+    metrics::observe("session_handles.insert", insert).await?;
+    Ok(handle)
+}
+
+// The handle's session, unless it is unknown, revoked or expired
+pub async fn find_active(pool: &PgPool, handle: &str, now: DateTime<Utc>) -> Result<Option<SessionHandle>, AppError> {
+    let query = sqlx::query(
+        "SELECT u.username, h.scope, h.audience, h.created_at, h.expires_at \
+         FROM session_handles h JOIN users u ON u.id = h.user_id \
+         WHERE h.handle_hash = $1 AND h.revoked_at IS NULL AND h.expires_at > $2"
+    )
+    .bind(hash_handle(handle))
+    .bind(now)
+    .map(|row: PgRow| SessionHandle {
+        username: row.get("username"),
+        scope: row.get("scope"),
+        audience: row.get("audience"),
+        issued_at: row.get("created_at"),
+        expires_at: row.get("expires_at"),
+    })
+    .fetch_optional(pool);
+    Ok(metrics::observe("session_handles.find", query).await?)
+}
+
+// Revoke a handle, returning the `jti` of its session; unknown or already revoked handles are left
+// as they are
+pub async fn revoke(pool: &PgPool, handle: &str, now: DateTime<Utc>) -> Result<Option<String>, AppError> {
+    let revoke = sqlx::query_scalar(
+        "UPDATE session_handles SET revoked_at = $1 WHERE handle_hash = $2 AND revoked_at IS NULL RETURNING jti"
+    )
+    .bind(now)
+    .bind(hash_handle(handle))
+    .fetch_optional(pool);
+    Ok(metrics::observe("session_handles.revoke", revoke).await?)
+}
+
+// Whether the token's handle has been revoked, which with SESSION_HANDLE_REVOKES_ACCESS_TOKEN
+// denylists the token too. Tokens without a `jti` were never paired with a handle.
+pub async fn is_access_token_revoked(pool: &PgPool, config: &Config, claims: &Claims) -> Result<bool, AppError> {
+    let Some(jti) = claims.jti.as_deref().filter(|_| config.session_handle_revokes_access_token) else {
+        return Ok(false);
+    };
+    let query = sqlx::query("SELECT 1 FROM session_handles WHERE jti = $1 AND revoked_at IS NOT NULL")
+        .bind(jti)
+        .fetch_optional(pool);
+    Ok(metrics::observe("session_handles.find_revoked_jti", query).await?.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_values_are_url_safe_and_unique() {
+        let value = random_value(32);
+        assert_eq!(value.len(), 43);
+        assert!(value.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+        assert_ne!(new_jti(), new_jti());
+        assert_eq!(hash_handle("sh_a"), hash_handle("sh_a"));
+        assert_ne!(hash_handle("sh_a"), hash_handle("sh_b"));
+    }
+}
@@ -0,0 +1,65 @@
+use std::{future::Future, pin::Pin, time::Duration};
+use tracing::{info, warn};
+
+type Hook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = Result<(), String>> + Send>> + Send>;
+
+// Cleanup that runs once the server has stopped accepting connections and drained the in-flight
+// requests. Hooks run one at a time, most recently registered first, so a component registered
+// after the ones it depends on is cleaned up before them; each gets SHUTDOWN_HOOK_TIMEOUT_SECONDS.
+pub struct ShutdownHooks {
+    hooks: Vec<(&'static str, Hook)>,
+    timeout: Duration,
+}
+
+impl ShutdownHooks {
+    pub fn new(timeout: Duration) -> Self {
+        Self { hooks: Vec::new(), timeout }
+    }
+
+    pub fn register<F, Fut>(&mut self, name: &'static str, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.hooks.push((name, Box::new(move || Box::pin(hook()))));
+    }
+
+    // Run every hook in LIFO order. A failed or timed-out hook is logged and doesn't stop the rest.
+    pub async fn run(self) {
+        for (name, hook) in self.hooks.into_iter().rev() {
+            match tokio::time::timeout(self.timeout, hook()).await {
+                Ok(Ok(())) => info!("Shutdown hook {} completed", name),
+                Ok(Err(e)) => warn!("Shutdown hook {} failed: {}", name, e),
+                Err(_) => warn!("Shutdown hook {} did not complete within {:?}", name, self.timeout),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn runs_hooks_in_reverse_order_past_failures_and_timeouts() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        let mut hooks = ShutdownHooks::new(Duration::from_millis(50));
+        for name in ["first", "second", "third"] {
+            let ran = ran.clone();
+            hooks.register(name, move || async move {
+                ran.lock().unwrap().push(name);
+                match name {
+                    "second" => Err("broken".to_string()),
+                    "third" => {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                }
+            });
+        }
+        hooks.run().await;
+        assert_eq!(*ran.lock().unwrap(), ["third", "second", "first"]);
+    }
+}
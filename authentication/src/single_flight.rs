@@ -0,0 +1,48 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+// Coalesces concurrent calls with the same key into a single execution whose result is
+// shared by every caller that joined while it was in flight. Entries are removed as soon
+// as the call completes, so results are never cached beyond the in-flight window.
+pub struct SingleFlight<K, T> {
+    inflight: Mutex<HashMap<K, Arc<OnceCell<T>>>>,
+}
+
+impl<K, T> SingleFlight<K, T>
+where
+    K: Hash + Eq + Clone,
+    T: Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(OnceCell::new()))
+            .clone();
+        let value = cell.get_or_init(f).await.clone();
+
+        // Only the entry we joined is removed; a newer flight for the same key is left alone
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(&key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+            inflight.remove(&key);
+        }
+        value
+    }
+}
@@ -1,8 +1,124 @@
+use arc_swap::ArcSwap;
 use sqlx::PgPool;
-use crate::config::Config;
+use std::sync::Arc;
+use crate::{
+    clock::Clock,
+    config::{Config, RuntimeConfig, TenantSource},
+    email_policy::EmailDomainPolicy,
+    errors::AppError,
+    events::EventEmitter,
+    federation::TrustedIssuers,
+    flags::FeatureFlags,
+    geo::GeoResolver,
+    mailer::Mailer,
+    metrics::Metrics,
+    models::Claims,
+    passwords::{BreachChecker, HashTaskError},
+    rate_limit::RateLimitStore,
+    refresh_tokens::RecentRotations,
+    single_flight::SingleFlight,
+    tarpit::Tarpit,
+    telemetry::LogLevelReloader,
+    tenant::ROOT_TENANT,
+    tokens::check_recent_auth,
+    users::UserRepository,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub pool: PgPool,
     pub config: Config,
+    pub runtime: Arc<ArcSwap<RuntimeConfig>>,
+    pub log_reloader: LogLevelReloader,
+    pub login_flights: Arc<SingleFlight<Vec<u8>, Result<bool, HashTaskError>>>,
+    pub breach_checker: Arc<dyn BreachChecker>,
+    pub rate_limiter: Arc<dyn RateLimitStore>,
+    pub tarpit: Arc<Tarpit>,
+    pub recent_rotations: Arc<RecentRotations>,
+    pub email_policy: Arc<EmailDomainPolicy>,
+    pub events: Arc<EventEmitter>,
+    pub flags: Arc<FeatureFlags>,
+    // Set when GEO_VELOCITY_ACTION is on and the GeoIP database loaded
+    pub geo: Option<Arc<dyn GeoResolver>>,
+    pub mailer: Arc<dyn Mailer>,
+    pub metrics: Arc<Metrics>,
+    pub trusted_issuers: Arc<TrustedIssuers>,
+    pub clock: Arc<dyn Clock>,
+    pub users: Arc<dyn UserRepository>,
+}
+
+impl AppState {
+    // Gate a sensitive operation on the user having signed in within SENSITIVE_OP_MAX_AGE
+    pub fn require_recent_auth(&self, claims: &Claims) -> Result<(), AppError> {
+        let max_age = chrono::Duration::seconds(self.config.sensitive_op_max_age_seconds);
+        check_recent_auth(claims, max_age, self.clock.now())
+    }
+
+    // Apply the configured per-IP and per-user rate limits, and the tenant's overall allowance
+    pub async fn check_rate_limits(&self, client_ip: std::net::IpAddr, tenant: Option<&str>, user: &str) -> Result<(), AppError> {
+        let limits = self.runtime.load().rate_limits(client_ip, tenant, user);
+        self.rate_limiter.check_limits(&limits, self.clock.now()).await.map_err(|(retry_after, exhausted)| {
+            let retry_after = retry_after.as_secs().max(1);
+            match tenant {
+                Some(tenant) if limits[exhausted].key.starts_with("tenant:") => {
+                    AppError::TenantRateLimited(tenant.to_string(), retry_after)
+                }
+                _ => AppError::RateLimited(retry_after),
+            }
+        })
+    }
+
+    // Each known tenant with its overall allowance and the requests it has left: those with a
+    // RATE_LIMIT_TENANT_LIMITS entry, plus the realms when they are the tenants
+    pub async fn tenant_quotas(&self) -> Vec<(String, u32, f64)> {
+        let runtime = self.runtime.load();
+        let mut tenants: Vec<String> = runtime.rate_limit_tenant_limits.keys().cloned().collect();
+        if self.config.tenant_source == TenantSource::Realm {
+            tenants.push(ROOT_TENANT.to_string());
+            tenants.extend(self.config.realms.iter().map(|realm| realm.name.clone()));
+        }
+        tenants.sort();
+        tenants.dedup();
+        let now = self.clock.now();
+        let mut quotas = Vec::new();
+        for limit in tenants.iter().map(|tenant| runtime.tenant_limit(tenant)).filter(|limit| limit.per_minute > 0) {
+            let remaining = self.rate_limiter.remaining(&limit, now).await;
+            quotas.push((limit.key.trim_start_matches("tenant:").to_string(), limit.per_minute, remaining));
+        }
+        quotas
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    // State for handler tests, signing with the test-mode keys and without any external services.
+    // The pool only connects once a handler queries it.
+    pub fn for_tests(mut config: Config, pool: PgPool) -> Self {
+        use crate::{clock, mailer, passwords, rate_limit, users::PgUserRepository};
+        use std::time::Duration;
+
+        config.test_mode = true;
+        config.deployment_environment = "test".to_string();
+        let metrics = Arc::new(Metrics::new(Duration::from_millis(config.slow_query_threshold_ms)));
+        Self {
+            pool: pool.clone(),
+            runtime: Arc::new(ArcSwap::from_pointee(RuntimeConfig::from_env())),
+            log_reloader: Arc::new(|_: &str| Ok(())),
+            login_flights: Arc::new(SingleFlight::new()),
+            breach_checker: passwords::breach_checker_from_config(&config),
+            rate_limiter: rate_limit::rate_limit_store_from_config(&config),
+            tarpit: Arc::new(Tarpit::new(config.tarpit_max_concurrent)),
+            recent_rotations: Arc::new(RecentRotations::new()),
+            email_policy: Arc::new(EmailDomainPolicy::from_config(&config)),
+            events: Arc::new(EventEmitter::from_config(&config, metrics.clone())),
+            flags: Arc::new(FeatureFlags::new(pool.clone(), &config)),
+            geo: None,
+            mailer: mailer::mailer_from_config(&config),
+            metrics,
+            trusted_issuers: Arc::new(TrustedIssuers::from_config(&config)),
+            clock: clock::clock_from_config(&config),
+            users: Arc::new(PgUserRepository::new(pool)),
+            config,
+        }
+    }
 }
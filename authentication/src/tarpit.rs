@@ -0,0 +1,116 @@
+use chrono::{DateTime, Utc};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::sync::Semaphore;
+
+// Failure counters are pruned once the table grows past this many keys
+const PRUNE_THRESHOLD: usize = 10_000;
+
+// How failed logins are slowed down; built from the runtime config
+#[derive(Debug, Clone, Copy)]
+pub struct TarpitPolicy {
+    // Delay after the first failure, doubled for each further one
+    pub base: Duration,
+    // Longest any single response is held
+    pub max: Duration,
+    // Failures older than this no longer count
+    pub window: Duration,
+}
+
+impl TarpitPolicy {
+    fn delay_for(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.base.saturating_mul(factor).min(self.max)
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: DateTime<Utc>,
+}
+
+// Escalating delay on failed logins, counted per key (e.g. `ip:...`, `user:...`). Cheaper than
+// lockout and never affects successful logins.
+pub struct Tarpit {
+    failures: Mutex<HashMap<String, Failures>>,
+    // Bounds how many responses are held at once so the delay can't exhaust connections
+    sleepers: Semaphore,
+}
+
+impl Tarpit {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            failures: Mutex::new(HashMap::new()),
+            sleepers: Semaphore::new(max_concurrent),
+        }
+    }
+
+    // Count a failure against every key and return the delay owed, driven by whichever key has
+    // failed most within the window
+    pub fn record_failure(&self, keys: &[String], policy: &TarpitPolicy, now: DateTime<Utc>) -> Duration {
+        let mut failures = self.failures.lock().unwrap();
+        let recent = |last: DateTime<Utc>| (now - last).to_std().unwrap_or(Duration::ZERO) < policy.window;
+
+        let mut worst = 0;
+        for key in keys {
+            let entry = failures.entry(key.clone()).or_insert(Failures { count: 0, last: now });
+            if !recent(entry.last) {
+                entry.count = 0;
+            }
+            entry.count = entry.count.saturating_add(1);
+            entry.last = now;
+            worst = worst.max(entry.count);
+        }
+
+        if failures.len() > PRUNE_THRESHOLD {
+            failures.retain(|_, entry| recent(entry.last));
+        }
+        policy.delay_for(worst)
+    }
+
+    // Forget the failures counted against `key`, e.g. once its user logs in
+    pub fn clear(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    // Hold the response for `delay`, or return at once when too many are already held
+    pub async fn sleep(&self, delay: Duration) {
+        if delay.is_zero() {
+            return;
+        }
+        if let Ok(_permit) = self.sleepers.try_acquire() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, FakeClock};
+
+    #[test]
+    fn delay_escalates_caps_and_expires() {
+        let clock = FakeClock::new(Utc::now());
+        let tarpit = Tarpit::new(10);
+        let policy = TarpitPolicy {
+            base: Duration::from_millis(250),
+            max: Duration::from_secs(1),
+            window: Duration::from_secs(60),
+        };
+        let keys = ["ip:10.0.0.1".to_string(), "user:johndoe".to_string()];
+        let delays: Vec<u128> = (0..4)
+            .map(|_| tarpit.record_failure(&keys, &policy, clock.now()).as_millis())
+            .collect();
+        assert_eq!(delays, [250, 500, 1000, 1000]);
+
+        // A new address still inherits the username's failures
+        let other_ip = ["ip:10.0.0.2".to_string(), "user:johndoe".to_string()];
+        assert_eq!(tarpit.record_failure(&other_ip, &policy, clock.now()).as_millis(), 1000);
+
+        tarpit.clear("user:johndoe");
+        assert_eq!(tarpit.record_failure(&other_ip, &policy, clock.now()).as_millis(), 500);
+
+        clock.advance(chrono::Duration::seconds(60));
+        assert_eq!(tarpit.record_failure(&keys, &policy, clock.now()).as_millis(), 250);
+    }
+}
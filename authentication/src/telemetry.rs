@@ -1,9 +1,14 @@
 use crate::config::Config;
 use opentelemetry::{global, trace::TracerProvider, KeyValue};
-use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
-use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
-use tracing::info;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::SdkTracerProvider,
+    Resource,
+};
+use tracing::{info, warn};
+use std::{sync::Arc, time::Duration};
+use tracing_subscriber::{layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer};
 
 // Create resource with service information
 fn get_resource(config: &Config) -> Resource {
@@ -16,34 +21,93 @@ fn get_resource(config: &Config) -> Resource {
         .build()
 }
 
-// Initialize OpenTelemetry tracer
-fn init_tracer(config: &Config) -> SdkTracerProvider {
-    info!("Initializing OpenTelemetry tracer with endpoint: {}", config.otel_exporter_otlp_endpoint);
+// Initialize OpenTelemetry tracer. A failure to build the exporter doesn't abort startup:
+// the provider is built without an exporter and the error is returned for logging once the
+// subscriber is up. An unreachable collector only causes failed batch exports at runtime.
+fn init_tracer(config: &Config) -> (SdkTracerProvider, Option<String>) {
     // Configure OTLP exporter using HTTP
     let exporter = SpanExporter::builder()
         .with_http()
         .with_endpoint(config.otel_exporter_otlp_endpoint.clone())
         .with_protocol(Protocol::HttpBinary)
-        .build()
-        .expect("Failed to build exporter");
+        .build();
     // Create tracer provider
-    SdkTracerProvider::builder()
+    let builder = SdkTracerProvider::builder().with_resource(get_resource(config));
+    match exporter {
+        Ok(exporter) => (builder.with_batch_exporter(exporter).build(), None),
+        Err(e) => (builder.build(), Some(e.to_string())),
+    }
+}
+
+// OTLP metrics go to the collector that receives traces: a `/v1/traces` endpoint is swapped
+// for `/v1/metrics`, any other endpoint is used as is
+fn metrics_endpoint(traces_endpoint: &str) -> String {
+    match traces_endpoint.strip_suffix("/v1/traces") {
+        Some(base) => format!("{}/v1/metrics", base),
+        None => traces_endpoint.to_string(),
+    }
+}
+
+// Initialize the OTLP metrics pipeline when OTEL_METRICS_ENABLED is set, with the same resource
+// as traces. Like the tracer, a failure to build the exporter is logged rather than fatal.
+pub fn init_meter_provider(config: &Config) -> Option<SdkMeterProvider> {
+    if !config.otel_metrics_enabled {
+        return None;
+    }
+    let endpoint = metrics_endpoint(&config.otel_exporter_otlp_endpoint);
+    let exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint.clone())
+        .with_protocol(Protocol::HttpBinary)
+        .build();
+    let exporter = match exporter {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            warn!("Failed to build OTLP metric exporter, metrics will only be served on /metrics: {}", e);
+            return None;
+        }
+    };
+    let reader = PeriodicReader::builder(exporter)
+        .with_interval(Duration::from_secs(config.otel_metrics_export_interval_seconds.max(1)))
+        .build();
+    let provider = SdkMeterProvider::builder()
         .with_resource(get_resource(config))
-        .with_batch_exporter(exporter)
-        .build()
+        .with_reader(reader)
+        .build();
+    global::set_meter_provider(provider.clone());
+    info!("Initialized OpenTelemetry metrics with endpoint: {}", endpoint);
+    Some(provider)
 }
 
-pub fn init_tracing_subscriber(config: &Config) -> SdkTracerProvider {
-    let tracer_provider = init_tracer(config);
-    global::set_tracer_provider(tracer_provider.clone());
-    let tracer = tracer_provider.tracer("craftista-authentication");
-    let otel_layer = tracing_opentelemetry::layer().with_tracer(tracer);
-    // Create fmt layer to suppress noisy OpenTelemetry debug logs
-    let filter_fmt = EnvFilter::new("info")
+// Swaps the active log level at runtime
+pub type LogLevelReloader = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+// Create fmt filter for the given level that suppresses noisy OpenTelemetry debug logs
+fn build_filter(level: &str, config: &Config) -> Result<EnvFilter, String> {
+    let mut filter_fmt = EnvFilter::try_new(level)
+        .map_err(|e| e.to_string())?
         .add_directive("opentelemetry=info".parse().unwrap())
         .add_directive("opentelemetry_sdk=warn".parse().unwrap())
         .add_directive("opentelemetry_otlp=warn".parse().unwrap())
         .add_directive("opentelemetry_http=warn".parse().unwrap());
+    // Body capture logs at debug level, so let it through when enabled
+    if config.debug_capture_enabled() {
+        filter_fmt = filter_fmt.add_directive("authentication_service::debug_capture=debug".parse().unwrap());
+    }
+    Ok(filter_fmt)
+}
+
+pub fn init_tracing_subscriber(config: &Config, log_level: &str) -> (SdkTracerProvider, LogLevelReloader) {
+    let (tracer_provider, exporter_error) = init_tracer(config);
+    global::set_tracer_provider(tracer_provider.clone());
+    let tracer = tracer_provider.tracer("craftista-authentication");
+    let otel_layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    let (filter_fmt, filter_error) = match build_filter(log_level, config) {
+        Ok(filter) => (filter, None),
+        Err(e) => (build_filter("info", config).expect("default log filter is valid"), Some(e)),
+    };
+    // Wrap the filter so the log level can be reloaded without a restart
+    let (filter_fmt, filter_handle) = reload::Layer::new(filter_fmt);
     let fmt_layer = tracing_subscriber::fmt::layer()
         .with_thread_names(true)
         .with_filter(filter_fmt);
@@ -53,5 +117,32 @@ pub fn init_tracing_subscriber(config: &Config) -> SdkTracerProvider {
         .with(fmt_layer)
         .init();
 
-    tracer_provider
+    match exporter_error {
+        None => info!("Initialized OpenTelemetry tracer with endpoint: {}", config.otel_exporter_otlp_endpoint),
+        Some(e) => warn!("Failed to build OTLP span exporter, traces will not be exported: {}", e),
+    }
+    if let Some(e) = filter_error {
+        warn!("Invalid log level {:?}, falling back to info: {}", log_level, e);
+    }
+
+    let reload_config = config.clone();
+    let reloader: LogLevelReloader = Arc::new(move |level: &str| {
+        let filter = build_filter(level, &reload_config)?;
+        filter_handle.reload(filter).map_err(|e| e.to_string())
+    });
+    (tracer_provider, reloader)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_metrics_to_the_trace_collector() {
+        assert_eq!(
+            metrics_endpoint("http://otel-collector:4318/v1/traces"),
+            "http://otel-collector:4318/v1/metrics"
+        );
+        assert_eq!(metrics_endpoint("http://otel-collector:4318"), "http://otel-collector:4318");
+    }
 }
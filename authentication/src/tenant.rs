@@ -0,0 +1,64 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{request::Parts, HeaderMap},
+};
+use std::convert::Infallible;
+
+use crate::{
+    config::{Config, TenantSource},
+    state::AppState,
+};
+
+// Tenant of requests served by the root issuer under RATE_LIMIT_TENANT_SOURCE=realm
+pub const ROOT_TENANT: &str = "root";
+
+// Longest tenant name taken from a header; longer or oddly formed names are ignored
+const MAX_TENANT_LENGTH: usize = 64;
+
+// The tenant a request is rate limited as, if RATE_LIMIT_TENANT_SOURCE names one
+#[derive(Debug, Clone)]
+pub struct Tenant(pub Option<String>);
+
+fn is_tenant_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_TENANT_LENGTH
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub fn resolve_tenant(config: &Config, headers: &HeaderMap) -> Option<String> {
+    match config.tenant_source {
+        TenantSource::None => None,
+        TenantSource::Realm => Some(config.realm_name.clone().unwrap_or_else(|| ROOT_TENANT.to_string())),
+        TenantSource::Header => headers
+            .get(config.tenant_header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|name| is_tenant_name(name))
+            .map(str::to_string),
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Tenant {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        Ok(Tenant(resolve_tenant(&state.config, &parts.headers)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_plain_tenant_names() {
+        assert!(is_tenant_name("acme"));
+        assert!(is_tenant_name("acme-eu_2.prod"));
+        assert!(!is_tenant_name(""));
+        assert!(!is_tenant_name("acme/ip"));
+        assert!(!is_tenant_name("tenant:acme"));
+        assert!(!is_tenant_name(&"a".repeat(MAX_TENANT_LENGTH + 1)));
+    }
+}
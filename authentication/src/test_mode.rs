@@ -0,0 +1,28 @@
+use jsonwebtoken::Algorithm;
+use std::io;
+
+use crate::{config::Config, key_pem};
+
+// Fixed key pairs used instead of the configured key files in TEST_MODE, so integration tests
+// need no key material and produce reproducible tokens. Public by design; never trust them.
+pub const RSA_PRIVATE_KEY: &str = include_str!("test_keys/rsa_private_key.pem");
+pub const RSA_PUBLIC_KEY: &str = include_str!("test_keys/rsa_public_key.pem");
+pub const PASETO_PRIVATE_KEY: &str = include_str!("test_keys/paseto_private_key.pem");
+pub const EC_PRIVATE_KEY: &str = include_str!("test_keys/ec_private_key.pem");
+pub const EC_PUBLIC_KEY: &str = include_str!("test_keys/ec_public_key.pem");
+
+// Embedded private and public key for signing JWTs with `algorithm`
+pub fn jwt_key_pair(algorithm: Algorithm) -> (&'static str, &'static str) {
+    match algorithm {
+        Algorithm::ES256 => (EC_PRIVATE_KEY, EC_PUBLIC_KEY),
+        _ => (RSA_PRIVATE_KEY, RSA_PUBLIC_KEY),
+    }
+}
+
+// Read and normalize a key PEM from disk, or return the embedded test key when test mode is enabled
+pub fn read_key_pem(config: &Config, path: &str, test_key: &'static str) -> io::Result<String> {
+    if config.test_mode_enabled() {
+        return Ok(test_key.to_string());
+    }
+    key_pem::read(path)
+}
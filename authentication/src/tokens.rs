@@ -0,0 +1,928 @@
+use crate::{
+    config::{AudienceClaimFormat, Config, TokenFormat, UnverifiedEmailPolicy},
+    errors::AppError,
+    issuance_warnings, key_pem,
+    models::{Actor, Audience, Claims, Confirmation},
+    paseto::{self, PasetoError},
+    test_mode::{self, read_key_pem},
+};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey};
+use serde_json::Value;
+use std::collections::HashMap;
+use rsa::pkcs8::der::pem;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{info, warn};
+
+// Coarse-grained reasons a presented token was rejected
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("expired")]
+    Expired,
+    #[error("invalid")]
+    Invalid,
+    // `iat` or `nbf` further ahead of our clock than CLOCK_SKEW_SECONDS allows
+    #[error("issued in the future")]
+    FromTheFuture,
+    // Denylisted along with the session handle it was issued with
+    #[error("revoked")]
+    Revoked,
+    // A claim listed in REQUIRED_CLAIMS is absent or empty
+    #[error("missing required claim {0}")]
+    MissingClaim(String),
+    // Longer than MAX_TOKEN_BYTES, so never decoded. Callers are only told the token is invalid,
+    // so the limit isn't revealed.
+    #[error("too large")]
+    TooLarge,
+}
+
+// Session attributes carried into every access token minted for a login and its refreshes
+#[derive(Debug, Clone)]
+pub struct TokenGrant {
+    pub sub: String,
+    pub role: String,
+    pub aud: Option<String>,
+    pub scope: Option<String>,
+    pub acr: Option<String>,
+    // Unix time of the login the grant descends from, if it came from one
+    pub auth_time: Option<usize>,
+    // Authentication methods used at that login (RFC 8176), e.g. `email` for an emailed code
+    pub amr: Option<Vec<String>>,
+    // Admin acting as the subject, for impersonation tokens
+    pub act: Option<String>,
+    // Thumbprint of the client key the tokens are DPoP-bound to
+    pub jkt: Option<String>,
+    // The user's email verification status, as reported under UNVERIFIED_EMAIL_POLICY
+    pub email_verified: Option<bool>,
+    // ID for the access token, set when a session handle is issued with it
+    pub jti: Option<String>,
+}
+
+// Helper function to load the private key for JWT_SIGNING_ALGORITHM
+fn load_encoding_key(config: &Config) -> Result<EncodingKey, AppError> {
+    let private_key_path = &config.rsa_private_key_path;
+    info!("Loading private key from: {}", private_key_path);
+    let (test_key, _) = test_mode::jwt_key_pair(config.jwt_signing_algorithm);
+    let read = || read_key_pem(config, private_key_path, test_key);
+    key_pem::load_last_good(private_key_path, config.key_fallback_retry(), read, |private_key_pem| {
+        let private_key_pem = private_key_pem
+            .map_err(|e| AppError::KeyLoading(format!("Failed to read private key from {}: {}", private_key_path, e)))?;
+        if config.jwt_signing_algorithm == Algorithm::ES256 {
+            return EncodingKey::from_ec_pem(private_key_pem.as_bytes()).map_err(|e| {
+                AppError::KeyLoading(format!("Failed to parse EC private key {}: {}", private_key_path, key_pem::describe_error(&private_key_pem, e)))
+            });
+        }
+        let private_key_pem = key_pem::decrypt_rsa_private_key(&private_key_pem, config.rsa_private_key_passphrase.as_deref())
+            .map_err(|e| AppError::KeyLoading(format!("Private key {} {}", private_key_path, e)))?;
+        EncodingKey::from_rsa_pem(private_key_pem.as_bytes()).map_err(|e| {
+            AppError::KeyLoading(format!("Failed to parse RSA private key {}: {}", private_key_path, key_pem::describe_error(&private_key_pem, e)))
+        })
+    })
+}
+
+// `x5t#S256` of a PEM X.509 certificate: the base64url SHA-256 digest of its DER encoding
+pub fn certificate_thumbprint(certificate_pem: &str) -> Result<String, String> {
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+
+    let (label, der) = pem::decode_vec(certificate_pem.trim().as_bytes()).map_err(|e| e.to_string())?;
+    if label != "CERTIFICATE" {
+        return Err(format!("expected a CERTIFICATE, found {}", label));
+    }
+    Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(&der)))
+}
+
+// Thumbprint of the signing key's certificate, when RSA_CERTIFICATE_PATH names one
+pub fn load_certificate_thumbprint(config: &Config) -> Result<Option<String>, AppError> {
+    let Some(certificate_path) = &config.rsa_certificate_path else {
+        return Ok(None);
+    };
+    let read = || key_pem::read(certificate_path);
+    key_pem::load_last_good(certificate_path, config.key_fallback_retry(), read, |certificate_pem| {
+        let certificate_pem = certificate_pem
+            .map_err(|e| AppError::KeyLoading(format!("Failed to read certificate from {}: {}", certificate_path, e)))?;
+        certificate_thumbprint(&certificate_pem)
+            .map(Some)
+            .map_err(|e| AppError::KeyLoading(format!("Failed to parse certificate {}: {}", certificate_path, e)))
+    })
+}
+
+// Tokens beyond this size risk exceeding proxy and server header limits
+const LARGE_TOKEN_BYTES: usize = 4096;
+
+// Share of MAX_TOKEN_BYTES an issued token may reach before a warning is logged, so claim growth
+// is noticed before tokens start being rejected
+const TOKEN_SIZE_WARNING_PERCENT: usize = 80;
+
+// Size past which issuing a token logs a warning
+fn token_size_warning_bytes(max_token_bytes: usize) -> usize {
+    match max_token_bytes {
+        0 => LARGE_TOKEN_BYTES,
+        max => LARGE_TOKEN_BYTES.min(max * TOKEN_SIZE_WARNING_PERCENT / 100),
+    }
+}
+
+// Key used to verify tokens in the configured format. JWT keys carry the algorithms they may be
+// used with (JWT_ALLOWED_ALGORITHMS); a token's own `alg` header is never trusted on its own. They
+// also carry CLAIM_NAME_MAP, to read renamed claims back under their internal names.
+pub enum VerificationKey {
+    Jwt {
+        key: DecodingKey,
+        algorithms: Vec<Algorithm>,
+        claim_names: HashMap<String, String>,
+        // AUDIENCE_KEYS by kid, with the audiences each may sign for
+        audience_keys: HashMap<String, (DecodingKey, Vec<String>)>,
+        // JWT_VERIFICATION_KEYS by kid, with the one algorithm each verifies
+        verification_keys: HashMap<String, (DecodingKey, Algorithm)>,
+    },
+    Paseto(UnparsedPublicKey<Vec<u8>>),
+}
+
+// Move each claim in `payload` from the first name of a pair to the second. All claims are taken
+// out before any is put back, so two claims can swap names; a rename onto a claim that stays put
+// is skipped rather than overwriting it.
+fn rename_claims<'a>(payload: &mut Value, renames: impl Iterator<Item = (&'a String, &'a String)>) {
+    let Value::Object(object) = payload else {
+        return;
+    };
+    let renames: Vec<(&String, &String)> = renames.collect();
+    let moving = |name: &String| renames.iter().any(|(from, _)| *from == name);
+    let renames: Vec<_> = renames.iter().filter(|(_, to)| !object.contains_key(to.as_str()) || moving(to)).collect();
+    let moved: Vec<(String, Value)> = renames
+        .into_iter()
+        .filter_map(|(from, to)| object.remove(from.as_str()).map(|value| (to.to_string(), value)))
+        .collect();
+    object.extend(moved);
+}
+
+// Helper function to load the Ed25519 key used for PASETO tokens
+fn load_paseto_key_pair(config: &Config) -> Result<Ed25519KeyPair, AppError> {
+    let private_key_path = &config.paseto_private_key_path;
+    info!("Loading PASETO key from: {}", private_key_path);
+    let read = || read_key_pem(config, private_key_path, test_mode::PASETO_PRIVATE_KEY);
+    key_pem::load_last_good(private_key_path, config.key_fallback_retry(), read, |private_key_pem| {
+        let private_key_pem = private_key_pem
+            .map_err(|e| AppError::KeyLoading(format!("Failed to read PASETO key from {}: {}", private_key_path, e)))?;
+        paseto::key_pair_from_pem(&private_key_pem).map_err(|e| {
+            AppError::KeyLoading(format!("Failed to parse PASETO key {}: {}", private_key_path, key_pem::describe_error(&private_key_pem, e)))
+        })
+    })
+}
+
+// Lifetime of access tokens issued to users
+pub const ACCESS_TOKEN_TTL_SECONDS: i64 = 3600;
+
+// Clamp a configured token lifetime to the MAX_TOKEN_TTL_SECONDS ceiling
+pub fn clamp_ttl(kind: &str, ttl_seconds: i64, max_ttl_seconds: i64) -> i64 {
+    if ttl_seconds > max_ttl_seconds {
+        warn!(
+            "Configured {} lifetime of {}s exceeds the {}s maximum; clamping",
+            kind, ttl_seconds, max_ttl_seconds
+        );
+        issuance_warnings::add(format!("{} lifetime clamped to the {}s MAX_TOKEN_TTL_SECONDS", kind, max_ttl_seconds));
+        return max_ttl_seconds;
+    }
+    ttl_seconds
+}
+
+// The `aud` claim for an audience, in the shape AUDIENCE_CLAIM_FORMAT asks for
+pub fn audience_claim(format: AudienceClaimFormat, audience: String) -> Audience {
+    match format {
+        AudienceClaimFormat::String => Audience::Single(audience),
+        AudienceClaimFormat::Array => Audience::Multiple(vec![audience]),
+    }
+}
+
+// Mint an access token for the grant issued at `now`, returning the token and its lifetime in seconds
+pub fn issue_access_token(
+    config: &Config,
+    grant: &TokenGrant,
+    ttl_seconds: i64,
+    now: DateTime<Utc>,
+) -> Result<(String, i64), AppError> {
+    let ttl_seconds = clamp_ttl("access token", ttl_seconds, config.max_token_ttl_seconds);
+    // Sessions of unverified users are kept short so they have to come back and verify
+    let ttl_seconds = match grant.email_verified {
+        Some(false) => ttl_seconds.min(config.unverified_email_token_ttl_seconds),
+        _ => ttl_seconds,
+    };
+
+    // Set token expiration time
+    let expiration = now
+        .checked_add_signed(Duration::seconds(ttl_seconds))
+        .expect("valid timestamp")
+        .timestamp() as usize;
+    let issued_at = now.timestamp() as usize;
+
+    if config.include_permissions && !config.role_permissions.contains_key(&grant.role) {
+        issuance_warnings::add(format!("role {} has no ROLE_PERMISSIONS entry, so the token carries no permissions", grant.role));
+    }
+
+    // Create the JWT claims
+    let claims = Claims {
+        sub: grant.sub.clone(),
+        role: grant.role.clone(),
+        exp: expiration,
+        iat: issued_at,
+        nbf: None,
+        iss: config.jwt_issuer.clone(),
+        aud: grant.aud.clone().map(|aud| audience_claim(config.audience_claim_format, aud)),
+        scope: grant.scope.clone(),
+        acr: grant.acr.clone(),
+        auth_time: grant.auth_time,
+        amr: grant.amr.clone(),
+        act: grant.act.clone().map(|sub| Actor { sub }),
+        permissions: config
+            .include_permissions
+            .then(|| config.role_permissions.get(&grant.role).cloned())
+            .flatten(),
+        cnf: grant.jkt.clone().map(|jkt| Confirmation { jkt }),
+        email_verified: grant.email_verified,
+        jti: grant.jti.clone(),
+    };
+
+    let token = match config.token_format {
+        TokenFormat::Jwt => {
+            // Sign with the audience's own key when it has one
+            let audience_config;
+            let config = match config.audience_key(grant.aud.as_deref()) {
+                Some(key) => {
+                    audience_config = config.with_audience_key(key);
+                    &audience_config
+                }
+                None => config,
+            };
+            // Load RSA private key and create token with RS256
+            let encoding_key = load_encoding_key(config)?;
+            let mut header = Header::new(config.jwt_signing_algorithm);
+            header.typ = Some(config.access_token_type.as_str().to_string());
+            header.kid = Some(config.product_key_id.clone());
+            header.x5t_s256 = load_certificate_thumbprint(config)?;
+            let mut payload = serde_json::to_value(&claims).map_err(jsonwebtoken::errors::Error::from)?;
+            rename_claims(&mut payload, config.claim_name_map.iter());
+            encode(&header, &payload, &encoding_key)?
+        }
+        TokenFormat::Paseto => {
+            let key_pair = load_paseto_key_pair(config)?;
+            paseto::sign(&key_pair, &claims, &config.product_key_id).map_err(jsonwebtoken::errors::Error::from)?
+        }
+    };
+    if token.len() > token_size_warning_bytes(config.max_token_bytes) {
+        warn!(
+            "Issued a {} byte access token for {} (MAX_TOKEN_BYTES is {}); consider trimming permissions or scopes",
+            token.len(),
+            grant.sub,
+            config.max_token_bytes
+        );
+        issuance_warnings::add(format!("access token is {} bytes, close to the MAX_TOKEN_BYTES limit", token.len()));
+    }
+    Ok((token, (expiration - issued_at) as i64))
+}
+
+// Parse a public key for verifying `algorithm`: a P-256 key for ES256, an RSA key otherwise
+fn decoding_key_from_pem(algorithm: Algorithm, pem: &str) -> Result<DecodingKey, String> {
+    let key = match algorithm {
+        Algorithm::ES256 => DecodingKey::from_ec_pem(pem.as_bytes()),
+        _ => DecodingKey::from_rsa_pem(pem.as_bytes()),
+    };
+    key.map_err(|e| key_pem::describe_error(pem, e))
+}
+
+// Helper function to load the public key for token verification
+fn load_decoding_key(config: &Config) -> Result<DecodingKey, AppError> {
+    let public_key_path = &config.rsa_public_key_path;
+    info!("Loading public key from: {}", public_key_path);
+    let (_, test_key) = test_mode::jwt_key_pair(config.jwt_signing_algorithm);
+    let read = || read_key_pem(config, public_key_path, test_key);
+    key_pem::load_last_good(public_key_path, config.key_fallback_retry(), read, |public_key_pem| {
+        let public_key_pem = public_key_pem
+            .map_err(|e| AppError::KeyLoading(format!("Failed to read public key from {}: {}", public_key_path, e)))?;
+        decoding_key_from_pem(config.jwt_signing_algorithm, &public_key_pem).map_err(|e| {
+            AppError::KeyLoading(format!("Failed to parse {:?} public key {}: {}", config.jwt_signing_algorithm, public_key_path, e))
+        })
+    })
+}
+
+// Public keys of AUDIENCE_KEYS by kid. Like retired keys in the JWKS, one that can't be read is
+// left out, so only tokens it signed fail to verify.
+fn load_audience_decoding_keys(config: &Config) -> HashMap<String, (DecodingKey, Vec<String>)> {
+    config
+        .audience_keys
+        .iter()
+        .filter_map(|audience_key| {
+            let path = &audience_key.public_key_path;
+            let key = key_pem::load_last_good(path, config.key_fallback_retry(), || key_pem::read(path), |pem| {
+                pem.map_err(|e| e.to_string())
+                    .and_then(|pem| decoding_key_from_pem(config.jwt_signing_algorithm, &pem))
+            });
+            match key {
+                Ok(key) => Some((audience_key.kid.clone(), (key, audience_key.audiences.clone()))),
+                Err(e) => {
+                    warn!("Skipping audience key {} ({}): {}", audience_key.kid, path, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Public keys of JWT_VERIFICATION_KEYS by kid. One that can't be read is left out with a warning,
+// so only the tokens it signed fail to verify.
+fn load_jwt_verification_keys(config: &Config) -> HashMap<String, (DecodingKey, Algorithm)> {
+    config
+        .jwt_verification_keys
+        .iter()
+        .filter_map(|verification_key| {
+            let path = &verification_key.public_key_path;
+            let key = key_pem::load_last_good(path, config.key_fallback_retry(), || key_pem::read(path), |pem| {
+                pem.map_err(|e| e.to_string())
+                    .and_then(|pem| decoding_key_from_pem(verification_key.algorithm, &pem))
+            });
+            match key {
+                Ok(key) => Some((verification_key.kid.clone(), (key, verification_key.algorithm))),
+                Err(e) => {
+                    warn!("Skipping verification key {} ({}): {}", verification_key.kid, path, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Load the key that verifies tokens in the configured format
+pub fn load_verification_key(config: &Config) -> Result<VerificationKey, AppError> {
+    match config.token_format {
+        TokenFormat::Jwt => Ok(VerificationKey::Jwt {
+            key: load_decoding_key(config)?,
+            algorithms: config.jwt_allowed_algorithms.clone(),
+            claim_names: config.claim_name_map.clone(),
+            audience_keys: load_audience_decoding_keys(config),
+            verification_keys: load_jwt_verification_keys(config),
+        }),
+        TokenFormat::Paseto => Ok(VerificationKey::Paseto(paseto::public_key(&load_paseto_key_pair(config)?))),
+    }
+}
+
+// Verify a token issued by this service and return its claims, judging expiry as of `now`, with
+// up to `skew` of clock difference tolerated. The `iss` claim must match `issuer` exactly, so one
+// realm's tokens are never accepted by another, and every claim named in `required_claims` must be
+// present. Tokens longer than `max_bytes` (unless 0) are rejected before any decoding.
+pub fn verify_token(
+    key: &VerificationKey,
+    token: &str,
+    issuer: Option<&str>,
+    required_claims: &[String],
+    now: DateTime<Utc>,
+    skew: Duration,
+    max_bytes: usize,
+) -> Result<Claims, TokenError> {
+    if max_bytes > 0 && token.len() > max_bytes {
+        return Err(TokenError::TooLarge);
+    }
+    let claims = match key {
+        VerificationKey::Jwt { key, algorithms, claim_names, audience_keys, verification_keys } => {
+            let keys = JwtKeys { key, audience_keys, verification_keys };
+            verify_jwt(&keys, algorithms, claim_names, token, now, skew)
+        }
+        VerificationKey::Paseto(public_key) => paseto::verify(public_key, token, now).map_err(|e| match e {
+            PasetoError::Expired => TokenError::Expired,
+            PasetoError::Invalid => TokenError::Invalid,
+        }),
+    }?;
+    check_required_claims(&claims, required_claims)?;
+    if claims.iss.as_deref() != issuer {
+        return Err(TokenError::Invalid);
+    }
+    check_not_from_the_future(&claims, now, skew)?;
+    Ok(claims)
+}
+
+// Fail closed on under-specified tokens. Claims are judged by name on the token's serialized form,
+// so an optional claim that was left out, null, an empty string or an empty array all count as missing.
+fn check_required_claims(claims: &Claims, required_claims: &[String]) -> Result<(), TokenError> {
+    let Ok(serde_json::Value::Object(present)) = serde_json::to_value(claims) else {
+        return Err(TokenError::Invalid);
+    };
+    let missing = required_claims.iter().find(|name| match present.get(name.as_str()) {
+        None | Some(serde_json::Value::Null) => true,
+        Some(serde_json::Value::String(value)) => value.is_empty(),
+        Some(serde_json::Value::Array(values)) => values.is_empty(),
+        Some(_) => false,
+    });
+    match missing {
+        Some(name) => Err(TokenError::MissingClaim(name.clone())),
+        None => Ok(()),
+    }
+}
+
+// A token issued or becoming valid well after `now` points at a broken clock on whoever minted or
+// presented it, rather than the small drift `skew` exists to absorb
+fn check_not_from_the_future(claims: &Claims, now: DateTime<Utc>, skew: Duration) -> Result<(), TokenError> {
+    let ahead = [Some(claims.iat), claims.nbf]
+        .into_iter()
+        .flatten()
+        .map(|t| t as i64 - now.timestamp())
+        .max()
+        .unwrap_or(0);
+    if ahead > skew.num_seconds() {
+        warn!(
+            target: "security",
+            "Rejected token for {} dated {}s in the future (allowed skew {}s); check the issuing and presenting clocks",
+            claims.sub,
+            ahead,
+            skew.num_seconds()
+        );
+        return Err(TokenError::FromTheFuture);
+    }
+    Ok(())
+}
+
+// The keys a JWT may be verified with
+struct JwtKeys<'a> {
+    key: &'a DecodingKey,
+    audience_keys: &'a HashMap<String, (DecodingKey, Vec<String>)>,
+    verification_keys: &'a HashMap<String, (DecodingKey, Algorithm)>,
+}
+
+impl<'a> JwtKeys<'a> {
+    // The keys to try for a token with this header, and the audiences the token must be addressed
+    // to if it names an audience key. A `kid` naming an audience or verification key selects just
+    // that key, and a verification key only for its own algorithm. Any other token may be signed
+    // by the active key or by any verification key for its `alg`.
+    fn candidates(&self, kid: Option<&str>, alg: Algorithm) -> (Vec<&'a DecodingKey>, Option<&'a Vec<String>>) {
+        if let Some((key, audiences)) = kid.and_then(|kid| self.audience_keys.get(kid)) {
+            return (vec![key], Some(audiences));
+        }
+        if let Some((key, algorithm)) = kid.and_then(|kid| self.verification_keys.get(kid)) {
+            return (if *algorithm == alg { vec![key] } else { Vec::new() }, None);
+        }
+        let for_alg = self.verification_keys.values().filter(|(_, algorithm)| *algorithm == alg).map(|(key, _)| key);
+        (std::iter::once(self.key).chain(for_alg).collect(), None)
+    }
+}
+
+fn verify_jwt(
+    keys: &JwtKeys<'_>,
+    algorithms: &[Algorithm],
+    claim_names: &HashMap<String, String>,
+    token: &str,
+    now: DateTime<Utc>,
+    skew: Duration,
+) -> Result<Claims, TokenError> {
+    // Reject a disallowed `alg` (e.g. `none` or HS256 keyed with our public key) before touching
+    // the signature; `none` doesn't even parse as an algorithm
+    let header = decode_header(token).map_err(|_| TokenError::Invalid)?;
+    if !algorithms.contains(&header.alg) {
+        return Err(TokenError::Invalid);
+    }
+    // A per-audience key only vouches for tokens addressed to its own audiences
+    let (candidates, audiences) = keys.candidates(header.kid.as_deref(), header.alg);
+    // Only the token's own (allowed) algorithm, since jsonwebtoken refuses a key unless every
+    // listed algorithm suits it, and RSA and EC keys can both be configured
+    let mut validation = Validation::new(header.alg);
+    // As the issuer we accept tokens minted for any audience; resource servers check their own
+    validation.validate_aud = false;
+    // Expiry is checked against our clock below rather than jsonwebtoken's wall-clock check
+    validation.validate_exp = false;
+    validation.leeway = skew.num_seconds().max(0) as u64;
+    let mut payload = candidates
+        .into_iter()
+        .find_map(|key| decode::<Value>(token, key, &validation).ok())
+        .map(|data| data.claims)
+        .ok_or(TokenError::Invalid)?;
+    rename_claims(&mut payload, claim_names.iter().map(|(internal, output)| (output, internal)));
+    let claims: Claims = serde_json::from_value(payload).map_err(|_| TokenError::Invalid)?;
+    let addressed_to_key = |audiences: &Vec<String>| {
+        claims.aud.as_ref().is_some_and(|aud| audiences.iter().any(|audience| aud.contains(audience)))
+    };
+    if audiences.is_some_and(|audiences| !addressed_to_key(audiences)) {
+        return Err(TokenError::Invalid);
+    }
+    if (claims.exp as i64) < now.timestamp() - validation.leeway as i64 {
+        return Err(TokenError::Expired);
+    }
+    Ok(claims)
+}
+
+// The `email_verified` value to issue tokens with for a user whose address is (or isn't)
+// `verified`, refusing them outright under UNVERIFIED_EMAIL_POLICY=block
+pub fn email_verified_claim(config: &Config, verified: bool) -> Result<Option<bool>, AppError> {
+    match config.unverified_email_policy {
+        UnverifiedEmailPolicy::Allow => Ok(None),
+        UnverifiedEmailPolicy::Warn => Ok(Some(verified)),
+        UnverifiedEmailPolicy::Block if verified => Ok(Some(true)),
+        UnverifiedEmailPolicy::Block => Err(AppError::EmailUnverified),
+    }
+}
+
+// Require that the token's user presented credentials within `max_age` of `now`, judged by
+// `auth_time` (or `iat` for tokens without one). A zero `max_age` imposes no requirement.
+pub fn check_recent_auth(claims: &Claims, max_age: Duration, now: DateTime<Utc>) -> Result<(), AppError> {
+    if max_age <= Duration::zero() {
+        return Ok(());
+    }
+    let authenticated_at = claims.auth_time.unwrap_or(claims.iat) as i64;
+    if now.timestamp() - authenticated_at > max_age.num_seconds() {
+        return Err(AppError::ReauthRequired(max_age.num_seconds() as u64));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine as _};
+
+    const SKEW: Duration = Duration::seconds(60);
+    const MAX_BYTES: usize = 8192;
+
+    fn test_key() -> VerificationKey {
+        VerificationKey::Jwt {
+            key: DecodingKey::from_rsa_pem(test_mode::RSA_PUBLIC_KEY.as_bytes()).unwrap(),
+            algorithms: vec![Algorithm::RS256],
+            claim_names: HashMap::new(),
+            audience_keys: HashMap::new(),
+            verification_keys: HashMap::new(),
+        }
+    }
+
+    fn test_claims(now: DateTime<Utc>) -> Claims {
+        Claims {
+            sub: "johndoe".to_string(),
+            role: "admin".to_string(),
+            exp: (now + Duration::seconds(3600)).timestamp() as usize,
+            iat: now.timestamp() as usize,
+            nbf: None,
+            iss: None,
+            aud: None,
+            scope: None,
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            permissions: None,
+            cnf: None,
+            email_verified: None,
+            jti: None,
+        }
+    }
+
+    #[test]
+    fn clamps_ttl_above_the_maximum() {
+        assert_eq!(clamp_ttl("access token", 10 * 365 * 24 * 3600, 86_400), 86_400);
+    }
+
+    #[test]
+    fn keeps_ttl_within_the_maximum() {
+        assert_eq!(clamp_ttl("access token", 3600, 86_400), 3600);
+        assert_eq!(clamp_ttl("access token", 86_400, 86_400), 86_400);
+    }
+
+    #[test]
+    fn judges_expiry_by_the_supplied_clock() {
+        let issued_at = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let claims = Claims {
+            sub: "johndoe".to_string(),
+            role: "user".to_string(),
+            exp: (issued_at + Duration::seconds(3600)).timestamp() as usize,
+            iat: issued_at.timestamp() as usize,
+            nbf: None,
+            iss: None,
+            aud: None,
+            scope: None,
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            permissions: None,
+            cnf: None,
+            email_verified: None,
+            jti: None,
+        };
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let header = Header::new(Algorithm::RS256);
+        let token = encode(&header, &claims, &encoding_key).unwrap();
+        // RS256 signatures are deterministic, so a fixed clock gives a reproducible token
+        assert_eq!(token, encode(&header, &claims, &encoding_key).unwrap());
+
+        let key = test_key();
+        assert!(verify_token(&key, &token, None, &[], issued_at + Duration::seconds(3599), SKEW, MAX_BYTES).is_ok());
+        assert!(matches!(
+            verify_token(&key, &token, None, &[], issued_at + Duration::seconds(7200), SKEW, MAX_BYTES),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversized_tokens_before_decoding() {
+        let now = Utc::now();
+        let mut claims = test_claims(now);
+        claims.permissions = Some((0..400).map(|i| format!("catalogue:item-{}:write", i)).collect());
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let token = encode(&Header::new(Algorithm::RS256), &claims, &encoding_key).unwrap();
+        assert!(token.len() > MAX_BYTES);
+
+        let error = verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES).unwrap_err();
+        assert!(matches!(error, TokenError::TooLarge));
+        assert!(!error.to_string().contains("8192"));
+        // Garbage is refused on its size alone, never reaching the parser
+        let garbage = "x".repeat(MAX_BYTES + 1);
+        assert!(matches!(verify_token(&test_key(), &garbage, None, &[], now, SKEW, MAX_BYTES), Err(TokenError::TooLarge)));
+        assert!(verify_token(&test_key(), &token, None, &[], now, SKEW, 0).is_ok());
+
+        assert_eq!(token_size_warning_bytes(MAX_BYTES), LARGE_TOKEN_BYTES);
+        assert_eq!(token_size_warning_bytes(2000), 1600);
+        assert_eq!(token_size_warning_bytes(0), LARGE_TOKEN_BYTES);
+    }
+
+    #[test]
+    fn rejects_tokens_from_another_issuer() {
+        let now = Utc::now();
+        let claims = Claims {
+            sub: "johndoe".to_string(),
+            role: "user".to_string(),
+            exp: (now + Duration::seconds(3600)).timestamp() as usize,
+            iat: now.timestamp() as usize,
+            nbf: None,
+            iss: Some("https://auth.example.com/realms/acme".to_string()),
+            aud: None,
+            scope: None,
+            acr: None,
+            auth_time: None,
+            amr: None,
+            act: None,
+            permissions: None,
+            cnf: None,
+            email_verified: None,
+            jti: None,
+        };
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let token = encode(&Header::new(Algorithm::RS256), &claims, &encoding_key).unwrap();
+        let key = test_key();
+
+        assert!(verify_token(&key, &token, Some("https://auth.example.com/realms/acme"), &[], now, SKEW, MAX_BYTES).is_ok());
+        // Even with a shared key, another realm or the default issuer must not accept it
+        assert!(verify_token(&key, &token, Some("https://auth.example.com/realms/globex"), &[], now, SKEW, MAX_BYTES).is_err());
+        assert!(verify_token(&key, &token, None, &[], now, SKEW, MAX_BYTES).is_err());
+    }
+
+    #[test]
+    fn rejects_unsigned_tokens() {
+        let now = Utc::now();
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&test_claims(now)).unwrap());
+        for token in [format!("{}.{}.", header, payload), format!("{}.{}", header, payload)] {
+            assert!(matches!(verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES), Err(TokenError::Invalid)));
+        }
+    }
+
+    #[test]
+    fn rejects_hmac_tokens_keyed_with_the_public_key() {
+        // Algorithm confusion: HS256 "signed" with the published RSA public key as the secret
+        let now = Utc::now();
+        let encoding_key = EncodingKey::from_secret(test_mode::RSA_PUBLIC_KEY.as_bytes());
+        let token = encode(&Header::new(Algorithm::HS256), &test_claims(now), &encoding_key).unwrap();
+        assert!(matches!(verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES), Err(TokenError::Invalid)));
+
+        // A genuine RS256 token is only accepted while RS256 is on the allowlist
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let token = encode(&Header::new(Algorithm::RS256), &test_claims(now), &encoding_key).unwrap();
+        assert!(verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES).is_ok());
+        let ps256_only = VerificationKey::Jwt {
+            key: DecodingKey::from_rsa_pem(test_mode::RSA_PUBLIC_KEY.as_bytes()).unwrap(),
+            algorithms: vec![Algorithm::PS256],
+            claim_names: HashMap::new(),
+            audience_keys: HashMap::new(),
+            verification_keys: HashMap::new(),
+        };
+        assert!(matches!(verify_token(&ps256_only, &token, None, &[], now, SKEW, MAX_BYTES), Err(TokenError::Invalid)));
+    }
+
+    #[test]
+    fn rejects_tokens_missing_a_required_claim() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let required: Vec<String> = ["sub", "exp", "iat", "iss", "aud"].map(str::to_string).to_vec();
+        let issuer = "https://auth.example.com";
+        let mut complete = test_claims(now);
+        complete.iss = Some(issuer.to_string());
+        complete.aud = Some(Audience::Single("catalogue".to_string()));
+        let complete = serde_json::to_value(&complete).unwrap();
+        let sign = |payload: &serde_json::Value| encode(&Header::new(Algorithm::RS256), payload, &encoding_key).unwrap();
+
+        assert!(verify_token(&test_key(), &sign(&complete), Some(issuer), &required, now, SKEW, MAX_BYTES).is_ok());
+        for claim in &required {
+            let mut payload = complete.clone();
+            payload.as_object_mut().unwrap().remove(claim.as_str());
+            let result = verify_token(&test_key(), &sign(&payload), Some(issuer), &required, now, SKEW, MAX_BYTES);
+            assert!(result.is_err(), "accepted a token without {}", claim);
+        }
+
+        // Optional claims are reported by name, and an empty value is as good as none
+        let mut payload = complete.clone();
+        payload["aud"] = serde_json::json!("");
+        let error = verify_token(&test_key(), &sign(&payload), Some(issuer), &required, now, SKEW, MAX_BYTES).unwrap_err();
+        assert_eq!(error.to_string(), "missing required claim aud");
+        payload["sub"] = serde_json::json!("");
+        assert!(verify_token(&test_key(), &sign(&payload), Some(issuer), &[], now, SKEW, MAX_BYTES).is_ok());
+    }
+
+    #[test]
+    fn rejects_tokens_dated_beyond_the_allowed_skew() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let sign = |claims: &Claims| encode(&Header::new(Algorithm::RS256), claims, &encoding_key).unwrap();
+
+        // A little drift is tolerated
+        let token = sign(&test_claims(now + Duration::seconds(30)));
+        assert!(verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES).is_ok());
+
+        // Issued ten minutes from now: a broken clock, not skew
+        let token = sign(&test_claims(now + Duration::minutes(10)));
+        let error = verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES).unwrap_err();
+        assert!(matches!(error, TokenError::FromTheFuture));
+        assert_eq!(error.to_string(), "issued in the future");
+        assert!(verify_token(&test_key(), &token, None, &[], now, Duration::minutes(15), MAX_BYTES).is_ok());
+
+        // Not valid before an hour from now
+        let mut claims = test_claims(now);
+        claims.nbf = Some((now + Duration::hours(1)).timestamp() as usize);
+        let token = sign(&claims);
+        assert!(matches!(verify_token(&test_key(), &token, None, &[], now, SKEW, MAX_BYTES), Err(TokenError::FromTheFuture)));
+    }
+
+    #[test]
+    fn requires_recent_authentication() {
+        let login = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let max_age = Duration::seconds(300);
+        // Refreshed an hour after login: `iat` is recent but `auth_time` is not
+        let mut claims = test_claims(login + Duration::hours(1));
+        claims.auth_time = Some(login.timestamp() as usize);
+
+        assert!(check_recent_auth(&claims, max_age, login + Duration::seconds(300)).is_ok());
+        let error = check_recent_auth(&claims, max_age, login + Duration::hours(1)).unwrap_err();
+        assert_eq!(error.code(), "reauth_required");
+        assert_eq!(error.status(), axum::http::StatusCode::UNAUTHORIZED);
+        assert!(check_recent_auth(&claims, Duration::zero(), login + Duration::days(1)).is_ok());
+
+        // Without `auth_time`, the token's issue time counts
+        claims.auth_time = None;
+        assert!(check_recent_auth(&claims, max_age, login + Duration::hours(1)).is_ok());
+    }
+
+    #[test]
+    fn renames_claims_on_issue_and_back_on_verify() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let claim_names: HashMap<String, String> =
+            [("role", "https://example.com/role"), ("sub", "user")].map(|(a, b)| (a.to_string(), b.to_string())).into();
+
+        let mut payload = serde_json::to_value(test_claims(now)).unwrap();
+        rename_claims(&mut payload, claim_names.iter());
+        assert_eq!(payload["https://example.com/role"], "admin");
+        assert_eq!(payload["user"], "johndoe");
+        assert!(payload.get("role").is_none() && payload.get("sub").is_none());
+
+        let token = encode(&Header::new(Algorithm::RS256), &payload, &encoding_key).unwrap();
+        let key = VerificationKey::Jwt {
+            key: DecodingKey::from_rsa_pem(test_mode::RSA_PUBLIC_KEY.as_bytes()).unwrap(),
+            algorithms: vec![Algorithm::RS256],
+            claim_names,
+            audience_keys: HashMap::new(),
+            verification_keys: HashMap::new(),
+        };
+        let required = ["sub".to_string()];
+        let claims = verify_token(&key, &token, None, &required, now, SKEW, MAX_BYTES).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.role.as_str()), ("johndoe", "admin"));
+        // Without the map the token lacks the internal names
+        assert!(verify_token(&test_key(), &token, None, &required, now, SKEW, MAX_BYTES).is_err());
+
+        // Swapping two names works; renaming onto a claim that stays is skipped
+        let swap = [("role", "scope"), ("scope", "role")].map(|(a, b)| (a.to_string(), b.to_string()));
+        let mut payload = serde_json::json!({ "role": "admin", "scope": "read", "sub": "johndoe" });
+        rename_claims(&mut payload, swap.iter().map(|(a, b)| (a, b)));
+        assert_eq!(payload, serde_json::json!({ "role": "read", "scope": "admin", "sub": "johndoe" }));
+        let onto = [("role".to_string(), "sub".to_string())];
+        rename_claims(&mut payload, onto.iter().map(|(a, b)| (a, b)));
+        assert_eq!(payload["sub"], "johndoe");
+    }
+
+    // The default key plus a `partner-a` audience key that signs for `catalogue`
+    fn partner_key() -> VerificationKey {
+        let public_key = || DecodingKey::from_rsa_pem(test_mode::RSA_PUBLIC_KEY.as_bytes()).unwrap();
+        VerificationKey::Jwt {
+            key: public_key(),
+            algorithms: vec![Algorithm::RS256],
+            claim_names: HashMap::new(),
+            audience_keys: HashMap::from([("partner-a".to_string(), (public_key(), vec!["catalogue".to_string()]))]),
+            verification_keys: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn audience_keys_only_verify_their_own_audiences() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let key = partner_key();
+        let sign = |kid: Option<&str>, aud: Option<&str>| {
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = kid.map(str::to_string);
+            let mut claims = test_claims(now);
+            claims.aud = aud.map(|aud| Audience::Single(aud.to_string()));
+            encode(&header, &claims, &encoding_key).unwrap()
+        };
+
+        assert!(verify_token(&key, &sign(Some("partner-a"), Some("catalogue")), None, &[], now, SKEW, MAX_BYTES).is_ok());
+        for aud in [Some("orders"), None] {
+            let result = verify_token(&key, &sign(Some("partner-a"), aud), None, &[], now, SKEW, MAX_BYTES);
+            assert!(matches!(result, Err(TokenError::Invalid)), "accepted a partner-a token for {:?}", aud);
+        }
+        // Other kids fall back to the default key, for any audience
+        assert!(verify_token(&key, &sign(Some("auth-key-1"), Some("orders")), None, &[], now, SKEW, MAX_BYTES).is_ok());
+        assert!(verify_token(&key, &sign(None, None), None, &[], now, SKEW, MAX_BYTES).is_ok());
+    }
+
+    #[test]
+    fn accepts_the_audience_as_a_string_or_an_array() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let encoding_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let sign = |aud: serde_json::Value| {
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some("partner-a".to_string());
+            let mut payload = serde_json::to_value(test_claims(now)).unwrap();
+            payload["aud"] = aud;
+            encode(&header, &payload, &encoding_key).unwrap()
+        };
+        let verify = |aud: serde_json::Value| verify_token(&partner_key(), &sign(aud), None, &[], now, SKEW, MAX_BYTES);
+
+        let single = verify(serde_json::json!("catalogue")).unwrap();
+        assert_eq!(single.aud, Some(Audience::Single("catalogue".to_string())));
+        let array = verify(serde_json::json!(["catalogue"])).unwrap();
+        assert_eq!(array.aud, Some(Audience::Multiple(vec!["catalogue".to_string()])));
+        // Any one of several audiences is enough
+        let multiple = verify(serde_json::json!(["orders", "catalogue"])).unwrap();
+        assert!(multiple.aud.as_ref().is_some_and(|aud| aud.contains("orders") && aud.contains("catalogue")));
+        for aud in [serde_json::json!(["orders", "billing"]), serde_json::json!([]), serde_json::json!(42)] {
+            assert!(verify(aud.clone()).is_err(), "accepted a partner-a token for {}", aud);
+        }
+
+        // Issued in the configured shape
+        let issued = |format| serde_json::to_value(audience_claim(format, "catalogue".to_string())).unwrap();
+        assert_eq!(issued(AudienceClaimFormat::String), serde_json::json!("catalogue"));
+        assert_eq!(issued(AudienceClaimFormat::Array), serde_json::json!(["catalogue"]));
+    }
+
+    #[test]
+    fn verifies_rs256_and_es256_tokens_during_a_migration() {
+        let now = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let rsa_key = EncodingKey::from_rsa_pem(test_mode::RSA_PRIVATE_KEY.as_bytes()).unwrap();
+        let ec_key = EncodingKey::from_ec_pem(test_mode::EC_PRIVATE_KEY.as_bytes()).unwrap();
+        let sign = |algorithm, kid: Option<&str>| {
+            let mut header = Header::new(algorithm);
+            header.kid = kid.map(str::to_string);
+            let key = if algorithm == Algorithm::ES256 { &ec_key } else { &rsa_key };
+            encode(&header, &test_claims(now), key).unwrap()
+        };
+        // Signing has moved to ES256; the old RS256 key is still listed as `old-key`
+        let mixed = |algorithms: Vec<Algorithm>| VerificationKey::Jwt {
+            key: DecodingKey::from_ec_pem(test_mode::EC_PUBLIC_KEY.as_bytes()).unwrap(),
+            algorithms,
+            claim_names: HashMap::new(),
+            audience_keys: HashMap::new(),
+            verification_keys: HashMap::from([(
+                "old-key".to_string(),
+                (DecodingKey::from_rsa_pem(test_mode::RSA_PUBLIC_KEY.as_bytes()).unwrap(), Algorithm::RS256),
+            )]),
+        };
+        let key = mixed(vec![Algorithm::RS256, Algorithm::ES256]);
+        let verify = |key: &VerificationKey, token: &str| verify_token(key, token, None, &[], now, SKEW, MAX_BYTES);
+
+        assert_eq!(verify(&key, &sign(Algorithm::RS256, Some("old-key"))).unwrap().sub, "johndoe");
+        assert_eq!(verify(&key, &sign(Algorithm::ES256, Some("new-key"))).unwrap().sub, "johndoe");
+        assert_eq!(verify(&key, &sign(Algorithm::ES256, None)).unwrap().sub, "johndoe");
+        // Without a kid the RS256 token is tried against every RS256 key
+        assert!(verify(&key, &sign(Algorithm::RS256, None)).is_ok());
+
+        // A verification key only vouches for its own algorithm
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("old-key".to_string());
+        let mismatched = encode(&header, &test_claims(now), &ec_key).unwrap();
+        assert!(matches!(verify(&key, &mismatched), Err(TokenError::Invalid)));
+        // Once RS256 leaves the allowlist its tokens stop verifying
+        let es256_only = mixed(vec![Algorithm::ES256]);
+        assert!(matches!(verify(&es256_only, &sign(Algorithm::RS256, Some("old-key"))), Err(TokenError::Invalid)));
+        assert!(verify(&es256_only, &sign(Algorithm::ES256, None)).is_ok());
+    }
+
+    #[test]
+    fn thumbprints_the_der_of_a_certificate() {
+        let der = b"not really a certificate";
+        let wrap = |label: &str| {
+            format!("-----BEGIN {0}-----\n{1}\n-----END {0}-----\n", label, STANDARD.encode(der))
+        };
+        assert_eq!(certificate_thumbprint(&wrap("CERTIFICATE")).unwrap(), URL_SAFE_NO_PAD.encode(Sha256::digest(der)));
+        assert!(certificate_thumbprint(&wrap("PUBLIC KEY")).is_err());
+        assert!(certificate_thumbprint("not pem").is_err());
+    }
+}
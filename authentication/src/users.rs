@@ -0,0 +1,316 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::{stream::BoxStream, StreamExt};
+use sqlx::{postgres::PgRow, PgPool, Postgres, Row};
+
+use crate::{
+    confusables,
+    errors::AppError,
+    metrics,
+    geo::GeoPoint,
+    models::{User, UserSummary},
+    pagination::Pagination,
+};
+
+// A user to be created; the password is already hashed
+pub struct NewUser<'a> {
+    pub username: &'a str,
+    pub email: &'a str,
+    pub password_hash: &'a str,
+    pub role: &'a str,
+}
+
+// Storage for user accounts, so handlers don't depend on a particular database
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, AppError>;
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, AppError>;
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, AppError>;
+    // Whether any account already has this username or email
+    async fn username_exists(&self, username: &str) -> Result<bool, AppError>;
+    async fn email_exists(&self, email: &str) -> Result<bool, AppError>;
+    // Whether any account has a username that looks like this one (same confusables skeleton)
+    async fn confusable_username_exists(&self, username: &str) -> Result<bool, AppError>;
+    // Create the user and return its id. A username or email already held by another account is
+    // reported as `UsernameTaken` or `EmailRegistered` when the database enforces uniqueness.
+    async fn insert(&self, user: NewUser<'_>) -> Result<i32, AppError>;
+    async fn record_login(&self, user_id: i32) -> Result<(), AppError>;
+    // Note that the user has shown they receive mail at their address, unless already noted
+    async fn mark_email_verified(&self, user_id: i32, at: DateTime<Utc>) -> Result<(), AppError>;
+    // Where and when the user last logged in from a location we could resolve
+    async fn last_located_login(&self, user_id: i32) -> Result<Option<(GeoPoint, DateTime<Utc>)>, AppError>;
+    async fn record_login_location(&self, user_id: i32, location: GeoPoint, at: DateTime<Utc>) -> Result<(), AppError>;
+    async fn update_password(&self, user_id: i32, password_hash: &str, changed_at: DateTime<Utc>) -> Result<(), AppError>;
+    // One page of users in id order, with the total count
+    async fn list(&self, page: Pagination) -> Result<(Vec<UserSummary>, i64), AppError>;
+    // Every user changed at or after `since` (or all of them) in id order, as JSON rows without the
+    // password hash, read from the database as the stream is consumed
+    fn export(&self, since: Option<DateTime<Utc>>) -> BoxStream<'_, Result<serde_json::Value, AppError>>;
+    // Store skeletons for accounts created before they were recorded; returns how many were filled
+    async fn backfill_username_skeletons(&self) -> Result<usize, AppError>;
+}
+
+// Constraints on the `users` table, as named in init-db.sql
+const USERNAME_CONSTRAINT: &str = "users_username_key";
+const EMAIL_CONSTRAINT: &str = "users_email_key";
+const ROLE_CONSTRAINT: &str = "users_role_check";
+
+// Roles accepted by `users_role_check`
+pub const ROLES: [&str; 2] = ["user", "admin"];
+
+// Translate a constraint violation on a user column into the matching field-specific error. A
+// rejected role means the service and schema disagree, so it is reported rather than a 500.
+fn insert_error(error: sqlx::Error) -> AppError {
+    match error.as_database_error().and_then(|e| e.constraint()) {
+        Some(USERNAME_CONSTRAINT) => AppError::UsernameTaken,
+        Some(EMAIL_CONSTRAINT) => AppError::EmailRegistered,
+        Some(ROLE_CONSTRAINT) => AppError::InvalidRole(ROLES.join(", ")),
+        _ => AppError::Database(error),
+    }
+}
+
+// Users stored in the Postgres `users` table
+pub struct PgUserRepository {
+    pool: PgPool,
+}
+
+impl PgUserRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    // Look up a single user by `column`. When uniqueness isn't enforced for that column, a value
+    // shared by several accounts finds nobody rather than an arbitrary one of them.
+    async fn find_by<T>(&self, name: &str, column: &str, value: T) -> Result<Option<User>, AppError>
+    where
+        T: Send + for<'q> sqlx::Encode<'q, Postgres> + sqlx::Type<Postgres>,
+    {
+        let sql = format!(
+            "SELECT id, username, email, password_hash, role, password_changed_at, email_verified_at FROM users \
+             WHERE {} = $1 LIMIT 2",
+            column
+        );
+        let query = sqlx::query(&sql)
+            .bind(value)
+            .map(|row: PgRow| User {
+                id: row.get("id"),
+                username: row.get("username"),
+                email: row.get("email"),
+                password_hash: row.get("password_hash"),
+                role: row.get("role"),
+                password_changed_at: row.get("password_changed_at"),
+                email_verified_at: row.get("email_verified_at"),
+            })
+            .fetch_all(&self.pool);
+        let mut users = metrics::observe(name, query).await?;
+        Ok(if users.len() == 1 { users.pop() } else { None })
+    }
+
+    async fn exists_by(&self, name: &str, column: &str, value: &str) -> Result<bool, AppError> {
+        let sql = format!("SELECT 1 FROM users WHERE {} = $1 LIMIT 1", column);
+        let query = sqlx::query(&sql).bind(value).fetch_optional(&self.pool);
+        Ok(metrics::observe(name, query).await?.is_some())
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn find_by_username(&self, username: &str) -> Result<Option<User>, AppError> {
+        self.find_by("users.find_by_username", "username", username).await
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
+        self.find_by("users.find_by_email", "email", email).await
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, AppError> {
+        self.find_by("users.find_by_id", "id", id).await
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool, AppError> {
+        self.exists_by("users.username_exists", "username", username).await
+    }
+
+    async fn email_exists(&self, email: &str) -> Result<bool, AppError> {
+        self.exists_by("users.email_exists", "email", email).await
+    }
+
+    async fn confusable_username_exists(&self, username: &str) -> Result<bool, AppError> {
+        let skeleton = confusables::skeleton(username);
+        self.exists_by("users.confusable_username_exists", "username_skeleton", &skeleton).await
+    }
+
+    async fn insert(&self, user: NewUser<'_>) -> Result<i32, AppError> {
+        let query = sqlx::query(
+            "INSERT INTO users (username, username_skeleton, email, password_hash, role) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING id"
+        )
+        .bind(user.username)
+        .bind(confusables::skeleton(user.username))
+        .bind(user.email)
+        .bind(user.password_hash)
+        .bind(user.role)
+        .fetch_one(&self.pool);
+        let row = metrics::observe("users.insert", query).await.map_err(insert_error)?;
+        Ok(row.get("id"))
+    }
+
+    async fn record_login(&self, user_id: i32) -> Result<(), AppError> {
+        let update = sqlx::query("UPDATE users SET last_login_at = NOW() WHERE id = $1")
+            .bind(user_id)
+            .execute(&self.pool);
+        metrics::observe("users.record_login", update).await?;
+        Ok(())
+    }
+
+    async fn last_located_login(&self, user_id: i32) -> Result<Option<(GeoPoint, DateTime<Utc>)>, AppError> {
+        let query = sqlx::query(
+            "SELECT last_login_latitude, last_login_longitude, last_located_login_at FROM users \
+             WHERE id = $1 AND last_located_login_at IS NOT NULL"
+        )
+        .bind(user_id)
+        .map(|row: PgRow| {
+            let location = GeoPoint {
+                latitude: row.get("last_login_latitude"),
+                longitude: row.get("last_login_longitude"),
+            };
+            (location, row.get("last_located_login_at"))
+        })
+        .fetch_optional(&self.pool);
+        Ok(metrics::observe("users.last_located_login", query).await?)
+    }
+
+    async fn mark_email_verified(&self, user_id: i32, at: DateTime<Utc>) -> Result<(), AppError> {
+        let update = sqlx::query("UPDATE users SET email_verified_at = $1 WHERE id = $2 AND email_verified_at IS NULL")
+            .bind(at)
+            .bind(user_id)
+            .execute(&self.pool);
+        metrics::observe("users.mark_email_verified", update).await?;
+        Ok(())
+    }
+
+    async fn record_login_location(&self, user_id: i32, location: GeoPoint, at: DateTime<Utc>) -> Result<(), AppError> {
+        let update = sqlx::query(
+            "UPDATE users SET last_login_latitude = $1, last_login_longitude = $2, last_located_login_at = $3 WHERE id = $4"
+        )
+        .bind(location.latitude)
+        .bind(location.longitude)
+        .bind(at)
+        .bind(user_id)
+        .execute(&self.pool);
+        metrics::observe("users.record_login_location", update).await?;
+        Ok(())
+    }
+
+    async fn update_password(&self, user_id: i32, password_hash: &str, changed_at: DateTime<Utc>) -> Result<(), AppError> {
+        let update = sqlx::query("UPDATE users SET password_hash = $1, password_changed_at = $2 WHERE id = $3")
+            .bind(password_hash)
+            .bind(changed_at)
+            .bind(user_id)
+            .execute(&self.pool);
+        metrics::observe("users.update_password", update).await?;
+        Ok(())
+    }
+
+    fn export(&self, since: Option<DateTime<Utc>>) -> BoxStream<'_, Result<serde_json::Value, AppError>> {
+        sqlx::query(
+            "SELECT to_jsonb(u) - 'password_hash' AS user FROM users u \
+             WHERE $1::timestamptz IS NULL OR u.updated_at >= $1 ORDER BY u.id"
+        )
+        .bind(since)
+        .fetch(&self.pool)
+        .map(|row| row.map(|row| row.get("user")).map_err(AppError::from))
+        .boxed()
+    }
+
+    async fn list(&self, page: Pagination) -> Result<(Vec<UserSummary>, i64), AppError> {
+        let query = sqlx::query(
+            "SELECT id, username, email, role, last_login_at FROM users ORDER BY id LIMIT $1 OFFSET $2"
+        )
+        .bind(i64::from(page.limit))
+        .bind(i64::from(page.offset))
+        .map(|row: PgRow| UserSummary {
+            id: row.get("id"),
+            username: row.get("username"),
+            email: row.get("email"),
+            role: row.get("role"),
+            last_login_at: row.get("last_login_at"),
+        })
+        .fetch_all(&self.pool);
+        let users = metrics::observe("users.list", query).await?;
+
+        let count = sqlx::query("SELECT COUNT(*) AS total FROM users").fetch_one(&self.pool);
+        let total: i64 = metrics::observe("users.count", count).await?.get("total");
+        Ok((users, total))
+    }
+
+    async fn backfill_username_skeletons(&self) -> Result<usize, AppError> {
+        let query = sqlx::query("SELECT id, username FROM users WHERE username_skeleton IS NULL")
+            .map(|row: PgRow| (row.get::<i32, _>("id"), row.get::<String, _>("username")))
+            .fetch_all(&self.pool);
+        let users = metrics::observe("users.missing_skeletons", query).await?;
+        for (id, username) in &users {
+            let update = sqlx::query("UPDATE users SET username_skeleton = $1 WHERE id = $2")
+                .bind(confusables::skeleton(username))
+                .bind(id)
+                .execute(&self.pool);
+            metrics::observe("users.set_skeleton", update).await?;
+        }
+        Ok(users.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::{borrow::Cow, fmt};
+
+    // A check violation as Postgres reports it, without needing a database
+    #[derive(Debug)]
+    struct CheckViolation(&'static str);
+
+    impl fmt::Display for CheckViolation {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "new row for relation \"users\" violates check constraint \"{}\"", self.0)
+        }
+    }
+
+    impl std::error::Error for CheckViolation {}
+
+    impl DatabaseError for CheckViolation {
+        fn message(&self) -> &str {
+            "check constraint violated"
+        }
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed("23514"))
+        }
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+        fn constraint(&self) -> Option<&str> {
+            Some(self.0)
+        }
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::CheckViolation
+        }
+    }
+
+    #[test]
+    fn maps_role_check_violations_to_invalid_role() {
+        let error = insert_error(sqlx::Error::Database(Box::new(CheckViolation(ROLE_CONSTRAINT))));
+        assert_eq!(error.status(), axum::http::StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.code(), "invalid_role");
+        assert_eq!(error.to_string(), "Invalid role; allowed roles are: user, admin");
+
+        // Other check constraints remain database errors
+        let error = insert_error(sqlx::Error::Database(Box::new(CheckViolation("users_other_check"))));
+        assert_eq!(error.code(), "database_error");
+    }
+}
@@ -1,17 +1,37 @@
 -- Create users table for authentication
 CREATE TABLE IF NOT EXISTS users (
     id SERIAL PRIMARY KEY,
-    username VARCHAR(50) UNIQUE NOT NULL,
-    email VARCHAR(100) UNIQUE NOT NULL,
+    username VARCHAR(50) NOT NULL,
+    -- Confusables skeleton of the username, for BLOCK_CONFUSABLE_USERNAMES
+    username_skeleton TEXT,
+    email VARCHAR(100) NOT NULL,
     password_hash VARCHAR(255) NOT NULL,
     role VARCHAR(20) NOT NULL DEFAULT 'user',
+    last_login_at TIMESTAMPTZ,
+    -- Last password change after registration, for MIN_PASSWORD_AGE_HOURS
+    password_changed_at TIMESTAMPTZ,
+    -- When the user proved they receive mail at `email` (by redeeming an emailed login code), for
+    -- UNVERIFIED_EMAIL_POLICY
+    email_verified_at TIMESTAMPTZ,
+    -- Location of the last GeoIP-located login, for GEO_VELOCITY_ACTION
+    last_login_latitude DOUBLE PRECISION,
+    last_login_longitude DOUBLE PRECISION,
+    last_located_login_at TIMESTAMPTZ,
     created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    -- Match UNIQUE_USERNAME / UNIQUE_EMAIL; the service maps violations to field-specific conflicts
+    -- by these names. See database/migrations/ to drop one when relaxing its policy.
+    CONSTRAINT users_username_key UNIQUE (username),
+    CONSTRAINT users_email_key UNIQUE (email),
+    -- Roles the service issues; keep in step with `users::ROLES`
+    CONSTRAINT users_role_check CHECK (role IN ('user', 'admin'))
 );
 
 -- Create index on username and email for faster lookups
 CREATE INDEX IF NOT EXISTS idx_users_username ON users(username);
 CREATE INDEX IF NOT EXISTS idx_users_email ON users(email);
+CREATE INDEX IF NOT EXISTS idx_users_username_skeleton ON users(username_skeleton);
+CREATE INDEX IF NOT EXISTS idx_users_last_login_at ON users(last_login_at);
 
 -- Update the updated_at column on every update
 CREATE OR REPLACE FUNCTION update_updated_at_column()
@@ -27,6 +47,90 @@ CREATE TRIGGER update_users_updated_at
     FOR EACH ROW 
     EXECUTE FUNCTION update_updated_at_column();
 
+-- Create registry of OAuth clients allowed to request tokens
+CREATE TABLE IF NOT EXISTS oauth_clients (
+    client_id VARCHAR(100) PRIMARY KEY,
+    audience VARCHAR(255),
+    client_secret_hash VARCHAR(255),
+    scopes TEXT,
+    access_ttl_seconds INTEGER,
+    refresh_enabled BOOLEAN,
+    refresh_ttl_seconds INTEGER,
+    refresh_rotation BOOLEAN,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Rotating refresh tokens, stored hashed and grouped into per-login families
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    token_hash VARCHAR(64) UNIQUE NOT NULL,
+    family_id VARCHAR(64) NOT NULL,
+    scope TEXT,
+    audience VARCHAR(255),
+    acr VARCHAR(50),
+    auth_time TIMESTAMPTZ,
+    amr TEXT,
+    client_id VARCHAR(100),
+    fingerprint VARCHAR(64),
+    jti VARCHAR(64),
+    expires_at TIMESTAMPTZ NOT NULL,
+    absolute_expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family_id ON refresh_tokens(family_id);
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_jti ON refresh_tokens(jti);
+
+-- One-time codes for EMAIL_LOGIN_ENABLED, stored hashed; each is used at most once
+CREATE TABLE IF NOT EXISTS login_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_codes_user_id ON login_codes(user_id);
+
+-- One-time codes for VERIFY_EMAIL_BEFORE_REGISTER, bound to the address being registered
+CREATE TABLE IF NOT EXISTS registration_codes (
+    id SERIAL PRIMARY KEY,
+    email VARCHAR(100) NOT NULL,
+    code_hash VARCHAR(64) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_registration_codes_email ON registration_codes(email);
+
+-- Opaque handles issued alongside JWT access tokens with SESSION_HANDLES_ENABLED, stored hashed
+CREATE TABLE IF NOT EXISTS session_handles (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    handle_hash VARCHAR(64) UNIQUE NOT NULL,
+    jti VARCHAR(64) NOT NULL,
+    scope TEXT,
+    audience VARCHAR(255),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_session_handles_jti ON session_handles(jti);
+
+-- Runtime switches set through the admin API; flags without a row use their configured default
+CREATE TABLE IF NOT EXISTS feature_flags (
+    name VARCHAR(100) PRIMARY KEY,
+    enabled BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
 -- Create products table
 CREATE TABLE IF NOT EXISTS products (
     id INTEGER PRIMARY KEY,
@@ -0,0 +1,7 @@
+-- Per-client token lifetimes and refresh behaviour; NULL keeps the global setting. Refresh tokens
+-- record their client so its policy also applies on refresh.
+ALTER TABLE oauth_clients ADD COLUMN IF NOT EXISTS access_ttl_seconds INTEGER;
+ALTER TABLE oauth_clients ADD COLUMN IF NOT EXISTS refresh_enabled BOOLEAN;
+ALTER TABLE oauth_clients ADD COLUMN IF NOT EXISTS refresh_ttl_seconds INTEGER;
+ALTER TABLE oauth_clients ADD COLUMN IF NOT EXISTS refresh_rotation BOOLEAN;
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS client_id VARCHAR(100);
@@ -0,0 +1,3 @@
+-- Record when each user's email address was verified, for UNVERIFIED_EMAIL_POLICY. Existing users
+-- start out unverified.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS email_verified_at TIMESTAMPTZ;
@@ -0,0 +1,7 @@
+-- Runtime switches set through the admin API (GET/PUT /api/auth/admin/flags); flags without a
+-- row use their configured default
+CREATE TABLE IF NOT EXISTS feature_flags (
+    name VARCHAR(100) PRIMARY KEY,
+    enabled BOOLEAN NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,4 @@
+-- Record each user's most recent successful login, returned by /api/auth/me
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_users_last_login_at ON users(last_login_at);
@@ -0,0 +1,15 @@
+-- One-time codes for EMAIL_LOGIN_ENABLED, and the authentication methods of the login a refresh
+-- token family descends from, so refreshed tokens keep their `amr` claim
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS amr TEXT;
+
+CREATE TABLE IF NOT EXISTS login_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_codes_user_id ON login_codes(user_id);
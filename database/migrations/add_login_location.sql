@@ -0,0 +1,4 @@
+-- Remember where each user last logged in from, for GEO_VELOCITY_ACTION
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_latitude DOUBLE PRECISION;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_login_longitude DOUBLE PRECISION;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS last_located_login_at TIMESTAMPTZ;
@@ -0,0 +1,10 @@
+-- Registry of OAuth clients, plus the bcrypt secret and space-delimited scopes the client credentials
+-- grant checks. Run this before add_client_token_policy.sql.
+CREATE TABLE IF NOT EXISTS oauth_clients (
+    client_id VARCHAR(100) PRIMARY KEY,
+    audience VARCHAR(255),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+ALTER TABLE oauth_clients ADD COLUMN IF NOT EXISTS client_secret_hash VARCHAR(255);
+ALTER TABLE oauth_clients ADD COLUMN IF NOT EXISTS scopes TEXT;
@@ -0,0 +1,3 @@
+-- Record when each password was last changed, for MIN_PASSWORD_AGE_HOURS. Existing rows stay
+-- NULL, so their next change is never blocked.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_changed_at TIMESTAMPTZ;
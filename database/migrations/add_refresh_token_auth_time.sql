@@ -0,0 +1,3 @@
+-- Carry the original login time across refreshes, for SENSITIVE_OP_MAX_AGE. Tokens refreshed from
+-- existing rows have no auth_time and are judged by their issue time instead.
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS auth_time TIMESTAMPTZ;
@@ -0,0 +1,3 @@
+-- Record the client each refresh token was issued to, for REFRESH_BINDING. Existing tokens stay
+-- NULL and are never rejected for a fingerprint mismatch.
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS fingerprint VARCHAR(64);
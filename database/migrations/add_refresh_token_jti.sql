@@ -0,0 +1,5 @@
+-- Record the session handle `jti` of the login that started each refresh token family, so
+-- revoking the handle revokes the family. Existing tokens stay NULL and keep refreshing.
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS jti VARCHAR(64);
+
+CREATE INDEX IF NOT EXISTS idx_refresh_tokens_jti ON refresh_tokens(jti);
@@ -0,0 +1,13 @@
+-- One-time codes proving ownership of an email address before registering it, for
+-- VERIFY_EMAIL_BEFORE_REGISTER. There is no user yet, so codes are bound to the address.
+CREATE TABLE IF NOT EXISTS registration_codes (
+    id SERIAL PRIMARY KEY,
+    email VARCHAR(100) NOT NULL,
+    code_hash VARCHAR(64) NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_registration_codes_email ON registration_codes(email);
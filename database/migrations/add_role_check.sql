@@ -0,0 +1,3 @@
+-- Reject roles the service doesn't know; violations are reported as 422 invalid_role. Fails if
+-- any existing row has another role, which should be corrected first.
+ALTER TABLE users ADD CONSTRAINT users_role_check CHECK (role IN ('user', 'admin'));
@@ -0,0 +1,14 @@
+-- Opaque session handles issued alongside JWT access tokens with SESSION_HANDLES_ENABLED
+CREATE TABLE IF NOT EXISTS session_handles (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    handle_hash VARCHAR(64) UNIQUE NOT NULL,
+    jti VARCHAR(64) NOT NULL,
+    scope TEXT,
+    audience VARCHAR(255),
+    expires_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_session_handles_jti ON session_handles(jti);
@@ -0,0 +1,4 @@
+-- Store each username's confusables skeleton for BLOCK_CONFUSABLE_USERNAMES. Existing rows are
+-- filled in by the service at startup when the flag is enabled.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS username_skeleton TEXT;
+CREATE INDEX IF NOT EXISTS idx_users_username_skeleton ON users(username_skeleton);
@@ -0,0 +1,6 @@
+-- Allow several accounts to share an email address. Apply together with UNIQUE_EMAIL=false.
+-- Lookups by email (e.g. token exchange subject matching) then find no account for a shared address.
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_email_key;
+
+-- To restore (fails while duplicates exist):
+-- ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
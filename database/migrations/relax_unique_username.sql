@@ -0,0 +1,6 @@
+-- Allow several accounts to share a username. Apply together with UNIQUE_USERNAME=false.
+-- Usernames are the login identifier and token subject, so a shared username can no longer log in.
+ALTER TABLE users DROP CONSTRAINT IF EXISTS users_username_key;
+
+-- To restore (fails while duplicates exist):
+-- ALTER TABLE users ADD CONSTRAINT users_username_key UNIQUE (username);